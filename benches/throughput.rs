@@ -25,6 +25,12 @@ const PACKETS: &[&[u8]] = &[
 /// Run and instance of quilkin that sends and received data
 /// from the given address.
 fn run_quilkin(port: u16, endpoint: SocketAddr) {
+    run_quilkin_with_batch_size(port, endpoint, quilkin::cli::Proxy::default().batch_size);
+}
+
+/// Run an instance of quilkin that receives and processes up to `batch_size`
+/// packets at once.
+fn run_quilkin_with_batch_size(port: u16, endpoint: SocketAddr, batch_size: usize) {
     std::thread::spawn(move || {
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let config = Arc::new(quilkin::Config::default());
@@ -34,6 +40,7 @@ fn run_quilkin(port: u16, endpoint: SocketAddr) {
 
         let proxy = quilkin::cli::Proxy {
             port,
+            batch_size,
             ..<_>::default()
         };
 
@@ -240,5 +247,70 @@ fn readwrite_benchmark(c: &mut Criterion) {
     stop.store(true, atomic::Ordering::Relaxed);
 }
 
-criterion_group!(benches, readwrite_benchmark, throughput_benchmark);
+const UNBATCHED_QUILKIN_PORT: u16 = 9005;
+static UNBATCHED_SERVER_INIT: Lazy<()> = Lazy::new(|| {
+    run_quilkin_with_batch_size(
+        UNBATCHED_QUILKIN_PORT,
+        FEEDBACK_LOOP_ADDR.parse().unwrap(),
+        1,
+    );
+});
+
+const BATCHED_QUILKIN_PORT: u16 = 9006;
+static BATCHED_SERVER_INIT: Lazy<()> = Lazy::new(|| {
+    run_quilkin(BATCHED_QUILKIN_PORT, FEEDBACK_LOOP_ADDR.parse().unwrap());
+});
+
+/// The number of packets sent at once by the burst benchmark.
+const BURST_SIZE: usize = 32;
+
+/// Compares processing bursts of packets one at a time with processing them
+/// in batches.
+fn burst_benchmark(c: &mut Criterion) {
+    Lazy::force(&FEEDBACK_LOOP);
+    Lazy::force(&UNBATCHED_SERVER_INIT);
+    Lazy::force(&BATCHED_SERVER_INIT);
+    // Sleep to give the servers some time to warm-up.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let mut packet = [0; MESSAGE_SIZE];
+
+    let mut group = c.benchmark_group("burst");
+    for message in PACKETS {
+        group.sample_size(NUMBER_OF_PACKETS / BURST_SIZE);
+        group.sampling_mode(criterion::SamplingMode::Flat);
+        group.throughput(criterion::Throughput::Bytes(
+            (message.len() * BURST_SIZE) as u64,
+        ));
+
+        for (name, port) in [
+            ("unbatched", UNBATCHED_QUILKIN_PORT),
+            ("batched", BATCHED_QUILKIN_PORT),
+        ] {
+            let addr = (Ipv4Addr::LOCALHOST, port);
+            group.bench_with_input(
+                BenchmarkId::new(name, format!("{} bytes", message.len())),
+                &message,
+                |b, message| {
+                    b.iter(|| {
+                        for _ in 0..BURST_SIZE {
+                            socket.send_to(message, addr).unwrap();
+                        }
+                        for _ in 0..BURST_SIZE {
+                            socket.recv_from(&mut packet).unwrap();
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    readwrite_benchmark,
+    throughput_benchmark,
+    burst_benchmark
+);
 criterion_main!(benches);
@@ -37,13 +37,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "proto/quilkin/filters/concatenate_bytes/v1alpha1/concatenate_bytes.proto",
         "proto/quilkin/filters/debug/v1alpha1/debug.proto",
         "proto/quilkin/filters/drop/v1alpha1/drop.proto",
+        "proto/quilkin/filters/encrypt/v1alpha1/encrypt.proto",
         "proto/quilkin/filters/firewall/v1alpha1/firewall.proto",
+        "proto/quilkin/filters/global_rate_limit/v1alpha1/global_rate_limit.proto",
         "proto/quilkin/filters/load_balancer/v1alpha1/load_balancer.proto",
         "proto/quilkin/filters/local_rate_limit/v1alpha1/local_rate_limit.proto",
         "proto/quilkin/filters/match/v1alpha1/match.proto",
         "proto/quilkin/filters/pass/v1alpha1/pass.proto",
+        "proto/quilkin/filters/pcap/v1alpha1/pcap.proto",
         "proto/quilkin/filters/token_router/v1alpha1/token_router.proto",
         "proto/quilkin/filters/timestamp/v1alpha1/timestamp.proto",
+        "proto/quilkin/service/rate_limit/v1alpha1/rate_limit.proto",
         "proto/udpa/xds/core/v3/resource_name.proto",
     ]
     .iter()
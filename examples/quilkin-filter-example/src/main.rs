@@ -60,12 +60,12 @@ struct Greet {
 impl Filter for Greet {
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
         ctx.contents
-            .splice(0..0, format!("{} ", self.config.greeting).into_bytes());
+            .prepend(format!("{} ", self.config.greeting).as_bytes());
         Some(())
     }
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
         ctx.contents
-            .splice(0..0, format!("{} ", self.config.greeting).into_bytes());
+            .prepend(format!("{} ", self.config.greeting).as_bytes());
         Some(())
     }
 }
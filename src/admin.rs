@@ -15,28 +15,35 @@
  */
 
 mod health;
+mod logging;
+mod pcap;
+mod sessions;
 
 use std::convert::Infallible;
 use std::sync::Arc;
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server as HyperServer, StatusCode};
+use tokio::sync::watch;
 
 use self::health::Health;
-use crate::config::Config;
+use crate::{config::Config, proxy::SessionRegistry, xds::NodeRegistry};
 
 pub const PORT: u16 = 8000;
 
 /// Define which mode Quilkin is in.
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum Mode {
-    Proxy,
-    Xds,
+    /// The proxy's sessions, and the directory that packet captures are
+    /// written to, if captures are enabled.
+    Proxy(SessionRegistry, Option<std::path::PathBuf>),
+    Xds(NodeRegistry),
 }
 
 pub fn server(
     mode: Mode,
     config: Arc<Config>,
+    shutdown_rx: watch::Receiver<()>,
     address: Option<std::net::SocketAddr>,
 ) -> tokio::task::JoinHandle<Result<(), hyper::Error>> {
     let address = address.unwrap_or_else(|| (std::net::Ipv6Addr::UNSPECIFIED, PORT).into());
@@ -46,13 +53,21 @@ pub fn server(
     let make_svc = make_service_fn(move |_conn| {
         let config = config.clone();
         let health = health.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        let mode = mode.clone();
         async move {
             let config = config.clone();
             let health = health.clone();
             Ok::<_, Infallible>(service_fn(move |req| {
                 let config = config.clone();
                 let health = health.clone();
-                async move { Ok::<_, Infallible>(handle_request(req, mode, config, health)) }
+                let shutdown_rx = shutdown_rx.clone();
+                let mode = mode.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        handle_request(req, mode, config, health, shutdown_rx).await,
+                    )
+                }
             }))
         }
     });
@@ -60,19 +75,50 @@ pub fn server(
     tokio::spawn(HyperServer::bind(&address).serve(make_svc))
 }
 
-fn handle_request(
+async fn handle_request(
     request: Request<Body>,
     mode: Mode,
     config: Arc<Config>,
     health: Health,
+    shutdown_rx: watch::Receiver<()>,
 ) -> Response<Body> {
     match (request.method(), request.uri().path()) {
         (&Method::GET, "/metrics") => collect_metrics(),
         (&Method::GET, "/live" | "/livez") => health.check_healthy(),
         (&Method::GET, "/ready" | "/readyz") => match mode {
-            Mode::Proxy => check_proxy_readiness(&config),
-            Mode::Xds => health.check_healthy(),
+            Mode::Proxy(sessions, _) => check_proxy_readiness(&config, &sessions, &shutdown_rx),
+            Mode::Xds(_) => health.check_healthy(),
         },
+        (&Method::GET | &Method::DELETE, "/sessions") => match mode {
+            Mode::Proxy(sessions, _) => self::sessions::handle_request(&request, &sessions),
+            Mode::Xds(_) => not_found(),
+        },
+        (&Method::GET | &Method::POST | &Method::DELETE, "/pcap") => match mode {
+            Mode::Proxy(_, capture_dir) => {
+                self::pcap::handle_request(request, capture_dir.as_deref()).await
+            }
+            Mode::Xds(_) => not_found(),
+        },
+        (&Method::GET, "/nodes") => match mode {
+            Mode::Xds(nodes) => match serde_json::to_string(&nodes.list()) {
+                Ok(body) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header(
+                        "Content-Type",
+                        hyper::header::HeaderValue::from_static("application/json"),
+                    )
+                    .body(Body::from(body))
+                    .unwrap(),
+                Err(err) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(format!("failed to serialize nodes: {err}")))
+                    .unwrap(),
+            },
+            Mode::Proxy(..) => not_found(),
+        },
+        (&Method::GET | &Method::PUT | &Method::DELETE, "/log") => {
+            self::logging::handle_request(request, crate::logging::filter()).await
+        }
         (&Method::GET, "/config") => match serde_json::to_string(&config) {
             Ok(body) => Response::builder()
                 .status(StatusCode::OK)
@@ -87,16 +133,27 @@ fn handle_request(
                 .body(Body::from(format!("failed to create config dump: {err}")))
                 .unwrap(),
         },
-        (_, _) => {
-            let mut response = Response::new(Body::empty());
-            *response.status_mut() = StatusCode::NOT_FOUND;
-            response
-        }
+        (_, _) => not_found(),
     }
 }
 
-fn check_proxy_readiness(config: &Config) -> Response<Body> {
-    if config.clusters.load().endpoints().count() > 0 {
+fn not_found() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+fn check_proxy_readiness(
+    config: &Config,
+    sessions: &SessionRegistry,
+    shutdown_rx: &watch::Receiver<()>,
+) -> Response<Body> {
+    // A proxy that is shutting down (e.g. draining sessions) shouldn't be
+    // sent any new traffic, and neither should one that hasn't bound its
+    // sockets yet, whose sessions aren't registered until it has.
+    let shutting_down = shutdown_rx.has_changed().unwrap_or(true);
+    let listening = !sessions.maps().is_empty();
+    if !shutting_down && listening && config.clusters.load().endpoints().count() > 0 {
         return Response::new("ok".into());
     }
 
@@ -135,6 +192,7 @@ mod tests {
     use super::*;
     use crate::cluster::ClusterMap;
     use crate::endpoint::Endpoint;
+    use crate::proxy::SessionMap;
 
     #[tokio::test]
     async fn collect_metrics() {
@@ -142,12 +200,14 @@ mod tests {
         assert_eq!(response.status(), hyper::StatusCode::OK);
     }
 
-    #[test]
-    fn check_proxy_readiness() {
+    #[tokio::test]
+    async fn check_proxy_readiness() {
         let config = Config::default();
+        let sessions = SessionRegistry::default();
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
         assert_eq!(config.clusters.load().endpoints().count(), 0);
 
-        let response = super::check_proxy_readiness(&config);
+        let response = super::check_proxy_readiness(&config, &sessions, &shutdown_rx);
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
 
         let cluster = ClusterMap::new_with_default_cluster(vec![Endpoint::new(
@@ -155,7 +215,20 @@ mod tests {
         )]);
         config.clusters.store(Arc::new(cluster));
 
-        let response = super::check_proxy_readiness(&config);
+        // Endpoints alone aren't enough, the proxy has to be listening.
+        let response = super::check_proxy_readiness(&config, &sessions, &shutdown_rx);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        sessions.register(SessionMap::new(
+            std::time::Duration::from_secs(60),
+            std::time::Duration::from_secs(60),
+        ));
+        let response = super::check_proxy_readiness(&config, &sessions, &shutdown_rx);
         assert_eq!(response.status(), StatusCode::OK);
+
+        // Once shutdown has started, the proxy should no longer be ready.
+        shutdown_tx.send(()).unwrap();
+        let response = super::check_proxy_readiness(&config, &sessions, &shutdown_rx);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 }
@@ -0,0 +1,177 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{sync::Arc, time::Duration};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::logging::LogFilter;
+
+/// Returns (`GET`), replaces (`PUT`) or resets (`DELETE`) the log filter.
+/// Replacing the filter takes its directives as the body, and an optional
+/// `timeout` query parameter, in seconds, after which the filter is reset.
+pub async fn handle_request(
+    request: Request<Body>,
+    filter: Option<&Arc<LogFilter>>,
+) -> Response<Body> {
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return response(StatusCode::NOT_FOUND, "logging is disabled".into()),
+    };
+
+    match *request.method() {
+        Method::PUT => {
+            let timeout = match parse_timeout(request.uri().query()) {
+                Ok(timeout) => timeout,
+                Err(error) => return response(StatusCode::BAD_REQUEST, error),
+            };
+
+            let body = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(body) => body,
+                Err(error) => {
+                    return response(
+                        StatusCode::BAD_REQUEST,
+                        format!("failed to read body: {error}"),
+                    )
+                }
+            };
+
+            let directives = match std::str::from_utf8(&body) {
+                Ok(directives) => directives.trim(),
+                Err(error) => {
+                    return response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid log filter: {error}"),
+                    )
+                }
+            };
+
+            if let Err(error) = filter.set(directives, timeout) {
+                return response(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid log filter: {error}"),
+                );
+            }
+        }
+        Method::DELETE => {
+            if let Err(error) = filter.reset() {
+                return response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("failed to reset log filter: {error}"),
+                );
+            }
+        }
+        _ => {}
+    }
+
+    match serde_json::to_string(&filter.status()) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                hyper::header::HeaderValue::from_static("application/json"),
+            )
+            .body(Body::from(body))
+            .unwrap(),
+        Err(error) => response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to serialize log filter: {error}"),
+        ),
+    }
+}
+
+fn parse_timeout(query: Option<&str>) -> Result<Option<Duration>, String> {
+    let mut timeout = None;
+
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match &*key {
+            "timeout" => {
+                let seconds = value
+                    .parse()
+                    .map_err(|error| format!("invalid `timeout`: {error}"))?;
+                timeout = Some(Duration::from_secs(seconds));
+            }
+            _ => return Err(format!("unknown query parameter `{key}`")),
+        }
+    }
+
+    Ok(timeout)
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let response = handle_request(request(Method::GET, "/log", ""), None).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[tokio::test]
+    async fn set_and_reset() {
+        let (filter, _subscriber) = LogFilter::test("quilkin=info");
+        let filter = Some(&filter);
+
+        let response = handle_request(request(Method::GET, "/log", ""), filter).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let default = body(response).await;
+        assert_eq!("quilkin=info", default["default"]);
+
+        let response = handle_request(request(Method::PUT, "/log", "quilkin=???"), filter).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let response = handle_request(
+            request(Method::PUT, "/log?timeout=soon", "quilkin=trace"),
+            filter,
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let response = handle_request(
+            request(Method::PUT, "/log?timeout=60", "quilkin=trace"),
+            filter,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status = body(response).await;
+        assert!(status["filter"].as_str().unwrap().contains("quilkin=trace"));
+        assert!(status["revert_at"].is_u64());
+
+        let response = handle_request(request(Method::DELETE, "/log", ""), filter).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(default, body(response).await);
+    }
+}
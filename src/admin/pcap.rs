@@ -0,0 +1,177 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::Path;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::pcap;
+
+/// Returns the status of (`GET`), starts (`POST`) or stops (`DELETE`) the
+/// proxy's packet capture. Starting a capture takes its JSON configuration as
+/// the body, with a file name rather than a path, which is written to in
+/// `capture_dir`, and replaces the current capture if there is one.
+pub async fn handle_request(request: Request<Body>, capture_dir: Option<&Path>) -> Response<Body> {
+    match *request.method() {
+        Method::POST => {
+            let capture_dir = match capture_dir {
+                Some(capture_dir) => capture_dir,
+                None => {
+                    return response(
+                        StatusCode::FORBIDDEN,
+                        "packet captures are disabled, start the proxy with `--capture-dir` to enable them".into(),
+                    )
+                }
+            };
+
+            let body = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(body) => body,
+                Err(error) => {
+                    return response(
+                        StatusCode::BAD_REQUEST,
+                        format!("failed to read body: {error}"),
+                    )
+                }
+            };
+
+            let config: pcap::Config = match serde_json::from_slice(&body) {
+                Ok(config) => config,
+                Err(error) => {
+                    return response(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid capture config: {error}"),
+                    )
+                }
+            };
+
+            match pcap::start(config, capture_dir) {
+                Ok(()) => status_response(pcap::status()),
+                Err(error) => response(
+                    StatusCode::BAD_REQUEST,
+                    format!("failed to start capture: {error}"),
+                ),
+            }
+        }
+        // Stopping waits for the capture's remaining packets to be written.
+        Method::DELETE => {
+            status_response(tokio::task::spawn_blocking(pcap::stop).await.ok().flatten())
+        }
+        _ => status_response(pcap::status()),
+    }
+}
+
+fn status_response(status: Option<pcap::Status>) -> Response<Body> {
+    let status = match status {
+        Some(status) => status,
+        None => return response(StatusCode::NOT_FOUND, "no capture is running".into()),
+    };
+
+    match serde_json::to_string(&status) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                hyper::header::HeaderValue::from_static("application/json"),
+            )
+            .body(Body::from(body))
+            .unwrap(),
+        Err(error) => response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to serialize capture status: {error}"),
+        ),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/pcap")
+            .body(body)
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn start_and_stop() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+
+        let capture_dir = Some(dir.path());
+
+        let response = handle_request(request(Method::POST, Body::from("{}")), capture_dir).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let config = serde_json::json!({
+            "path": "capture.pcapng",
+            "sources": ["192.0.2.0/24"],
+        });
+
+        // Captures are only written to the capture directory.
+        let response =
+            handle_request(request(Method::POST, Body::from(config.to_string())), None).await;
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let outside = serde_json::json!({ "path": dir.path().join("capture.pcapng") });
+        let response = handle_request(
+            request(Method::POST, Body::from(outside.to_string())),
+            capture_dir,
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let response = handle_request(
+            request(Method::POST, Body::from(config.to_string())),
+            capture_dir,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status = body(response).await;
+        assert_eq!(true, status["active"]);
+        assert_eq!(
+            serde_json::json!([dir.path().join("capture-00000.pcapng")]),
+            status["files"]
+        );
+
+        let client = "192.0.2.1:7000".parse().unwrap();
+        pcap::record(pcap::Stage::DownstreamReceive, &client, None, b"hello");
+
+        let response = handle_request(request(Method::GET, Body::empty()), capture_dir).await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = handle_request(request(Method::DELETE, Body::empty()), capture_dir).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let status = body(response).await;
+        assert_eq!(false, status["active"]);
+        assert_eq!(1, status["packets"]);
+
+        let response = handle_request(request(Method::GET, Body::empty()), capture_dir).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+}
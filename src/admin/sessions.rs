@@ -0,0 +1,242 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::{
+    endpoint::EndpointAddress,
+    proxy::{SessionKey, SessionRegistry},
+};
+
+/// Lists (`GET`) or closes (`DELETE`) the proxy's sessions, optionally
+/// filtered by the `source` and `dest` query parameters.
+pub fn handle_request(request: &Request<Body>, sessions: &SessionRegistry) -> Response<Body> {
+    let filter = match SessionFilter::from_query(request.uri().query()) {
+        Ok(filter) => filter,
+        Err(error) => return response(StatusCode::BAD_REQUEST, error),
+    };
+
+    if request.method() == Method::DELETE {
+        // Closing every session has to be done explicitly through a shutdown.
+        if filter.is_empty() {
+            return response(
+                StatusCode::BAD_REQUEST,
+                "closing sessions requires a `source` or `dest` filter".into(),
+            );
+        }
+
+        return match sessions.remove(|key| filter.matches(key)) {
+            0 => response(StatusCode::NOT_FOUND, "no matching sessions found".into()),
+            closed => json_response(&serde_json::json!({ "closed": closed })),
+        };
+    }
+
+    json_response(&sessions.list(|key| filter.matches(key)))
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                hyper::header::HeaderValue::from_static("application/json"),
+            )
+            .body(Body::from(body))
+            .unwrap(),
+        Err(error) => response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to serialize sessions: {error}"),
+        ),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// The query parameters used to select sessions. An address filter matches
+/// either the full address (e.g. `127.0.0.1:7000`) or only its host
+/// (e.g. `127.0.0.1`).
+#[derive(Debug, Default, PartialEq)]
+struct SessionFilter {
+    source: Option<String>,
+    dest: Option<String>,
+}
+
+impl SessionFilter {
+    fn from_query(query: Option<&str>) -> Result<Self, String> {
+        let mut filter = Self::default();
+
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match &*key {
+                "source" => filter.source = Some(value.into_owned()),
+                "dest" => filter.dest = Some(value.into_owned()),
+                _ => return Err(format!("unknown query parameter `{key}`")),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.source.is_none() && self.dest.is_none()
+    }
+
+    fn matches(&self, key: &SessionKey) -> bool {
+        let address_matches = |filter: &Option<String>, address: &EndpointAddress| {
+            filter.as_deref().map_or(true, |filter| {
+                address.to_string() == filter || address.host.to_string() == filter
+            })
+        };
+
+        address_matches(&self.source, &key.source) && address_matches(&self.dest, &key.dest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::{
+        endpoint::Endpoint,
+        proxy::{SessionArgs, SessionMap},
+        test_utils::create_socket,
+    };
+
+    async fn registry() -> SessionRegistry {
+        let sessions = SessionMap::new(Duration::from_secs(60), Duration::from_secs(60));
+        let socket = Arc::new(create_socket().await);
+
+        for (source, dest) in [
+            ("127.0.0.1:7000", "127.0.0.1:8000"),
+            ("127.0.0.1:7001", "127.0.0.1:8000"),
+            ("127.0.0.2:7000", "127.0.0.1:9000"),
+        ] {
+            let source: EndpointAddress = source.parse().unwrap();
+            let dest: EndpointAddress = dest.parse().unwrap();
+            let session = SessionArgs {
+                config: <_>::default(),
+                listener: None,
+                source: source.clone(),
+                downstream_socket: socket.clone(),
+                dest: Endpoint::new(dest.clone()),
+            }
+            .into_session()
+            .await
+            .unwrap();
+            sessions.insert((source, dest).into(), session);
+        }
+
+        let registry = SessionRegistry::default();
+        registry.register(sessions);
+        registry
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> serde_json::Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn filter() {
+        let key: SessionKey = (
+            "127.0.0.1:7000".parse().unwrap(),
+            "127.0.0.1:8000".parse().unwrap(),
+        )
+            .into();
+
+        let filter = SessionFilter::from_query(None).unwrap();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&key));
+
+        for (query, matches) in [
+            ("source=127.0.0.1", true),
+            ("source=127.0.0.1:7000", true),
+            ("source=127.0.0.1:7001", false),
+            ("dest=127.0.0.1:8000", true),
+            ("source=127.0.0.1&dest=127.0.0.2", false),
+        ] {
+            let filter = SessionFilter::from_query(Some(query)).unwrap();
+            assert_eq!(matches, filter.matches(&key), "{query}");
+        }
+
+        assert!(SessionFilter::from_query(Some("port=7000")).is_err());
+    }
+
+    #[tokio::test]
+    async fn list() {
+        let sessions = registry().await;
+
+        let response = handle_request(&request(Method::GET, "/sessions"), &sessions);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(3, body(response).await.as_array().unwrap().len());
+
+        let response = handle_request(
+            &request(Method::GET, "/sessions?dest=127.0.0.1:8000"),
+            &sessions,
+        );
+        let body = body(response).await;
+        let body = body.as_array().unwrap();
+        assert_eq!(2, body.len());
+        assert!(body
+            .iter()
+            .all(|session| session["dest"] == "127.0.0.1:8000"));
+        assert_eq!(0, body[0]["read"]["packets"]);
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let sessions = registry().await;
+
+        let response = handle_request(&request(Method::DELETE, "/sessions"), &sessions);
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let response = handle_request(
+            &request(Method::DELETE, "/sessions?source=127.0.0.3"),
+            &sessions,
+        );
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let response = handle_request(
+            &request(Method::DELETE, "/sessions?source=127.0.0.1"),
+            &sessions,
+        );
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(2, body(response).await["closed"]);
+
+        let remaining = sessions.list(|_| true);
+        assert_eq!(1, remaining.len());
+        assert_eq!(
+            "127.0.0.2:7000".parse::<EndpointAddress>().unwrap(),
+            remaining[0].source
+        );
+    }
+}
@@ -22,17 +22,25 @@ use std::{
 use clap::crate_version;
 use tokio::{signal, sync::watch};
 
-use crate::{admin::Mode, Config};
+use crate::{
+    admin::Mode,
+    logging::{LogFormat, LogOutput},
+    Config,
+};
 
 pub use self::{
     generate_config_schema::GenerateConfigSchema,
     manage::{Manage, Providers},
     proxy::Proxy,
+    replay::Replay,
+    validate::Validate,
 };
 
 pub mod generate_config_schema;
 pub mod manage;
 pub mod proxy;
+pub mod replay;
+pub mod validate;
 
 const ETC_CONFIG_PATH: &str = "/etc/quilkin/quilkin.yaml";
 const PORT_ENV_VAR: &str = "QUILKIN_PORT";
@@ -54,6 +62,16 @@ pub struct Cli {
     /// Whether Quilkin will report any results to stdout/stderr.
     #[clap(short, long, env)]
     pub quiet: bool,
+    /// The format that logs are written in.
+    #[clap(long, env = "QUILKIN_LOG_FORMAT", value_enum, default_value = "json")]
+    pub log_format: LogFormat,
+    /// Where logs are written to, either `stdout`, `stderr`, or the path of a
+    /// file that logs are appended to. The `replay` command writes to `stderr`
+    /// instead of `stdout`.
+    #[clap(long, env = "QUILKIN_LOG_OUTPUT", default_value = "stdout")]
+    pub log_output: LogOutput,
+    #[clap(flatten)]
+    pub otlp: crate::telemetry::Otlp,
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -64,14 +82,19 @@ pub enum Commands {
     Proxy(Proxy),
     GenerateConfigSchema(GenerateConfigSchema),
     Manage(Manage),
+    Validate(Validate),
+    Replay(Replay),
 }
 
 impl Commands {
     pub fn admin_mode(&self) -> Option<Mode> {
         match self {
-            Self::Proxy(_) => Some(Mode::Proxy),
-            Self::Manage(_) => Some(Mode::Xds),
-            Self::GenerateConfigSchema(_) => None,
+            Self::Proxy(proxy) => Some(Mode::Proxy(
+                proxy.sessions.clone(),
+                proxy.capture_dir.clone(),
+            )),
+            Self::Manage(manage) => Some(Mode::Xds(manage.nodes.clone())),
+            Self::GenerateConfigSchema(_) | Self::Validate(_) | Self::Replay(_) => None,
         }
     }
 }
@@ -81,15 +104,16 @@ impl Cli {
     /// arguments.
     #[tracing::instrument(skip_all)]
     pub async fn drive(self) -> crate::Result<()> {
-        if !self.quiet {
-            let env_filter = tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
-                .from_env_lossy();
-            tracing_subscriber::fmt()
-                .json()
-                .with_file(true)
-                .with_env_filter(env_filter)
-                .init();
+        // Replaying writes the outcome of each packet to stdout, so its logs
+        // are written to stderr instead.
+        let log_output = match (&self.command, &self.log_output) {
+            (Commands::Replay(_), LogOutput::Stdout) => &LogOutput::Stderr,
+            (_, log_output) => log_output,
+        };
+
+        // Spans are still exported when logging is disabled.
+        if !self.quiet || self.otlp.endpoint.is_some() {
+            crate::logging::init(self.log_format, (!self.quiet).then_some(log_output))?;
         }
 
         tracing::info!(
@@ -98,7 +122,27 @@ impl Cli {
             "Starting Quilkin"
         );
 
+        // Validation reads the config itself, so that it can report every error
+        // rather than only the first one.
+        if let Commands::Validate(validate) = &self.command {
+            return validate.validate(&self.config);
+        }
+
+        // Set before the config is read, as it can create `Pcap` filters.
+        if let Commands::Proxy(proxy) = &self.command {
+            crate::pcap::set_capture_dir(proxy.capture_dir.clone());
+        }
+
         let config = Arc::new(Self::read_config(self.config)?);
+
+        // Replaying only runs the filter chain, without any servers, and
+        // isn't retried so that packets aren't printed more than once.
+        if let Commands::Replay(replay) = &self.command {
+            return replay.replay(&config);
+        }
+
+        self.otlp.start(&config.id.load())?;
+        let (shutdown_tx, mut shutdown_rx) = watch::channel::<()>(());
         let _admin_task = self
             .command
             .admin_mode()
@@ -107,12 +151,11 @@ impl Cli {
                 tokio::spawn(crate::admin::server(
                     mode,
                     config.clone(),
+                    shutdown_rx.clone(),
                     self.admin_address,
                 ))
             });
 
-        let (shutdown_tx, mut shutdown_rx) = watch::channel::<()>(());
-
         #[cfg(target_os = "linux")]
         let mut sig_term_fut = signal::unix::signal(signal::unix::SignalKind::terminate())?;
 
@@ -133,6 +176,9 @@ impl Cli {
             shutdown_tx.send(()).ok();
         });
 
+        // The proxy drains its existing sessions once it has received the
+        // shutdown signal, so we wait for it to finish rather than exiting.
+        let wait_for_command = matches!(self.command, Commands::Proxy(_));
         let fut = tryhard::retry_fn({
             let shutdown_rx = shutdown_rx.clone();
             move || match self.command.clone() {
@@ -150,6 +196,8 @@ impl Cli {
                 Commands::GenerateConfigSchema(generator) => {
                     tokio::spawn(std::future::ready(generator.generate_config_schema()))
                 }
+                Commands::Validate(_) => unreachable!("validation runs before the config is read"),
+                Commands::Replay(_) => unreachable!("replaying runs before the admin server"),
             }
         })
         .retries(3)
@@ -160,10 +208,18 @@ impl Cli {
             }
         });
 
-        tokio::select! {
-            result = fut => result?,
-            _ = shutdown_rx.changed() => Ok(())
-        }
+        tokio::pin!(fut);
+        let result = tokio::select! {
+            result = &mut fut => result?,
+            _ = shutdown_rx.changed() => if wait_for_command {
+                fut.await?
+            } else {
+                Ok(())
+            }
+        };
+
+        crate::telemetry::shutdown().await;
+        result
     }
 
     /// Searches for the configuration file, and panics if not found.
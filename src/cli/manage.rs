@@ -35,9 +35,14 @@ pub struct Manage {
     /// for any provider endpoints discovered.
     #[clap(long, env = "QUILKIN_SUB_ZONE")]
     sub_zone: Option<String>,
+    #[clap(flatten)]
+    tls: crate::xds::ServerTls,
     /// The configuration source for a management server.
     #[clap(subcommand)]
     pub provider: Providers,
+    /// The status of the connected nodes, shared with the admin server.
+    #[clap(skip)]
+    pub(crate) nodes: crate::xds::NodeRegistry,
 }
 
 /// The available xDS source providers.
@@ -108,7 +113,7 @@ impl Manage {
         };
 
         tokio::select! {
-            result = crate::xds::server::spawn(self.port, config) => result,
+            result = crate::xds::server::spawn(self.port, config, self.tls.clone(), self.nodes.clone()) => result,
             result = provider_task => result.map_err(From::from).and_then(|result| result),
         }
     }
@@ -15,14 +15,20 @@
  */
 
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use tokio::{net::UdpSocket, sync::watch, time::Duration};
 use tonic::transport::Endpoint;
 
-use crate::{proxy::SessionMap, utils::net, xds::ResourceType, Config, Result};
+use crate::{
+    proxy::{SessionMap, SessionRegistry},
+    utils::net,
+    xds::ResourceType,
+    Config, Result,
+};
 
 #[cfg(doc)]
 use crate::filters::FilterFactory;
@@ -35,38 +41,131 @@ pub struct Proxy {
     /// One or more `quilkin manage` endpoints to listen to for config changes
     #[clap(short, long, env = "QUILKIN_MANAGEMENT_SERVER", conflicts_with("to"))]
     pub management_server: Vec<Endpoint>,
+    #[clap(flatten)]
+    pub xds_tls: crate::xds::ClientTls,
+    /// The `region` the proxy runs in, which is sent to management servers
+    /// so that they only send it the endpoints in its region, and in the
+    /// region's failover regions.
+    #[clap(long, env = "QUILKIN_REGION")]
+    pub region: Option<String>,
+    /// The `zone` in the `region` the proxy runs in.
+    #[clap(long, env = "QUILKIN_ZONE")]
+    pub zone: Option<String>,
+    /// The `sub_zone` in the `zone` in the `region` the proxy runs in.
+    #[clap(long, env = "QUILKIN_SUB_ZONE")]
+    pub sub_zone: Option<String>,
+    /// Labels sent to management servers as `key=value` pairs, which decide
+    /// the node group the proxy is in, e.g. `track=canary`.
+    #[clap(
+        long = "node-metadata",
+        env = "QUILKIN_NODE_METADATA",
+        value_delimiter = ','
+    )]
+    pub node_metadata: Vec<crate::config::nodes::NodeMetadata>,
+    /// The file where the last configuration received from the management
+    /// servers is saved. It's loaded when the proxy starts, and packets are
+    /// forwarded with it straight away, while the proxy connects to the
+    /// management servers in the background. It's marked as stale until the
+    /// proxy receives a fresh configuration.
+    #[clap(long, env = "QUILKIN_XDS_SNAPSHOT", requires = "management_server")]
+    pub xds_snapshot: Option<std::path::PathBuf>,
     /// The remote URL or local file path to retrieve the Maxmind database.
     #[clap(long, env)]
     pub mmdb: Option<crate::maxmind_db::Source>,
     /// The port to listen on.
     #[clap(short, long, env = super::PORT_ENV_VAR, default_value_t = PORT)]
     pub port: u16,
+    /// One or more IP addresses to listen on. Listening on `::` will accept
+    /// both IPv4 and IPv6 traffic (dual-stack), unless `--ipv6-only` is set.
+    #[clap(
+        long = "address",
+        env = "QUILKIN_ADDRESS",
+        default_value = "0.0.0.0",
+        value_delimiter = ','
+    )]
+    pub addresses: Vec<IpAddr>,
+    /// Only accept IPv6 traffic on IPv6 listen addresses.
+    #[clap(long, env = "QUILKIN_IPV6_ONLY")]
+    pub ipv6_only: bool,
     /// One or more socket addresses to forward packets to.
     #[clap(short, long, env = "QUILKIN_DEST")]
     pub to: Vec<SocketAddr>,
+    /// The maximum number of seconds to keep forwarding packets for existing
+    /// sessions after receiving a shutdown signal. No new sessions are
+    /// created while draining. A value of `0` shuts down immediately.
+    #[clap(long, env = "QUILKIN_DRAIN_TIMEOUT", default_value_t = 0)]
+    pub drain_timeout: u64,
+    /// The maximum number of packets each worker receives and processes at
+    /// once. On Linux, packets are received and sent in batches with
+    /// `recvmmsg` and `sendmmsg` (or UDP GSO where available).
+    #[clap(long, env = "QUILKIN_BATCH_SIZE", default_value_t = net::DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+    /// The size in bytes of the largest packet the proxy receives from
+    /// clients, larger packets are dropped. Each worker has a buffer of this
+    /// size for every packet in its batch.
+    #[clap(long, env = "QUILKIN_MAX_PACKET_SIZE", default_value_t = net::DEFAULT_MAX_DATAGRAM_SIZE)]
+    pub max_packet_size: usize,
+    /// The directory that packet captures started through the admin server's
+    /// `/pcap` endpoint, and by `Pcap` filters, are written to. Nothing is
+    /// captured when this isn't set.
+    #[clap(long, env = "QUILKIN_CAPTURE_DIR")]
+    pub capture_dir: Option<std::path::PathBuf>,
+    /// The sessions of the running proxy, shared with the admin server.
+    #[clap(skip)]
+    pub(crate) sessions: SessionRegistry,
 }
 
 impl Default for Proxy {
     fn default() -> Self {
         Self {
             management_server: <_>::default(),
+            xds_tls: <_>::default(),
+            region: <_>::default(),
+            zone: <_>::default(),
+            sub_zone: <_>::default(),
+            node_metadata: <_>::default(),
+            xds_snapshot: <_>::default(),
             mmdb: <_>::default(),
             port: PORT,
+            addresses: vec![Ipv4Addr::UNSPECIFIED.into()],
+            ipv6_only: false,
             to: <_>::default(),
+            drain_timeout: 0,
+            batch_size: net::DEFAULT_BATCH_SIZE,
+            max_packet_size: net::DEFAULT_MAX_DATAGRAM_SIZE,
+            capture_dir: <_>::default(),
+            sessions: <_>::default(),
         }
     }
 }
 
 impl Proxy {
+    /// Returns how the proxy describes itself to management servers.
+    fn node(&self, id: &str) -> crate::config::NodeInfo {
+        let locality = (self.region.is_some() || self.zone.is_some() || self.sub_zone.is_some())
+            .then(|| crate::endpoint::Locality {
+                region: self.region.clone().unwrap_or_default(),
+                zone: self.zone.clone().unwrap_or_default(),
+                sub_zone: self.sub_zone.clone().unwrap_or_default(),
+            });
+
+        crate::config::NodeInfo {
+            id: id.into(),
+            locality,
+            metadata: self
+                .node_metadata
+                .iter()
+                .map(|label| (label.key.clone(), label.value.clone()))
+                .collect(),
+        }
+    }
+
     /// Start and run a proxy.
     pub async fn run(
         &self,
         config: std::sync::Arc<crate::Config>,
         mut shutdown_rx: tokio::sync::watch::Receiver<()>,
     ) -> crate::Result<()> {
-        const SESSION_TIMEOUT_SECONDS: Duration = Duration::from_secs(60);
-        const SESSION_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
-
         let _mmdb_task = self.mmdb.clone().map(|source| {
             tokio::spawn(async move {
                 use crate::config::BACKOFF_INITIAL_DELAY_MILLISECONDS;
@@ -89,44 +188,264 @@ impl Proxy {
             });
         }
 
+        // Loaded before the listeners are bound, as the snapshot can contain
+        // named listeners.
+        let snapshot = self
+            .xds_snapshot
+            .clone()
+            .map(|path| crate::config::Snapshot::load(path, config.clone()));
+
         if config.clusters.load().endpoints().count() == 0 && self.management_server.is_empty() {
             return Err(eyre::eyre!(
                 "`quilkin proxy` requires at least one `to` address or `management_server` endpoint."
             ));
         }
 
+        // Checked before the proxy starts, as the management servers are only
+        // connected to in the background once it's running.
+        crate::xds::Client::check_endpoints(&self.management_server)?;
+
         let id = config.id.load();
-        tracing::info!(port = self.port, proxy_id = &*id, "Starting");
 
-        let sessions = SessionMap::new(SESSION_TIMEOUT_SECONDS, SESSION_EXPIRY_POLL_INTERVAL);
+        tracing::info!(
+            port = self.port,
+            addresses = ?self.addresses,
+            proxy_id = &*id,
+            "Starting"
+        );
 
-        let _xds_stream = if !self.management_server.is_empty() {
-            let client =
-                crate::xds::Client::connect(String::clone(&id), self.management_server.clone())
-                    .await?;
-            let mut stream = client
-                .stream({
-                    let config = config.clone();
-                    move |resource| config.apply(resource)
-                })
-                .await?;
+        let sessions = new_session_map(&config);
 
-            tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
-            stream.send(ResourceType::Endpoint, &[]).await?;
-            tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
-            stream.send(ResourceType::Listener, &[]).await?;
-            Some(stream)
-        } else {
-            None
+        // The workers are shutdown separately from the proxy, so that they can
+        // keep forwarding packets for existing sessions while draining.
+        let (workers_shutdown_tx, workers_shutdown_rx) = watch::channel::<()>(());
+        let draining = Arc::new(AtomicBool::new(false));
+        self.sessions.clear();
+
+        self.run_recv_from(
+            &config,
+            &self.addresses,
+            self.port,
+            None,
+            sessions.clone(),
+            draining.clone(),
+            workers_shutdown_rx.clone(),
+        )?;
+        // Registered once the sockets are bound, as the proxy isn't ready
+        // until then.
+        self.sessions.register(sessions);
+
+        // Named listeners are bound and unbound as they're added to and
+        // removed from the config, whether by the config file or by a
+        // management server.
+        let (listeners_tx, mut listeners_rx) = watch::channel(());
+        config.listeners.watch(move |_| {
+            listeners_tx.send_replace(());
+        });
+        let mut listeners = HashMap::new();
+        self.update_listeners(&config, &mut listeners, &draining)?;
+        tokio::spawn({
+            let proxy = self.clone();
+            let config = config.clone();
+            let draining = draining.clone();
+            let mut shutdown_rx = workers_shutdown_rx.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        result = listeners_rx.changed() => {
+                            if result.is_err() {
+                                break;
+                            }
+
+                            let result =
+                                proxy.update_listeners(&config, &mut listeners, &draining);
+                            if let Err(error) = result {
+                                tracing::error!(%error, "failed to update listeners");
+                            }
+                        }
+                        _ = shutdown_rx.changed() => break,
+                    }
+                }
+            }
+        });
+
+        crate::proxy::spawn_active_checks(config.clone(), workers_shutdown_rx.clone());
+        crate::proxy::spawn_ejection_expiry(config.clone(), workers_shutdown_rx.clone());
+
+        // The management servers are connected to in the background, so that
+        // the proxy forwards packets with the endpoints it already has, such
+        // as those from a snapshot, while it can't reach any of them.
+        let xds_task = (!self.management_server.is_empty()).then(|| {
+            let node = self.node(&id);
+            let proxy = self.clone();
+            let config = config.clone();
+            let mut shutdown_rx = workers_shutdown_rx.clone();
+            tokio::spawn(async move {
+                // The stream is kept open until the workers are shutdown.
+                let _stream = proxy.stream_xds(node, config, snapshot).await?;
+                shutdown_rx.changed().await.ok();
+                crate::Result::<()>::Ok(())
+            })
+        });
+        let xds_failed = async move {
+            match xds_task {
+                Some(task) => task
+                    .await
+                    .map_err(eyre::Error::from)
+                    .and_then(|result| result),
+                None => std::future::pending().await,
+            }
         };
 
-        self.run_recv_from(&config, sessions, shutdown_rx.clone())?;
+        // Apply changes to the session config to the existing sessions.
+        config.session.watch({
+            let clusters = config.clusters.clone();
+            let sessions = self.sessions.clone();
+            move |session_config| {
+                tracing::info!(timeout = session_config.timeout, "Updating session config");
+                let clusters = clusters.load();
+                for sessions in sessions.maps() {
+                    sessions.set_poll_interval(session_config.expiry_poll_interval());
+                    sessions.update_ttl(session_config.timeout(), |_, session| {
+                        session_config.timeout_for(&clusters, session.dest())
+                    });
+                }
+            }
+        });
         tracing::info!("Quilkin is ready");
 
-        shutdown_rx
-            .changed()
-            .await
-            .map_err(|error| eyre::eyre!(error))
+        tokio::select! {
+            result = shutdown_rx.changed() => result.map_err(|error| eyre::eyre!(error))?,
+            // The xDS task only returns before the workers are shutdown if it
+            // failed to connect or subscribe, which stops the proxy so that
+            // it's restarted, rather than running without its configuration.
+            result = xds_failed => {
+                workers_shutdown_tx.send(()).ok();
+                result?;
+                return Err(eyre::eyre!("the xDS stream ended before the proxy was shutdown"));
+            }
+        }
+
+        if self.drain_timeout > 0 {
+            crate::proxy::drain(
+                &self.sessions.maps(),
+                &draining,
+                Duration::from_secs(self.drain_timeout),
+            )
+            .await;
+        }
+
+        workers_shutdown_tx.send(()).ok();
+        Ok(())
+    }
+
+    /// Connects to the management servers and subscribes to the endpoints and
+    /// listeners, applying them to `config`, or to `snapshot` when set.
+    async fn stream_xds(
+        &self,
+        node: crate::config::NodeInfo,
+        config: Arc<Config>,
+        snapshot: Option<Arc<crate::config::Snapshot>>,
+    ) -> Result<crate::xds::client::Stream> {
+        let client =
+            crate::xds::Client::connect(node, self.management_server.clone(), self.xds_tls.clone())
+                .await?;
+        let mut stream = client
+            .stream(
+                {
+                    let config = config.clone();
+                    let snapshot = snapshot.clone();
+                    move |resources| match &snapshot {
+                        Some(snapshot) => snapshot.apply(&config, resources),
+                        None => config.apply_all(resources),
+                    }
+                },
+                move |resource_type, name| match &snapshot {
+                    Some(snapshot) => snapshot.remove(&config, resource_type, name),
+                    None => config.remove(resource_type, name),
+                },
+            )
+            .await?;
+
+        tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
+        stream.send(ResourceType::Endpoint, &[]).await?;
+        tokio::time::sleep(std::time::Duration::from_nanos(1)).await;
+        stream.send(ResourceType::Listener, &[]).await?;
+        Ok(stream)
+    }
+
+    /// Binds the named listeners in `config.listeners` that aren't in
+    /// `listeners` yet, and unbinds the ones that have been removed or whose
+    /// port or address has changed. Nothing is bound if any of the listeners
+    /// would share a port.
+    fn update_listeners(
+        &self,
+        config: &Arc<Config>,
+        listeners: &mut HashMap<String, BoundListener>,
+        draining: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let configured = config.listeners.load();
+
+        let mut ports = std::collections::HashSet::from([self.port]);
+        for (name, listener) in configured.iter() {
+            if !ports.insert(listener.port) {
+                return Err(eyre::eyre!(
+                    "listener `{name}`'s port {} is already in use by another listener",
+                    listener.port
+                ));
+            }
+        }
+
+        listeners.retain(|name, bound| {
+            let keep = configured.get(name).map_or(false, |listener| {
+                listener.port == bound.port && listener.address == bound.address
+            });
+            if !keep {
+                tracing::info!(listener = %name, port = bound.port, "Stopping listener");
+                self.sessions.unregister(&bound.sessions);
+                // Sessions hold on to the listener's sockets, which would
+                // otherwise keep receiving a share of the packets sent to
+                // the port once it's bound again.
+                bound.sessions.clear();
+            }
+            keep
+        });
+
+        for (name, listener) in configured.iter() {
+            if listeners.contains_key(name) {
+                continue;
+            }
+
+            tracing::info!(listener = %name, port = listener.port, "Starting listener");
+            // Each listener has its own sessions, as the same client could
+            // be sending to the same endpoint through multiple listeners.
+            let sessions = new_session_map(config);
+            let (shutdown_tx, shutdown_rx) = watch::channel(());
+            self.run_recv_from(
+                config,
+                listener
+                    .address
+                    .as_ref()
+                    .map_or(&self.addresses[..], std::slice::from_ref),
+                listener.port,
+                Some(name.as_str().into()),
+                sessions.clone(),
+                draining.clone(),
+                shutdown_rx,
+            )?;
+            self.sessions.register(sessions.clone());
+            listeners.insert(
+                name.clone(),
+                BoundListener {
+                    port: listener.port,
+                    address: listener.address,
+                    sessions,
+                    _shutdown_tx: shutdown_tx,
+                },
+            );
+        }
+
+        Ok(())
     }
 
     /// Spawns a background task that sits in a loop, receiving packets from the passed in socket.
@@ -134,27 +453,44 @@ impl Proxy {
     /// This function also spawns the set of worker tasks responsible for consuming packets
     /// off the aforementioned queue and processing them through the filter chain and session
     /// pipeline.
+    #[allow(clippy::too_many_arguments)]
     fn run_recv_from(
         &self,
         config: &Arc<Config>,
+        addresses: &[IpAddr],
+        port: u16,
+        listener: Option<Arc<str>>,
         sessions: SessionMap,
+        draining: Arc<AtomicBool>,
         shutdown_rx: watch::Receiver<()>,
     ) -> Result<()> {
-        // The number of worker tasks to spawn. Each task gets a dedicated queue to
-        // consume packets off.
+        if addresses.is_empty() {
+            return Err(eyre::eyre!(
+                "`quilkin proxy` requires at least one address to listen on."
+            ));
+        }
+
+        // The number of worker tasks to spawn per address. Each task gets a
+        // dedicated queue to consume packets off.
         let num_workers = num_cpus::get();
 
         // Contains config for each worker task.
-        let mut workers = Vec::with_capacity(num_workers);
-        for worker_id in 0..num_workers {
-            let socket = Arc::new(self.bind(self.port)?);
-            workers.push(crate::proxy::DownstreamReceiveWorkerConfig {
-                worker_id,
-                socket: socket.clone(),
-                shutdown_rx: shutdown_rx.clone(),
-                config: config.clone(),
-                sessions: sessions.clone(),
-            })
+        let mut workers = Vec::with_capacity(num_workers * addresses.len());
+        for address in addresses {
+            for _ in 0..num_workers {
+                let socket = Arc::new(self.bind((*address, port).into())?);
+                workers.push(crate::proxy::DownstreamReceiveWorkerConfig {
+                    worker_id: workers.len(),
+                    socket: socket.clone(),
+                    shutdown_rx: shutdown_rx.clone(),
+                    config: config.clone(),
+                    listener: listener.clone(),
+                    sessions: sessions.clone(),
+                    batch_size: self.batch_size,
+                    max_packet_size: self.max_packet_size,
+                    draining: draining.clone(),
+                })
+            }
         }
 
         // Start the worker tasks that pick up received packets from their queue
@@ -166,13 +502,30 @@ impl Proxy {
         Ok(())
     }
 
-    /// binds the local configured port with port and address reuse applied.
-    fn bind(&self, port: u16) -> Result<UdpSocket> {
-        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-        net::socket_with_reuse(addr.into())
+    /// binds the local configured address with port and address reuse applied.
+    fn bind(&self, addr: SocketAddr) -> Result<UdpSocket> {
+        net::socket_with_reuse_and_ipv6_only(addr, self.ipv6_only)
     }
 }
 
+/// A named listener that the proxy is receiving packets on.
+struct BoundListener {
+    port: u16,
+    address: Option<IpAddr>,
+    sessions: SessionMap,
+    /// The listener's workers stop once this is dropped.
+    _shutdown_tx: watch::Sender<()>,
+}
+
+/// Returns an empty session map, using the current session config.
+fn new_session_map(config: &Config) -> SessionMap {
+    let session_config = config.session.load();
+    SessionMap::new(
+        session_config.timeout(),
+        session_config.expiry_poll_interval(),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -264,6 +617,71 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn run_with_snapshot_and_unreachable_management_server() {
+        let mut t = TestHelper::default();
+
+        let endpoint = t.open_socket_and_recv_single_packet().await;
+        let directory = tempdir::TempDir::new("snapshot").unwrap();
+        let snapshot = directory.path().join("snapshot.yaml");
+        let clusters = crate::cluster::ClusterMap::new_with_default_cluster(vec![Endpoint::new(
+            endpoint.socket.local_addr().unwrap().into(),
+        )]);
+        std::fs::write(
+            &snapshot,
+            serde_yaml::to_string(&serde_json::json!({
+                "saved_at": 0,
+                "clusters": clusters,
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+
+        // Nothing is listening on the management server's address.
+        let management_server = available_addr().await;
+        let local_addr = available_addr().await;
+        let proxy = crate::cli::Proxy {
+            port: local_addr.port(),
+            management_server: vec![format!("http://{management_server}").parse().unwrap()],
+            xds_snapshot: Some(snapshot),
+            ..<_>::default()
+        };
+        t.run_server(Arc::new(Config::default()), proxy, None);
+
+        let msg = "hello";
+        endpoint
+            .socket
+            .send_to(msg.as_bytes(), &local_addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            msg,
+            timeout(Duration::from_secs(1), endpoint.packet_rx)
+                .await
+                .expect("should get a packet")
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_with_invalid_management_server() {
+        let proxy = crate::cli::Proxy {
+            port: available_addr().await.port(),
+            // No scheme.
+            management_server: vec!["127.0.0.1:7800".parse().unwrap()],
+            ..<_>::default()
+        };
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let result = timeout(
+            Duration::from_secs(1),
+            proxy.run(Arc::new(Config::default()), shutdown_rx),
+        )
+        .await
+        .expect("should fail without waiting for a management server");
+        assert!(result.is_err());
+    }
+
     #[tokio::test]
     async fn run_with_filter() {
         let mut t = TestHelper::default();
@@ -329,7 +747,11 @@ mod tests {
             worker_id: 1,
             socket: socket.clone(),
             config,
+            listener: None,
             sessions: <_>::default(),
+            batch_size: net::DEFAULT_BATCH_SIZE,
+            max_packet_size: net::DEFAULT_MAX_DATAGRAM_SIZE,
+            draining: <_>::default(),
             shutdown_rx,
         }
         .spawn();
@@ -365,7 +787,15 @@ mod tests {
         });
 
         proxy
-            .run_recv_from(&config, <_>::default(), shutdown_rx)
+            .run_recv_from(
+                &config,
+                &proxy.addresses,
+                proxy.port,
+                None,
+                <_>::default(),
+                <_>::default(),
+                shutdown_rx,
+            )
             .unwrap();
 
         let socket = create_socket().await;
@@ -378,4 +808,229 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn run_dual_stack() {
+        let mut t = TestHelper::default();
+
+        let endpoint = t.open_socket_and_recv_single_packet().await;
+        let local_addr = available_addr().await;
+        let proxy = crate::cli::Proxy {
+            port: local_addr.port(),
+            addresses: vec![std::net::Ipv6Addr::UNSPECIFIED.into()],
+            ..<_>::default()
+        };
+        let config = Arc::new(Config::default());
+        config.clusters.modify(|clusters| {
+            clusters.insert_default(vec![Endpoint::new(
+                endpoint.socket.local_addr().unwrap().into(),
+            )])
+        });
+        t.run_server(config, proxy, None);
+
+        let msg = "hello";
+        let socket = create_socket().await;
+        socket
+            .send_to(msg.as_bytes(), (Ipv4Addr::LOCALHOST, local_addr.port()))
+            .await
+            .unwrap();
+        assert_eq!(
+            msg,
+            timeout(Duration::from_secs(1), endpoint.packet_rx)
+                .await
+                .expect("should receive a packet")
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn run_with_listeners() {
+        let mut t = TestHelper::default();
+
+        load_test_filters();
+        let game = t.open_socket_and_recv_single_packet().await;
+        let chat = t.open_socket_and_recv_single_packet().await;
+        let local_addr = available_addr().await;
+        let listener_addr = available_addr().await;
+
+        let config = Arc::new(Config::default());
+        config.clusters.modify(|clusters| {
+            clusters.insert(crate::cluster::Cluster::new(
+                "game".into(),
+                vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                    game.socket.local_addr().unwrap().into(),
+                ))],
+            ));
+            clusters.insert(crate::cluster::Cluster::new(
+                "chat".into(),
+                vec![crate::endpoint::LocalityEndpoints::from(Endpoint::new(
+                    chat.socket.local_addr().unwrap().into(),
+                ))],
+            ));
+        });
+        config.listeners.modify(|listeners| {
+            listeners.insert(
+                "chat".into(),
+                config::Listener {
+                    port: listener_addr.port(),
+                    address: None,
+                    filters: Arc::new(
+                        crate::filters::FilterChain::try_from(vec![config::Filter {
+                            name: "TestFilter".to_string(),
+                            config: None,
+                        }])
+                        .unwrap(),
+                    ),
+                    clusters: vec!["chat".into()],
+                },
+            );
+        });
+
+        t.run_server(
+            config,
+            crate::cli::Proxy {
+                port: local_addr.port(),
+                ..<_>::default()
+            },
+            None,
+        );
+
+        let msg = "hello";
+        let socket = create_socket().await;
+        socket
+            .send_to(msg.as_bytes(), (Ipv4Addr::LOCALHOST, listener_addr.port()))
+            .await
+            .unwrap();
+
+        // Only the listener's cluster should receive the packet, and it
+        // should have gone through the listener's filter chain.
+        let result = timeout(Duration::from_secs(1), chat.packet_rx)
+            .await
+            .expect("should receive a packet")
+            .unwrap();
+        assert!(result.contains(msg), "'{}' not found in '{}'", msg, result);
+        assert!(result.contains(":odr:"), ":odr: not found in '{}'", result);
+        assert!(timeout(Duration::from_millis(500), game.packet_rx)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn update_listeners() {
+        let mut t = TestHelper::default();
+
+        let echo = t.run_echo_server().await;
+        let local_addr = available_addr().await;
+        let listener_addr = available_addr().await;
+        let config = Arc::new(Config::default());
+        config
+            .clusters
+            .modify(|clusters| clusters.insert_default(vec![Endpoint::new(echo.clone())]));
+        t.run_server(
+            config.clone(),
+            crate::cli::Proxy {
+                port: local_addr.port(),
+                ..<_>::default()
+            },
+            None,
+        );
+
+        let msg = "hello";
+        let mut buf = vec![0; 1024];
+        let socket = create_socket().await;
+        let listener_addr = (Ipv4Addr::LOCALHOST, listener_addr.port());
+
+        // Listeners added after the proxy has started are bound.
+        config.listeners.modify(|listeners| {
+            listeners.insert("chat".into(), config::Listener::new(listener_addr.1));
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        socket.send_to(msg.as_bytes(), listener_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .expect("should receive a packet")
+            .unwrap();
+        assert_eq!(msg.as_bytes(), &buf[..size]);
+
+        // And unbound once they're removed.
+        config.listeners.modify(|listeners| {
+            listeners.remove("chat");
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        socket.send_to(msg.as_bytes(), listener_addr).await.unwrap();
+        let result = timeout(Duration::from_millis(500), socket.recv_from(&mut buf)).await;
+        assert!(!matches!(result, Ok(Ok(_))));
+
+        // The removed listener's sessions are closed along with it, so none
+        // of its sockets are left to take packets sent to a listener that's
+        // bound to the same port again.
+        config.listeners.modify(|listeners| {
+            listeners.insert("chat".into(), config::Listener::new(listener_addr.1));
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..10 {
+            socket.send_to(msg.as_bytes(), listener_addr).await.unwrap();
+            let (size, _) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+                .await
+                .expect("should receive a packet")
+                .unwrap();
+            assert_eq!(msg.as_bytes(), &buf[..size]);
+        }
+    }
+
+    #[tokio::test]
+    async fn drain() {
+        let mut t = TestHelper::default();
+
+        let echo = t.run_echo_server().await;
+        let local_addr = available_addr().await;
+        let proxy = crate::cli::Proxy {
+            port: local_addr.port(),
+            drain_timeout: 2,
+            ..<_>::default()
+        };
+        let config = Arc::new(Config::default());
+        config
+            .clusters
+            .modify(|clusters| clusters.insert_default(vec![Endpoint::new(echo.clone())]));
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let server = tokio::spawn(async move { proxy.run(config, shutdown_rx).await });
+        let proxy_addr = (Ipv4Addr::LOCALHOST, local_addr.port());
+
+        let msg = "hello";
+        let mut buf = vec![0; 1024];
+        let existing = create_socket().await;
+        existing.send_to(msg.as_bytes(), proxy_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), existing.recv_from(&mut buf))
+            .await
+            .expect("should receive a packet")
+            .unwrap();
+        assert_eq!(msg.as_bytes(), &buf[..size]);
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Existing sessions should continue to be forwarded while draining.
+        existing.send_to(msg.as_bytes(), proxy_addr).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(1), existing.recv_from(&mut buf))
+            .await
+            .expect("should receive a packet while draining")
+            .unwrap();
+        assert_eq!(msg.as_bytes(), &buf[..size]);
+
+        // New sessions should not be created while draining.
+        let new = create_socket().await;
+        new.send_to(msg.as_bytes(), proxy_addr).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), new.recv_from(&mut buf))
+            .await
+            .is_err());
+
+        // The proxy should stop once the drain timeout has been reached.
+        timeout(Duration::from_secs(5), server)
+            .await
+            .expect("proxy should stop after draining")
+            .unwrap()
+            .unwrap();
+    }
 }
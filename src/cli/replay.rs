@@ -0,0 +1,424 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::BTreeMap, io::Write, net::SocketAddr, path::PathBuf};
+
+use crate::{
+    endpoint::{Endpoint, EndpointAddress},
+    filters::{ReadContext, WriteContext},
+    metadata::{DynamicMetadata, Value},
+    Config,
+};
+
+/// Runs packets from a file through the filter chain of the configuration,
+/// without sending them anywhere, and prints what happened to each packet as
+/// a line of JSON.
+#[derive(clap::Args, Clone)]
+pub struct Replay {
+    /// The file of packets to replay, either a pcap or pcapng capture, or one
+    /// packet per line, optionally preceded by the address it was received
+    /// from and a space.
+    pub input: PathBuf,
+    /// How the packets of a text file are encoded, which is also how the
+    /// resulting contents and metadata bytes are printed. Captures are
+    /// detected automatically.
+    #[clap(long, value_enum, default_value = "hex")]
+    pub encoding: Encoding,
+    /// Whether packets are run through the filter chain's `read` (received
+    /// from a client) or `write` (received from an endpoint). By default,
+    /// packets from a capture are written if they were sent from one of the
+    /// endpoints and read otherwise, and packets from a text file are read.
+    #[clap(long, value_enum)]
+    pub direction: Option<Direction>,
+    /// Uses the filter chain and endpoints of the listener with this name,
+    /// rather than the top level filter chain.
+    #[clap(long)]
+    pub listener: Option<String>,
+    /// Additional endpoints that packets can be sent to, alongside the
+    /// endpoints of the configuration's clusters.
+    #[clap(long = "endpoint")]
+    pub endpoints: Vec<SocketAddr>,
+    /// The address of the client, which read packets are received from and
+    /// written packets are sent to, unless the input says otherwise.
+    #[clap(long, default_value = "127.0.0.1:8000")]
+    pub client: SocketAddr,
+}
+
+/// How packets are encoded in a text file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Self::Hex => bytes.iter().map(|byte| format!("{byte:02x}")).collect(),
+            Self::Base64 => base64::encode(bytes),
+        }
+    }
+
+    fn decode(self, input: &str) -> crate::Result<Vec<u8>> {
+        match self {
+            Self::Hex => {
+                if input.len() % 2 != 0 {
+                    return Err(eyre::eyre!("hex has an odd number of digits"));
+                }
+
+                (0..input.len())
+                    .step_by(2)
+                    .map(|index| {
+                        input
+                            .get(index..index + 2)
+                            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                            .ok_or_else(|| eyre::eyre!("`{input}` is not valid hex"))
+                    })
+                    .collect()
+            }
+            Self::Base64 => Ok(base64::decode(input)?),
+        }
+    }
+}
+
+/// The path of the filter chain that a packet is run through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A packet from the input file, with its addresses if they're known.
+struct Packet {
+    source: Option<EndpointAddress>,
+    dest: Option<EndpointAddress>,
+    contents: Vec<u8>,
+}
+
+/// What happened to a packet, which is printed as a line of JSON.
+#[derive(Debug, serde::Serialize)]
+struct Outcome {
+    /// The position of the packet in the input, starting at `1`.
+    packet: usize,
+    direction: Direction,
+    source: EndpointAddress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dest: Option<EndpointAddress>,
+    /// The name of the filter that dropped the packet, if it was dropped.
+    dropped_by: Option<String>,
+    /// The contents after the filter chain, or when the packet was dropped.
+    contents: String,
+    /// The endpoints the packet would be sent to, for read packets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoints: Option<Vec<EndpointAddress>>,
+    metadata: BTreeMap<String, serde_json::Value>,
+}
+
+impl Replay {
+    pub fn replay(&self, config: &Config) -> crate::Result<()> {
+        let input = std::fs::read(&self.input)
+            .map_err(|error| eyre::eyre!("failed to read `{}`: {error}", self.input.display()))?;
+
+        let stdout = std::io::stdout();
+        self.run(config, &input, &mut stdout.lock())
+    }
+
+    /// Runs every packet in `input` through the filter chain, writing the
+    /// outcome of each packet to `output`.
+    fn run(&self, config: &Config, input: &[u8], output: &mut impl Write) -> crate::Result<()> {
+        let clusters = config.clusters.load();
+        let (filters, mut endpoints) = match self.listener.as_deref() {
+            Some(name) => {
+                let listeners = config.listeners.load();
+                let listener = listeners
+                    .get(name)
+                    .ok_or_else(|| eyre::eyre!("there is no listener named `{name}`"))?;
+                (
+                    listener.filters.clone(),
+                    listener.endpoints(&clusters, &config.endpoint_health),
+                )
+            }
+            None => (
+                config.filters.load(),
+                clusters
+                    .available_endpoints()
+                    .filter(|endpoint| config.endpoint_health.is_available(endpoint))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        endpoints.extend(
+            self.endpoints
+                .iter()
+                .map(|address| Endpoint::new((*address).into())),
+        );
+
+        if endpoints.is_empty() {
+            return Err(eyre::eyre!(
+                "there are no endpoints to replay packets to, add some to the configuration or with `--endpoint`"
+            ));
+        }
+
+        let packets = if crate::pcap::read::is_capture(input) {
+            crate::pcap::read::read(input)?
+                .into_iter()
+                .map(|datagram| Packet {
+                    source: Some(datagram.source.into()),
+                    dest: Some(datagram.dest.into()),
+                    contents: datagram.contents,
+                })
+                .collect()
+        } else {
+            self.parse(input)?
+        };
+        let encoding = self.encoding;
+
+        let client = EndpointAddress::from(self.client);
+        for (index, packet) in packets.into_iter().enumerate() {
+            let from_endpoint = packet.source.as_ref().map_or(false, |source| {
+                endpoints.iter().any(|endpoint| endpoint.address == *source)
+            });
+            let direction = self
+                .direction
+                .unwrap_or(if from_endpoint && packet.dest.is_some() {
+                    Direction::Write
+                } else {
+                    Direction::Read
+                });
+
+            let outcome = match direction {
+                Direction::Read => {
+                    let source = packet.source.unwrap_or_else(|| client.clone());
+                    let mut ctx =
+                        ReadContext::new(endpoints.clone(), source.clone(), packet.contents)
+                            .clusters(clusters.clone());
+                    let dropped_by = filters.try_read(&mut ctx).err().map(String::from);
+
+                    Outcome {
+                        packet: index + 1,
+                        direction,
+                        source,
+                        dest: None,
+                        dropped_by,
+                        contents: encoding.encode(&ctx.contents),
+                        endpoints: Some(
+                            ctx.endpoints
+                                .into_iter()
+                                .map(|endpoint| endpoint.address)
+                                .collect(),
+                        ),
+                        metadata: metadata(&ctx.metadata, encoding),
+                    }
+                }
+                Direction::Write => {
+                    let source = packet
+                        .source
+                        .unwrap_or_else(|| endpoints[0].address.clone());
+                    let dest = packet.dest.unwrap_or_else(|| client.clone());
+                    let endpoint = endpoints
+                        .iter()
+                        .find(|endpoint| endpoint.address == source)
+                        .cloned()
+                        .unwrap_or_else(|| Endpoint::new(source.clone()));
+                    let mut ctx =
+                        WriteContext::new(endpoint, source.clone(), dest.clone(), packet.contents);
+                    let dropped_by = filters.try_write(&mut ctx).err().map(String::from);
+
+                    Outcome {
+                        packet: index + 1,
+                        direction,
+                        source,
+                        dest: Some(dest),
+                        dropped_by,
+                        contents: encoding.encode(&ctx.contents),
+                        endpoints: None,
+                        metadata: metadata(&ctx.metadata, encoding),
+                    }
+                }
+            };
+
+            serde_json::to_writer(&mut *output, &outcome)?;
+            writeln!(output)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parses a text file with a packet on each line, skipping empty lines
+    /// and lines starting with `#`.
+    fn parse(&self, input: &[u8]) -> crate::Result<Vec<Packet>> {
+        let input = std::str::from_utf8(input).map_err(|_| {
+            eyre::eyre!(
+                "`{}` is neither a capture nor a text file",
+                self.input.display()
+            )
+        })?;
+
+        input
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(index, line)| {
+                self.parse_line(line).map_err(|error| {
+                    error.wrap_err(format!("invalid packet on line {}", index + 1))
+                })
+            })
+            .collect()
+    }
+
+    fn parse_line(&self, line: &str) -> crate::Result<Packet> {
+        let (source, contents) = match line.split_once(char::is_whitespace) {
+            Some((source, contents)) => (Some(source.parse()?), contents.trim()),
+            None => (None, line),
+        };
+
+        Ok(Packet {
+            source,
+            dest: None,
+            contents: self.encoding.decode(contents)?,
+        })
+    }
+}
+
+/// Converts `metadata` into JSON, with its keys sorted and any bytes encoded
+/// with `encoding`.
+fn metadata(metadata: &DynamicMetadata, encoding: Encoding) -> BTreeMap<String, serde_json::Value> {
+    fn to_json(value: &Value, encoding: Encoding) -> serde_json::Value {
+        match value {
+            Value::Bool(value) => (*value).into(),
+            Value::Number(value) => (*value).into(),
+            Value::String(value) => value.clone().into(),
+            Value::Bytes(value) => encoding.encode(value).into(),
+            Value::List(values) => values
+                .iter()
+                .map(|value| to_json(value, encoding))
+                .collect(),
+        }
+    }
+
+    metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), to_json(value, encoding)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(direction: Option<Direction>) -> Replay {
+        Replay {
+            input: "packets.txt".into(),
+            encoding: Encoding::Hex,
+            direction,
+            listener: None,
+            endpoints: vec!["127.0.0.1:7001".parse().unwrap()],
+            client: "127.0.0.1:8000".parse().unwrap(),
+        }
+    }
+
+    fn run(replay: &Replay, config: &Config, input: &[u8]) -> Vec<serde_json::Value> {
+        let mut output = Vec::new();
+        replay.run(config, input, &mut output).unwrap();
+        output
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn encoding() {
+        for encoding in [Encoding::Hex, Encoding::Base64] {
+            let encoded = encoding.encode(b"\x00hello\xff");
+            assert_eq!(
+                b"\x00hello\xff".to_vec(),
+                encoding.decode(&encoded).unwrap()
+            );
+        }
+
+        assert_eq!("0068656c6c6fff", Encoding::Hex.encode(b"\x00hello\xff"));
+        assert!(Encoding::Hex.decode("abc").is_err());
+        assert!(Encoding::Hex.decode("zz").is_err());
+    }
+
+    #[test]
+    fn read_and_write() {
+        let config: Config = serde_yaml::from_str(
+            "
+filters:
+  - name: quilkin.filters.capture.v1alpha1.Capture
+    config:
+      suffix:
+        size: 2
+        remove: true
+  - name: quilkin.filters.token_router.v1alpha1.TokenRouter
+clusters:
+  default:
+    localities:
+      - endpoints:
+        - address: 127.0.0.1:7000
+          metadata:
+            quilkin.dev:
+              tokens:
+                - YWI=
+",
+        )
+        .unwrap();
+
+        let input =
+            b"# A packet for each endpoint\n68656c6c6f6162\n127.0.0.2:9000 68656c6c6f6364\n";
+        let outcomes = run(&replay(None), &config, input);
+        assert_eq!(2, outcomes.len());
+
+        assert_eq!("read", outcomes[0]["direction"]);
+        assert_eq!("127.0.0.1:8000", outcomes[0]["source"]);
+        assert_eq!(serde_json::Value::Null, outcomes[0]["dropped_by"]);
+        assert_eq!("68656c6c6f", outcomes[0]["contents"]);
+        assert_eq!(
+            serde_json::json!(["127.0.0.1:7000"]),
+            outcomes[0]["endpoints"]
+        );
+        assert_eq!("6162", outcomes[0]["metadata"]["quilkin.dev/capture"]);
+
+        assert_eq!("127.0.0.2:9000", outcomes[1]["source"]);
+        assert_eq!(
+            "quilkin.filters.token_router.v1alpha1.TokenRouter",
+            outcomes[1]["dropped_by"]
+        );
+
+        let outcomes = run(&replay(Some(Direction::Write)), &config, b"68656c6c6f\n");
+        assert_eq!("write", outcomes[0]["direction"]);
+        assert_eq!("127.0.0.1:7000", outcomes[0]["source"]);
+        assert_eq!("127.0.0.1:8000", outcomes[0]["dest"]);
+        assert_eq!(serde_json::Value::Null, outcomes[0]["dropped_by"]);
+        assert_eq!("68656c6c6f", outcomes[0]["contents"]);
+        assert!(outcomes[0].get("endpoints").is_none());
+    }
+
+    #[test]
+    fn no_endpoints() {
+        let replay = Replay {
+            endpoints: Vec::new(),
+            ..replay(None)
+        };
+
+        assert!(replay
+            .run(&Config::default(), b"68656c6c6f", &mut Vec::new())
+            .is_err());
+    }
+}
@@ -0,0 +1,54 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+
+/// Validates a configuration file without running it, reporting every error
+/// found in it, and exiting with a non-zero status if there are any.
+#[derive(clap::Args, Clone)]
+pub struct Validate {
+    /// The path to the configuration file to validate, defaults to the path
+    /// set with `--config`.
+    pub path: Option<PathBuf>,
+}
+
+impl Validate {
+    pub fn validate(&self, config_path: &Path) -> crate::Result<()> {
+        let path = self.path.as_deref().unwrap_or(config_path);
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| eyre::eyre!("failed to read `{}`: {error}", path.display()))?;
+
+        let diagnostics = crate::config::validate::validate(&source);
+        for diagnostic in &diagnostics {
+            tracing::error!(
+                path = %path.display(),
+                line = diagnostic.line,
+                "{diagnostic}"
+            );
+        }
+
+        if diagnostics.is_empty() {
+            tracing::info!(path = %path.display(), "Configuration is valid");
+            Ok(())
+        } else {
+            Err(eyre::eyre!(
+                "found {} error(s) in `{}`",
+                diagnostics.len(),
+                path.display()
+            ))
+        }
+    }
+}
@@ -17,9 +17,11 @@
 use once_cell::sync::Lazy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, num::NonZeroU32};
 
-use crate::endpoint::{Endpoint, EndpointAddress, Locality, LocalityEndpoints, LocalitySet};
+use crate::endpoint::{
+    Endpoint, EndpointAddress, HealthStatus, Locality, LocalityEndpoints, LocalitySet,
+};
 
 const DEFAULT_CLUSTER_NAME: &str = "default";
 const SUBSYSTEM: &str = "cluster";
@@ -54,6 +56,21 @@ pub(crate) fn active_endpoints() -> &'static prometheus::IntGauge {
     &ACTIVE_ENDPOINTS
 }
 
+pub(crate) fn unavailable_endpoints() -> &'static prometheus::IntGauge {
+    static UNAVAILABLE_ENDPOINTS: Lazy<prometheus::IntGauge> = Lazy::new(|| {
+        crate::metrics::register(
+            prometheus::IntGauge::with_opts(crate::metrics::opts(
+                "unavailable_endpoints",
+                SUBSYSTEM,
+                "Number of endpoints that are unhealthy or draining, and not receiving packets.",
+            ))
+            .unwrap(),
+        )
+    });
+
+    &UNAVAILABLE_ENDPOINTS
+}
+
 #[derive(Clone, Default, Debug, Eq, PartialEq, Deserialize, Serialize, JsonSchema)]
 pub struct Cluster {
     #[serde(skip, default = "default_cluster_name")]
@@ -93,6 +110,13 @@ impl Cluster {
             .iter()
             .flat_map(|locality| locality.endpoints.iter())
     }
+
+    /// Provides a flat iterator over the endpoints that are able to receive
+    /// packets, see [`HealthStatus::is_available`].
+    pub fn available_endpoints(&self) -> impl Iterator<Item = &Endpoint> + '_ {
+        self.endpoints()
+            .filter(|endpoint| endpoint.health.is_available())
+    }
 }
 
 fn default_cluster_name() -> String {
@@ -164,6 +188,13 @@ impl ClusterMap {
             .flat_map(|locality| locality.endpoints.clone())
     }
 
+    /// Returns the endpoints of all clusters that are able to receive packets,
+    /// see [`HealthStatus::is_available`].
+    pub fn available_endpoints(&self) -> impl Iterator<Item = Endpoint> + '_ {
+        self.endpoints()
+            .filter(|endpoint| endpoint.health.is_available())
+    }
+
     pub fn contains_only_unique_endpoints(&self) -> bool {
         self.endpoints()
             .collect::<std::collections::BTreeSet<_>>()
@@ -294,6 +325,8 @@ impl TryFrom<crate::xds::config::endpoint::v3::ClusterLoadAssignment> for Cluste
                     .into_iter()
                     .map(|endpoint| {
                         let metadata = endpoint.metadata;
+                        let health = HealthStatus::from_xds(endpoint.health_status);
+                        let weight = endpoint.load_balancing_weight.and_then(NonZeroU32::new);
                         let endpoint = match endpoint.host_identifier {
                             Some(lb_endpoint::HostIdentifier::Endpoint(endpoint)) => Ok(endpoint),
                             Some(lb_endpoint::HostIdentifier::EndpointName(name_reference)) => {
@@ -315,20 +348,25 @@ impl TryFrom<crate::xds::config::endpoint::v3::ClusterLoadAssignment> for Cluste
                             .ok_or_else(|| eyre::eyre!("No address provided."))?
                             .try_into()?;
 
-                        let endpoint = Endpoint::with_metadata(
+                        let mut endpoint = Endpoint::with_metadata(
                             address,
                             metadata
                                 .map(crate::metadata::MetadataView::try_from)
                                 .transpose()?
                                 .unwrap_or_default(),
                         );
+                        endpoint.health = health;
+                        endpoint.weight = weight;
                         Ok(endpoint)
                     })
                     .collect::<Result<_, eyre::Error>>()?;
 
+                let weight = locality.load_balancing_weight.and_then(NonZeroU32::new);
                 let locality = locality.locality.map(From::from);
 
-                Ok(LocalityEndpoints::new(endpoints).with_locality(locality))
+                Ok(LocalityEndpoints::new(endpoints)
+                    .with_locality(locality)
+                    .with_weight(weight))
             })
             .collect::<Result<_, eyre::Error>>()?;
 
@@ -25,20 +25,36 @@ use uuid::Uuid;
 
 mod config_type;
 mod error;
+pub mod health_check;
+pub mod listener;
+pub mod nodes;
+pub mod session;
 mod slot;
+mod snapshot;
+pub mod validate;
 pub mod watch;
 
 use crate::{
     cluster::{Cluster, ClusterMap},
     filters::prelude::*,
     xds::{
-        config::{endpoint::v3::ClusterLoadAssignment, listener::v3::Listener},
+        config::{endpoint::v3::ClusterLoadAssignment, listener::v3::Listener as ProtoListener},
         service::discovery::v3::DiscoveryResponse,
         Resource, ResourceType,
     },
 };
 
-pub use self::{config_type::ConfigType, error::ValidationError, slot::Slot};
+pub use self::{
+    config_type::ConfigType,
+    error::ValidationError,
+    health_check::HealthCheckConfig,
+    listener::{Listener, ListenerMap},
+    nodes::{NodeConfig, NodeInfo},
+    session::SessionConfig,
+    slot::Slot,
+};
+
+pub(crate) use self::snapshot::Snapshot;
 
 base64_serde_type!(pub Base64Standard, base64::STANDARD);
 
@@ -59,6 +75,20 @@ pub struct Config {
     pub clusters: Slot<ClusterMap>,
     #[serde(default)]
     pub filters: Slot<crate::filters::FilterChain>,
+    #[serde(default)]
+    pub listeners: Slot<ListenerMap>,
+    #[serde(default)]
+    pub session: Slot<SessionConfig>,
+    #[serde(default)]
+    pub health_check: Slot<HealthCheckConfig>,
+    /// How the management server tailors the configuration to each node.
+    #[serde(default)]
+    pub nodes: Slot<NodeConfig>,
+    /// The health of the endpoints found by health checking, which isn't
+    /// part of the configuration, and is kept separately from `clusters` so
+    /// that updating them doesn't reset it.
+    #[serde(skip)]
+    pub endpoint_health: crate::endpoint::EndpointHealth,
     #[serde(default = "default_proxy_id")]
     pub id: Slot<String>,
     #[serde(default)]
@@ -86,7 +116,15 @@ impl Config {
             }
         }
 
-        replace_if_present!(clusters, filters, id);
+        replace_if_present!(
+            clusters,
+            filters,
+            listeners,
+            session,
+            health_check,
+            nodes,
+            id
+        );
 
         if let Some(locality) = locality {
             self.clusters
@@ -98,32 +136,79 @@ impl Config {
         Ok(())
     }
 
+    /// Returns the resources of `resource_type` for `node`, with only the
+    /// endpoints and filters that apply to it, see [`NodeConfig`].
     pub fn discovery_request(
         &self,
-        _node_id: &str,
+        node: &NodeInfo,
         resource_type: ResourceType,
         names: &[String],
     ) -> Result<DiscoveryResponse, eyre::Error> {
+        let nodes = self.nodes.load();
         let mut resources = Vec::new();
         match resource_type {
             ResourceType::Endpoint => {
                 for value in self.clusters.load().values() {
-                    resources.push(
-                        resource_type.encode_to_any(&ClusterLoadAssignment::try_from(value)?)?,
-                    );
+                    resources.push(resource_type.encode_to_any(
+                        &ClusterLoadAssignment::try_from(&*nodes.cluster_for(node, value))?,
+                    )?);
                 }
             }
             ResourceType::Listener => {
-                resources.push(resource_type.encode_to_any(&Listener {
-                    filter_chains: vec![(&*self.filters.load()).try_into()?],
+                // The session and health check configs are sent as part of the
+                // top level listener's metadata, as they apply to all listeners.
+                let metadata = crate::xds::config::core::v3::Metadata {
+                    filter_metadata: [(
+                        crate::metadata::KEY.into(),
+                        prost_types::Struct {
+                            fields: [
+                                (
+                                    session::METADATA_KEY.into(),
+                                    prost_types::Value {
+                                        kind: Some(prost_types::value::Kind::StructValue(
+                                            (&*self.session.load()).try_into()?,
+                                        )),
+                                    },
+                                ),
+                                (
+                                    health_check::METADATA_KEY.into(),
+                                    prost_types::Value {
+                                        kind: Some(prost_types::value::Kind::StructValue(
+                                            (&*self.health_check.load()).try_into()?,
+                                        )),
+                                    },
+                                ),
+                            ]
+                            .into_iter()
+                            .collect(),
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                    ..<_>::default()
+                };
+
+                let filters = match nodes.group(node) {
+                    Some(group) => group.filters.clone(),
+                    None => self.filters.load(),
+                };
+                resources.push(resource_type.encode_to_any(&ProtoListener {
+                    filter_chains: vec![(&*filters).try_into()?],
+                    metadata: Some(metadata),
                     ..<_>::default()
                 })?);
+
+                for (name, listener) in self.listeners.load().iter() {
+                    resources.push(resource_type.encode_to_any(&listener.to_xds(name)?)?);
+                }
             }
             ResourceType::Cluster => {
                 let clusters = self.clusters.load();
                 for cluster in names.iter().filter_map(|name| clusters.get(name)) {
                     resources.push(resource_type.encode_to_any(
-                        &crate::xds::config::cluster::v3::Cluster::try_from(cluster)?,
+                        &crate::xds::config::cluster::v3::Cluster::try_from(
+                            &*nodes.cluster_for(node, cluster),
+                        )?,
                     )?);
                 }
             }
@@ -139,23 +224,78 @@ impl Config {
 
     #[tracing::instrument(skip_all, fields(response = response.type_url()))]
     pub fn apply(&self, response: &Resource) -> crate::Result<()> {
-        let apply_cluster = |cluster: Cluster| {
-            if cluster.endpoints().count() == 0 {
-                return;
+        self.apply_all(std::slice::from_ref(response))
+    }
+
+    /// Applies every resource of a discovery response, or none of them if
+    /// any is invalid.
+    #[tracing::instrument(skip_all, fields(resources = resources.len()))]
+    pub fn apply_all(&self, resources: &[Resource]) -> crate::Result<()> {
+        self.commit(Self::prepare(resources)?);
+        Ok(())
+    }
+
+    /// Validates `resources`, returning the changes they make to the
+    /// configuration once they're committed with [`Self::commit`].
+    pub(crate) fn prepare(resources: &[Resource]) -> crate::Result<Changes> {
+        resources
+            .iter()
+            .map(Self::change)
+            .collect::<crate::Result<_>>()
+            .map(Changes)
+    }
+
+    /// Applies changes returned by [`Self::prepare`].
+    pub(crate) fn commit(&self, Changes(changes): Changes) {
+        for change in changes {
+            match change {
+                Change::Cluster(cluster) => {
+                    tracing::trace!(endpoints = %serde_json::to_value(&cluster).unwrap(), "applying new endpoints");
+                    self.clusters.modify(|clusters| {
+                        clusters.insert(cluster.clone());
+                    });
+                }
+                Change::Filters {
+                    filters,
+                    session,
+                    health_check,
+                } => {
+                    self.filters.store(Arc::new(filters));
+                    if let Some(session) = session {
+                        self.session.try_replace(Slot::new(session));
+                    }
+                    if let Some(health_check) = health_check {
+                        self.health_check.try_replace(Slot::new(health_check));
+                    }
+                }
+                Change::Listener(name, listener) => {
+                    self.listeners.modify(|listeners| {
+                        listeners.insert(name.clone(), listener.clone());
+                    });
+                }
+                Change::None => {}
             }
+        }
 
-            tracing::trace!(endpoints = %serde_json::to_value(&cluster).unwrap(), "applying new endpoints");
-            self.clusters.modify(|clusters| {
-                clusters.insert(cluster.clone());
-            });
-        };
+        self.apply_metrics();
+    }
 
-        match response {
-            Resource::Endpoint(cla) => {
-                let cluster = Cluster::try_from(*cla.clone()).unwrap();
-                (apply_cluster)(cluster)
+    /// Converts `response` into the change it makes to the configuration,
+    /// without applying it.
+    fn change(response: &Resource) -> crate::Result<Change> {
+        // Clusters without endpoints are ignored.
+        let with_endpoints = |cluster: Cluster| {
+            if cluster.endpoints().count() == 0 {
+                Change::None
+            } else {
+                Change::Cluster(cluster)
             }
-            Resource::Listener(listener) => {
+        };
+
+        Ok(match response {
+            Resource::Endpoint(cla) => with_endpoints(Cluster::try_from(*cla.clone())?),
+            // The unnamed listener is the top level filter chain.
+            Resource::Listener(listener) if listener.name.is_empty() => {
                 let chain = listener
                     .filter_chains
                     .get(0)
@@ -164,16 +304,65 @@ impl Config {
                     .into_iter()
                     .map(Filter::try_from)
                     .collect::<Result<Vec<_>, _>>()?;
-                self.filters.store(Arc::new(chain.try_into()?));
+
+                let metadata = |key: &str| {
+                    listener
+                        .metadata
+                        .as_ref()
+                        .and_then(|metadata| metadata.filter_metadata.get(crate::metadata::KEY))
+                        .and_then(|value| value.fields.get(key))
+                        .and_then(|value| value.kind.clone())
+                };
+                let session = match metadata(session::METADATA_KEY) {
+                    Some(prost_types::value::Kind::StructValue(value)) => {
+                        Some(SessionConfig::try_from(value)?)
+                    }
+                    Some(_) => return Err(eyre::eyre!("session config must be an object")),
+                    None => None,
+                };
+                let health_check = match metadata(health_check::METADATA_KEY) {
+                    Some(prost_types::value::Kind::StructValue(value)) => {
+                        Some(HealthCheckConfig::try_from(value)?)
+                    }
+                    Some(_) => return Err(eyre::eyre!("health check config must be an object")),
+                    None => None,
+                };
+
+                Change::Filters {
+                    filters: chain.try_into()?,
+                    session,
+                    health_check,
+                }
+            }
+            Resource::Listener(listener) => Change::Listener(
+                listener.name.clone(),
+                Listener::try_from(ProtoListener::clone(listener))?,
+            ),
+            Resource::Cluster(cluster) => match cluster.load_assignment.clone() {
+                Some(load_assignment) => with_endpoints(Cluster::try_from(load_assignment)?),
+                None => Change::None,
+            },
+        })
+    }
+
+    /// Removes the resource called `name`, which the management server no
+    /// longer has. The unnamed listener is the top level filter chain, which
+    /// is always kept.
+    pub fn remove(&self, resource_type: ResourceType, name: &str) -> crate::Result<()> {
+        tracing::trace!(%resource_type, name, "removing resource");
+        match resource_type {
+            ResourceType::Endpoint | ResourceType::Cluster => {
+                self.clusters.modify(|clusters| {
+                    clusters.remove(name);
+                });
             }
-            Resource::Cluster(cluster) => {
-                cluster
-                    .load_assignment
-                    .clone()
-                    .map(Cluster::try_from)
-                    .transpose()?
-                    .map(apply_cluster);
+            ResourceType::Listener if name.is_empty() => {}
+            ResourceType::Listener => {
+                self.listeners.modify(|listeners| {
+                    listeners.remove(name);
+                });
             }
+            resource => return Err(eyre::eyre!("Unsupported resource {}", resource.type_url())),
         }
 
         self.apply_metrics();
@@ -181,11 +370,31 @@ impl Config {
         Ok(())
     }
 
+    /// Returns the filter chain for the listener named `listener`, or the top
+    /// level filter chain if `listener` is `None`. Returns `None` if there is
+    /// no longer a listener with that name.
+    pub fn filter_chain(&self, listener: Option<&str>) -> Option<Arc<crate::filters::FilterChain>> {
+        match listener {
+            Some(name) => self
+                .listeners
+                .load()
+                .get(name)
+                .map(|listener| listener.filters.clone()),
+            None => Some(self.filters.load()),
+        }
+    }
+
     pub fn apply_metrics(&self) {
         let clusters = self.clusters.load();
 
         crate::cluster::active_clusters().set(clusters.len() as i64);
         crate::cluster::active_endpoints().set(clusters.endpoints().count() as i64);
+        crate::cluster::unavailable_endpoints().set(
+            clusters
+                .endpoints()
+                .filter(|endpoint| !self.endpoint_health.is_available(endpoint))
+                .count() as i64,
+        );
     }
 }
 
@@ -194,6 +403,11 @@ impl Default for Config {
         Self {
             clusters: <_>::default(),
             filters: <_>::default(),
+            listeners: <_>::default(),
+            session: <_>::default(),
+            health_check: <_>::default(),
+            nodes: <_>::default(),
+            endpoint_health: <_>::default(),
             id: default_proxy_id(),
             version: Slot::with_default(),
         }
@@ -205,10 +419,30 @@ impl PartialEq for Config {
         self.id == rhs.id
             && self.clusters == rhs.clusters
             && self.filters == rhs.filters
+            && self.listeners == rhs.listeners
+            && self.session == rhs.session
+            && self.health_check == rhs.health_check
+            && self.nodes == rhs.nodes
             && self.version == rhs.version
     }
 }
 
+/// The changes to the configuration made by the resources of a discovery
+/// response, which have been validated but not applied yet.
+pub(crate) struct Changes(Vec<Change>);
+
+/// A change to the configuration made by a resource from a management server.
+enum Change {
+    Cluster(Cluster),
+    Filters {
+        filters: crate::filters::FilterChain,
+        session: Option<SessionConfig>,
+        health_check: Option<HealthCheckConfig>,
+    },
+    Listener(String, Listener),
+    None,
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, Serialize, JsonSchema, PartialEq)]
 pub enum Version {
     #[serde(rename = "v1alpha1")]
@@ -503,4 +737,145 @@ dynamic:
             assert!(format!("{error:?}").contains("unknown field"));
         }
     }
+
+    #[test]
+    fn session_xds() {
+        let config = parse_config(
+            "
+version: v1alpha1
+session:
+  timeout: 120
+  clusters:
+    voice:
+      timeout: 300
+health_check:
+  passive:
+    timeout: 10
+",
+        );
+        assert_eq!(120, config.session.load().timeout);
+
+        let response = config
+            .discovery_request(&NodeInfo::default(), ResourceType::Listener, &[])
+            .unwrap();
+        let resource = Resource::try_from(response.resources[0].clone()).unwrap();
+
+        let applied = Config::default();
+        applied.apply(&resource).unwrap();
+        assert_eq!(config.session, applied.session);
+        assert_eq!(config.health_check, applied.health_check);
+    }
+
+    #[test]
+    fn node_xds() {
+        let config = parse_config(
+            "
+version: v1alpha1
+clusters:
+  default:
+    localities:
+      - locality:
+          region: us-east1
+        endpoints:
+          - address: 127.0.0.1:7001
+      - locality:
+          region: europe-west1
+        endpoints:
+          - address: 127.0.0.1:7002
+nodes:
+  groups:
+    - name: canary
+      selector:
+        metadata:
+          track: canary
+      filters:
+        - name: quilkin.filters.debug.v1alpha1.Debug
+",
+        );
+        let node = NodeInfo {
+            id: "proxy".into(),
+            locality: Some(crate::endpoint::Locality {
+                region: "us-east1".into(),
+                ..<_>::default()
+            }),
+            metadata: [("track".into(), "canary".into())].into(),
+        };
+
+        let applied = Config::default();
+        for resource_type in [ResourceType::Endpoint, ResourceType::Listener] {
+            let response = config.discovery_request(&node, resource_type, &[]).unwrap();
+            let resource = Resource::try_from(response.resources[0].clone()).unwrap();
+            applied.apply(&resource).unwrap();
+        }
+
+        let clusters = applied.clusters.load();
+        let addresses = clusters
+            .endpoints()
+            .map(|endpoint| endpoint.address.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["127.0.0.1:7001"], addresses);
+        assert_eq!(1, applied.filters.load().len());
+        assert!(config.filters.load().is_empty());
+    }
+
+    #[test]
+    fn apply_all_or_nothing() {
+        let config = parse_config(
+            "
+version: v1alpha1
+clusters:
+  default:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7001
+",
+        );
+        let response = config
+            .discovery_request(&NodeInfo::default(), ResourceType::Endpoint, &[])
+            .unwrap();
+        let cluster = Resource::try_from(response.resources[0].clone()).unwrap();
+        let invalid = Resource::Listener(Box::new(ProtoListener {
+            filter_chains: vec![crate::xds::config::listener::v3::FilterChain {
+                filters: vec![crate::xds::config::listener::v3::Filter {
+                    name: "unknown".into(),
+                    config_type: None,
+                }],
+                ..<_>::default()
+            }],
+            ..<_>::default()
+        }));
+
+        let applied = Config::default();
+        applied.apply_all(&[cluster.clone(), invalid]).unwrap_err();
+        assert_eq!(0, applied.clusters.load().endpoints().count());
+
+        applied.apply_all(&[cluster]).unwrap();
+        assert_eq!(config.clusters, applied.clusters);
+    }
+
+    #[test]
+    fn remove_resources() {
+        let config = parse_config(
+            "
+version: v1alpha1
+clusters:
+  default:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7001
+listeners:
+  voice:
+    port: 7002
+",
+        );
+
+        config.remove(ResourceType::Listener, "").unwrap();
+        config.remove(ResourceType::Listener, "voice").unwrap();
+        assert!(config.listeners.load().is_empty());
+
+        config.remove(ResourceType::Endpoint, "default").unwrap();
+        assert!(config.clusters.load().is_empty());
+
+        assert!(config.remove(ResourceType::Secret, "default").is_err());
+    }
 }
@@ -0,0 +1,223 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Base64Standard;
+
+/// The key of the health check config in the metadata of the top level
+/// listener sent over xDS.
+pub(crate) const METADATA_KEY: &str = "health_check";
+
+/// The default number of seconds between active health checks.
+pub const DEFAULT_INTERVAL_SECONDS: u64 = 5;
+/// The default number of seconds to wait for a health check response.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 1;
+/// The default number of consecutive failed checks before an endpoint is
+/// marked unhealthy.
+pub const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+/// The default number of consecutive successful checks before an endpoint is
+/// marked healthy.
+pub const DEFAULT_HEALTHY_THRESHOLD: u32 = 1;
+/// The default number of seconds a session can go without a response from
+/// its endpoint before the endpoint is marked unhealthy.
+pub const DEFAULT_PASSIVE_TIMEOUT_SECONDS: u64 = 30;
+/// The default number of seconds an endpoint marked unhealthy by passive
+/// health checking is excluded for.
+pub const DEFAULT_EJECTION_TIME_SECONDS: u64 = 30;
+
+/// Configuration for checking the health of upstream endpoints, unhealthy
+/// endpoints don't receive packets. Both active and passive health checking
+/// are disabled by default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Periodically sends a probe to every endpoint, and checks its response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<ActiveHealthCheck>,
+    /// Marks endpoints as unhealthy when sessions stop receiving responses
+    /// from them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passive: Option<PassiveHealthCheck>,
+}
+
+/// Configuration for probing endpoints with a UDP packet.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ActiveHealthCheck {
+    /// The number of seconds between checks.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// The number of seconds to wait for a response.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// The base64 encoded packet sent to each endpoint.
+    #[serde(
+        deserialize_with = "Base64Standard::deserialize",
+        serialize_with = "Base64Standard::serialize"
+    )]
+    pub payload: Vec<u8>,
+    /// The base64 encoded response expected from each endpoint, if empty any
+    /// response is considered healthy.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "Base64Standard::deserialize",
+        serialize_with = "Base64Standard::serialize"
+    )]
+    pub response: Vec<u8>,
+    /// The number of consecutive failed checks before an endpoint is marked
+    /// unhealthy.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// The number of consecutive successful checks before an endpoint is
+    /// marked healthy.
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+impl ActiveHealthCheck {
+    /// Returns the interval between checks, which is at least one second.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    /// Returns how long to wait for a response.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Returns whether `response` is a healthy response to the probe.
+    pub fn is_expected_response(&self, response: &[u8]) -> bool {
+        self.response.is_empty() || self.response == response
+    }
+}
+
+/// Configuration for detecting unhealthy endpoints from session traffic.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PassiveHealthCheck {
+    /// The number of seconds a session can send packets to an endpoint
+    /// without receiving a response, before the endpoint is marked unhealthy.
+    #[serde(default = "default_passive_timeout")]
+    pub timeout: u64,
+    /// The number of seconds the endpoint is excluded for, before it's able
+    /// to receive packets again.
+    #[serde(default = "default_ejection_time")]
+    pub ejection_time: u64,
+}
+
+impl PassiveHealthCheck {
+    /// Returns how long a session can go without a response.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Returns how long an unhealthy endpoint is excluded for.
+    pub fn ejection_time(&self) -> Duration {
+        Duration::from_secs(self.ejection_time)
+    }
+}
+
+impl TryFrom<&'_ HealthCheckConfig> for prost_types::Struct {
+    type Error = eyre::Error;
+
+    fn try_from(config: &HealthCheckConfig) -> Result<Self, Self::Error> {
+        crate::prost::struct_from_json(serde_json::to_value(config)?)
+            .ok_or_else(|| eyre::eyre!("health check config must be an object"))
+    }
+}
+
+impl TryFrom<prost_types::Struct> for HealthCheckConfig {
+    type Error = eyre::Error;
+
+    fn try_from(value: prost_types::Struct) -> Result<Self, Self::Error> {
+        let value = crate::prost::value_from_kind(prost_types::value::Kind::StructValue(value));
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL_SECONDS
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    DEFAULT_UNHEALTHY_THRESHOLD
+}
+
+fn default_healthy_threshold() -> u32 {
+    DEFAULT_HEALTHY_THRESHOLD
+}
+
+fn default_passive_timeout() -> u64 {
+    DEFAULT_PASSIVE_TIMEOUT_SECONDS
+}
+
+fn default_ejection_time() -> u64 {
+    DEFAULT_EJECTION_TIME_SECONDS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config: HealthCheckConfig = serde_yaml::from_str(
+            "
+active:
+  payload: cGluZw==
+  response: cG9uZw==
+  unhealthy_threshold: 2
+passive:
+  timeout: 10
+",
+        )
+        .unwrap();
+
+        let active = config.active.unwrap();
+        assert_eq!(b"ping", &*active.payload);
+        assert_eq!(
+            Duration::from_secs(DEFAULT_INTERVAL_SECONDS),
+            active.interval()
+        );
+        assert_eq!(2, active.unhealthy_threshold);
+        assert_eq!(DEFAULT_HEALTHY_THRESHOLD, active.healthy_threshold);
+        assert!(active.is_expected_response(b"pong"));
+        assert!(!active.is_expected_response(b"ping"));
+
+        let passive = config.passive.unwrap();
+        assert_eq!(Duration::from_secs(10), passive.timeout());
+        assert_eq!(
+            Duration::from_secs(DEFAULT_EJECTION_TIME_SECONDS),
+            passive.ejection_time()
+        );
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let config: HealthCheckConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(HealthCheckConfig::default(), config);
+        assert!(config.active.is_none() && config.passive.is_none());
+    }
+}
@@ -0,0 +1,283 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cluster::ClusterMap,
+    endpoint::{AddressKind, Endpoint, EndpointAddress, EndpointHealth},
+    filters::FilterChain,
+    xds::config::{core::v3::Metadata as ProtoMetadata, listener::v3::Listener as ProtoListener},
+};
+
+/// The key in the listener's metadata containing Quilkin specific values.
+const METADATA_KEY: &str = "quilkin.dev";
+const CLUSTERS: &str = "clusters";
+
+/// A map of listener names to their configuration.
+pub type ListenerMap = BTreeMap<String, Listener>;
+
+/// A named listener, which receives packets on its own port, and processes
+/// them with its own filter chain, independently of the top level `filters`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    /// The port to receive packets on.
+    pub port: u16,
+    /// The IP address to receive packets on, if none is provided the
+    /// listener receives packets on the proxy's addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// The filter chain that packets received on this listener go through.
+    #[serde(default)]
+    pub filters: Arc<FilterChain>,
+    /// The names of the clusters to send packets to, if empty packets will be
+    /// sent to the endpoints of all clusters.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<String>,
+}
+
+impl Listener {
+    /// Creates a new listener on `port`, with an empty filter chain and
+    /// access to all clusters.
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            ..<_>::default()
+        }
+    }
+
+    /// Returns the available endpoints from `clusters` that this listener is
+    /// allowed to send packets to, excluding the ones found unavailable by
+    /// health checking.
+    pub fn endpoints(&self, clusters: &ClusterMap, health: &EndpointHealth) -> Vec<Endpoint> {
+        if self.clusters.is_empty() {
+            return clusters
+                .available_endpoints()
+                .filter(|endpoint| health.is_available(endpoint))
+                .collect();
+        }
+
+        self.clusters
+            .iter()
+            .filter_map(|name| clusters.get(name))
+            .flat_map(|cluster| cluster.available_endpoints().cloned())
+            .filter(|endpoint| health.is_available(endpoint))
+            .collect()
+    }
+
+    /// Converts the listener into its xDS representation named `name`. A
+    /// listener without an address is sent with the unspecified address.
+    pub(crate) fn to_xds(&self, name: &str) -> Result<ProtoListener, crate::filters::Error> {
+        let metadata = (!self.clusters.is_empty()).then(|| {
+            let clusters = prost_types::Value {
+                kind: Some(prost_types::value::Kind::ListValue(
+                    prost_types::ListValue {
+                        values: self
+                            .clusters
+                            .iter()
+                            .cloned()
+                            .map(|name| prost_types::Value {
+                                kind: Some(prost_types::value::Kind::StringValue(name)),
+                            })
+                            .collect(),
+                    },
+                )),
+            };
+
+            ProtoMetadata {
+                filter_metadata: [(
+                    METADATA_KEY.into(),
+                    prost_types::Struct {
+                        fields: [(CLUSTERS.into(), clusters)].into_iter().collect(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                ..<_>::default()
+            }
+        });
+
+        Ok(ProtoListener {
+            name: name.into(),
+            address: Some(
+                EndpointAddress::from((
+                    self.address.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+                    self.port,
+                ))
+                .into(),
+            ),
+            filter_chains: vec![(&*self.filters).try_into()?],
+            metadata,
+            ..<_>::default()
+        })
+    }
+}
+
+impl TryFrom<ProtoListener> for Listener {
+    type Error = eyre::Error;
+
+    fn try_from(listener: ProtoListener) -> Result<Self, Self::Error> {
+        let address = listener
+            .address
+            .map(EndpointAddress::try_from)
+            .transpose()?;
+        let port = address
+            .as_ref()
+            .and_then(|address| address.port)
+            .ok_or_else(|| eyre::eyre!("listener `{}` is missing a port", listener.name))?;
+        let address = match address.map(|address| address.host) {
+            Some(AddressKind::Ip(ip)) if !ip.is_unspecified() => Some(ip),
+            Some(AddressKind::Name(name)) => {
+                return Err(eyre::eyre!(
+                    "listener `{}`'s address `{name}` must be an IP address",
+                    listener.name
+                ))
+            }
+            _ => None,
+        };
+
+        let filters = listener
+            .filter_chains
+            .into_iter()
+            .next()
+            .map(FilterChain::try_from)
+            .transpose()?
+            .unwrap_or_default();
+
+        let clusters = listener
+            .metadata
+            .and_then(|mut metadata| metadata.filter_metadata.remove(METADATA_KEY))
+            .and_then(|mut value| value.fields.remove(CLUSTERS))
+            .and_then(|value| value.kind)
+            .map(|kind| match kind {
+                prost_types::value::Kind::ListValue(list) => list
+                    .values
+                    .into_iter()
+                    .map(|value| match value.kind {
+                        Some(prost_types::value::Kind::StringValue(name)) => Ok(name),
+                        _ => Err(eyre::eyre!("listener cluster names must be strings")),
+                    })
+                    .collect::<Result<Vec<_>, _>>(),
+                _ => Err(eyre::eyre!("listener clusters must be a list")),
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            port,
+            address,
+            filters: Arc::new(filters),
+            clusters,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{cluster::Cluster, endpoint::LocalityEndpoints};
+
+    #[test]
+    fn parse() {
+        let listener: Listener = serde_yaml::from_str(
+            "
+port: 7000
+clusters:
+  - game
+filters:
+  - name: quilkin.filters.debug.v1alpha1.Debug
+",
+        )
+        .unwrap();
+
+        assert_eq!(7000, listener.port);
+        assert_eq!(vec![String::from("game")], listener.clusters);
+        assert_eq!(1, listener.filters.len());
+    }
+
+    #[test]
+    fn endpoints() {
+        let mut clusters = ClusterMap::default();
+        clusters.insert(Cluster::new(
+            "game".into(),
+            vec![LocalityEndpoints::from(vec![Endpoint::new(
+                "127.0.0.1:8000".parse().unwrap(),
+            )])],
+        ));
+        clusters.insert(Cluster::new(
+            "chat".into(),
+            vec![LocalityEndpoints::from(vec![Endpoint::new(
+                "127.0.0.1:9000".parse().unwrap(),
+            )])],
+        ));
+
+        let health = EndpointHealth::default();
+        let mut listener = Listener::new(7000);
+        assert_eq!(2, listener.endpoints(&clusters, &health).len());
+
+        listener.clusters = vec!["game".into()];
+        assert_eq!(
+            vec![Endpoint::new("127.0.0.1:8000".parse().unwrap())],
+            listener.endpoints(&clusters, &health)
+        );
+
+        health.set(
+            &"127.0.0.1:8000".parse().unwrap(),
+            crate::endpoint::HealthStatus::Unhealthy,
+        );
+        assert!(listener.endpoints(&clusters, &health).is_empty());
+
+        listener.clusters = vec!["missing".into()];
+        assert!(listener.endpoints(&clusters, &health).is_empty());
+    }
+
+    #[test]
+    fn xds_roundtrip() {
+        let mut listener = Listener {
+            port: 7000,
+            address: None,
+            filters: Arc::new(
+                FilterChain::try_from(vec![crate::config::Filter {
+                    name: "quilkin.filters.debug.v1alpha1.Debug".into(),
+                    config: None,
+                }])
+                .unwrap(),
+            ),
+            clusters: vec!["game".into()],
+        };
+
+        let proto = listener.to_xds("game").unwrap();
+        assert_eq!("game", proto.name);
+        assert_eq!(listener, Listener::try_from(proto).unwrap());
+
+        listener.address = Some("10.0.0.1".parse().unwrap());
+        let proto = listener.to_xds("game").unwrap();
+        assert_eq!(
+            EndpointAddress::from(("10.0.0.1".parse::<IpAddr>().unwrap(), 7000)),
+            EndpointAddress::try_from(proto.address.clone().unwrap()).unwrap()
+        );
+        assert_eq!(listener, Listener::try_from(proto).unwrap());
+    }
+}
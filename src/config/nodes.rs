@@ -0,0 +1,346 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Configuration which the management server tailors to each node.
+
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cluster::Cluster,
+    endpoint::{Locality, LocalityEndpoints},
+    filters::FilterChain,
+    xds::config::core::v3::Node,
+};
+
+/// How the management server tailors the configuration it sends to each
+/// node, based on the locality and metadata the node connected with.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    /// The regions whose endpoints are also sent to the nodes of a region, so
+    /// that they can fail over to them, keyed by region.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failover: BTreeMap<String, Vec<String>>,
+    /// Groups of nodes which use a different top level filter chain. A node
+    /// belongs to the first group that it matches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<NodeGroup>,
+}
+
+/// A group of nodes, and the filter chain they use instead of the top level
+/// `filters`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NodeGroup {
+    pub name: String,
+    /// Which nodes are in the group, every node is if empty.
+    #[serde(default)]
+    pub selector: NodeSelector,
+    /// The filter chain used by the nodes in the group.
+    #[serde(default)]
+    pub filters: Arc<FilterChain>,
+}
+
+/// Matches the nodes with all of the given locality fields and metadata.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NodeSelector {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_zone: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A node connected to the management server, as described by its requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: String,
+    /// Where the node is running, nodes without a region receive the
+    /// endpoints of every region.
+    pub locality: Option<Locality>,
+    /// Arbitrary labels used to select the node's group.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A `key=value` label that a proxy sends to management servers as part of
+/// its node metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub key: String,
+    pub value: String,
+}
+
+impl NodeConfig {
+    /// Returns the group that `node` belongs to, if any.
+    pub fn group(&self, node: &NodeInfo) -> Option<&NodeGroup> {
+        self.groups
+            .iter()
+            .find(|group| group.selector.matches(node))
+    }
+
+    /// Returns `cluster` with only the localities that `node` receives, which
+    /// are the ones in its own region, its failover regions, and the ones
+    /// without a region.
+    pub fn cluster_for<'cluster>(
+        &self,
+        node: &NodeInfo,
+        cluster: &'cluster Cluster,
+    ) -> Cow<'cluster, Cluster> {
+        let Some(region) = node
+            .locality
+            .as_ref()
+            .map(|locality| &*locality.region)
+            .filter(|region| !region.is_empty())
+        else {
+            return Cow::Borrowed(cluster);
+        };
+
+        let failover = self.failover.get(region);
+        let receives = |endpoints: &LocalityEndpoints| match &endpoints.locality {
+            Some(locality) if !locality.region.is_empty() => {
+                locality.region == region
+                    || failover.map_or(false, |regions| regions.contains(&locality.region))
+            }
+            _ => true,
+        };
+
+        if cluster.localities.iter().all(receives) {
+            return Cow::Borrowed(cluster);
+        }
+
+        Cow::Owned(Cluster {
+            name: cluster.name.clone(),
+            localities: cluster
+                .localities
+                .iter()
+                .filter(|endpoints| receives(endpoints))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+impl NodeSelector {
+    /// Returns whether `node` has every field and label of the selector.
+    pub fn matches(&self, node: &NodeInfo) -> bool {
+        let locality = node.locality.clone().unwrap_or_default();
+        let field_matches = |expected: &Option<String>, actual: &str| {
+            expected
+                .as_deref()
+                .map_or(true, |expected| expected == actual)
+        };
+
+        field_matches(&self.region, &locality.region)
+            && field_matches(&self.zone, &locality.zone)
+            && field_matches(&self.sub_zone, &locality.sub_zone)
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| node.metadata.get(key) == Some(value))
+    }
+}
+
+impl std::str::FromStr for NodeMetadata {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                key: key.trim().into(),
+                value: value.trim().into(),
+            }),
+            _ => Err(format!("`{input}` is not a `key=value` pair")),
+        }
+    }
+}
+
+impl From<&str> for NodeInfo {
+    fn from(id: &str) -> Self {
+        Self::from(id.to_owned())
+    }
+}
+
+impl From<String> for NodeInfo {
+    fn from(id: String) -> Self {
+        Self {
+            id,
+            ..<_>::default()
+        }
+    }
+}
+
+impl From<&Node> for NodeInfo {
+    fn from(node: &Node) -> Self {
+        // Only string values are supported as metadata.
+        let metadata = node
+            .metadata
+            .iter()
+            .flat_map(|metadata| &metadata.fields)
+            .filter_map(|(key, value)| match &value.kind {
+                Some(prost_types::value::Kind::StringValue(value)) => {
+                    Some((key.clone(), value.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            id: node.id.clone(),
+            locality: node.locality.clone().map(From::from),
+            metadata,
+        }
+    }
+}
+
+impl From<&NodeInfo> for Node {
+    fn from(node: &NodeInfo) -> Self {
+        let metadata = (!node.metadata.is_empty()).then(|| prost_types::Struct {
+            fields: node
+                .metadata
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        prost_types::Value {
+                            kind: Some(prost_types::value::Kind::StringValue(value.clone())),
+                        },
+                    )
+                })
+                .collect(),
+        });
+
+        Self {
+            id: node.id.clone(),
+            user_agent_name: "quilkin".into(),
+            locality: node.locality.clone().map(From::from),
+            metadata,
+            ..<_>::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    fn locality(region: &str, zone: &str) -> Locality {
+        Locality {
+            region: region.into(),
+            zone: zone.into(),
+            ..<_>::default()
+        }
+    }
+
+    fn node(region: &str, metadata: &[(&str, &str)]) -> NodeInfo {
+        NodeInfo {
+            id: "proxy".into(),
+            locality: (!region.is_empty()).then(|| locality(region, "a")),
+            metadata: metadata
+                .iter()
+                .map(|(key, value)| ((*key).into(), (*value).into()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn regional_endpoints() {
+        let config: NodeConfig = serde_yaml::from_str(
+            "
+failover:
+  us-east1: [us-central1]
+",
+        )
+        .unwrap();
+
+        let cluster = Cluster::new_default(vec![
+            LocalityEndpoints::from((
+                Endpoint::new("127.0.0.1:1000".parse().unwrap()),
+                locality("us-east1", "b"),
+            )),
+            LocalityEndpoints::from((
+                Endpoint::new("127.0.0.1:2000".parse().unwrap()),
+                locality("us-central1", "a"),
+            )),
+            LocalityEndpoints::from((
+                Endpoint::new("127.0.0.1:3000".parse().unwrap()),
+                locality("europe-west1", "a"),
+            )),
+            LocalityEndpoints::from(Endpoint::new("127.0.0.1:4000".parse().unwrap())),
+        ]);
+        let ports = |node: &NodeInfo| {
+            let mut ports = config
+                .cluster_for(node, &cluster)
+                .endpoints()
+                .map(|endpoint| endpoint.address.port())
+                .collect::<Vec<_>>();
+            ports.sort_unstable();
+            ports
+        };
+
+        assert_eq!(vec![1000, 2000, 4000], ports(&node("us-east1", &[])));
+        assert_eq!(vec![3000, 4000], ports(&node("europe-west1", &[])));
+        assert_eq!(vec![4000], ports(&node("asia-east1", &[])));
+        assert_eq!(vec![1000, 2000, 3000, 4000], ports(&node("", &[])));
+    }
+
+    #[test]
+    fn groups() {
+        let config: NodeConfig = serde_yaml::from_str(
+            "
+groups:
+  - name: canary
+    selector:
+      region: us-east1
+      metadata:
+        track: canary
+    filters:
+      - name: quilkin.filters.debug.v1alpha1.Debug
+  - name: everyone-else
+",
+        )
+        .unwrap();
+
+        let group = |node: &NodeInfo| config.group(node).map(|group| &*group.name);
+        assert_eq!(
+            Some("canary"),
+            group(&node("us-east1", &[("track", "canary"), ("team", "a")]))
+        );
+        assert_eq!(
+            Some("everyone-else"),
+            group(&node("us-east1", &[("track", "stable")]))
+        );
+        assert_eq!(
+            Some("everyone-else"),
+            group(&node("", &[("track", "canary")]))
+        );
+        assert_eq!(1, config.groups[0].filters.len());
+        assert!(NodeConfig::default()
+            .group(&node("us-east1", &[]))
+            .is_none());
+
+        // Nodes keep their locality and metadata through xDS.
+        let node = node("us-east1", &[("track", "canary")]);
+        assert_eq!(node, NodeInfo::from(&Node::from(&node)));
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::BTreeMap, time::Duration};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{cluster::ClusterMap, endpoint::EndpointAddress};
+
+/// The default number of seconds a session can be idle before it's removed.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+/// The default number of seconds between checks for expired sessions.
+pub const DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS: u64 = 60;
+
+/// The key in the top level listener's metadata containing the session config.
+pub(crate) const METADATA_KEY: &str = "session";
+
+/// Configuration for how long sessions are kept alive.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    /// The number of seconds a session can be idle (no packets sent in
+    /// either direction) before it's removed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// The number of seconds between checks for expired sessions.
+    #[serde(default = "default_expiry_poll_interval")]
+    pub expiry_poll_interval: u64,
+    /// Overrides for sessions sending packets to the endpoints of specific
+    /// clusters, keyed by cluster name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub clusters: BTreeMap<String, ClusterSessionConfig>,
+}
+
+/// Session configuration for the endpoints of a single cluster.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterSessionConfig {
+    /// The number of seconds a session can be idle before it's removed.
+    pub timeout: u64,
+}
+
+impl SessionConfig {
+    /// Returns the default session timeout.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Returns the session timeout for sessions sending to `dest`, using the
+    /// cluster override for the first cluster in `clusters` containing `dest`,
+    /// if present.
+    pub fn timeout_for(&self, clusters: &ClusterMap, dest: &EndpointAddress) -> Duration {
+        if self.clusters.is_empty() {
+            return self.timeout();
+        }
+
+        clusters
+            .values()
+            .filter(|cluster| {
+                cluster
+                    .endpoints()
+                    .any(|endpoint| endpoint.address == *dest)
+            })
+            .find_map(|cluster| self.clusters.get(&cluster.name))
+            .map(|config| Duration::from_secs(config.timeout))
+            .unwrap_or_else(|| self.timeout())
+    }
+
+    /// Returns the interval between checks for expired sessions, which is
+    /// at least one second.
+    pub fn expiry_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.expiry_poll_interval.max(1))
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT_SECONDS,
+            expiry_poll_interval: DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS,
+            clusters: <_>::default(),
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+fn default_expiry_poll_interval() -> u64 {
+    DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS
+}
+
+impl TryFrom<&'_ SessionConfig> for prost_types::Struct {
+    type Error = eyre::Error;
+
+    fn try_from(config: &SessionConfig) -> Result<Self, Self::Error> {
+        crate::prost::struct_from_json(serde_json::to_value(config)?)
+            .ok_or_else(|| eyre::eyre!("session config must be an object"))
+    }
+}
+
+impl TryFrom<prost_types::Struct> for SessionConfig {
+    type Error = eyre::Error;
+
+    fn try_from(value: prost_types::Struct) -> Result<Self, Self::Error> {
+        let value = crate::prost::value_from_kind(prost_types::value::Kind::StructValue(value));
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{cluster::Cluster, endpoint::Endpoint, endpoint::LocalityEndpoints};
+
+    #[test]
+    fn parse() {
+        let config: SessionConfig = serde_yaml::from_str(
+            "
+timeout: 120
+clusters:
+  voice:
+    timeout: 300
+",
+        )
+        .unwrap();
+
+        assert_eq!(Duration::from_secs(120), config.timeout());
+        assert_eq!(
+            Duration::from_secs(DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS),
+            config.expiry_poll_interval()
+        );
+        assert_eq!(300, config.clusters["voice"].timeout);
+    }
+
+    #[test]
+    fn timeout_for() {
+        let game: EndpointAddress = "127.0.0.1:8000".parse().unwrap();
+        let voice: EndpointAddress = "127.0.0.1:9000".parse().unwrap();
+        let clusters = ClusterMap::from([
+            Cluster::new(
+                "game".into(),
+                vec![LocalityEndpoints::from(Endpoint::new(game.clone()))],
+            ),
+            Cluster::new(
+                "voice".into(),
+                vec![LocalityEndpoints::from(Endpoint::new(voice.clone()))],
+            ),
+        ]);
+
+        let mut config = SessionConfig::default();
+        assert_eq!(config.timeout(), config.timeout_for(&clusters, &voice));
+
+        config
+            .clusters
+            .insert("voice".into(), ClusterSessionConfig { timeout: 300 });
+        assert_eq!(
+            Duration::from_secs(300),
+            config.timeout_for(&clusters, &voice)
+        );
+        assert_eq!(config.timeout(), config.timeout_for(&clusters, &game));
+        assert_eq!(
+            config.timeout(),
+            config.timeout_for(&clusters, &"127.0.0.1:1000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn xds_roundtrip() {
+        let mut config = SessionConfig {
+            timeout: 90,
+            expiry_poll_interval: 10,
+            ..<_>::default()
+        };
+        config
+            .clusters
+            .insert("voice".into(), ClusterSessionConfig { timeout: 300 });
+
+        let proto = prost_types::Struct::try_from(&config).unwrap();
+        assert_eq!(config, SessionConfig::try_from(proto).unwrap());
+    }
+}
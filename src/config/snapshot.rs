@@ -0,0 +1,319 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The last known good configuration a proxy received over xDS, saved to a
+//! file so that the proxy can start with it when it can't reach a management
+//! server.
+
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    xds::{metrics, Resource, ResourceType},
+    Config,
+};
+
+/// How long to wait after a change before saving, so that the resources of
+/// one response are saved together.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+/// How often the age of a stale snapshot is updated.
+const AGE_INTERVAL: Duration = Duration::from_secs(1);
+/// The key containing when the snapshot was saved, in seconds since the UNIX
+/// epoch.
+const SAVED_AT: &str = "saved_at";
+
+/// Saves the configuration a proxy receives over xDS to a file, which is
+/// loaded when the proxy starts. The loaded configuration is stale until it's
+/// replaced by configuration from a management server.
+pub(crate) struct Snapshot {
+    path: PathBuf,
+    /// The resource types whose configuration is still the one loaded from
+    /// the file.
+    stale: parking_lot::Mutex<HashSet<ResourceType>>,
+    /// When the loaded snapshot was saved, in seconds since the UNIX epoch.
+    saved_at: AtomicU64,
+    changed: tokio::sync::watch::Sender<()>,
+}
+
+impl Snapshot {
+    /// Loads the snapshot at `path` into `config`, if there is one, and
+    /// spawns a task which saves `config` to `path` whenever it's changed by
+    /// a management server. The task stops once the snapshot is dropped.
+    pub(crate) fn load(path: PathBuf, config: Arc<Config>) -> Arc<Self> {
+        let (changed, mut changed_rx) = tokio::sync::watch::channel(());
+        let this = Arc::new(Self {
+            path,
+            stale: <_>::default(),
+            saved_at: <_>::default(),
+            changed,
+        });
+
+        match this.read(&config) {
+            Ok(Some(saved_at)) => {
+                tracing::info!(path = %this.path.display(), saved_at, "loaded xDS snapshot");
+                this.saved_at.store(saved_at, Ordering::Relaxed);
+                *this.stale.lock() = [ResourceType::Endpoint, ResourceType::Listener].into();
+            }
+            Ok(None) => {
+                tracing::info!(path = %this.path.display(), "no xDS snapshot to load");
+            }
+            Err(error) => {
+                tracing::warn!(path = %this.path.display(), %error, "failed to load xDS snapshot");
+            }
+        }
+        this.update_metrics();
+
+        let snapshot = Arc::downgrade(&this);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(AGE_INTERVAL);
+            loop {
+                tokio::select! {
+                    result = changed_rx.changed() => {
+                        if result.is_err() {
+                            break;
+                        }
+
+                        tokio::time::sleep(SAVE_DELAY).await;
+                        let Some(snapshot) = Weak::upgrade(&snapshot) else {
+                            break;
+                        };
+                        if let Err(error) = snapshot.save(&config) {
+                            tracing::warn!(path = %snapshot.path.display(), %error, "failed to save xDS snapshot");
+                        }
+                    }
+                    _ = interval.tick() => {
+                        let Some(snapshot) = Weak::upgrade(&snapshot) else {
+                            break;
+                        };
+                        snapshot.update_metrics();
+                    }
+                }
+            }
+        });
+
+        this
+    }
+
+    /// Applies `resources` from a management server to `config`, replacing
+    /// any configuration of their types which was loaded from the file.
+    /// Nothing is replaced if any of the resources are invalid.
+    pub(crate) fn apply(&self, config: &Config, resources: &[Resource]) -> crate::Result<()> {
+        let changes = Config::prepare(resources)?;
+        for resource in resources {
+            self.refresh(config, resource.resource_type());
+        }
+        config.commit(changes);
+        self.changed.send_replace(());
+        Ok(())
+    }
+
+    /// Removes the resource called `name` from `config`, see
+    /// [`Config::remove`].
+    pub(crate) fn remove(
+        &self,
+        config: &Config,
+        resource_type: ResourceType,
+        name: &str,
+    ) -> crate::Result<()> {
+        self.refresh(config, resource_type);
+        config.remove(resource_type, name)?;
+        self.changed.send_replace(());
+        Ok(())
+    }
+
+    /// Returns whether any of the configuration is still the one loaded from
+    /// the file.
+    pub(crate) fn is_stale(&self) -> bool {
+        !self.stale.lock().is_empty()
+    }
+
+    /// Clears the configuration of `resource_type` loaded from the file, the
+    /// first time a management server sends a resource of that type, so that
+    /// resources the management server no longer has aren't kept.
+    fn refresh(&self, config: &Config, resource_type: ResourceType) {
+        let resource_type = match resource_type {
+            ResourceType::Cluster => ResourceType::Endpoint,
+            resource_type => resource_type,
+        };
+
+        let mut stale = self.stale.lock();
+        if !stale.remove(&resource_type) {
+            return;
+        }
+
+        tracing::info!(%resource_type, "replacing configuration loaded from the xDS snapshot");
+        match resource_type {
+            ResourceType::Endpoint => config.clusters.modify(|clusters| clusters.clear()),
+            ResourceType::Listener => config.listeners.modify(|listeners| listeners.clear()),
+            _ => {}
+        }
+
+        if stale.is_empty() {
+            drop(stale);
+            self.update_metrics();
+        }
+    }
+
+    /// Reads the snapshot into `config`, returning when it was saved, or
+    /// `None` if there isn't one.
+    fn read(&self, config: &Config) -> crate::Result<Option<u64>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut map: serde_json::Map<String, serde_json::Value> = serde_yaml::from_str(&contents)?;
+        let saved_at = map
+            .remove(SAVED_AT)
+            .and_then(|value| value.as_u64())
+            .unwrap_or_default();
+        config.update_from_json(map, None)?;
+        Ok(Some(saved_at))
+    }
+
+    /// Saves the configuration received over xDS, replacing the file at once
+    /// so that a partially written snapshot is never loaded. While some of the
+    /// configuration is still the one loaded from the file, the snapshot keeps
+    /// the time the loaded snapshot was saved at, as that's when the stale
+    /// configuration was received.
+    fn save(&self, config: &Config) -> crate::Result<()> {
+        let saved_at = if self.is_stale() {
+            self.saved_at.load(Ordering::Relaxed)
+        } else {
+            now()
+        };
+
+        let contents = serde_json::json!({
+            SAVED_AT: saved_at,
+            "clusters": &*config.clusters.load(),
+            "filters": &*config.filters.load(),
+            "listeners": &*config.listeners.load(),
+            "session": &*config.session.load(),
+            "health_check": &*config.health_check.load(),
+        });
+
+        let temporary = temporary_path(&self.path);
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(serde_yaml::to_string(&contents)?.as_bytes())?;
+        // Written to disk before replacing the snapshot, so that a crash can't
+        // leave an empty snapshot behind.
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+        tracing::debug!(path = %self.path.display(), "saved xDS snapshot");
+        Ok(())
+    }
+
+    fn update_metrics(&self) {
+        if self.is_stale() {
+            let saved_at = self.saved_at.load(Ordering::Relaxed);
+            metrics::SNAPSHOT_STALE.set(1);
+            metrics::SNAPSHOT_AGE.set(now().saturating_sub(saved_at) as i64);
+        } else {
+            metrics::SNAPSHOT_STALE.set(0);
+            metrics::SNAPSHOT_AGE.set(0);
+        }
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cluster::ClusterMap, endpoint::Endpoint};
+
+    fn clusters(port: u16) -> ClusterMap {
+        ClusterMap::new_with_default_cluster(vec![Endpoint::new(
+            (std::net::Ipv4Addr::LOCALHOST, port).into(),
+        )])
+    }
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let directory = tempdir::TempDir::new("snapshot").unwrap();
+        let path = directory.path().join("snapshot.yaml");
+
+        // Nothing is loaded until a snapshot has been saved.
+        let config = Arc::new(Config::default());
+        let snapshot = Snapshot::load(path.clone(), config.clone());
+        assert!(!snapshot.is_stale());
+
+        let resource = Resource::Endpoint(Box::new((&clusters(7001)["default"]).into()));
+        snapshot.apply(&config, &[resource]).unwrap();
+        snapshot.save(&config).unwrap();
+        drop(snapshot);
+
+        let loaded = Arc::new(Config::default());
+        let snapshot = Snapshot::load(path.clone(), loaded.clone());
+        assert!(snapshot.is_stale());
+        assert_eq!(config.clusters, loaded.clusters);
+        assert_eq!(1, metrics::SNAPSHOT_STALE.get());
+
+        // The first resource of a type replaces all of the stale resources
+        // of that type.
+        loaded.clusters.modify(|map| {
+            map.insert(crate::cluster::Cluster {
+                name: "old".into(),
+                localities: <_>::default(),
+            });
+        });
+        let resource = Resource::Endpoint(Box::new((&clusters(7002)["default"]).into()));
+        snapshot.apply(&loaded, &[resource]).unwrap();
+        assert_eq!(clusters(7002), *loaded.clusters.load());
+        assert!(snapshot.is_stale());
+
+        // The listeners are still stale, so the snapshot is still as old as
+        // the one that was loaded.
+        snapshot.saved_at.store(1, Ordering::Relaxed);
+        snapshot.save(&loaded).unwrap();
+        assert_eq!(Some(1), snapshot.read(&Config::default()).unwrap());
+
+        snapshot
+            .remove(&loaded, ResourceType::Listener, "game")
+            .unwrap();
+        assert!(!snapshot.is_stale());
+        assert_eq!(0, metrics::SNAPSHOT_STALE.get());
+
+        snapshot.save(&loaded).unwrap();
+        assert!(snapshot.read(&Config::default()).unwrap().unwrap() > 1);
+
+        // Invalid snapshots are ignored.
+        std::fs::write(&path, "clusters: [").unwrap();
+        let config = Arc::new(Config::default());
+        assert!(!Snapshot::load(path, config.clone()).is_stale());
+        assert!(config.clusters.load().is_empty());
+    }
+}
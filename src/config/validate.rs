@@ -0,0 +1,689 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Validation of configuration files, which unlike deserializing a [`Config`]
+//! reports every error in the file rather than only the first one.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+
+use crate::{
+    cluster::Cluster,
+    endpoint::{Endpoint, LocalityEndpoints},
+    filters::{CreateFilterArgs, FilterRegistry},
+};
+
+use super::{Config, Filter, HealthCheckConfig, Listener, NodeConfig, SessionConfig, Version};
+
+/// A segment of the path to a value in a configuration file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// An error found in a configuration file, along with the path to the value
+/// it was found in, which is displayed as `path.to[0].value: message`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: Vec<Segment>,
+    /// The line of the value in the file, starting at `1`, if it could be
+    /// found.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.path.is_empty() {
+            for (i, segment) in self.path.iter().enumerate() {
+                match segment {
+                    Segment::Key(key) if i == 0 => write!(f, "{key}")?,
+                    Segment::Key(key) => write!(f, ".{key}")?,
+                    Segment::Index(index) => write!(f, "[{index}]")?,
+                }
+            }
+            f.write_str(": ")?;
+        }
+
+        f.write_str(&self.message)
+    }
+}
+
+/// Validates the YAML configuration in `source`, by deserializing every part
+/// of it, checking the configuration of every filter, and checking that its listeners and
+/// clusters are consistent, returning every error found.
+pub fn validate(source: &str) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        source,
+        diagnostics: Vec::new(),
+    };
+    validator.validate();
+    validator.diagnostics
+}
+
+struct Validator<'source> {
+    source: &'source str,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator<'_> {
+    fn validate(&mut self) {
+        let root = match serde_yaml::from_str::<Value>(self.source) {
+            Ok(Value::Null) => return,
+            Ok(Value::Mapping(root)) => root,
+            Ok(_) => return self.error(&[], "the configuration must be a mapping"),
+            Err(error) => {
+                self.diagnostics.push(Diagnostic {
+                    path: Vec::new(),
+                    line: error.location().map(|location| location.line()),
+                    message: error.to_string(),
+                });
+                return;
+            }
+        };
+
+        let mut clusters = None;
+        let mut listeners = None;
+        for (key, value) in &root {
+            let Some(key) = key.as_str() else {
+                self.error(&[], "configuration keys must be strings");
+                continue;
+            };
+            let path = [Segment::Key(key.into())];
+
+            match key {
+                "version" => self.check::<Version>(&path, value),
+                "id" => self.check::<String>(&path, value),
+                "session" => self.check::<SessionConfig>(&path, value),
+                "health_check" => self.check::<HealthCheckConfig>(&path, value),
+                "filters" => self.filters(&path, value),
+                "clusters" => clusters = Some(self.clusters(value)),
+                "listeners" => listeners = Some(value),
+                "nodes" => self.nodes(value),
+                _ => self.error(&path, &format!("unknown field `{key}`")),
+            }
+        }
+
+        if let Some(listeners) = listeners {
+            self.listeners(listeners, clusters.as_ref());
+        }
+
+        // Catches anything the checks above don't cover, such as structural
+        // constraints of the configuration as a whole.
+        if self.diagnostics.is_empty() {
+            if let Err(error) = Config::from_reader(self.source.as_bytes()) {
+                self.diagnostics.push(Diagnostic {
+                    path: Vec::new(),
+                    line: error.location().map(|location| location.line()),
+                    message: error.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Validates the clusters, returning the names of the clusters.
+    fn clusters(&mut self, value: &Value) -> Vec<String> {
+        let path = [Segment::Key("clusters".into())];
+        let Some(clusters) = self.mapping(&path, value) else {
+            return Vec::new();
+        };
+
+        let mut names = Vec::new();
+        for (name, cluster) in clusters {
+            let Some(name) = name.as_str() else {
+                self.error(&path, "cluster names must be strings");
+                continue;
+            };
+            names.push(name.to_owned());
+
+            let path = [path[0].clone(), Segment::Key(name.into())];
+            if serde_yaml::from_value::<Cluster>(cluster.clone()).is_ok() {
+                self.duplicate_endpoints(&path, cluster);
+                continue;
+            }
+
+            // Narrows the error down to the locality or endpoint that caused it.
+            let localities = cluster.get("localities").and_then(Value::as_sequence);
+            let Some(localities) = localities else {
+                self.check::<Cluster>(&path, cluster);
+                continue;
+            };
+
+            for (i, locality) in localities.iter().enumerate() {
+                let path = [
+                    path[0].clone(),
+                    path[1].clone(),
+                    Segment::Key("localities".into()),
+                    Segment::Index(i),
+                ];
+                if serde_yaml::from_value::<LocalityEndpoints>(locality.clone()).is_ok() {
+                    continue;
+                }
+
+                let endpoints = locality.get("endpoints").and_then(Value::as_sequence);
+                let Some(endpoints) = endpoints else {
+                    self.check::<LocalityEndpoints>(&path, locality);
+                    continue;
+                };
+
+                let mut endpoints_valid = true;
+                for (j, endpoint) in endpoints.iter().enumerate() {
+                    let path = [
+                        &path[..],
+                        &[Segment::Key("endpoints".into()), Segment::Index(j)],
+                    ]
+                    .concat();
+                    endpoints_valid &= self.parse::<Endpoint>(&path, endpoint).is_some();
+                }
+
+                if endpoints_valid {
+                    self.check::<LocalityEndpoints>(&path, locality);
+                }
+            }
+        }
+
+        names
+    }
+
+    /// Reports endpoints which are in more than one of a cluster's localities.
+    fn duplicate_endpoints(&mut self, path: &[Segment], cluster: &Value) {
+        let localities = cluster.get("localities").and_then(Value::as_sequence);
+        let mut seen = HashMap::new();
+
+        for (i, locality) in localities.into_iter().flatten().enumerate() {
+            let endpoints = locality.get("endpoints").and_then(Value::as_sequence);
+            for (j, endpoint) in endpoints.into_iter().flatten().enumerate() {
+                let Ok(endpoint) = serde_yaml::from_value::<Endpoint>(endpoint.clone()) else {
+                    continue;
+                };
+
+                if let Some(first) = seen.insert(endpoint.address.clone(), i) {
+                    if first != i {
+                        let path = [
+                            path,
+                            &[
+                                Segment::Key("localities".into()),
+                                Segment::Index(i),
+                                Segment::Key("endpoints".into()),
+                                Segment::Index(j),
+                            ],
+                        ]
+                        .concat();
+                        self.error(
+                            &path,
+                            &format!(
+                                "endpoint `{}` is also in locality {first} of the cluster",
+                                endpoint.address
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn listeners(&mut self, value: &Value, clusters: Option<&Vec<String>>) {
+        let path = [Segment::Key("listeners".into())];
+        let Some(listeners) = self.mapping(&path, value) else {
+            return;
+        };
+
+        let mut ports = BTreeMap::new();
+        for (name, listener) in listeners {
+            let Some(name) = name.as_str() else {
+                self.error(&path, "listener names must be strings");
+                continue;
+            };
+            let path = [path[0].clone(), Segment::Key(name.into())];
+
+            // The filters are validated separately, so that each of their
+            // errors are reported.
+            let mut without_filters = listener.clone();
+            if let Some(filters) = listener.get("filters") {
+                let path = [&path[..], &[Segment::Key("filters".into())]].concat();
+                self.filters(&path, filters);
+                if let Some(listener) = without_filters.as_mapping_mut() {
+                    listener.remove("filters");
+                }
+            }
+
+            let Some(listener) = self.parse::<Listener>(&path, &without_filters) else {
+                continue;
+            };
+
+            if let Some(other) = ports.insert(listener.port, name) {
+                let path = [&path[..], &[Segment::Key("port".into())]].concat();
+                self.error(
+                    &path,
+                    &format!(
+                        "port {} is already used by listener `{other}`",
+                        listener.port
+                    ),
+                );
+            }
+
+            for (i, cluster) in listener.clusters.iter().enumerate() {
+                if clusters.map_or(true, |clusters| !clusters.contains(cluster)) {
+                    let path = [
+                        &path[..],
+                        &[Segment::Key("clusters".into()), Segment::Index(i)],
+                    ]
+                    .concat();
+                    self.error(&path, &format!("cluster `{cluster}` doesn't exist"));
+                }
+            }
+        }
+    }
+
+    fn nodes(&mut self, value: &Value) {
+        let path = [Segment::Key("nodes".into())];
+
+        // The filters of each group are validated separately, so that each of
+        // their errors are reported.
+        let mut without_filters = value.clone();
+        if let Some(groups) = without_filters
+            .get_mut("groups")
+            .and_then(Value::as_sequence_mut)
+        {
+            for (i, group) in groups.iter_mut().enumerate() {
+                let filters = group
+                    .as_mapping_mut()
+                    .and_then(|group| group.remove("filters"));
+                let Some(filters) = filters else {
+                    continue;
+                };
+                let path = [
+                    path[0].clone(),
+                    Segment::Key("groups".into()),
+                    Segment::Index(i),
+                    Segment::Key("filters".into()),
+                ];
+                self.filters(&path, &filters);
+            }
+        }
+
+        self.check::<NodeConfig>(&path, &without_filters);
+    }
+
+    /// Validates a filter chain, by checking the configuration of each of its
+    /// filters without creating them.
+    fn filters(&mut self, path: &[Segment], value: &Value) {
+        let Some(filters) = value.as_sequence() else {
+            if !value.is_null() {
+                self.error(path, "the filter chain must be a list of filters");
+            }
+            return;
+        };
+
+        for (i, filter) in filters.iter().enumerate() {
+            let path = [path, &[Segment::Index(i)]].concat();
+            let Some(filter) = self.parse::<Filter>(&path, filter) else {
+                continue;
+            };
+
+            if let Err(error) =
+                FilterRegistry::validate(&filter.name, CreateFilterArgs::fixed(filter.config))
+            {
+                let field = match error {
+                    crate::filters::Error::NotFound(_) => "name",
+                    _ => "config",
+                };
+                let path = [&path[..], &[Segment::Key(field.into())]].concat();
+                self.error(&path, &error.to_string());
+            }
+        }
+    }
+
+    fn mapping<'value>(
+        &mut self,
+        path: &[Segment],
+        value: &'value Value,
+    ) -> Option<&'value serde_yaml::Mapping> {
+        match value {
+            Value::Mapping(mapping) => Some(mapping),
+            Value::Null => None,
+            _ => {
+                self.error(path, "expected a mapping");
+                None
+            }
+        }
+    }
+
+    /// Deserializes `value`, reporting an error at `path` if it fails.
+    fn parse<T: DeserializeOwned>(&mut self, path: &[Segment], value: &Value) -> Option<T> {
+        serde_yaml::from_value(value.clone())
+            .map_err(|error| self.error(path, &error.to_string()))
+            .ok()
+    }
+
+    fn check<T: DeserializeOwned>(&mut self, path: &[Segment], value: &Value) {
+        self.parse::<T>(path, value);
+    }
+
+    fn error(&mut self, path: &[Segment], message: &str) {
+        self.diagnostics.push(Diagnostic {
+            path: path.to_vec(),
+            line: locate(self.source, path),
+            message: message.into(),
+        });
+    }
+}
+
+/// Finds the line of the value at `path` in `source`, or of its closest
+/// parent that could be found. Only block style YAML is supported, values in
+/// flow style mappings and sequences are located at their parent.
+fn locate(source: &str, path: &[Segment]) -> Option<usize> {
+    // (line number, indentation, contents) of every line with a value.
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let contents = line.trim_start();
+            (!contents.is_empty() && !contents.starts_with('#') && contents != "---")
+                .then(|| (i + 1, line.len() - contents.len(), contents))
+        })
+        .collect::<Vec<_>>();
+
+    // The lines of the value that's currently being searched, the children
+    // of which have the indentation of its first line.
+    let mut block = 0..lines.len();
+    let mut found = None;
+
+    for segment in path {
+        let Some(&(_, indent, _)) = lines.get(block.start).filter(|_| !block.is_empty()) else {
+            return found;
+        };
+        let children = block
+            .clone()
+            .filter(|&i| lines[i].1 == indent)
+            .collect::<Vec<_>>();
+
+        let child = match segment {
+            Segment::Key(key) => children
+                .iter()
+                .find(|&&i| mapping_key(lines[i].2) == Some(key.as_str())),
+            Segment::Index(index) => children
+                .iter()
+                .filter(|&&i| lines[i].2 == "-" || lines[i].2.starts_with("- "))
+                .nth(*index),
+        };
+        let Some(&i) = child else {
+            return found;
+        };
+        found = Some(lines[i].0);
+
+        // The value ends at the next line that's at most as indented, apart
+        // from sequences which can have the same indentation as their key.
+        let end = (i + 1..block.end)
+            .find(|&j| {
+                lines[j].1 < indent
+                    || (lines[j].1 == indent
+                        && (matches!(segment, Segment::Index(_)) || !lines[j].2.starts_with('-')))
+            })
+            .unwrap_or(block.end);
+
+        block = match segment {
+            // The first value of a sequence item can be on the same line as
+            // its `-`, in which case it's treated as its own line.
+            Segment::Index(_) if lines[i].2 != "-" => {
+                let contents = lines[i].2[1..].trim_start();
+                lines[i].1 += lines[i].2.len() - contents.len();
+                lines[i].2 = contents;
+                i..end
+            }
+            _ => i + 1..end,
+        };
+    }
+
+    found
+}
+
+/// Returns the key of a line containing a `key: value` pair.
+fn mapping_key(line: &str) -> Option<&str> {
+    for quote in ['"', '\''] {
+        if let Some(rest) = line.strip_prefix(quote) {
+            let (key, rest) = rest.split_once(quote)?;
+            return rest.trim_start().starts_with(':').then_some(key);
+        }
+    }
+
+    let (key, rest) = line.split_once(':')?;
+    (rest.is_empty() || rest.starts_with(' ')).then(|| key.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<String> {
+        validate(source).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn valid() {
+        let source = "
+version: v1alpha1
+id: proxy
+clusters:
+  default:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7001
+listeners:
+  game:
+    port: 7100
+    clusters: [default]
+    filters:
+      - name: quilkin.filters.debug.v1alpha1.Debug
+filters:
+  - name: quilkin.filters.debug.v1alpha1.Debug
+nodes:
+  failover:
+    us-east1: [us-central1]
+  groups:
+    - name: canary
+      selector:
+        metadata:
+          track: canary
+      filters:
+        - name: quilkin.filters.debug.v1alpha1.Debug
+";
+        assert_eq!(Vec::<String>::new(), messages(source));
+        assert!(validate("").is_empty());
+    }
+
+    #[test]
+    fn syntax_error() {
+        let diagnostics = validate("filters:\n  - name: [unclosed\n");
+        assert_eq!(1, diagnostics.len());
+        assert!(diagnostics[0].path.is_empty());
+        assert!(diagnostics[0].line.is_some());
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let source = "
+version: v2
+unknown: true
+clusters:
+  default:
+    localities:
+      - endpoints:
+          - address: 127.0.0.1:7001
+          - address: not an address
+  chat:
+    localities:
+      - locality:
+          region: us
+        endpoints:
+          - address: 127.0.0.1:7002
+      - locality:
+          region: eu
+        endpoints:
+          - address: 127.0.0.1:7002
+listeners:
+  game:
+    port: 7100
+    clusters:
+      - default
+      - missing
+  voice:
+    port: 7100
+    filters:
+      - name: quilkin.filters.not_a_filter.v1alpha1.NotAFilter
+filters:
+  - name: quilkin.filters.debug.v1alpha1.Debug
+  - name: quilkin.filters.capture.v1alpha1.Capture
+    config:
+      metadataKey: myapp.com/myownkey
+      prefix:
+        size: not a size
+";
+
+        let diagnostics = validate(source);
+        let located = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let path = Diagnostic {
+                    line: None,
+                    message: String::new(),
+                    ..diagnostic.clone()
+                }
+                .to_string();
+                (path, diagnostic.line)
+            })
+            .collect::<Vec<_>>();
+
+        for expected in [
+            ("version: ", Some(2)),
+            ("unknown: ", Some(3)),
+            ("clusters.default.localities[0].endpoints[1]: ", Some(9)),
+            ("clusters.chat.localities[1].endpoints[0]: ", Some(19)),
+            ("listeners.game.clusters[1]: ", Some(25)),
+            ("listeners.voice.port: ", Some(27)),
+            ("listeners.voice.filters[0].name: ", Some(29)),
+            ("filters[1].config: ", Some(33)),
+        ] {
+            assert!(
+                located.contains(&(expected.0.into(), expected.1)),
+                "{expected:?} not in {located:#?}"
+            );
+        }
+        assert_eq!(8, diagnostics.len(), "{located:#?}");
+    }
+
+    #[test]
+    fn node_groups() {
+        let source = "
+nodes:
+  groups:
+    - name: canary
+      selector:
+        track: canary
+      filters:
+        - name: quilkin.filters.not_a_filter.v1alpha1.NotAFilter
+";
+        let diagnostics = validate(source);
+        assert_eq!(2, diagnostics.len(), "{diagnostics:#?}");
+        assert_eq!(
+            "nodes.groups[0].filters[0].name: ",
+            Diagnostic {
+                line: None,
+                message: String::new(),
+                ..diagnostics[0].clone()
+            }
+            .to_string()
+        );
+        assert_eq!(Some(8), diagnostics[0].line);
+        // The rest of the node config is reported as a whole.
+        assert_eq!(vec![Segment::Key("nodes".into())], diagnostics[1].path);
+        assert!(diagnostics[1].message.contains("track"));
+    }
+
+    #[test]
+    fn locate_paths() {
+        let source = "
+# comment
+clusters:
+  default:
+    localities:
+    - endpoints:
+      - address: 127.0.0.1:7001
+      - address: 127.0.0.1:7002
+        metadata:
+          quilkin.dev:
+            tokens: []
+filters:
+  -
+    name: quilkin.filters.debug.v1alpha1.Debug
+  - name: \"quilkin.filters.pass.v1alpha1.Pass\"
+    'config': {}
+";
+        let key = |key: &str| Segment::Key(key.into());
+        let index = Segment::Index;
+
+        assert_eq!(Some(3), locate(source, &[key("clusters")]));
+        assert_eq!(
+            Some(8),
+            locate(
+                source,
+                &[
+                    key("clusters"),
+                    key("default"),
+                    key("localities"),
+                    index(0),
+                    key("endpoints"),
+                    index(1)
+                ]
+            )
+        );
+        assert_eq!(
+            Some(10),
+            locate(
+                source,
+                &[
+                    key("clusters"),
+                    key("default"),
+                    key("localities"),
+                    index(0),
+                    key("endpoints"),
+                    index(1),
+                    key("metadata"),
+                    key("quilkin.dev"),
+                ]
+            )
+        );
+        assert_eq!(
+            Some(14),
+            locate(source, &[key("filters"), index(0), key("name")])
+        );
+        assert_eq!(
+            Some(16),
+            locate(source, &[key("filters"), index(1), key("config")])
+        );
+        // Missing values are located at their closest parent.
+        assert_eq!(
+            Some(15),
+            locate(source, &[key("filters"), index(1), key("missing")])
+        );
+    }
+}
@@ -94,6 +94,7 @@ impl Watcher {
             },
             Event::Deleted(_) => {
                 self.config.filters.remove();
+                self.config.nodes.remove();
                 return Ok(());
             }
         };
@@ -122,6 +123,16 @@ impl Watcher {
             self.config.filters.store(Arc::new(filters));
         }
 
+        if let Some(nodes) = data
+            .get("nodes")
+            .cloned()
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|error| tonic::Status::internal(error.to_string()))?
+        {
+            self.config.nodes.store(Arc::new(nodes));
+        }
+
         Ok(())
     }
 
@@ -17,14 +17,18 @@
 //! Types representing where the data is the sent.
 
 mod address;
+mod health;
 mod locality;
 
+use std::num::NonZeroU32;
+
 use serde::{Deserialize, Serialize};
 
 use crate::xds::config::endpoint::v3::{lb_endpoint::HostIdentifier, Endpoint as EnvoyEndpoint};
 
 pub use self::{
-    address::EndpointAddress,
+    address::{AddressKind, EndpointAddress},
+    health::{EndpointHealth, HealthStatus},
     locality::{Locality, LocalityEndpoints, LocalitySet},
 };
 
@@ -39,6 +43,14 @@ pub struct Endpoint {
     pub address: EndpointAddress,
     #[serde(default)]
     pub metadata: EndpointMetadata,
+    /// Whether the endpoint is able to receive packets, which is updated by
+    /// health checks, or provided by the endpoint's provider.
+    #[serde(default, skip_serializing_if = "HealthStatus::is_unknown")]
+    pub health: HealthStatus,
+    /// The weight of the endpoint relative to the other endpoints in its
+    /// locality, used by the weighted load balancing policies. Defaults to `1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<NonZeroU32>,
 }
 
 impl Endpoint {
@@ -58,6 +70,11 @@ impl Endpoint {
             ..<_>::default()
         }
     }
+
+    /// Returns the weight of the endpoint within its locality.
+    pub fn weight(&self) -> u32 {
+        self.weight.map_or(1, NonZeroU32::get)
+    }
 }
 
 impl Default for Endpoint {
@@ -65,6 +82,8 @@ impl Default for Endpoint {
         Self {
             address: EndpointAddress::UNSPECIFIED,
             metadata: <_>::default(),
+            health: <_>::default(),
+            weight: None,
         }
     }
 }
@@ -88,6 +107,8 @@ impl From<Endpoint> for crate::xds::config::endpoint::v3::LbEndpoint {
                 ..<_>::default()
             })),
             metadata: Some(endpoint.metadata.into()),
+            health_status: crate::xds::config::core::v3::HealthStatus::from(endpoint.health) as i32,
+            load_balancing_weight: endpoint.weight.map(NonZeroU32::get),
             ..<_>::default()
         }
     }
@@ -111,7 +132,9 @@ impl TryFrom<crate::xds::config::endpoint::v3::LbEndpoint> for Endpoint {
                 .map(crate::metadata::MetadataView::try_from)
                 .transpose()?
                 .unwrap_or_default(),
-            ..<_>::default()
+            health: HealthStatus::from_xds(endpoint.health_status),
+            weight: endpoint.load_balancing_weight.and_then(NonZeroU32::new),
+            ..Self::default()
         })
     }
 }
@@ -280,6 +303,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn weight() {
+        let endpoint: Endpoint = serde_yaml::from_str("address: 127.0.0.1:80").unwrap();
+        assert_eq!(1, endpoint.weight());
+
+        let endpoint: Endpoint = serde_yaml::from_str("address: 127.0.0.1:80\nweight: 3").unwrap();
+        assert_eq!(3, endpoint.weight());
+        assert_eq!(
+            endpoint,
+            Endpoint::try_from(crate::xds::config::endpoint::v3::LbEndpoint::from(
+                endpoint.clone()
+            ))
+            .unwrap()
+        );
+
+        serde_yaml::from_str::<Endpoint>("address: 127.0.0.1:80\nweight: 0").unwrap_err();
+    }
+
     #[test]
     fn parse_dns_endpoints() {
         let localhost = "address: localhost:80";
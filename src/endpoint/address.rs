@@ -92,9 +92,17 @@ impl FromStr for EndpointAddress {
             .next()
             .ok_or_else(|| eyre::eyre!("No valid socket address found."))?;
 
-        Ok(match string.split_once(':') {
+        if let Ok(addr) = string.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+
+        Ok(match string.rsplit_once(':') {
             Some((host, port)) => {
-                let host = host.parse().unwrap();
+                let host = host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .unwrap();
                 let port = port.parse()?;
 
                 Self {
@@ -250,13 +258,13 @@ impl TryFrom<crate::xds::config::endpoint::v3::Endpoint> for EndpointAddress {
 
 impl fmt::Display for EndpointAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.host)?;
-
-        if let Some(port) = self.port {
-            write!(f, ":{}", port)?;
+        match (&self.host, self.port) {
+            // IPv6 addresses need to be wrapped in brackets to be able to
+            // separate the address from the port.
+            (AddressKind::Ip(IpAddr::V6(ip)), Some(port)) => write!(f, "[{}]:{}", ip, port),
+            (host, Some(port)) => write!(f, "{}:{}", host, port),
+            (host, None) => write!(f, "{}", host),
         }
-
-        Ok(())
     }
 }
 
@@ -319,3 +327,32 @@ impl Serialize for EndpointAddress {
         serializer.serialize_str(&self.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv4() {
+        let address: EndpointAddress = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(address, EndpointAddress::from(([127, 0, 0, 1], 8080)));
+        assert_eq!("127.0.0.1:8080", address.to_string());
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        let address: EndpointAddress = "[::1]:8080".parse().unwrap();
+        assert_eq!(address, EndpointAddress::from((Ipv6Addr::LOCALHOST, 8080)));
+        assert_eq!("[::1]:8080", address.to_string());
+        assert_eq!(address, address.to_string().parse().unwrap());
+        assert!(address.to_socket_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn parse_name() {
+        let address: EndpointAddress = "localhost:8080".parse().unwrap();
+        assert_eq!(address.host, AddressKind::Name("localhost".into()));
+        assert_eq!(address.port, Some(8080));
+        assert_eq!("localhost:8080", address.to_string());
+    }
+}
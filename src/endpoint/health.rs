@@ -0,0 +1,274 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::xds::config::core::v3::HealthStatus as ProtoHealthStatus;
+
+use super::{Endpoint, EndpointAddress};
+
+/// Whether an [`Endpoint`][super::Endpoint] is able to receive packets.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, schemars::JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// The endpoint's health hasn't been checked, it receives packets as if
+    /// it were healthy.
+    Unknown,
+    /// The endpoint passed its last health check.
+    Healthy,
+    /// The endpoint failed its health checks, and doesn't receive packets.
+    Unhealthy,
+    /// The endpoint is being removed by its provider, and doesn't receive
+    /// packets.
+    Draining,
+}
+
+impl HealthStatus {
+    /// Returns whether packets can be sent to an endpoint with this status.
+    pub fn is_available(&self) -> bool {
+        matches!(self, Self::Unknown | Self::Healthy)
+    }
+
+    /// Returns whether the status is [`HealthStatus::Unknown`].
+    pub fn is_unknown(&self) -> bool {
+        *self == Self::Unknown
+    }
+
+    /// Converts the `health_status` field of an xDS `LbEndpoint`, unknown
+    /// values being treated as [`HealthStatus::Unknown`].
+    pub(crate) fn from_xds(status: i32) -> Self {
+        ProtoHealthStatus::from_i32(status)
+            .map(Self::from)
+            .unwrap_or_default()
+    }
+}
+
+/// The health of endpoints found by health checking, by their address. It's
+/// kept separately from the clusters, so that updating them doesn't reset it.
+#[derive(Clone, Debug, Default)]
+pub struct EndpointHealth(Arc<HealthState>);
+
+#[derive(Debug, Default)]
+struct HealthState {
+    statuses: dashmap::DashMap<EndpointAddress, HealthStatus>,
+    /// When each endpoint ejected by passive health checking becomes
+    /// available again, ordered by time.
+    ejections: Mutex<BTreeSet<(Instant, EndpointAddress)>>,
+    ejected: Notify,
+}
+
+impl EndpointHealth {
+    /// Returns the health of the endpoints with `address`.
+    pub fn get(&self, address: &EndpointAddress) -> HealthStatus {
+        self.0
+            .statuses
+            .get(address)
+            .map(|health| *health)
+            .unwrap_or_default()
+    }
+
+    /// Sets the health of the endpoints with `address`, returning whether it
+    /// changed.
+    pub fn set(&self, address: &EndpointAddress, health: HealthStatus) -> bool {
+        let previous = if health.is_unknown() {
+            self.0.statuses.remove(address).map(|(_, health)| health)
+        } else {
+            self.0.statuses.insert(address.clone(), health)
+        };
+
+        previous.unwrap_or_default() != health
+    }
+
+    /// Forgets the health of the endpoints whose address isn't in
+    /// `addresses`.
+    pub fn retain(&self, addresses: &std::collections::BTreeSet<EndpointAddress>) {
+        self.0
+            .statuses
+            .retain(|address, _| addresses.contains(address));
+    }
+
+    /// Records that the endpoints with `address` have been ejected until
+    /// `until`, waking the task waiting in [`EndpointHealth::ejected`].
+    pub(crate) fn eject(&self, address: &EndpointAddress, until: Instant) {
+        self.0.ejections.lock().insert((until, address.clone()));
+        self.0.ejected.notify_one();
+    }
+
+    /// Returns when the next ejection ends, if any endpoints are ejected.
+    pub(crate) fn next_ejection_end(&self) -> Option<Instant> {
+        self.0
+            .ejections
+            .lock()
+            .iter()
+            .next()
+            .map(|(until, _)| *until)
+    }
+
+    /// Removes and returns the addresses whose ejection has ended by `now`.
+    pub(crate) fn end_ejections(&self, now: Instant) -> Vec<EndpointAddress> {
+        let mut ejections = self.0.ejections.lock();
+        let ended = ejections
+            .iter()
+            .take_while(|(until, _)| *until <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        ended
+            .into_iter()
+            .map(|ejection| {
+                ejections.remove(&ejection);
+                ejection.1
+            })
+            .collect()
+    }
+
+    /// Waits until an endpoint is ejected, or returns immediately if one has
+    /// been ejected since the last call.
+    pub(crate) async fn ejected(&self) {
+        self.0.ejected.notified().await;
+    }
+
+    /// Returns whether `endpoint` is able to receive packets, which it isn't
+    /// if either its provider or health checking found it unavailable.
+    pub fn is_available(&self, endpoint: &Endpoint) -> bool {
+        endpoint.health.is_available() && self.get(&endpoint.address).is_available()
+    }
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "unknown",
+            Self::Healthy => "healthy",
+            Self::Unhealthy => "unhealthy",
+            Self::Draining => "draining",
+        })
+    }
+}
+
+impl From<HealthStatus> for ProtoHealthStatus {
+    fn from(status: HealthStatus) -> Self {
+        match status {
+            HealthStatus::Unknown => Self::Unknown,
+            HealthStatus::Healthy => Self::Healthy,
+            HealthStatus::Unhealthy => Self::Unhealthy,
+            HealthStatus::Draining => Self::Draining,
+        }
+    }
+}
+
+impl From<ProtoHealthStatus> for HealthStatus {
+    fn from(status: ProtoHealthStatus) -> Self {
+        match status {
+            ProtoHealthStatus::Unknown => Self::Unknown,
+            ProtoHealthStatus::Healthy | ProtoHealthStatus::Degraded => Self::Healthy,
+            ProtoHealthStatus::Unhealthy | ProtoHealthStatus::Timeout => Self::Unhealthy,
+            ProtoHealthStatus::Draining => Self::Draining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xds_roundtrip() {
+        for status in [
+            HealthStatus::Unknown,
+            HealthStatus::Healthy,
+            HealthStatus::Unhealthy,
+            HealthStatus::Draining,
+        ] {
+            assert_eq!(
+                status,
+                HealthStatus::from_xds(ProtoHealthStatus::from(status) as i32)
+            );
+        }
+
+        assert_eq!(
+            HealthStatus::Unhealthy,
+            HealthStatus::from_xds(ProtoHealthStatus::Timeout as i32)
+        );
+        assert_eq!(HealthStatus::Unknown, HealthStatus::from_xds(100));
+    }
+
+    #[test]
+    fn endpoint_health() {
+        let health = EndpointHealth::default();
+        let endpoint = Endpoint::new("127.0.0.1:8000".parse().unwrap());
+        assert!(health.is_available(&endpoint));
+
+        assert!(health.set(&endpoint.address, HealthStatus::Unhealthy));
+        assert!(!health.set(&endpoint.address, HealthStatus::Unhealthy));
+        assert!(!health.is_available(&endpoint));
+
+        assert!(health.set(&endpoint.address, HealthStatus::Unknown));
+        assert!(health.is_available(&endpoint));
+
+        // Endpoints drained by their provider stay unavailable.
+        let draining = Endpoint {
+            health: HealthStatus::Draining,
+            ..endpoint.clone()
+        };
+        health.set(&endpoint.address, HealthStatus::Healthy);
+        assert!(!health.is_available(&draining));
+    }
+
+    #[test]
+    fn ejections() {
+        let health = EndpointHealth::default();
+        let first: EndpointAddress = "127.0.0.1:8000".parse().unwrap();
+        let second: EndpointAddress = "127.0.0.1:8001".parse().unwrap();
+        let now = Instant::now();
+        assert_eq!(None, health.next_ejection_end());
+
+        health.eject(&second, now + std::time::Duration::from_secs(2));
+        health.eject(&first, now + std::time::Duration::from_secs(1));
+        assert_eq!(
+            Some(now + std::time::Duration::from_secs(1)),
+            health.next_ejection_end()
+        );
+
+        assert!(health.end_ejections(now).is_empty());
+        assert_eq!(
+            vec![first],
+            health.end_ejections(now + std::time::Duration::from_secs(1))
+        );
+        assert_eq!(
+            vec![second],
+            health.end_ejections(now + std::time::Duration::from_secs(5))
+        );
+        assert_eq!(None, health.next_ejection_end());
+    }
+
+    #[test]
+    fn is_available() {
+        assert!(HealthStatus::Unknown.is_available());
+        assert!(HealthStatus::Healthy.is_available());
+        assert!(!HealthStatus::Unhealthy.is_available());
+        assert!(!HealthStatus::Draining.is_available());
+    }
+}
@@ -14,7 +14,7 @@
  *  limitations under the License.
  */
 
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, num::NonZeroU32};
 
 use serde::{Deserialize, Serialize};
 
@@ -63,6 +63,10 @@ pub struct Locality {
 pub struct LocalityEndpoints {
     pub locality: Option<Locality>,
     pub endpoints: BTreeSet<Endpoint>,
+    /// The weight of the locality relative to the other localities, used by
+    /// the weighted load balancing policies. Defaults to `1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<NonZeroU32>,
 }
 
 impl LocalityEndpoints {
@@ -77,6 +81,12 @@ impl LocalityEndpoints {
         self
     }
 
+    /// Sets the weight of the locality.
+    pub fn with_weight(mut self, weight: impl Into<Option<NonZeroU32>>) -> Self {
+        self.weight = weight.into();
+        self
+    }
+
     /// Removes an endpoint.
     pub fn remove(&mut self, endpoint: &Endpoint) {
         self.endpoints.remove(endpoint);
@@ -149,6 +159,7 @@ impl TryFrom<LocalityLbEndpoints> for LocalityEndpoints {
                 .map(TryFrom::try_from)
                 .collect::<Result<_, Self::Error>>()?,
             locality: value.locality.map(From::from),
+            weight: value.load_balancing_weight.and_then(NonZeroU32::new),
         })
     }
 }
@@ -176,6 +187,7 @@ impl From<LocalityEndpoints> for LocalityLbEndpoints {
         Self {
             lb_endpoints: value.endpoints.into_iter().map(From::from).collect(),
             locality: value.locality.map(From::from),
+            load_balancing_weight: value.weight.map(NonZeroU32::get),
             ..Self::default()
         }
     }
@@ -196,6 +208,9 @@ impl LocalitySet {
     pub fn insert(&mut self, mut locality: LocalityEndpoints) {
         let mut entry = self.0.entry(locality.locality.clone()).or_default();
         entry.locality = locality.locality;
+        if locality.weight.is_some() {
+            entry.weight = locality.weight;
+        }
         entry.endpoints.append(&mut locality.endpoints);
     }
 
@@ -30,11 +30,14 @@ pub mod compress;
 pub mod concatenate_bytes;
 pub mod debug;
 pub mod drop;
+pub mod encrypt;
 pub mod firewall;
+pub mod global_rate_limit;
 pub mod load_balancer;
 pub mod local_rate_limit;
 pub mod r#match;
 pub mod pass;
+pub mod pcap;
 pub mod timestamp;
 pub mod token_router;
 
@@ -45,6 +48,7 @@ pub mod prelude {
         ConvertProtoConfigError, CreateFilterArgs, Error, Filter, FilterInstance, ReadContext,
         StaticFilter, WriteContext,
     };
+    pub use crate::pool::PacketBuffer;
 }
 
 // Core Filter types
@@ -55,12 +59,15 @@ pub use self::{
     concatenate_bytes::ConcatenateBytes,
     debug::Debug,
     drop::Drop,
+    encrypt::Encrypt,
     error::{ConvertProtoConfigError, Error},
     factory::{CreateFilterArgs, DynFilterFactory, FilterFactory, FilterInstance},
     firewall::Firewall,
+    global_rate_limit::GlobalRateLimit,
     load_balancer::LoadBalancer,
     local_rate_limit::LocalRateLimit,
     pass::Pass,
+    pcap::Pcap,
     r#match::Match,
     read::ReadContext,
     registry::FilterRegistry,
@@ -133,6 +140,17 @@ where
     /// If the provided configuration is invalid.
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error>;
 
+    /// Checks the given configuration, if any, without instantiating the
+    /// filter, as instantiating some filters has side effects (e.g. creating
+    /// files). By default a configuration is required, filters that check
+    /// their configuration in [`Self::try_from_config`] should override this
+    /// with the same checks.
+    /// # Errors
+    /// If the provided configuration is invalid.
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config.map(drop).ok_or(Error::MissingConfig(Self::NAME))
+    }
+
     /// Instantiates a new [`StaticFilter`] from the given configuration, if any.
     /// # Panics
     /// If the provided configuration is invalid.
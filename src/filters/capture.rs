@@ -35,7 +35,7 @@ pub use self::{
 pub trait CaptureStrategy {
     /// Capture packet data from the contents, and optionally returns a value if
     /// anything was captured.
-    fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<metadata::Value>;
+    fn capture(&self, contents: &mut PacketBuffer, metrics: &Metrics) -> Option<metadata::Value>;
 }
 
 pub struct Capture {
@@ -192,7 +192,7 @@ mod tests {
         let end = Regex {
             pattern: ::regex::bytes::Regex::new(".{3}$").unwrap(),
         };
-        let mut contents = b"helloabc".to_vec();
+        let mut contents = PacketBuffer::from(b"helloabc".to_vec());
         let result = end.capture(&mut contents, &metrics).unwrap();
         assert_eq!(Value::Bytes(b"abc".to_vec().into()), result);
         assert_eq!(b"helloabc".to_vec(), contents);
@@ -205,7 +205,7 @@ mod tests {
             size: 3,
             remove: false,
         };
-        let mut contents = b"helloabc".to_vec();
+        let mut contents = PacketBuffer::from(b"helloabc".to_vec());
         let result = end.capture(&mut contents, &metrics).unwrap();
         assert_eq!(Value::Bytes(b"abc".to_vec().into()), result);
         assert_eq!(b"helloabc".to_vec(), contents);
@@ -224,7 +224,7 @@ mod tests {
             size: 3,
             remove: false,
         };
-        let mut contents = b"abchello".to_vec();
+        let mut contents = PacketBuffer::from(b"abchello".to_vec());
 
         let result = beg.capture(&mut contents, &metrics);
         assert_eq!(Some(Value::Bytes(b"abc".to_vec().into())), result);
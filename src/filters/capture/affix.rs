@@ -1,4 +1,4 @@
-use crate::metadata::Value;
+use crate::{metadata::Value, pool::PacketBuffer};
 
 use super::Metrics;
 
@@ -28,12 +28,14 @@ pub struct Prefix {
 }
 
 impl super::CaptureStrategy for Prefix {
-    fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<Value> {
+    fn capture(&self, contents: &mut PacketBuffer, metrics: &Metrics) -> Option<Value> {
         is_valid_size(contents, self.size, metrics).then(|| {
             if self.remove {
-                Value::Bytes(contents.drain(..self.size as usize).collect())
+                Value::Bytes(contents.split_prefix(self.size as usize))
             } else {
-                Value::Bytes(contents.iter().take(self.size as usize).copied().collect())
+                Value::Bytes(bytes::Bytes::copy_from_slice(
+                    &contents[..self.size as usize],
+                ))
             }
         })
     }
@@ -50,14 +52,13 @@ pub struct Suffix {
 }
 
 impl super::CaptureStrategy for Suffix {
-    fn capture(&self, contents: &mut Vec<u8>, metrics: &Metrics) -> Option<Value> {
+    fn capture(&self, contents: &mut PacketBuffer, metrics: &Metrics) -> Option<Value> {
         is_valid_size(contents, self.size, metrics).then(|| {
-            let index = contents.len() - self.size as usize;
-
             if self.remove {
-                Value::Bytes(contents.split_off(index).into())
+                Value::Bytes(contents.split_suffix(self.size as usize))
             } else {
-                Value::Bytes(contents.iter().skip(index).copied().collect())
+                let index = contents.len() - self.size as usize;
+                Value::Bytes(bytes::Bytes::copy_from_slice(&contents[index..]))
             }
         })
     }
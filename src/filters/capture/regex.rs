@@ -1,4 +1,4 @@
-use crate::metadata::Value;
+use crate::{metadata::Value, pool::PacketBuffer};
 
 use super::Metrics;
 
@@ -12,7 +12,7 @@ pub struct Regex {
 }
 
 impl super::CaptureStrategy for Regex {
-    fn capture(&self, contents: &mut Vec<u8>, _metrics: &Metrics) -> Option<Value> {
+    fn capture(&self, contents: &mut PacketBuffer, _metrics: &Metrics) -> Option<Value> {
         let matches = self
             .pattern
             .find_iter(contents)
@@ -205,6 +205,19 @@ impl TryFrom<&'_ FilterChain> for crate::xds::config::listener::v3::FilterChain
     }
 }
 
+impl TryFrom<crate::xds::config::listener::v3::FilterChain> for FilterChain {
+    type Error = Error;
+
+    fn try_from(chain: crate::xds::config::listener::v3::FilterChain) -> Result<Self, Error> {
+        chain
+            .filters
+            .into_iter()
+            .map(FilterConfig::try_from)
+            .collect::<Result<Vec<_>, _>>()?
+            .try_into()
+    }
+}
+
 impl std::ops::Index<usize> for FilterChain {
     type Output = (String, FilterInstance);
 
@@ -249,50 +262,65 @@ impl schemars::JsonSchema for FilterChain {
     }
 }
 
-impl Filter for FilterChain {
-    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+impl FilterChain {
+    /// Runs `ctx` through the [`Filter::read`] of each filter in order,
+    /// returning the name of the filter which dropped the packet as the error
+    /// if one did.
+    pub fn try_read(&self, ctx: &mut ReadContext) -> Result<(), &str> {
         self.filters
             .iter()
             .zip(self.filter_read_duration_seconds.iter())
-            .try_fold((), |_, ((id, instance), histogram)| {
+            .try_for_each(|((id, instance), histogram)| {
                 tracing::trace!(%id, "read filtering packet");
                 match histogram.observe_closure_duration(|| instance.filter.read(ctx)) {
                     Some(()) => {
                         tracing::trace!(%id, "read passing packet");
+                        Ok(())
                     }
                     None => {
                         tracing::trace!(%id, "read dropping packet");
                         crate::metrics::packets_dropped_total(crate::metrics::READ, id).inc();
-                        return None;
+                        Err(&**id)
                     }
                 }
-
-                Some(())
             })
     }
 
-    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+    /// Runs `ctx` through the [`Filter::write`] of each filter in reverse
+    /// order, returning the name of the filter which dropped the packet as the
+    /// error if one did.
+    pub fn try_write(&self, ctx: &mut WriteContext) -> Result<(), &str> {
         self.filters
             .iter()
             .rev()
             .zip(self.filter_write_duration_seconds.iter().rev())
-            .try_fold((), |_, ((id, instance), histogram)| {
+            .try_for_each(|((id, instance), histogram)| {
                 tracing::trace!(%id, "write filtering packet");
                 match histogram.observe_closure_duration(|| instance.filter.write(ctx)) {
                     Some(()) => {
                         tracing::trace!(%id, "write passing packet");
-                        Some(())
+                        Ok(())
                     }
                     None => {
                         tracing::trace!(%id, "write dropping packet");
                         crate::metrics::packets_dropped_total(crate::metrics::WRITE, id).inc();
-                        None
+                        Err(&**id)
                     }
                 }
             })
     }
 }
 
+impl Filter for FilterChain {
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        self.try_read(ctx).ok()
+    }
+
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        self.try_write(ctx).ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -428,6 +456,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn dropped_by() {
+        let chain = FilterChain::try_create(&[
+            config::Filter {
+                name: crate::filters::Pass::NAME.into(),
+                config: None,
+            },
+            config::Filter {
+                name: crate::filters::Drop::NAME.into(),
+                config: None,
+            },
+        ])
+        .unwrap();
+
+        let mut context = ReadContext::new(endpoints(), "127.0.0.1:70".parse().unwrap(), vec![]);
+        assert_eq!(
+            Err(crate::filters::Drop::NAME),
+            chain.try_read(&mut context)
+        );
+
+        let endpoint = endpoints().remove(0);
+        let mut context = WriteContext::new(
+            endpoint.clone(),
+            endpoint.address,
+            "127.0.0.1:70".parse().unwrap(),
+            vec![],
+        );
+        assert_eq!(
+            Err(crate::filters::Drop::NAME),
+            chain.try_write(&mut context)
+        );
+    }
+
     #[test]
     fn get_configs() {
         struct TestFilter2;
@@ -27,7 +27,14 @@ use self::quilkin::filters::compress::v1alpha1 as proto;
 use compressor::Compressor;
 use metrics::Metrics;
 
-pub use config::{Action, Config, Mode};
+pub use config::{Action, Config, DictionaryPath, Mode};
+
+/// Marks a packet that was compressed when [`Config::min_size`] is set.
+const COMPRESSED: u8 = 1;
+
+/// Marks a packet that was too small to be compressed when
+/// [`Config::min_size`] is set.
+const UNCOMPRESSED: u8 = 0;
 
 /// Filter for compressing and decompressing packet data
 pub struct Compress {
@@ -35,17 +42,96 @@ pub struct Compress {
     compression_mode: Mode,
     on_read: Action,
     on_write: Action,
+    min_size: Option<usize>,
     compressor: Box<dyn Compressor + Sync + Send>,
 }
 
 impl Compress {
-    fn new(config: Config, metrics: Metrics) -> Self {
-        Self {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        Ok(Self {
             metrics,
-            compressor: config.mode.as_compressor(),
+            compressor: config.as_compressor()?,
             compression_mode: config.mode,
             on_read: config.on_read,
             on_write: config.on_write,
+            min_size: config.min_size.map(|min_size| min_size as usize),
+        })
+    }
+
+    /// Compresses `contents`, prefixing it with whether it was compressed
+    /// when a minimum size is set.
+    fn encode(&self, contents: &mut PacketBuffer) -> std::io::Result<()> {
+        match self.min_size {
+            None => self.compressor.encode(contents),
+            Some(min_size) if contents.len() < min_size => {
+                contents.prepend(&[UNCOMPRESSED]);
+                Ok(())
+            }
+            Some(_) => {
+                self.compressor.encode(contents)?;
+                contents.prepend(&[COMPRESSED]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decompresses `contents`, unless it's marked as uncompressed when a
+    /// minimum size is set.
+    fn decode(&self, contents: &mut PacketBuffer) -> std::io::Result<()> {
+        if self.min_size.is_none() {
+            return self.compressor.decode(contents);
+        }
+
+        match contents.first().copied() {
+            Some(UNCOMPRESSED) => {
+                contents.split_prefix(1);
+                Ok(())
+            }
+            Some(COMPRESSED) => {
+                contents.split_prefix(1);
+                self.compressor.decode(contents)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "packet is not marked as compressed or uncompressed",
+            )),
+        }
+    }
+
+    fn compress(&self, contents: &mut PacketBuffer) -> Option<()> {
+        let original_size = contents.len();
+        match self.encode(contents) {
+            Ok(()) => {
+                self.record(contents.len(), original_size);
+                Some(())
+            }
+            Err(err) => self.failed_compression(&err),
+        }
+    }
+
+    fn decompress(&self, contents: &mut PacketBuffer) -> Option<()> {
+        let original_size = contents.len();
+        match self.decode(contents) {
+            Ok(()) => {
+                self.record(original_size, contents.len());
+                Some(())
+            }
+            Err(err) => self.failed_decompression(&err),
+        }
+    }
+
+    /// Track the sizes of a packet that was compressed or decompressed
+    fn record(&self, compressed_size: usize, decompressed_size: usize) {
+        self.metrics
+            .compressed_bytes_total
+            .inc_by(compressed_size as u64);
+        self.metrics
+            .decompressed_bytes_total
+            .inc_by(decompressed_size as u64);
+        if decompressed_size > 0 {
+            self.metrics
+                .compression_ratio
+                .observe(compressed_size as f64 / decompressed_size as f64);
         }
     }
 
@@ -73,66 +159,18 @@ impl Compress {
 impl Filter for Compress {
     #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
-        let original_size = ctx.contents.len();
-
         match self.on_read {
-            Action::Compress => match self.compressor.encode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(())
-                }
-                Err(err) => self.failed_compression(&err),
-            },
-            Action::Decompress => match self.compressor.decode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(())
-                }
-                Err(err) => self.failed_decompression(&err),
-            },
+            Action::Compress => self.compress(&mut ctx.contents),
+            Action::Decompress => self.decompress(&mut ctx.contents),
             Action::DoNothing => Some(()),
         }
     }
 
     #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
-        let original_size = ctx.contents.len();
         match self.on_write {
-            Action::Compress => match self.compressor.encode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(())
-                }
-                Err(err) => self.failed_compression(&err),
-            },
-            Action::Decompress => match self.compressor.decode(&mut ctx.contents) {
-                Ok(()) => {
-                    self.metrics
-                        .compressed_bytes_total
-                        .inc_by(original_size as u64);
-                    self.metrics
-                        .decompressed_bytes_total
-                        .inc_by(ctx.contents.len() as u64);
-                    Some(())
-                }
-
-                Err(err) => self.failed_decompression(&err),
-            },
+            Action::Compress => self.compress(&mut ctx.contents),
+            Action::Decompress => self.decompress(&mut ctx.contents),
             Action::DoNothing => Some(()),
         }
     }
@@ -144,10 +182,16 @@ impl StaticFilter for Compress {
     type BinaryConfiguration = proto::Compress;
 
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
-        Ok(Compress::new(
-            Self::ensure_config_exists(config)?,
-            Metrics::new()?,
-        ))
+        let config = Self::ensure_config_exists(config)?;
+        let metrics = Metrics::new(config.mode)?;
+        Compress::new(config, metrics)
+    }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config
+            .ok_or(Error::MissingConfig(Self::NAME))
+            .and_then(Config::as_compressor)
+            .map(drop)
     }
 }
 
@@ -155,7 +199,10 @@ impl StaticFilter for Compress {
 mod tests {
     use tracing_test::traced_test;
 
-    use crate::{endpoint::Endpoint, filters::compress::compressor::Snappy};
+    use crate::{
+        endpoint::Endpoint,
+        filters::compress::compressor::{Lz4, Snappy, Zstd},
+    };
 
     use super::*;
 
@@ -186,12 +233,13 @@ mod tests {
     fn upstream() {
         let compress = Compress::new(
             Config {
-                mode: Default::default(),
                 on_read: Action::Compress,
                 on_write: Action::Decompress,
+                ..<_>::default()
             },
-            Metrics::new().unwrap(),
-        );
+            Metrics::new(Mode::default()).unwrap(),
+        )
+        .unwrap();
         let expected = contents_fixture();
 
         // read compress
@@ -249,12 +297,13 @@ mod tests {
     fn downstream() {
         let compress = Compress::new(
             Config {
-                mode: Default::default(),
                 on_read: Action::Decompress,
                 on_write: Action::Compress,
+                ..<_>::default()
             },
-            Metrics::new().unwrap(),
-        );
+            Metrics::new(Mode::default()).unwrap(),
+        )
+        .unwrap();
 
         let (expected, compressed) = assert_downstream(&compress);
 
@@ -277,12 +326,13 @@ mod tests {
     fn failed_decompress() {
         let compression = Compress::new(
             Config {
-                mode: Default::default(),
                 on_read: Action::Compress,
                 on_write: Action::Decompress,
+                ..<_>::default()
             },
-            Metrics::new().unwrap(),
-        );
+            Metrics::new(Mode::default()).unwrap(),
+        )
+        .unwrap();
 
         assert!(compression
             .write(&mut WriteContext::new(
@@ -301,12 +351,13 @@ mod tests {
 
         let compression = Compress::new(
             Config {
-                mode: Default::default(),
                 on_read: Action::Decompress,
                 on_write: Action::Compress,
+                ..<_>::default()
             },
-            Metrics::new().unwrap(),
-        );
+            Metrics::new(Mode::default()).unwrap(),
+        )
+        .unwrap();
 
         assert!(compression
             .read(&mut ReadContext::new(
@@ -334,12 +385,13 @@ mod tests {
     fn do_nothing() {
         let compression = Compress::new(
             Config {
-                mode: Default::default(),
                 on_read: Action::default(),
                 on_write: Action::default(),
+                ..<_>::default()
             },
-            Metrics::new().unwrap(),
-        );
+            Metrics::new(Mode::default()).unwrap(),
+        )
+        .unwrap();
 
         let mut read_context = ReadContext::new(
             vec![Endpoint::new("127.0.0.1:80".parse().unwrap())],
@@ -364,7 +416,7 @@ mod tests {
     #[test]
     fn snappy() {
         let expected = contents_fixture();
-        let mut contents = expected.clone();
+        let mut contents = PacketBuffer::from(expected.clone());
         let snappy = Snappy {};
 
         let ok = snappy.encode(&mut contents);
@@ -392,6 +444,129 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lz4() {
+        assert_round_trip(&Lz4 {});
+    }
+
+    #[test]
+    fn zstd() {
+        assert_round_trip(&Zstd::new(zstd::DEFAULT_COMPRESSION_LEVEL, None));
+
+        let dictionary = b"hello my name is mark".repeat(4);
+        assert_round_trip(&Zstd::new(
+            zstd::DEFAULT_COMPRESSION_LEVEL,
+            Some(&dictionary[..]),
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let expected = vec![0; (1 << 16) + 1];
+        for compressor in [
+            &Lz4 {} as &dyn Compressor,
+            &Zstd::new(zstd::DEFAULT_COMPRESSION_LEVEL, None),
+        ] {
+            let mut contents = PacketBuffer::from(expected.clone());
+            compressor.encode(&mut contents).unwrap();
+            assert!(compressor.decode(&mut contents).is_err());
+        }
+    }
+
+    #[test]
+    fn dictionary_requires_zstd() {
+        let config = Config {
+            mode: Mode::Lz4,
+            dictionary: Some("dictionary".into()),
+            ..<_>::default()
+        };
+        assert!(config.as_compressor().is_err());
+
+        let config = Config {
+            mode: Mode::Zstd,
+            dictionary: Some("does/not/exist".into()),
+            ..<_>::default()
+        };
+        assert!(config.as_compressor().is_err());
+    }
+
+    #[test]
+    fn min_size() {
+        for mode in [Mode::Snappy, Mode::Lz4, Mode::Zstd] {
+            let compress = Compress::new(
+                Config {
+                    mode,
+                    on_read: Action::Decompress,
+                    on_write: Action::Compress,
+                    min_size: Some(10),
+                    ..<_>::default()
+                },
+                Metrics::new(mode).unwrap(),
+            )
+            .unwrap();
+
+            // too small, so only the flag byte is added
+            let mut write_context = WriteContext::new(
+                Endpoint::new("127.0.0.1:80".parse().unwrap()),
+                "127.0.0.1:8080".parse().unwrap(),
+                "127.0.0.1:8081".parse().unwrap(),
+                b"hello".to_vec(),
+            );
+            compress.write(&mut write_context).unwrap();
+            assert_eq!(b"\0hello", &*write_context.contents);
+
+            let mut read_context = ReadContext::new(
+                vec![Endpoint::new("127.0.0.1:80".parse().unwrap())],
+                "127.0.0.1:8080".parse().unwrap(),
+                write_context.contents.to_vec(),
+            );
+            compress.read(&mut read_context).unwrap();
+            assert_eq!(b"hello", &*read_context.contents);
+
+            let (expected, compressed) = assert_downstream(&compress);
+            assert_eq!(COMPRESSED, compressed[0]);
+            assert!(expected.len() > compressed.len());
+
+            // no flag byte
+            let mut read_context = ReadContext::new(
+                vec![Endpoint::new("127.0.0.1:80".parse().unwrap())],
+                "127.0.0.1:8080".parse().unwrap(),
+                Vec::new(),
+            );
+            assert!(compress.read(&mut read_context).is_none());
+        }
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            mode: Mode::Zstd,
+            on_read: Action::Decompress,
+            on_write: Action::Compress,
+            level: 5,
+            dictionary: Some("dictionary".into()),
+            min_size: Some(32),
+        };
+
+        assert_eq!(config, Config::from(proto::Compress::from(config)));
+    }
+
+    fn assert_round_trip(compressor: &dyn Compressor) {
+        let expected = contents_fixture();
+        let mut contents = PacketBuffer::from(expected.clone());
+
+        compressor.encode(&mut contents).unwrap();
+        assert!(
+            expected.len() > contents.len(),
+            "Original: {}. Compressed: {}",
+            expected.len(),
+            contents.len()
+        );
+
+        compressor.decode(&mut contents).unwrap();
+        assert_eq!(expected, contents);
+    }
+
     /// At small data packets, compression will add data, so let's give a bigger data packet!
     fn contents_fixture() -> Vec<u8> {
         String::from("hello my name is mark and I like to do things")
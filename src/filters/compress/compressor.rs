@@ -14,35 +14,158 @@
  * limitations under the License.
  */
 
+use std::convert::TryInto;
 use std::io;
 
+use parking_lot::Mutex;
 use snap::read::FrameDecoder;
 use snap::write::FrameEncoder;
+use zstd::bulk;
+
+use crate::pool::PacketBuffer;
 
 /// A trait that provides a compression and decompression strategy for this filter.
-/// Conversion takes place on a mutable Vec, to ensure the most performant compression or
+/// Conversion takes place on a mutable buffer, to ensure the most performant compression or
 /// decompression operation can occur.
 pub(crate) trait Compressor {
-    /// Compress the contents of the Vec - overwriting the original content.
-    fn encode(&self, contents: &mut Vec<u8>) -> io::Result<()>;
-    /// Decompress the contents of the Vec - overwriting the original content.
-    fn decode(&self, contents: &mut Vec<u8>) -> io::Result<()>;
+    /// Compress the contents of the buffer - overwriting the original content.
+    fn encode(&self, contents: &mut PacketBuffer) -> io::Result<()>;
+    /// Decompress the contents of the buffer - overwriting the original content.
+    fn decode(&self, contents: &mut PacketBuffer) -> io::Result<()>;
 }
 
 pub(crate) struct Snappy {}
 
 impl Compressor for Snappy {
-    fn encode(&self, contents: &mut Vec<u8>) -> io::Result<()> {
-        let input = std::mem::take(contents);
+    fn encode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        let input = contents.split_prefix(contents.len());
         let mut wtr = FrameEncoder::new(contents);
-        io::copy(&mut input.as_slice(), &mut wtr)?;
+        io::copy(&mut &*input, &mut wtr)?;
         Ok(())
     }
 
-    fn decode(&self, contents: &mut Vec<u8>) -> io::Result<()> {
-        let input = std::mem::take(contents);
-        let mut rdr = FrameDecoder::new(input.as_slice());
+    fn decode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        let input = contents.split_prefix(contents.len());
+        let mut rdr = FrameDecoder::new(&*input);
         io::copy(&mut rdr, contents)?;
         Ok(())
     }
 }
+
+/// The largest size a packet can be decompressed to, which is the largest
+/// size of a UDP datagram, so that a small packet can't be decompressed into
+/// an arbitrarily large amount of memory.
+const MAX_DECOMPRESSED_LEN: usize = 1 << 16;
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("decompressed packet is larger than {MAX_DECOMPRESSED_LEN} bytes"),
+    )
+}
+
+/// LZ4 block compression, with the length of the decompressed contents
+/// prepended as a little-endian 32-bit integer.
+pub(crate) struct Lz4 {}
+
+impl Compressor for Lz4 {
+    fn encode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        let input = contents.split_prefix(contents.len());
+        contents.extend_from_slice(&lz4_flex::compress_prepend_size(&input));
+        Ok(())
+    }
+
+    fn decode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        let len = contents
+            .get(..4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if len > MAX_DECOMPRESSED_LEN {
+            return Err(too_large());
+        }
+
+        let input = contents.split_prefix(contents.len());
+        let output = lz4_flex::decompress_size_prepended(&input)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        contents.extend_from_slice(&output);
+        Ok(())
+    }
+}
+
+/// Zstandard compression, optionally with a pre-trained dictionary, which
+/// compresses small packets much better than compressing them on their own.
+pub(crate) struct Zstd {
+    level: i32,
+    /// The pre-trained dictionary, which is empty when none is used.
+    dictionary: Vec<u8>,
+    /// Idle compression contexts, each with a buffer for its output, which
+    /// are reused so that the dictionary is only loaded once per context.
+    compressors: Mutex<Vec<(bulk::Compressor<'static>, Vec<u8>)>>,
+    /// Idle decompression contexts, each with a buffer for its output.
+    decompressors: Mutex<Vec<(bulk::Decompressor<'static>, Vec<u8>)>>,
+}
+
+impl Zstd {
+    pub(crate) fn new(level: i32, dictionary: Option<&[u8]>) -> Self {
+        Self {
+            level,
+            dictionary: dictionary.map(Vec::from).unwrap_or_default(),
+            compressors: <_>::default(),
+            decompressors: <_>::default(),
+        }
+    }
+}
+
+/// Runs `f` with an idle context from `pool`, or a new one from `new` if
+/// there are none, returning the context to the pool afterwards.
+fn with_context<T, R>(
+    pool: &Mutex<Vec<T>>,
+    new: impl FnOnce() -> io::Result<T>,
+    f: impl FnOnce(&mut T) -> io::Result<R>,
+) -> io::Result<R> {
+    let idle = pool.lock().pop();
+    let mut context = match idle {
+        Some(context) => context,
+        None => new()?,
+    };
+    let result = f(&mut context);
+    pool.lock().push(context);
+    result
+}
+
+impl Compressor for Zstd {
+    fn encode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        with_context(
+            &self.compressors,
+            || {
+                bulk::Compressor::with_dictionary(self.level, &self.dictionary)
+                    .map(|compressor| (compressor, Vec::new()))
+            },
+            |(compressor, output)| {
+                output.reserve(zstd::zstd_safe::compress_bound(contents.len()));
+                compressor.compress_to_buffer(&**contents, output)?;
+                contents.clear();
+                contents.extend_from_slice(output);
+                Ok(())
+            },
+        )
+    }
+
+    fn decode(&self, contents: &mut PacketBuffer) -> io::Result<()> {
+        with_context(
+            &self.decompressors,
+            || {
+                bulk::Decompressor::with_dictionary(&self.dictionary)
+                    .map(|decompressor| (decompressor, Vec::with_capacity(MAX_DECOMPRESSED_LEN)))
+            },
+            |(decompressor, output)| {
+                // The output can't grow beyond its capacity, so packets which
+                // decompress to more than the maximum length fail.
+                decompressor.decompress_to_buffer(&**contents, output)?;
+                contents.clear();
+                contents.extend_from_slice(output);
+                Ok(())
+            },
+        )
+    }
+}
@@ -14,29 +14,43 @@
  * limitations under the License.
  */
 
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use super::compressor::{Compressor, Snappy};
+use super::compressor::{Compressor, Lz4, Snappy, Zstd};
 use super::quilkin::filters::compress::v1alpha1::{
     compress::{Action as ProtoAction, ActionValue, Mode as ProtoMode, ModeValue},
     Compress as ProtoConfig,
 };
+use crate::filters::Error;
 
 /// The library to use when compressing.
 #[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
 #[non_exhaustive]
 pub enum Mode {
-    // we only support one mode for now, but adding in the config option to
-    // provide the option to expand for later.
     #[serde(rename = "SNAPPY")]
     Snappy,
+    #[serde(rename = "LZ4")]
+    Lz4,
+    /// Zstandard, which can use a pre-trained [`Config::dictionary`].
+    #[serde(rename = "ZSTD")]
+    Zstd,
 }
 
 impl Mode {
-    pub(crate) fn as_compressor(&self) -> Box<dyn Compressor + Send + Sync> {
+    /// The label of the mode in metrics.
+    pub(crate) fn as_str(&self) -> &'static str {
         match self {
-            Self::Snappy => Box::from(Snappy {}),
+            Self::Snappy => "Snappy",
+            Self::Lz4 => "Lz4",
+            Self::Zstd => "Zstd",
         }
     }
 }
@@ -51,6 +65,8 @@ impl From<Mode> for ProtoMode {
     fn from(mode: Mode) -> Self {
         match mode {
             Mode::Snappy => Self::Snappy,
+            Mode::Lz4 => Self::Lz4,
+            Mode::Zstd => Self::Zstd,
         }
     }
 }
@@ -59,6 +75,8 @@ impl From<ProtoMode> for Mode {
     fn from(mode: ProtoMode) -> Self {
         match mode {
             ProtoMode::Snappy => Self::Snappy,
+            ProtoMode::Lz4 => Self::Lz4,
+            ProtoMode::Zstd => Self::Zstd,
         }
     }
 }
@@ -116,13 +134,145 @@ impl From<Action> for ActionValue {
     }
 }
 
-#[derive(Clone, Copy, Default, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+/// default value for [`Config::level`]
+fn default_level() -> i32 {
+    zstd::DEFAULT_COMPRESSION_LEVEL
+}
+
+/// The path to a pre-trained Zstandard dictionary. Paths are interned, so
+/// that [`Config`] stays `Copy`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DictionaryPath(&'static Path);
+
+impl DictionaryPath {
+    /// Returns the interned copy of `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        static PATHS: Lazy<Mutex<HashSet<&'static Path>>> = Lazy::new(<_>::default);
+
+        let path = path.as_ref();
+        let mut paths = PATHS.lock();
+        match paths.get(path) {
+            Some(interned) => Self(*interned),
+            None => {
+                // Each distinct path is only leaked once, and there are
+                // only as many as there are dictionaries configured.
+                let interned: &'static Path = Box::leak(path.into());
+                paths.insert(interned);
+                Self(interned)
+            }
+        }
+    }
+
+    /// Returns the path to the dictionary.
+    pub fn as_path(&self) -> &'static Path {
+        self.0
+    }
+}
+
+impl From<&'_ str> for DictionaryPath {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<PathBuf> for DictionaryPath {
+    fn from(path: PathBuf) -> Self {
+        Self::new(path)
+    }
+}
+
+impl Serialize for DictionaryPath {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DictionaryPath {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        PathBuf::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl JsonSchema for DictionaryPath {
+    fn schema_name() -> String {
+        PathBuf::schema_name()
+    }
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        PathBuf::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        PathBuf::is_referenceable()
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
 #[non_exhaustive]
 pub struct Config {
     #[serde(default)]
     pub mode: Mode,
     pub on_read: Action,
     pub on_write: Action,
+    /// The compression level used by [`Mode::Zstd`].
+    #[serde(default = "default_level")]
+    pub level: i32,
+    /// The path to a pre-trained dictionary used by [`Mode::Zstd`], which
+    /// must be the same on both sides of the connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<DictionaryPath>,
+    /// When set, packets smaller than this many bytes are sent uncompressed,
+    /// and every packet is prefixed with a byte marking whether it was
+    /// compressed, so this must be set on both sides of the connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<u32>,
+}
+
+impl Config {
+    pub(crate) fn as_compressor(&self) -> Result<Box<dyn Compressor + Send + Sync>, Error> {
+        if self.dictionary.is_some() && self.mode != Mode::Zstd {
+            return Err(Error::FieldInvalid {
+                field: "dictionary".into(),
+                reason: "dictionaries are only supported by the ZSTD mode".into(),
+            });
+        }
+
+        Ok(match self.mode {
+            Mode::Snappy => Box::from(Snappy {}),
+            Mode::Lz4 => Box::from(Lz4 {}),
+            Mode::Zstd => {
+                let dictionary = self
+                    .dictionary
+                    .as_ref()
+                    .map(|path| std::fs::read(path.as_path()))
+                    .transpose()
+                    .map_err(|error| Error::FieldInvalid {
+                        field: "dictionary".into(),
+                        reason: format!("failed to read dictionary: {error}"),
+                    })?;
+
+                Box::from(Zstd::new(self.level, dictionary.as_deref()))
+            }
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            on_read: Action::default(),
+            on_write: Action::default(),
+            level: default_level(),
+            dictionary: None,
+            min_size: None,
+        }
+    }
 }
 
 impl From<Config> for ProtoConfig {
@@ -131,6 +281,11 @@ impl From<Config> for ProtoConfig {
             mode: Some(config.mode.into()),
             on_read: Some(config.on_read.into()),
             on_write: Some(config.on_write.into()),
+            level: Some(config.level),
+            dictionary: config
+                .dictionary
+                .map(|path| path.as_path().to_string_lossy().into_owned()),
+            min_size: config.min_size,
         }
     }
 }
@@ -159,6 +314,9 @@ impl From<ProtoConfig> for Config {
             mode,
             on_read,
             on_write,
+            level: p.level.unwrap_or_else(default_level),
+            dictionary: p.dictionary.map(DictionaryPath::new),
+            min_size: p.min_size,
         }
     }
 }
@@ -15,9 +15,10 @@
  */
 use prometheus::{
     core::{AtomicU64, GenericCounter},
-    IntCounter, IntCounterVec, Result as MetricsResult,
+    linear_buckets, Histogram, HistogramOpts, IntCounter, IntCounterVec, Result as MetricsResult,
 };
 
+use super::Mode;
 use crate::metrics::{filter_opts, CollectorExt};
 
 /// Register and manage metrics for this filter
@@ -26,10 +27,11 @@ pub(super) struct Metrics {
     pub(super) packets_dropped_total_decompress: GenericCounter<AtomicU64>,
     pub(super) compressed_bytes_total: GenericCounter<AtomicU64>,
     pub(super) decompressed_bytes_total: GenericCounter<AtomicU64>,
+    pub(super) compression_ratio: Histogram,
 }
 
 impl Metrics {
-    pub(super) fn new() -> MetricsResult<Self> {
+    pub(super) fn new(mode: Mode) -> MetricsResult<Self> {
         let operation_labels = vec!["action"];
         let dropped_metric = IntCounterVec::new(
             filter_opts(
@@ -41,18 +43,35 @@ impl Metrics {
         )?
         .register_if_not_exists()?;
 
-        let decompressed_bytes_total = IntCounter::with_opts(filter_opts(
-            "decompressed_bytes_total",
-            "Compress",
-            "Total number of decompressed bytes either received or sent.",
-        ))?
+        let decompressed_bytes_total = IntCounter::with_opts(
+            filter_opts(
+                "decompressed_bytes_total",
+                "Compress",
+                "Total number of decompressed bytes either received or sent. Labels: mode.",
+            )
+            .const_label("mode", mode.as_str()),
+        )?
+        .register_if_not_exists()?;
+
+        let compressed_bytes_total = IntCounter::with_opts(
+            filter_opts(
+                "compressed_bytes_total",
+                "Compress",
+                "Total number of compressed bytes either received or sent. Labels: mode.",
+            )
+            .const_label("mode", mode.as_str()),
+        )?
         .register_if_not_exists()?;
 
-        let compressed_bytes_total = IntCounter::with_opts(filter_opts(
-            "compressed_bytes_total",
-            "Compress",
-            "Total number of compressed bytes either received or sent.",
-        ))?
+        let compression_ratio = Histogram::with_opts(
+            HistogramOpts::from(filter_opts(
+                "compression_ratio",
+                "Compress",
+                "The size of packets once compressed, relative to their decompressed size. Labels: mode.",
+            ))
+            .buckets(linear_buckets(0.1, 0.1, 15)?)
+            .const_label("mode", mode.as_str()),
+        )?
         .register_if_not_exists()?;
 
         Ok(Metrics {
@@ -62,6 +81,7 @@ impl Metrics {
                 .get_metric_with_label_values(vec!["Decompress"].as_slice())?,
             compressed_bytes_total,
             decompressed_bytes_total,
+            compression_ratio,
         })
     }
 }
@@ -47,10 +47,10 @@ impl Filter for ConcatenateBytes {
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
         match self.on_read {
             Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
+                ctx.contents.extend_from_slice(&self.bytes);
             }
             Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
+                ctx.contents.prepend(&self.bytes);
             }
             Strategy::DoNothing => {}
         }
@@ -61,10 +61,10 @@ impl Filter for ConcatenateBytes {
     fn write(&self, ctx: &mut WriteContext) -> Option<()> {
         match self.on_write {
             Strategy::Append => {
-                ctx.contents.extend(self.bytes.iter());
+                ctx.contents.extend_from_slice(&self.bytes);
             }
             Strategy::Prepend => {
-                ctx.contents.splice(..0, self.bytes.iter().cloned());
+                ctx.contents.prepend(&self.bytes);
             }
             Strategy::DoNothing => {}
         }
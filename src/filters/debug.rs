@@ -63,6 +63,10 @@ impl StaticFilter for Debug {
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
         Ok(Debug::new(config))
     }
+
+    fn validate_config(_: Option<&Self::Configuration>) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// A Debug filter's configuration.
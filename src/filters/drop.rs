@@ -53,6 +53,10 @@ impl StaticFilter for Drop {
     fn try_from_config(_: Option<Self::Configuration>) -> Result<Self, Error> {
         Ok(Drop::new())
     }
+
+    fn validate_config(_: Option<&Self::Configuration>) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// `pass` filter's configuration.
@@ -0,0 +1,645 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod config;
+mod metrics;
+mod replay;
+
+crate::include_proto!("quilkin.filters.encrypt.v1alpha1");
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use rand::RngCore;
+use ring::{aead, hmac};
+use tracing::warn;
+
+use crate::{
+    config::LOG_SAMPLING_RATE,
+    filters::prelude::*,
+    ttl_map::{Entry, TtlMap},
+};
+
+use self::{metrics::Metrics, quilkin::filters::encrypt::v1alpha1 as proto, replay::ReplayWindow};
+
+pub use config::{Action, Config, Key, Mode};
+
+/// The length of the key identifier, sender identifier, timestamp and
+/// sequence number sent with every sealed packet.
+const HEADER_LEN: usize = 1 + 8 + 4 + 8;
+
+/// The length that secrets must have.
+const SECRET_LEN: usize = 32;
+
+/// A [`Key`] along with the cipher created from its secret.
+struct SealingKey {
+    key: Key,
+    cipher: Cipher,
+}
+
+enum Cipher {
+    Aead(aead::LessSafeKey),
+    Hmac(hmac::Key),
+}
+
+/// Filter for encrypting and authenticating packet data, so that it can't be
+/// read, modified or replayed by anyone without one of the keys.
+///
+/// A sealed packet is made of the sealed contents, followed by the tag, the
+/// key identifier, the big-endian 64-bit sender identifier, 32-bit UNIX
+/// timestamp in seconds and 64-bit sequence number, and with
+/// [`Mode::ChaCha20Poly1305`] the nonce.
+pub struct Encrypt {
+    metrics: Metrics,
+    mode: Mode,
+    on_read: Action,
+    on_write: Action,
+    keys: Vec<SealingKey>,
+    replay_window: u32,
+    /// How long before or after a packet was sealed that it's opened.
+    max_age: Duration,
+    /// The replay window of each sender that packets are opened from, which
+    /// outlives `max_age` so that a sender's packets are too old to be opened
+    /// by the time its window expires.
+    windows: TtlMap<u64, Mutex<ReplayWindow>>,
+    /// The sender identifier of the packets sealed by the filter, so that
+    /// they're checked against the same replay window whatever address
+    /// they're received from.
+    sender: u64,
+    /// The sequence number of the next sealed packet.
+    sequence: AtomicU64,
+}
+
+impl Encrypt {
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let keys = sealing_keys(&config)?;
+        let max_age = Duration::from_secs(config.max_age);
+
+        Ok(Self {
+            metrics,
+            mode: config.mode,
+            on_read: config.on_read,
+            on_write: config.on_write,
+            keys,
+            replay_window: config.replay_window,
+            max_age,
+            windows: TtlMap::new(
+                max_age * 2,
+                Duration::from_secs(crate::config::session::DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS),
+            ),
+            // A new sender every time the filter is created, so that its
+            // sequence numbers can start from zero.
+            sender: rand::random(),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    fn process(&self, action: Action, contents: &mut PacketBuffer) -> Option<()> {
+        match action {
+            Action::Seal => self.seal(contents),
+            Action::Open => self.open(contents),
+            Action::DoNothing => Some(()),
+        }
+    }
+
+    /// Seals `contents` with the key that became active most recently.
+    fn seal(&self, contents: &mut PacketBuffer) -> Option<()> {
+        let now = unix_time().as_secs();
+        let key = match self
+            .keys
+            .iter()
+            .filter(|key| key.key.is_active(now))
+            .max_by_key(|key| key.key.active_from.unwrap_or_default())
+        {
+            Some(key) => key,
+            None => return self.dropped(&self.metrics.packets_dropped_no_active_key),
+        };
+
+        let mut header = [0; HEADER_LEN];
+        header[0] = key.key.id;
+        header[1..9].copy_from_slice(&self.sender.to_be_bytes());
+        header[9..13].copy_from_slice(&(now as u32).to_be_bytes());
+        header[13..].copy_from_slice(&self.sequence.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+
+        match &key.cipher {
+            Cipher::Aead(cipher) => {
+                let mut nonce = [0; aead::NONCE_LEN];
+                rand::thread_rng().fill_bytes(&mut nonce);
+
+                let tag = match cipher.seal_in_place_separate_tag(
+                    aead::Nonce::assume_unique_for_key(nonce),
+                    aead::Aad::from(header),
+                    contents,
+                ) {
+                    Ok(tag) => tag,
+                    Err(_) => return self.dropped(&self.metrics.packets_dropped_malformed),
+                };
+
+                contents.extend_from_slice(tag.as_ref());
+                contents.extend_from_slice(&header);
+                contents.extend_from_slice(&nonce);
+            }
+            Cipher::Hmac(cipher) => {
+                let mut context = hmac::Context::with_key(cipher);
+                context.update(contents);
+                context.update(&header);
+
+                contents.extend_from_slice(context.sign().as_ref());
+                contents.extend_from_slice(&header);
+            }
+        }
+
+        Some(())
+    }
+
+    /// Opens `contents`, dropping it if it wasn't sealed with one of the keys,
+    /// is too old, or has already been received.
+    fn open(&self, contents: &mut PacketBuffer) -> Option<()> {
+        let tag_len = self.mode.tag_len();
+        let body_len = match contents
+            .len()
+            .checked_sub(tag_len + HEADER_LEN + self.mode.nonce_len())
+        {
+            Some(len) => len,
+            None => return self.dropped(&self.metrics.packets_dropped_malformed),
+        };
+
+        let nonce = contents.split_suffix(self.mode.nonce_len());
+        let header = contents.split_suffix(HEADER_LEN);
+        let now = unix_time().as_secs();
+        let key = match self
+            .keys
+            .iter()
+            .find(|key| key.key.id == header[0] && !key.key.is_expired(now))
+        {
+            Some(key) => key,
+            None => return self.dropped(&self.metrics.packets_dropped_unknown_key),
+        };
+
+        let authentic = match &key.cipher {
+            Cipher::Aead(cipher) => aead::Nonce::try_assume_unique_for_key(&nonce)
+                .and_then(|nonce| cipher.open_in_place(nonce, aead::Aad::from(&header), contents))
+                .is_ok(),
+            Cipher::Hmac(cipher) => {
+                let tag = contents.split_suffix(tag_len);
+                let mut context = hmac::Context::with_key(cipher);
+                context.update(contents);
+                context.update(&header);
+                ring::constant_time::verify_slices_are_equal(context.sign().as_ref(), &tag).is_ok()
+            }
+        };
+
+        if !authentic {
+            return self.dropped(&self.metrics.packets_dropped_invalid);
+        }
+
+        // The length is checked above, so these can't fail.
+        let sender = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let sealed_at = u32::from_be_bytes(header[9..13].try_into().unwrap());
+        let sequence = u64::from_be_bytes(header[13..].try_into().unwrap());
+        if self.replay_window > 0
+            && !(self.is_fresh(sealed_at.into(), now) && self.accept(sender, sequence))
+        {
+            return self.dropped(&self.metrics.packets_dropped_replayed);
+        }
+
+        contents.truncate(body_len);
+        Some(())
+    }
+
+    /// Returns whether a packet sealed at `sealed_at` is less than `max_age`
+    /// older or newer than `now`, both in seconds since the UNIX epoch.
+    fn is_fresh(&self, sealed_at: u64, now: u64) -> bool {
+        let max_age = self.max_age.as_secs();
+        sealed_at.saturating_add(max_age) > now && sealed_at < now.saturating_add(max_age)
+    }
+
+    /// Returns whether `sequence` hasn't been received from `sender` before,
+    /// within its replay window.
+    fn accept(&self, sender: u64, sequence: u64) -> bool {
+        if let Some(window) = self.windows.get(&sender) {
+            return window.lock().accept(sequence);
+        }
+
+        match self.windows.entry(sender) {
+            Entry::Occupied(entry) => entry.get().lock().accept(sequence),
+            Entry::Vacant(entry) => entry
+                .insert(Mutex::new(ReplayWindow::new(self.replay_window)))
+                .lock()
+                .accept(sequence),
+        }
+    }
+
+    /// Track a dropped packet.
+    fn dropped(&self, counter: &prometheus::IntCounter) -> Option<()> {
+        if counter.get() % LOG_SAMPLING_RATE == 0 {
+            warn!(mode = ?self.mode, count = counter.get(),
+            "Packets are being dropped as they could not be sealed or opened");
+        }
+        counter.inc();
+        None
+    }
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+impl Filter for Encrypt {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        self.process(self.on_read, &mut ctx.contents)
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        self.process(self.on_write, &mut ctx.contents)
+    }
+}
+
+/// Checks the configuration, and creates the ciphers of its keys.
+fn sealing_keys(config: &Config) -> Result<Vec<SealingKey>, Error> {
+    if config.keys.is_empty() {
+        return Err(Error::FieldInvalid {
+            field: "keys".into(),
+            reason: "at least one key is required".into(),
+        });
+    }
+
+    if config.replay_window > 0 && config.max_age == 0 {
+        return Err(Error::FieldInvalid {
+            field: "max_age".into(),
+            reason: "must be greater than 0 when replay protection is enabled".into(),
+        });
+    }
+
+    let mut ids = HashSet::new();
+    config
+        .keys
+        .iter()
+        .map(|key| {
+            if !ids.insert(key.id) {
+                return Err(Error::FieldInvalid {
+                    field: "keys.id".into(),
+                    reason: format!("key id {} is used by more than one key", key.id),
+                });
+            }
+
+            let secret = key.read_secret()?;
+            if secret.len() != SECRET_LEN {
+                return Err(Error::FieldInvalid {
+                    field: "keys.secret_file".into(),
+                    reason: format!("secret must be {SECRET_LEN} bytes"),
+                });
+            }
+
+            let cipher = match config.mode {
+                Mode::ChaCha20Poly1305 => aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &secret)
+                    .map(aead::LessSafeKey::new)
+                    .map(Cipher::Aead)
+                    .map_err(|_| Error::FieldInvalid {
+                        field: "keys.secret_file".into(),
+                        reason: "secret is not a valid key".into(),
+                    })?,
+                Mode::HmacSha256 => Cipher::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &secret)),
+            };
+
+            Ok(SealingKey {
+                key: key.clone(),
+                cipher,
+            })
+        })
+        .collect()
+}
+
+impl StaticFilter for Encrypt {
+    const NAME: &'static str = "quilkin.filters.encrypt.v1alpha1.Encrypt";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Encrypt;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config
+            .ok_or(Error::MissingConfig(Self::NAME))
+            .and_then(sealing_keys)
+            .map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use once_cell::sync::Lazy;
+
+    use crate::endpoint::Endpoint;
+
+    use super::*;
+
+    /// The secret files of the keys used in the tests, the secret of key `n`
+    /// being `n` repeated, and `short` being too short.
+    static SECRETS: Lazy<tempdir::TempDir> = Lazy::new(|| {
+        let directory = tempdir::TempDir::new("encrypt").unwrap();
+        for id in 1..=2 {
+            let secret = base64::encode([id; SECRET_LEN]);
+            std::fs::write(directory.path().join(id.to_string()), secret).unwrap();
+        }
+        std::fs::write(directory.path().join("short"), base64::encode([1; 16])).unwrap();
+        directory
+    });
+
+    fn key(id: u8) -> Key {
+        Key {
+            id,
+            secret_file: SECRETS.path().join(id.to_string()),
+            active_from: None,
+            expires_at: None,
+        }
+    }
+
+    fn config(mode: Mode, keys: Vec<Key>) -> Config {
+        Config {
+            mode,
+            on_read: Action::Open,
+            on_write: Action::Seal,
+            keys,
+            replay_window: 64,
+            max_age: 30,
+        }
+    }
+
+    fn encrypt(mode: Mode, keys: Vec<Key>) -> Encrypt {
+        Encrypt::new(config(mode, keys), Metrics::new().unwrap()).unwrap()
+    }
+
+    /// Seals `contents` on the write path.
+    fn seal(filter: &Encrypt, contents: &[u8]) -> Vec<u8> {
+        let endpoint = Endpoint::new("127.0.0.1:81".parse().unwrap());
+        let mut context = WriteContext::new(
+            endpoint.clone(),
+            endpoint.address,
+            "127.0.0.1:80".parse().unwrap(),
+            contents.to_vec(),
+        );
+
+        filter.write(&mut context).unwrap();
+        context.contents.to_vec()
+    }
+
+    /// Opens `contents` on the read path.
+    fn open(filter: &Encrypt, contents: &[u8]) -> Option<Vec<u8>> {
+        open_from(filter, "127.0.0.1:80", contents)
+    }
+
+    /// Opens `contents` received from `source` on the read path.
+    fn open_from(filter: &Encrypt, source: &str, contents: &[u8]) -> Option<Vec<u8>> {
+        let mut context = ReadContext::new(
+            vec![Endpoint::new("127.0.0.1:81".parse().unwrap())],
+            source.parse().unwrap(),
+            contents.to_vec(),
+        );
+
+        filter.read(&mut context).map(|_| context.contents.to_vec())
+    }
+
+    #[tokio::test]
+    async fn chacha20_poly1305() {
+        let client = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+        let server = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+
+        let sealed = seal(&client, b"hello");
+        assert_eq!(5 + 16 + HEADER_LEN + aead::NONCE_LEN, sealed.len());
+        assert!(!sealed.windows(5).any(|window| window == b"hello"));
+        assert_eq!(b"hello".to_vec(), open(&server, &sealed).unwrap());
+
+        // The same contents are sealed differently every time.
+        assert_ne!(sealed, seal(&client, b"hello"));
+    }
+
+    #[tokio::test]
+    async fn hmac_sha256() {
+        let client = encrypt(Mode::HmacSha256, vec![key(1)]);
+        let server = encrypt(Mode::HmacSha256, vec![key(1)]);
+
+        let sealed = seal(&client, b"hello");
+        assert_eq!(5 + 32 + HEADER_LEN, sealed.len());
+        assert!(sealed.starts_with(b"hello"));
+        assert_eq!(b"hello".to_vec(), open(&server, &sealed).unwrap());
+    }
+
+    #[tokio::test]
+    async fn drops_tampered_packets() {
+        for mode in [Mode::ChaCha20Poly1305, Mode::HmacSha256] {
+            let client = encrypt(mode, vec![key(1)]);
+            let server = encrypt(mode, vec![key(1)]);
+
+            let sealed = seal(&client, b"hello");
+            for index in 0..sealed.len() {
+                let mut tampered = sealed.clone();
+                tampered[index] ^= 1;
+                // Changing the key id can only make the key unknown, every
+                // other change makes the packet invalid.
+                assert!(open(&server, &tampered).is_none(), "{mode:?} {index}");
+            }
+
+            assert!(open(&server, &sealed[1..]).is_none());
+            assert!(open(&server, b"hello").is_none());
+            assert!(open(&server, &sealed).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_replayed_packets() {
+        let client = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+        let server = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+
+        let first = seal(&client, b"first");
+        let second = seal(&client, b"second");
+
+        // Packets can arrive out of order, but only once.
+        assert!(open(&server, &second).is_some());
+        assert!(open(&server, &first).is_some());
+        assert!(open(&server, &first).is_none());
+        assert!(open(&server, &second).is_none());
+
+        // The replay window follows the sender, not its address.
+        assert!(open_from(&server, "127.0.0.2:80", &first).is_none());
+        let third = seal(&client, b"third");
+        assert!(open_from(&server, "127.0.0.2:80", &third).is_some());
+        assert!(open(&server, &third).is_none());
+
+        // Each sender has its own window.
+        let other = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+        assert!(open(&server, &seal(&other, b"first")).is_some());
+    }
+
+    #[tokio::test]
+    async fn drops_old_packets() {
+        let server = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+        let now = unix_time().as_secs();
+
+        assert!(server.is_fresh(now, now));
+        assert!(server.is_fresh(now - 29, now));
+        assert!(server.is_fresh(now + 29, now));
+        assert!(!server.is_fresh(now - 30, now));
+        assert!(!server.is_fresh(now + 30, now));
+        assert!(!server.is_fresh(0, now));
+    }
+
+    #[tokio::test]
+    async fn drops_unknown_keys() {
+        let client = encrypt(Mode::ChaCha20Poly1305, vec![key(1)]);
+        let server = encrypt(Mode::ChaCha20Poly1305, vec![key(2)]);
+
+        assert!(open(&server, &seal(&client, b"hello")).is_none());
+
+        let expired = encrypt(
+            Mode::ChaCha20Poly1305,
+            vec![Key {
+                expires_at: Some(1),
+                ..key(1)
+            }],
+        );
+        assert!(open(&expired, &seal(&client, b"hello")).is_none());
+    }
+
+    #[tokio::test]
+    async fn key_rotation() {
+        let now = unix_time().as_secs();
+        let key_id = |sealed: &[u8]| sealed[sealed.len() - aead::NONCE_LEN - HEADER_LEN];
+
+        let old = Key {
+            expires_at: Some(now + 3600),
+            ..key(1)
+        };
+        let new = Key {
+            active_from: Some(now + 60),
+            ..key(2)
+        };
+
+        // The new key isn't active yet, but packets sealed with it are
+        // already accepted.
+        let client = encrypt(Mode::ChaCha20Poly1305, vec![old.clone(), new.clone()]);
+        let server = encrypt(Mode::ChaCha20Poly1305, vec![old.clone(), new.clone()]);
+        let sealed = seal(&client, b"hello");
+        assert_eq!(1, key_id(&sealed));
+        assert!(open(&server, &sealed).is_some());
+
+        let client = encrypt(
+            Mode::ChaCha20Poly1305,
+            vec![
+                old,
+                Key {
+                    active_from: Some(now),
+                    ..new
+                },
+            ],
+        );
+        let sealed = seal(&client, b"hello");
+        assert_eq!(2, key_id(&sealed));
+        assert!(open(&server, &sealed).is_some());
+    }
+
+    #[tokio::test]
+    async fn drops_without_active_key() {
+        let client = encrypt(
+            Mode::ChaCha20Poly1305,
+            vec![Key {
+                active_from: Some(u64::MAX),
+                ..key(1)
+            }],
+        );
+
+        let endpoint = Endpoint::new("127.0.0.1:81".parse().unwrap());
+        let mut context = WriteContext::new(
+            endpoint.clone(),
+            endpoint.address,
+            "127.0.0.1:80".parse().unwrap(),
+            b"hello".to_vec(),
+        );
+        assert!(client.write(&mut context).is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_config() {
+        let configs = [
+            (vec![], "at least one key is required"),
+            (vec![key(1), key(1)], "is used by more than one key"),
+            (
+                vec![Key {
+                    secret_file: SECRETS.path().join("short"),
+                    ..key(1)
+                }],
+                "secret must be 32 bytes",
+            ),
+            (
+                vec![Key {
+                    secret_file: SECRETS.path().join("missing"),
+                    ..key(1)
+                }],
+                "missing",
+            ),
+        ];
+
+        for (keys, reason) in configs {
+            let error = Encrypt::new(
+                config(Mode::ChaCha20Poly1305, keys),
+                Metrics::new().unwrap(),
+            )
+            .err()
+            .unwrap();
+            assert!(error.to_string().contains(reason), "{error}");
+        }
+
+        let error = Encrypt::new(
+            Config {
+                max_age: 0,
+                ..config(Mode::ChaCha20Poly1305, vec![key(1)])
+            },
+            Metrics::new().unwrap(),
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("max_age"), "{error}");
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config: Config = serde_yaml::from_str(
+            "
+on_read: OPEN
+on_write: SEAL
+keys:
+  - id: 1
+    secret_file: /etc/quilkin/key-1
+",
+        )
+        .unwrap();
+
+        assert_eq!(Mode::ChaCha20Poly1305, config.mode);
+        assert_eq!(64, config.replay_window);
+        assert_eq!(30, config.max_age);
+        assert_eq!(
+            std::path::Path::new("/etc/quilkin/key-1"),
+            config.keys[0].secret_file
+        );
+    }
+}
@@ -0,0 +1,354 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{convert::TryFrom, path::PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::quilkin::filters::encrypt::v1alpha1::{
+    encrypt::{Action as ProtoAction, ActionValue, Key as ProtoKey, Mode as ProtoMode, ModeValue},
+    Encrypt as ProtoConfig,
+};
+use crate::filters::{ConvertProtoConfigError, Error};
+
+/// The algorithm used to seal and open packets.
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Mode {
+    /// Encrypts and authenticates packets with ChaCha20-Poly1305.
+    #[serde(rename = "CHACHA20_POLY1305")]
+    ChaCha20Poly1305,
+    /// Only authenticates packets with HMAC-SHA256, leaving their contents
+    /// readable.
+    #[serde(rename = "HMAC_SHA256")]
+    HmacSha256,
+}
+
+impl Mode {
+    /// The length of the tag appended to every sealed packet.
+    pub(super) fn tag_len(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => ring::aead::CHACHA20_POLY1305.tag_len(),
+            Self::HmacSha256 => ring::digest::SHA256_OUTPUT_LEN,
+        }
+    }
+
+    /// The length of the nonce appended to every sealed packet.
+    pub(super) fn nonce_len(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => ring::aead::NONCE_LEN,
+            Self::HmacSha256 => 0,
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::ChaCha20Poly1305
+    }
+}
+
+impl From<Mode> for ProtoMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::ChaCha20Poly1305 => Self::ChaCha20Poly1305,
+            Mode::HmacSha256 => Self::HmacSha256,
+        }
+    }
+}
+
+impl From<ProtoMode> for Mode {
+    fn from(mode: ProtoMode) -> Self {
+        match mode {
+            ProtoMode::ChaCha20Poly1305 => Self::ChaCha20Poly1305,
+            ProtoMode::HmacSha256 => Self::HmacSha256,
+        }
+    }
+}
+
+impl From<Mode> for ModeValue {
+    fn from(mode: Mode) -> Self {
+        ModeValue {
+            value: ProtoMode::from(mode) as i32,
+        }
+    }
+}
+
+/// Whether to do nothing, seal or open the packet.
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+pub enum Action {
+    #[serde(rename = "DO_NOTHING")]
+    DoNothing,
+    /// Encrypts and/or authenticates the packet with the active key.
+    #[serde(rename = "SEAL")]
+    Seal,
+    /// Checks that the packet was sealed with one of the keys and hasn't
+    /// been received before, and removes the seal, dropping the packet otherwise.
+    #[serde(rename = "OPEN")]
+    Open,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Action::DoNothing
+    }
+}
+
+impl From<Action> for ProtoAction {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::DoNothing => Self::DoNothing,
+            Action::Seal => Self::Seal,
+            Action::Open => Self::Open,
+        }
+    }
+}
+
+impl From<ProtoAction> for Action {
+    fn from(action: ProtoAction) -> Self {
+        match action {
+            ProtoAction::DoNothing => Self::DoNothing,
+            ProtoAction::Seal => Self::Seal,
+            ProtoAction::Open => Self::Open,
+        }
+    }
+}
+
+impl From<Action> for ActionValue {
+    fn from(action: Action) -> Self {
+        Self {
+            value: ProtoAction::from(action) as i32,
+        }
+    }
+}
+
+/// A secret key shared with the other side of the connection.
+///
+/// Only the path of the file holding the secret is part of the configuration,
+/// so that the secret isn't logged, returned by the admin server or saved
+/// with the rest of the configuration.
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Key {
+    /// The identifier of the key, which is sent with every packet sealed
+    /// with it.
+    pub id: u8,
+    /// The path of the file holding the base64 encoded 32 byte secret, which
+    /// is read when the filter is created.
+    pub secret_file: PathBuf,
+    /// The UNIX timestamp in seconds from which packets are sealed with this
+    /// key. Packets sealed with the key are opened as soon as it's configured,
+    /// so that the other side can start using it first.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_from: Option<u64>,
+    /// The UNIX timestamp in seconds after which the key is no longer used to
+    /// seal or open packets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl Key {
+    /// Reads the secret from [`Key::secret_file`].
+    pub(super) fn read_secret(&self) -> Result<Vec<u8>, Error> {
+        let invalid = |reason: String| Error::FieldInvalid {
+            field: "keys.secret_file".into(),
+            reason,
+        };
+
+        let contents = std::fs::read_to_string(&self.secret_file)
+            .map_err(|error| invalid(format!("{}: {error}", self.secret_file.display())))?;
+        base64::decode(contents.trim()).map_err(|error| {
+            invalid(format!(
+                "{} isn't base64 encoded: {error}",
+                self.secret_file.display()
+            ))
+        })
+    }
+
+    /// Whether packets sealed with the key can be opened at `now`.
+    pub(super) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| now > expires_at)
+    }
+
+    /// Whether packets can be sealed with the key at `now`.
+    pub(super) fn is_active(&self, now: u64) -> bool {
+        self.active_from
+            .map_or(true, |active_from| now >= active_from)
+            && !self.is_expired(now)
+    }
+}
+
+impl From<Key> for ProtoKey {
+    fn from(key: Key) -> Self {
+        Self {
+            id: key.id.into(),
+            secret_file: key.secret_file.to_string_lossy().into_owned(),
+            active_from: key.active_from,
+            expires_at: key.expires_at,
+        }
+    }
+}
+
+impl TryFrom<ProtoKey> for Key {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(key: ProtoKey) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: u8::try_from(key.id)
+                .map_err(|error| ConvertProtoConfigError::new(error, Some("keys.id".into())))?,
+            secret_file: key.secret_file.into(),
+            active_from: key.active_from,
+            expires_at: key.expires_at,
+        })
+    }
+}
+
+/// default value for [`Config::replay_window`]
+fn default_replay_window() -> u32 {
+    64
+}
+
+/// default value for [`Config::max_age`]
+fn default_max_age() -> u64 {
+    30
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq, Serialize, JsonSchema)]
+#[non_exhaustive]
+pub struct Config {
+    #[serde(default)]
+    pub mode: Mode,
+    pub on_read: Action,
+    pub on_write: Action,
+    /// The keys used to seal and open packets. Packets are sealed with the
+    /// active key that became active most recently, and opened with the key
+    /// they were sealed with, as long as it hasn't expired.
+    pub keys: Vec<Key>,
+    /// The number of sequence numbers behind the highest one received from a
+    /// sender that are still accepted, to allow for packets arriving out of
+    /// order. Each sequence number is only accepted once, `0` disables replay
+    /// protection.
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u32,
+    /// The number of seconds after, or before, a packet was sealed that it's
+    /// still accepted, to allow for the clocks of both sides differing. Older
+    /// packets are dropped as replayed.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+impl From<Config> for ProtoConfig {
+    fn from(config: Config) -> Self {
+        Self {
+            mode: Some(config.mode.into()),
+            on_read: Some(config.on_read.into()),
+            on_write: Some(config.on_write.into()),
+            keys: config.keys.into_iter().map(From::from).collect(),
+            replay_window: Some(config.replay_window),
+            max_age: Some(config.max_age),
+        }
+    }
+}
+
+impl TryFrom<ProtoConfig> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: ProtoConfig) -> Result<Self, Self::Error> {
+        let mode = p
+            .mode
+            .map(|p| p.value())
+            .map(Mode::from)
+            .unwrap_or_default();
+
+        let on_read = p
+            .on_read
+            .map(|p| p.value())
+            .map(Action::from)
+            .unwrap_or_default();
+
+        let on_write = p
+            .on_write
+            .map(|p| p.value())
+            .map(Action::from)
+            .unwrap_or_default();
+
+        Ok(Self {
+            mode,
+            on_read,
+            on_write,
+            keys: p
+                .keys
+                .into_iter()
+                .map(Key::try_from)
+                .collect::<Result<_, _>>()?,
+            replay_window: p.replay_window.unwrap_or_else(default_replay_window),
+            max_age: p.max_age.unwrap_or_else(default_max_age),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_proto_config() {
+        let config = Config {
+            mode: Mode::HmacSha256,
+            on_read: Action::Open,
+            on_write: Action::Seal,
+            keys: vec![Key {
+                id: 1,
+                secret_file: "/etc/quilkin/key-1".into(),
+                active_from: Some(10),
+                expires_at: None,
+            }],
+            replay_window: 128,
+            max_age: 10,
+        };
+
+        assert_eq!(
+            config,
+            Config::try_from(ProtoConfig::from(config.clone())).unwrap()
+        );
+
+        let proto = ProtoConfig {
+            keys: vec![ProtoKey {
+                id: 256,
+                ..<_>::default()
+            }],
+            ..<_>::default()
+        };
+        assert!(Config::try_from(proto).is_err());
+    }
+
+    #[test]
+    fn key_validity() {
+        let key = Key {
+            id: 1,
+            secret_file: "/etc/quilkin/key-1".into(),
+            active_from: Some(10),
+            expires_at: Some(20),
+        };
+
+        assert!(!key.is_active(9));
+        assert!(!key.is_expired(9));
+        assert!(key.is_active(10));
+        assert!(key.is_active(20));
+        assert!(!key.is_active(21));
+        assert!(key.is_expired(21));
+    }
+}
@@ -0,0 +1,56 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use prometheus::{
+    core::{AtomicU64, GenericCounter},
+    IntCounterVec, Result as MetricsResult,
+};
+
+use crate::metrics::{filter_opts, CollectorExt};
+
+/// Register and manage metrics for this filter
+pub(super) struct Metrics {
+    pub(super) packets_dropped_malformed: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_unknown_key: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_invalid: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_replayed: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_no_active_key: GenericCounter<AtomicU64>,
+}
+
+impl Metrics {
+    pub(super) fn new() -> MetricsResult<Self> {
+        let dropped_metric = IntCounterVec::new(
+            filter_opts(
+                "packets_dropped_total",
+                "Encrypt",
+                "Total number of packets dropped as they could not be sealed or opened. Labels: reason.",
+            ),
+            &["reason"],
+        )?
+        .register_if_not_exists()?;
+
+        Ok(Metrics {
+            packets_dropped_malformed: dropped_metric
+                .get_metric_with_label_values(&["Malformed"])?,
+            packets_dropped_unknown_key: dropped_metric
+                .get_metric_with_label_values(&["UnknownKey"])?,
+            packets_dropped_invalid: dropped_metric.get_metric_with_label_values(&["Invalid"])?,
+            packets_dropped_replayed: dropped_metric.get_metric_with_label_values(&["Replayed"])?,
+            packets_dropped_no_active_key: dropped_metric
+                .get_metric_with_label_values(&["NoActiveKey"])?,
+        })
+    }
+}
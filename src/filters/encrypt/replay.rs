@@ -0,0 +1,129 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// A sliding window over the most recent sequence numbers received from a
+/// sender, which tracks which of them have been received so that each
+/// sequence number is only accepted once.
+#[derive(Debug)]
+pub(super) struct ReplayWindow {
+    /// The highest sequence number received, if any.
+    highest: Option<u64>,
+    /// One bit per sequence number in the window, the bit of sequence number
+    /// `n` being `n % size`.
+    bits: Vec<u64>,
+    size: u64,
+}
+
+impl ReplayWindow {
+    /// Creates a window of `size` sequence numbers, `size` must not be `0`.
+    pub(super) fn new(size: u32) -> Self {
+        Self {
+            highest: None,
+            bits: vec![0; (size as usize + 63) / 64],
+            size: size.into(),
+        }
+    }
+
+    /// Returns whether `sequence` hasn't been received before and is within
+    /// the window, and marks it as received if so.
+    pub(super) fn accept(&mut self, sequence: u64) -> bool {
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                if highest - sequence >= self.size || self.get(sequence) {
+                    return false;
+                }
+            }
+            Some(highest) => {
+                // Slide the window forward, forgetting the sequence numbers
+                // that are no longer in it.
+                if sequence - highest >= self.size {
+                    self.bits.iter_mut().for_each(|word| *word = 0);
+                } else {
+                    (highest + 1..sequence).for_each(|sequence| self.clear(sequence));
+                }
+                self.highest = Some(sequence);
+            }
+            None => self.highest = Some(sequence),
+        }
+
+        self.set(sequence);
+        true
+    }
+
+    fn position(&self, sequence: u64) -> (usize, u64) {
+        let bit = sequence % self.size;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn get(&self, sequence: u64) -> bool {
+        let (word, mask) = self.position(sequence);
+        self.bits[word] & mask != 0
+    }
+
+    fn set(&mut self, sequence: u64) {
+        let (word, mask) = self.position(sequence);
+        self.bits[word] |= mask;
+    }
+
+    fn clear(&mut self, sequence: u64) {
+        let (word, mask) = self.position(sequence);
+        self.bits[word] &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_replays() {
+        let mut window = ReplayWindow::new(4);
+
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        // Out of order, but within the window.
+        assert!(window.accept(8));
+        assert!(!window.accept(8));
+        assert!(window.accept(7));
+        // Outside of the window.
+        assert!(!window.accept(6));
+
+        assert!(window.accept(12));
+        assert!(!window.accept(8));
+        assert!(window.accept(9));
+        assert!(window.accept(11));
+        assert!(!window.accept(12));
+
+        // Jumping past the whole window forgets every sequence number.
+        assert!(window.accept(100));
+        assert!(window.accept(99));
+        assert!(window.accept(97));
+        assert!(!window.accept(96));
+    }
+
+    #[test]
+    fn large_windows() {
+        let mut window = ReplayWindow::new(1000);
+
+        for sequence in (0..2000).rev() {
+            assert_eq!(window.accept(sequence), sequence >= 1000, "{sequence}");
+        }
+
+        for sequence in 1000..2000 {
+            assert!(!window.accept(sequence));
+        }
+    }
+}
@@ -64,6 +64,10 @@ pub trait FilterFactory: Sync + Send {
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<FilterInstance, Error>;
 
+    /// Checks the configuration in the provided arguments without creating
+    /// the filter.
+    fn validate_config(&self, args: CreateFilterArgs) -> Result<(), Error>;
+
     /// Converts YAML configuration into its Protobuf equivalvent.
     fn encode_config_to_protobuf(&self, args: serde_json::Value)
         -> Result<prost_types::Any, Error>;
@@ -94,14 +98,7 @@ where
 
     /// Returns a filter based on the provided arguments.
     fn create_filter(&self, args: CreateFilterArgs) -> Result<FilterInstance, Error> {
-        let (config_json, config): (_, Option<F::Configuration>) = if let Some(config) = args.config
-        {
-            config
-                .deserialize::<F::Configuration, F::BinaryConfiguration>(self.name())
-                .map(|(j, c)| (j, Some(c)))?
-        } else {
-            (serde_json::Value::Null, None)
-        };
+        let (config_json, config) = deserialize_config::<F>(args)?;
 
         Ok(FilterInstance::new(
             config_json,
@@ -109,6 +106,11 @@ where
         ))
     }
 
+    fn validate_config(&self, args: CreateFilterArgs) -> Result<(), Error> {
+        let (_, config) = deserialize_config::<F>(args)?;
+        F::validate_config(config.as_ref())
+    }
+
     fn encode_config_to_protobuf(
         &self,
         config: serde_json::Value,
@@ -136,6 +138,23 @@ where
     }
 }
 
+/// Deserializes the configuration in `args`, if any, for the filter `F`.
+fn deserialize_config<F>(
+    args: CreateFilterArgs,
+) -> Result<(serde_json::Value, Option<F::Configuration>), Error>
+where
+    F: StaticFilter,
+    Error: From<<F::Configuration as TryFrom<F::BinaryConfiguration>>::Error>
+        + From<<F::BinaryConfiguration as TryFrom<F::Configuration>>::Error>,
+{
+    match args.config {
+        Some(config) => config
+            .deserialize::<F::Configuration, F::BinaryConfiguration>(F::NAME)
+            .map(|(json, config)| (json, Some(config))),
+        None => Ok((serde_json::Value::Null, None)),
+    }
+}
+
 /// Arguments needed to create a new filter.
 pub struct CreateFilterArgs {
     /// Configuration for the filter.
@@ -97,6 +97,8 @@ impl Rule {
     /// assert!(!rule.contains(([192, 168, 76, 10], 40).into()));
     /// ```
     pub fn contains(&self, address: SocketAddr) -> bool {
+        // IPv4-mapped IPv6 addresses are matched against IPv4 rules.
+        let address = crate::utils::net::to_canonical(address);
         if !self.source.contains(address.ip()) {
             return false;
         }
@@ -386,4 +388,30 @@ on_write:
         assert!(!rule.contains((ip, 1000).into()));
         assert!(!rule.contains(([192, 168, 76, 10], 40).into()));
     }
+
+    #[test]
+    fn rule_contains_ipv6() {
+        let rule = Rule {
+            action: Action::Allow,
+            source: "2001:db8::/32".parse().unwrap(),
+            ports: vec![PortRange::new(10, 100).unwrap()],
+        };
+
+        let ip: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert!(rule.contains((ip, 50).into()));
+        assert!(!rule.contains((ip, 1000).into()));
+        let other: std::net::Ipv6Addr = "2001:db9::1".parse().unwrap();
+        assert!(!rule.contains((other, 50).into()));
+        assert!(!rule.contains(([192, 168, 75, 10], 50).into()));
+
+        let rule = Rule {
+            action: Action::Allow,
+            source: "192.168.75.0/24".parse().unwrap(),
+            ports: vec![PortRange::new(10, 100).unwrap()],
+        };
+
+        // IPv4-mapped addresses, such as from dual-stack sockets, match IPv4 rules.
+        let mapped = std::net::Ipv4Addr::new(192, 168, 75, 10).to_ipv6_mapped();
+        assert!(rule.contains((mapped, 50).into()));
+    }
 }
@@ -0,0 +1,682 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod metrics;
+pub(crate) mod service;
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tonic::transport::Channel;
+
+use crate::{filters::prelude::*, xds::ClientTls};
+
+use metrics::Metrics;
+use service::proto::{rate_limit_service_client::RateLimitServiceClient, Usage, UsageReport};
+
+crate::include_proto!("quilkin.filters.global_rate_limit.v1alpha1");
+use self::quilkin::filters::global_rate_limit::v1alpha1 as proto;
+
+/// The maximum time to wait for the rate limit service to respond.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The packets received from a source in the current window.
+#[derive(Debug, Default)]
+struct SourceUsage {
+    /// The window the packets were received in.
+    window: u64,
+    /// The packets received by every proxy, as of the last report.
+    global: u64,
+    /// The packets received by this proxy which haven't been reported.
+    unreported: u64,
+    /// The packets received by this proxy.
+    local: u64,
+}
+
+/// The rate limiting state, which is shared with the task that reports usage
+/// to the rate limit service.
+struct State {
+    config: Config,
+    usage: DashMap<IpAddr, SourceUsage>,
+    service: tonic::transport::Endpoint,
+    /// The client of the rate limit service, which is created on the first
+    /// report, as creating a channel requires a Tokio runtime, and again
+    /// after a failed report, so that renewed certificates are used.
+    client: Mutex<Option<RateLimitServiceClient<Channel>>>,
+    /// Whether the last report to the rate limit service succeeded.
+    connected: AtomicBool,
+    metrics: Metrics,
+}
+
+impl State {
+    /// Returns the current window, the number of periods since the UNIX epoch.
+    fn window(&self) -> u64 {
+        unix_secs() / u64::from(self.config.period)
+    }
+
+    /// Records a packet from `source`, returning whether it's within the
+    /// rate limit.
+    fn acquire(&self, source: IpAddr) -> bool {
+        let window = self.window();
+        let mut usage = self.usage.entry(source).or_default();
+        if usage.window != window {
+            *usage = SourceUsage {
+                window,
+                ..<_>::default()
+            };
+        }
+
+        usage.unreported += 1;
+        usage.local += 1;
+
+        if self.connected.load(Ordering::Relaxed) {
+            return usage.global + usage.unreported <= self.config.max_packets;
+        }
+
+        match self.config.fallback {
+            Fallback::Local => usage.local <= self.config.max_packets,
+            Fallback::Allow => true,
+            Fallback::Deny => false,
+        }
+    }
+
+    /// Returns the client of the rate limit service, creating it if there
+    /// isn't one, with the TLS configuration when the service is `https`.
+    fn client(&self) -> crate::Result<RateLimitServiceClient<Channel>> {
+        let mut client = self.client.lock();
+        if let Some(client) = &*client {
+            return Ok(client.clone());
+        }
+
+        let mut service = self.service.clone();
+        if service.uri().scheme_str() == Some("https") {
+            service = service.tls_config(self.config.tls.config()?)?;
+        }
+
+        Ok(client
+            .insert(RateLimitServiceClient::new(service.connect_lazy()))
+            .clone())
+    }
+
+    /// Reports the packets received since the last report to the rate limit
+    /// service, and updates the totals of every proxy. Every source in the
+    /// current window is reported, so that their totals are kept up to date
+    /// even when this proxy hasn't received any new packets from them.
+    async fn sync(&self) -> Result<(), tonic::Status> {
+        let mut client = self.client().map_err(|error| {
+            self.connected.store(false, Ordering::Relaxed);
+            self.metrics.sync_errors_total.inc();
+            tonic::Status::unavailable(error.to_string())
+        })?;
+
+        let window = self.window();
+        let mut usage = Vec::new();
+        self.usage.retain(|source, source_usage| {
+            if source_usage.window != window {
+                return false;
+            }
+
+            usage.push(Usage {
+                key: source.to_string(),
+                packets: std::mem::take(&mut source_usage.unreported),
+            });
+            true
+        });
+
+        // Nothing needs to be reported, unless checking whether the service
+        // is reachable again.
+        if usage.is_empty() && self.connected.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let mut request = tonic::Request::new(UsageReport {
+            domain: self.config.domain.clone(),
+            period: self.config.period,
+            window,
+            usage: usage.clone(),
+        });
+        request.set_timeout(SYNC_TIMEOUT);
+
+        match client.report(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                self.connected.store(true, Ordering::Relaxed);
+                for total in response.usage {
+                    let source_usage = total
+                        .key
+                        .parse::<IpAddr>()
+                        .ok()
+                        .and_then(|source| self.usage.get_mut(&source));
+
+                    if let Some(mut source_usage) = source_usage {
+                        if source_usage.window == response.window {
+                            source_usage.global = total.packets;
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            Err(status) => {
+                self.connected.store(false, Ordering::Relaxed);
+                self.metrics.sync_errors_total.inc();
+                self.client.lock().take();
+                // The packets are reported again once the service is
+                // reachable, if it's still the same window.
+                for unreported in usage {
+                    let source_usage = unreported
+                        .key
+                        .parse::<IpAddr>()
+                        .ok()
+                        .and_then(|source| self.usage.get_mut(&source));
+
+                    if let Some(mut source_usage) = source_usage {
+                        if source_usage.window == window {
+                            source_usage.unreported += unreported.packets;
+                        }
+                    }
+                }
+
+                Err(status)
+            }
+        }
+    }
+}
+
+/// A filter that rate limits packets across every proxy which reports to the
+/// same rate limit service, so that the limit applies to the packets a source
+/// sends to all of the proxies. Each proxy periodically reports the packets it
+/// has received to the service, which returns the totals of every proxy.
+/// Packets that violate the rate limit are dropped, and packets coming from
+/// upstream endpoints flow through the filter untouched.
+pub struct GlobalRateLimit {
+    state: Arc<State>,
+    /// Whether the task reporting usage to the rate limit service has been
+    /// spawned.
+    sync_started: AtomicBool,
+}
+
+impl GlobalRateLimit {
+    /// Creates the filter. The task which reports usage to the rate limit
+    /// service is spawned once the filter receives its first packet within a
+    /// Tokio runtime, and runs until the filter is dropped.
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let service = config.endpoint()?;
+
+        let state = Arc::new(State {
+            service,
+            client: <_>::default(),
+            config,
+            usage: <_>::default(),
+            connected: AtomicBool::new(false),
+            metrics,
+        });
+
+        Ok(Self {
+            state,
+            sync_started: AtomicBool::new(false),
+        })
+    }
+
+    /// Spawns the task which reports usage to the rate limit service, unless
+    /// it's already running. Outside of a Tokio runtime the task can't be
+    /// spawned, and is spawned by a later packet instead.
+    fn start_sync_task(&self) {
+        if self.sync_started.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+
+        if !self.sync_started.swap(true, Ordering::Relaxed) {
+            spawn_sync_task(
+                &runtime,
+                Arc::downgrade(&self.state),
+                Duration::from_millis(self.state.config.sync_interval_ms),
+            );
+        }
+    }
+}
+
+/// Spawns a task on `runtime` which reports usage to the rate limit service
+/// every `period`, until the filter's state is dropped.
+fn spawn_sync_task(runtime: &tokio::runtime::Handle, state: Weak<State>, period: Duration) {
+    runtime.spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+            let state = match state.upgrade() {
+                Some(state) => state,
+                None => return,
+            };
+
+            if let Err(error) = state.sync().await {
+                tracing::debug!(
+                    %error,
+                    service = %state.config.service,
+                    "failed to report usage to the rate limit service"
+                );
+            }
+        }
+    });
+}
+
+/// Returns the number of seconds since the UNIX epoch.
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Filter for GlobalRateLimit {
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        let source = match ctx.source.to_socket_addr() {
+            Ok(addr) => crate::utils::net::to_canonical(addr).ip(),
+            Err(_) => return Some(()),
+        };
+
+        self.start_sync_task();
+        if self.state.acquire(source) {
+            Some(())
+        } else {
+            self.state.metrics.packets_dropped_total.inc();
+            None
+        }
+    }
+}
+
+impl StaticFilter for GlobalRateLimit {
+    const NAME: &'static str = "quilkin.filters.global_rate_limit.v1alpha1.GlobalRateLimit";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::GlobalRateLimit;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
+    }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config
+            .ok_or(Error::MissingConfig(Self::NAME))
+            .and_then(Config::endpoint)
+            .map(drop)
+    }
+}
+
+/// Config represents a [self]'s configuration.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
+pub struct Config {
+    /// The maximum number of packets allowed from a source IP address by all
+    /// of the proxies in a given duration.
+    pub max_packets: u64,
+    /// The duration in seconds during which max_packets applies. If none is
+    /// provided, it defaults to one second.
+    #[serde(default = "default_period")]
+    pub period: u32,
+    /// The URL of the rate limit service, such as the management server of
+    /// `quilkin manage`.
+    pub service: String,
+    /// The name of the rate limit in the service, proxies with the same
+    /// domain share the same limit.
+    #[serde(default = "default_domain")]
+    pub domain: String,
+    /// How often in milliseconds the proxy reports usage to the service.
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+    /// How packets are rate limited when the service is unreachable.
+    #[serde(default)]
+    pub fallback: Fallback,
+    /// The certificates used to connect to an `https` service, such as a
+    /// management server that requires mutual TLS.
+    #[serde(default, skip_serializing_if = "ClientTls::is_empty")]
+    pub tls: ClientTls,
+}
+
+impl Config {
+    /// Checks the configuration, returning the rate limit service's endpoint.
+    fn endpoint(&self) -> Result<tonic::transport::Endpoint, Error> {
+        if self.period < 1 {
+            return Err(Error::FieldInvalid {
+                field: "period".into(),
+                reason: "value must be at least 1 second".into(),
+            });
+        }
+
+        if self.sync_interval_ms < 1 {
+            return Err(Error::FieldInvalid {
+                field: "sync_interval_ms".into(),
+                reason: "value must be at least 1 millisecond".into(),
+            });
+        }
+
+        tonic::transport::Endpoint::from_shared(self.service.clone()).map_err(|error| {
+            Error::FieldInvalid {
+                field: "service".into(),
+                reason: error.to_string(),
+            }
+        })
+    }
+}
+
+/// default value for [`Config::period`]
+fn default_period() -> u32 {
+    1
+}
+
+/// default value for [`Config::domain`]
+fn default_domain() -> String {
+    "default".into()
+}
+
+/// default value for [`Config::sync_interval_ms`]
+fn default_sync_interval_ms() -> u64 {
+    100
+}
+
+/// How packets are rate limited when the rate limit service is unreachable.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
+pub enum Fallback {
+    /// Limit the packets received by this proxy to `max_packets`.
+    #[serde(rename = "LOCAL")]
+    Local,
+    /// Allow every packet.
+    #[serde(rename = "ALLOW")]
+    Allow,
+    /// Drop every packet.
+    #[serde(rename = "DENY")]
+    Deny,
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Self::Local
+    }
+}
+
+impl From<Fallback> for proto::global_rate_limit::Fallback {
+    fn from(fallback: Fallback) -> Self {
+        match fallback {
+            Fallback::Local => Self::Local,
+            Fallback::Allow => Self::Allow,
+            Fallback::Deny => Self::Deny,
+        }
+    }
+}
+
+impl From<proto::global_rate_limit::Fallback> for Fallback {
+    fn from(fallback: proto::global_rate_limit::Fallback) -> Self {
+        match fallback {
+            proto::global_rate_limit::Fallback::Local => Self::Local,
+            proto::global_rate_limit::Fallback::Allow => Self::Allow,
+            proto::global_rate_limit::Fallback::Deny => Self::Deny,
+        }
+    }
+}
+
+impl From<Config> for proto::GlobalRateLimit {
+    fn from(config: Config) -> Self {
+        Self {
+            max_packets: config.max_packets,
+            period: Some(config.period),
+            service: config.service,
+            domain: Some(config.domain),
+            sync_interval_ms: Some(config.sync_interval_ms),
+            fallback: Some(proto::global_rate_limit::FallbackValue {
+                value: proto::global_rate_limit::Fallback::from(config.fallback) as i32,
+            }),
+            tls: (!config.tls.is_empty()).then(|| {
+                let path = |path: Option<std::path::PathBuf>| {
+                    path.map(|path| path.to_string_lossy().into_owned())
+                };
+
+                proto::global_rate_limit::Tls {
+                    ca: path(config.tls.ca),
+                    cert: path(config.tls.cert),
+                    key: path(config.tls.key),
+                }
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::GlobalRateLimit> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::GlobalRateLimit) -> Result<Self, Self::Error> {
+        Ok(Self {
+            max_packets: p.max_packets,
+            period: p.period.unwrap_or_else(default_period),
+            service: p.service,
+            domain: p.domain.unwrap_or_else(default_domain),
+            sync_interval_ms: p.sync_interval_ms.unwrap_or_else(default_sync_interval_ms),
+            fallback: p
+                .fallback
+                .map(|fallback| fallback.value())
+                .map(Fallback::from)
+                .unwrap_or_default(),
+            tls: p
+                .tls
+                .map(|tls| ClientTls {
+                    ca: tls.ca.map(From::from),
+                    cert: tls.cert.map(From::from),
+                    key: tls.key.map(From::from),
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::endpoint::{Endpoint, EndpointAddress};
+
+    fn rate_limiter(service: String, fallback: Fallback) -> GlobalRateLimit {
+        GlobalRateLimit::new(
+            Config {
+                max_packets: 4,
+                period: 3600,
+                service,
+                domain: default_domain(),
+                // Usage is only reported when the tests call `sync`.
+                sync_interval_ms: 3_600_000,
+                fallback,
+                tls: <_>::default(),
+            },
+            Metrics::new().unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Sends a packet to the filter and returns whether it was forwarded.
+    fn read(filter: &GlobalRateLimit, port: u16) -> bool {
+        let mut context = ReadContext::new(
+            vec![Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into())],
+            EndpointAddress::from((Ipv4Addr::LOCALHOST, port)),
+            vec![9],
+        );
+
+        filter.read(&mut context).is_some()
+    }
+
+    #[tokio::test]
+    async fn shares_limit_between_proxies() {
+        let addr = crate::test_utils::available_addr().await;
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service::GlobalRateLimitService::server())
+                .serve(addr),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let service = format!("http://{addr}");
+        let first = rate_limiter(service.clone(), Fallback::Deny);
+        let second = rate_limiter(service, Fallback::Deny);
+
+        // Packets are dropped until the service has been reached.
+        assert!(!read(&first, 1));
+        first.state.sync().await.unwrap();
+        second.state.sync().await.unwrap();
+
+        // Every port of a source shares the limit, and the dropped packet
+        // counts towards it.
+        assert!(read(&first, 2));
+        assert!(read(&second, 3));
+        first.state.sync().await.unwrap();
+        second.state.sync().await.unwrap();
+        first.state.sync().await.unwrap();
+
+        assert!(read(&second, 4));
+        second.state.sync().await.unwrap();
+        first.state.sync().await.unwrap();
+
+        assert!(!read(&first, 5));
+        assert!(!read(&second, 6));
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let addr = crate::test_utils::available_addr().await;
+        let service = format!("http://{addr}");
+
+        let local = rate_limiter(service.clone(), Fallback::Local);
+        local.state.sync().await.unwrap_err();
+        for port in 0..4 {
+            assert!(read(&local, port));
+        }
+        assert!(!read(&local, 4));
+
+        let allow = rate_limiter(service.clone(), Fallback::Allow);
+        allow.state.sync().await.unwrap_err();
+        assert!((0..10).all(|port| read(&allow, port)));
+
+        let deny = rate_limiter(service, Fallback::Deny);
+        deny.state.sync().await.unwrap_err();
+        assert!(!read(&deny, 0));
+    }
+
+    #[tokio::test]
+    async fn config_validation() {
+        let config = |yaml: &str| serde_yaml::from_str::<Config>(yaml).unwrap();
+
+        assert!(GlobalRateLimit::try_from_config(Some(config(
+            "
+max_packets: 10
+period: 0
+service: http://127.0.0.1:7800
+"
+        )))
+        .is_err());
+
+        assert!(GlobalRateLimit::try_from_config(Some(config(
+            "
+max_packets: 10
+service: not a url
+"
+        )))
+        .is_err());
+
+        let filter = GlobalRateLimit::try_from_config(Some(config(
+            "
+max_packets: 10
+service: http://127.0.0.1:7800
+",
+        )))
+        .unwrap();
+        assert_eq!(1, filter.state.config.period);
+        assert_eq!(Fallback::Local, filter.state.config.fallback);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let directory = tempdir::TempDir::new("rate-limit-tls").unwrap();
+        let ca = crate::xds::tls::tests::generate(directory.path(), &["localhost", "proxy"]);
+        let path = |name: &str| Some(directory.path().join(name));
+
+        let port = crate::test_utils::available_addr().await.port();
+        tokio::spawn(crate::xds::server::spawn(
+            port,
+            <_>::default(),
+            crate::xds::ServerTls {
+                cert: path("localhost.pem"),
+                key: path("localhost.key"),
+                ca: Some(ca.clone()),
+            },
+            <_>::default(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let config = rate_limiter(format!("https://localhost:{port}"), Fallback::Deny)
+            .state
+            .config
+            .clone();
+        let with_tls = |tls| {
+            GlobalRateLimit::new(
+                Config {
+                    tls,
+                    ..config.clone()
+                },
+                Metrics::new().unwrap(),
+            )
+            .unwrap()
+        };
+
+        let filter = with_tls(ClientTls {
+            ca: Some(ca.clone()),
+            cert: path("proxy.pem"),
+            key: path("proxy.key"),
+        });
+        filter.state.sync().await.unwrap();
+        assert!(read(&filter, 1));
+
+        // The management server requires a client certificate.
+        let filter = with_tls(ClientTls {
+            ca: Some(ca),
+            ..<_>::default()
+        });
+        filter.state.sync().await.unwrap_err();
+        assert!(!read(&filter, 1));
+    }
+
+    #[test]
+    fn outside_runtime() {
+        // The filter can be created and receive packets without a runtime,
+        // falling back to the local limit as the service is never reached.
+        let filter = rate_limiter("http://127.0.0.1:7800".into(), Fallback::Local);
+        assert!((0..4).all(|port| read(&filter, port)));
+        assert!(!read(&filter, 4));
+        assert!(!filter.sync_started.load(Ordering::Relaxed));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async { read(&filter, 5) });
+        assert!(filter.sync_started.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The rate limit service that aggregates the packets received by proxies,
+//! which is served by the management server.
+
+use std::{collections::HashMap, time::Duration};
+
+use parking_lot::Mutex;
+
+crate::include_proto!("quilkin.service.rate_limit.v1alpha1");
+pub(crate) use self::quilkin::service::rate_limit::v1alpha1 as proto;
+
+use proto::{
+    rate_limit_service_server::{RateLimitService, RateLimitServiceServer},
+    Usage, UsageReport, UsageResponse,
+};
+
+/// How often windows which have ended are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The packets received by every proxy for a key in a domain.
+struct WindowUsage {
+    window: u64,
+    packets: u64,
+    /// When the window's totals can be removed, in seconds since the UNIX
+    /// epoch.
+    expires_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    usage: HashMap<(String, String), WindowUsage>,
+    last_cleanup: u64,
+}
+
+/// Aggregates the usage reported by proxies running the
+/// [`GlobalRateLimit`][super::GlobalRateLimit] filter, keeping the totals of
+/// the current window of each key.
+#[derive(Default)]
+pub(crate) struct GlobalRateLimitService {
+    state: Mutex<State>,
+}
+
+impl GlobalRateLimitService {
+    /// Returns the service as a gRPC server.
+    pub(crate) fn server() -> RateLimitServiceServer<Self> {
+        RateLimitServiceServer::new(Self::default())
+    }
+
+    fn aggregate(&self, report: UsageReport) -> UsageResponse {
+        let now = super::unix_secs();
+        let period = u64::from(report.period.max(1));
+        let mut state = self.state.lock();
+
+        if now.saturating_sub(state.last_cleanup) >= CLEANUP_INTERVAL.as_secs() {
+            state.usage.retain(|_, usage| usage.expires_at > now);
+            state.last_cleanup = now;
+        }
+
+        let usage = report
+            .usage
+            .into_iter()
+            .filter_map(|usage| {
+                let total = state
+                    .usage
+                    .entry((report.domain.clone(), usage.key.clone()))
+                    .or_insert(WindowUsage {
+                        window: report.window,
+                        packets: 0,
+                        expires_at: 0,
+                    });
+
+                // Proxies which are behind the current window have their
+                // packets ignored, and the totals of newer windows aren't
+                // returned to them.
+                if report.window > total.window {
+                    total.window = report.window;
+                    total.packets = 0;
+                } else if report.window < total.window {
+                    return None;
+                }
+
+                total.packets = total.packets.saturating_add(usage.packets);
+                // Windows are kept until the end of the following window, to
+                // allow for proxies with clocks that are slightly behind.
+                total.expires_at = report.window.saturating_add(2).saturating_mul(period);
+
+                Some(Usage {
+                    key: usage.key,
+                    packets: total.packets,
+                })
+            })
+            .collect();
+
+        UsageResponse {
+            window: report.window,
+            usage,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimitService for GlobalRateLimitService {
+    async fn report(
+        &self,
+        request: tonic::Request<UsageReport>,
+    ) -> Result<tonic::Response<UsageResponse>, tonic::Status> {
+        Ok(tonic::Response::new(self.aggregate(request.into_inner())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(domain: &str, window: u64, usage: &[(&str, u64)]) -> UsageReport {
+        UsageReport {
+            domain: domain.into(),
+            period: 1,
+            window,
+            usage: usage
+                .iter()
+                .map(|(key, packets)| Usage {
+                    key: (*key).into(),
+                    packets: *packets,
+                })
+                .collect(),
+        }
+    }
+
+    fn totals(response: UsageResponse) -> Vec<(String, u64)> {
+        response
+            .usage
+            .into_iter()
+            .map(|usage| (usage.key, usage.packets))
+            .collect()
+    }
+
+    #[test]
+    fn aggregates_usage() {
+        let service = GlobalRateLimitService::default();
+        let window = super::super::unix_secs();
+
+        assert_eq!(
+            vec![("a".to_owned(), 2), ("b".to_owned(), 1)],
+            totals(service.aggregate(report("test", window, &[("a", 2), ("b", 1)])))
+        );
+        assert_eq!(
+            vec![("a".to_owned(), 5)],
+            totals(service.aggregate(report("test", window, &[("a", 3)])))
+        );
+
+        // Domains are counted separately.
+        assert_eq!(
+            vec![("a".to_owned(), 1)],
+            totals(service.aggregate(report("other", window, &[("a", 1)])))
+        );
+
+        // A new window resets the totals, and reports for the previous window
+        // are ignored.
+        assert_eq!(
+            vec![("a".to_owned(), 1)],
+            totals(service.aggregate(report("test", window + 1, &[("a", 1)])))
+        );
+        assert!(totals(service.aggregate(report("test", window, &[("a", 1)]))).is_empty());
+    }
+}
@@ -19,27 +19,43 @@ crate::include_proto!("quilkin.filters.load_balancer.v1alpha1");
 mod config;
 mod endpoint_chooser;
 
+use std::sync::Arc;
+
 use self::quilkin::filters::load_balancer::v1alpha1 as proto;
 use crate::filters::prelude::*;
-use endpoint_chooser::EndpointChooser;
+use endpoint_chooser::{EndpointChooser, LocalityCache};
 
-pub use config::{Config, Policy};
+pub use config::{Config, Failover, HashKey, LocalityPreference, Policy};
 
 /// Balances packets over the upstream endpoints.
 pub struct LoadBalancer {
     endpoint_chooser: Box<dyn EndpointChooser>,
+    locality: Option<LocalityPreference>,
+    /// The localities of the endpoints, shared with the endpoint chooser.
+    localities: Arc<LocalityCache>,
 }
 
 impl LoadBalancer {
     fn new(config: Config) -> Self {
+        let localities = Arc::<LocalityCache>::default();
         Self {
-            endpoint_chooser: config.policy.as_endpoint_chooser(),
+            endpoint_chooser: config.as_endpoint_chooser(localities.clone()),
+            locality: config.locality,
+            localities,
         }
     }
 }
 
 impl Filter for LoadBalancer {
     fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        if let Some(locality) = &self.locality {
+            let localities = self.localities.get(ctx.clusters.as_ref());
+            locality.retain_preferred(ctx, &localities);
+            if ctx.endpoints.is_empty() {
+                return None;
+            }
+        }
+
         self.endpoint_chooser.choose_endpoints(ctx);
         Some(())
     }
@@ -57,10 +73,13 @@ impl StaticFilter for LoadBalancer {
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, net::Ipv4Addr};
+    use std::{collections::HashSet, net::Ipv4Addr, sync::Arc};
 
     use super::*;
-    use crate::endpoint::{Endpoint, EndpointAddress};
+    use crate::{
+        cluster::{Cluster, ClusterMap},
+        endpoint::{Endpoint, EndpointAddress, Locality, LocalityEndpoints},
+    };
 
     fn get_response_addresses(
         filter: &dyn Filter,
@@ -246,4 +265,346 @@ policy: RANDOM
             "the same sequence of addresses were chosen for hash load balancer"
         );
     }
+
+    #[test]
+    fn hash_load_balancer_policy_ipv6() {
+        let addresses: Vec<EndpointAddress> = vec![
+            "[::1]:8080".parse().unwrap(),
+            "[::2]:8080".parse().unwrap(),
+            "[::3]:8080".parse().unwrap(),
+        ];
+
+        let yaml = "policy: HASH";
+        let filter = LoadBalancer::from_config(serde_yaml::from_str(yaml).unwrap());
+
+        // The same IPv6 source should always be routed to the same endpoint.
+        let source: EndpointAddress = "[2001:db8::1]:11111".parse().unwrap();
+        let expected = get_response_addresses(&filter, &addresses, source.clone());
+        for _ in 0..10 {
+            assert_eq!(
+                expected,
+                get_response_addresses(&filter, &addresses, source.clone())
+            );
+        }
+
+        // IPv4-mapped sources are routed the same as their IPv4 equivalent.
+        let mapped = Ipv4Addr::new(127, 1, 1, 1).to_ipv6_mapped();
+        for port in [11111u16, 22222, 33333] {
+            assert_eq!(
+                get_response_addresses(&filter, &addresses, ([127, 1, 1, 1], port).into()),
+                get_response_addresses(&filter, &addresses, (mapped, port).into()),
+            );
+        }
+    }
+
+    fn located_endpoints(localities: Vec<LocalityEndpoints>) -> Arc<ClusterMap> {
+        Arc::new(ClusterMap::from(Cluster::new_default(localities)))
+    }
+
+    fn locality(region: &str, zone: &str) -> Option<Locality> {
+        Some(Locality {
+            region: region.into(),
+            zone: zone.into(),
+            ..<_>::default()
+        })
+    }
+
+    fn weighted(address: EndpointAddress, weight: u32) -> Endpoint {
+        Endpoint {
+            weight: std::num::NonZeroU32::new(weight),
+            ..Endpoint::new(address)
+        }
+    }
+
+    fn choose(filter: &dyn Filter, clusters: &Arc<ClusterMap>) -> Option<EndpointAddress> {
+        let mut context = ReadContext::new(
+            clusters.endpoints().collect(),
+            "127.0.0.1:8080".parse().unwrap(),
+            vec![],
+        )
+        .clusters(clusters.clone());
+
+        filter
+            .read(&mut context)
+            .map(|_| context.endpoints[0].address.clone())
+    }
+
+    #[test]
+    fn weighted_round_robin_load_balancer_policy() {
+        let a: EndpointAddress = ([127, 0, 0, 1], 8080).into();
+        let b: EndpointAddress = ([127, 0, 0, 2], 8080).into();
+        let c: EndpointAddress = ([127, 0, 0, 3], 8080).into();
+
+        let filter = LoadBalancer::from_config(
+            serde_yaml::from_str("policy: WEIGHTED_ROUND_ROBIN").unwrap(),
+        );
+
+        // Endpoints are chosen in proportion to their weight.
+        let endpoints = located_endpoints(vec![LocalityEndpoints::from(vec![
+            weighted(a.clone(), 3),
+            weighted(b.clone(), 1),
+        ])]);
+        for _ in 0..10 {
+            assert_eq!(
+                vec![a.clone(), a.clone(), a.clone(), b.clone()],
+                (0..4)
+                    .map(|_| choose(&filter, &endpoints).unwrap())
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // Localities are chosen in proportion to their weight, and endpoints
+        // in proportion to their weight within the locality.
+        let filter = LoadBalancer::from_config(
+            serde_yaml::from_str("policy: WEIGHTED_ROUND_ROBIN").unwrap(),
+        );
+        let endpoints = located_endpoints(vec![
+            LocalityEndpoints::from(vec![weighted(a.clone(), 1), weighted(b.clone(), 1)])
+                .with_locality(locality("us", "east"))
+                .with_weight(std::num::NonZeroU32::new(1)),
+            LocalityEndpoints::from(Endpoint::new(c.clone()))
+                .with_locality(locality("eu", "west"))
+                .with_weight(std::num::NonZeroU32::new(2)),
+        ]);
+        let chosen = (0..300)
+            .map(|_| choose(&filter, &endpoints).unwrap())
+            .collect::<Vec<_>>();
+        let count = |address: &EndpointAddress| chosen.iter().filter(|&a| a == address).count();
+        assert_eq!(200, count(&c));
+        assert_eq!(50, count(&a));
+        assert_eq!(50, count(&b));
+    }
+
+    #[test]
+    fn weighted_random_load_balancer_policy() {
+        let a: EndpointAddress = ([127, 0, 0, 1], 8080).into();
+        let b: EndpointAddress = ([127, 0, 0, 2], 8080).into();
+
+        let filter =
+            LoadBalancer::from_config(serde_yaml::from_str("policy: WEIGHTED_RANDOM").unwrap());
+        let endpoints = located_endpoints(vec![LocalityEndpoints::from(vec![
+            weighted(a.clone(), 9),
+            weighted(b.clone(), 1),
+        ])]);
+
+        let chosen = (0..1000)
+            .map(|_| choose(&filter, &endpoints).unwrap())
+            .collect::<Vec<_>>();
+        let count = |address: &EndpointAddress| chosen.iter().filter(|&a| a == address).count();
+
+        // Check that both endpoints were chosen, and that the heavier
+        // endpoint was chosen the most.
+        assert!(count(&b) > 0);
+        assert!(count(&a) > count(&b) * 3);
+    }
+
+    #[test]
+    fn locality_cache() {
+        let address: EndpointAddress = ([127, 0, 0, 1], 8080).into();
+        let endpoint = Endpoint::new(address);
+        let clusters = located_endpoints(vec![LocalityEndpoints::from(endpoint.clone())
+            .with_locality(locality("us", "east"))
+            .with_weight(std::num::NonZeroU32::new(2))]);
+
+        let cache = LocalityCache::default();
+        let localities = cache.get(Some(&clusters));
+        assert_eq!(
+            locality("us", "east").as_ref(),
+            localities.locality(&endpoint)
+        );
+        assert_eq!(2, localities.weight(&endpoint));
+
+        // The localities are only rebuilt when the clusters change.
+        assert!(Arc::ptr_eq(&localities, &cache.get(Some(&clusters))));
+        let updated = located_endpoints(vec![LocalityEndpoints::from(endpoint.clone())]);
+        let localities = cache.get(Some(&updated));
+        assert_eq!(None, localities.locality(&endpoint));
+        assert_eq!(1, localities.weight(&endpoint));
+        assert_eq!(None, cache.get(None).locality(&endpoint));
+    }
+
+    #[test]
+    fn locality_preference() {
+        let zone: EndpointAddress = ([127, 0, 0, 1], 8080).into();
+        let region: EndpointAddress = ([127, 0, 0, 2], 8080).into();
+        let other: EndpointAddress = ([127, 0, 0, 3], 8080).into();
+
+        let localities = vec![
+            LocalityEndpoints::from(Endpoint::new(zone.clone()))
+                .with_locality(locality("us-east1", "us-east1-b")),
+            LocalityEndpoints::from(Endpoint::new(region.clone()))
+                .with_locality(locality("us-east1", "us-east1-c")),
+            LocalityEndpoints::from(Endpoint::new(other.clone()))
+                .with_locality(locality("europe-west1", "europe-west1-b")),
+        ];
+
+        let filter = |failover: &str| {
+            LoadBalancer::from_config(
+                serde_yaml::from_str(&format!(
+                    "
+policy: ROUND_ROBIN
+locality:
+  region: us-east1
+  zone: us-east1-b
+  failover: {failover}
+"
+                ))
+                .unwrap(),
+            )
+        };
+
+        // The proxy's zone is always preferred.
+        let endpoints = located_endpoints(localities.clone());
+        for _ in 0..10 {
+            assert_eq!(Some(zone.clone()), choose(&filter("ANY"), &endpoints));
+        }
+
+        // Without endpoints in the proxy's zone, packets fail over to the
+        // proxy's region, and then to any region.
+        let endpoints = located_endpoints(localities[1..].to_vec());
+        assert_eq!(Some(region.clone()), choose(&filter("REGION"), &endpoints));
+        assert_eq!(None, choose(&filter("DISABLED"), &endpoints));
+
+        let endpoints = located_endpoints(localities[2..].to_vec());
+        assert_eq!(Some(other), choose(&filter("ANY"), &endpoints));
+        assert_eq!(None, choose(&filter("REGION"), &endpoints));
+    }
+
+    /// Returns how many of 1000 sources are routed to a different endpoint
+    /// once the endpoints change from `before` to `after`.
+    fn remapped(
+        filter: &dyn Filter,
+        before: &[EndpointAddress],
+        after: &[EndpointAddress],
+    ) -> usize {
+        let sources = (0..1000u16)
+            .map(|port| EndpointAddress::from(([127, 1, 1, 1], 10000 + port)))
+            .collect::<Vec<_>>();
+        let choose = |addresses: &[EndpointAddress]| {
+            sources
+                .iter()
+                .map(|source| get_response_addresses(filter, addresses, source.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        choose(before)
+            .into_iter()
+            .zip(choose(after))
+            .filter(|(before, after)| before != after)
+            .count()
+    }
+
+    #[test]
+    fn consistent_hash_load_balancer_policies() {
+        let addresses: Vec<EndpointAddress> = (1..=4)
+            .map(|i| ([127, 0, 0, i], 8080).into())
+            .collect::<Vec<_>>();
+
+        for policy in ["RING_HASH", "MAGLEV"] {
+            let filter = LoadBalancer::from_config(
+                serde_yaml::from_str(&format!("policy: {policy}")).unwrap(),
+            );
+
+            // Every endpoint is chosen, and sources are always routed to the
+            // same endpoint.
+            let chosen = (0..1000u16)
+                .map(|port| {
+                    get_response_addresses(&filter, &addresses, ([127, 1, 1, 1], port).into())
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                addresses.iter().cloned().collect::<HashSet<_>>(),
+                chosen.iter().flatten().cloned().collect::<HashSet<_>>(),
+                "{policy}"
+            );
+            for (port, expected) in chosen.iter().enumerate().take(10) {
+                assert_eq!(
+                    *expected,
+                    get_response_addresses(
+                        &filter,
+                        &addresses,
+                        ([127, 1, 1, 1], port as u16).into()
+                    ),
+                    "{policy}"
+                );
+            }
+
+            // Without endpoints, there's nothing to choose.
+            assert!(
+                get_response_addresses(&filter, &[], ([127, 1, 1, 1], 1).into()).is_empty(),
+                "{policy}"
+            );
+
+            // Adding or removing an endpoint only moves a fraction of the
+            // sources, unlike `HASH` which moves most of them.
+            for (before, after) in [
+                (&addresses[..3], &addresses[..]),
+                (&addresses[..], &addresses[..3]),
+            ] {
+                let moved = remapped(&filter, before, after);
+                assert!(moved < 400, "{policy} moved {moved} of 1000 sources");
+            }
+        }
+
+        let filter = LoadBalancer::from_config(serde_yaml::from_str("policy: HASH").unwrap());
+        let moved = remapped(&filter, &addresses[..3], &addresses);
+        assert!(moved > 500, "HASH moved {moved} of 1000 sources");
+    }
+
+    #[test]
+    fn hash_keys() {
+        let addresses: Vec<EndpointAddress> = (1..=4)
+            .map(|i| ([127, 0, 0, i], 8080).into())
+            .collect::<Vec<_>>();
+        let endpoints = || Vec::from_iter(addresses.iter().cloned().map(Endpoint::new));
+
+        let filter = LoadBalancer::from_config(
+            serde_yaml::from_str("policy: MAGLEV\nhashKey: SOURCE_IP").unwrap(),
+        );
+        let expected = get_response_addresses(&filter, &addresses, ([127, 1, 1, 1], 1).into());
+        for port in 2..100 {
+            assert_eq!(
+                expected,
+                get_response_addresses(&filter, &addresses, ([127, 1, 1, 1], port).into())
+            );
+        }
+
+        let filter = LoadBalancer::from_config(
+            serde_yaml::from_str(
+                "
+policy: RING_HASH
+hashKey: METADATA
+metadataKey: quilkin.dev/token
+",
+            )
+            .unwrap(),
+        );
+        let key = crate::metadata::Key::from_static("quilkin.dev/token");
+        let choose_with_token = |token: &[u8], port: u16| {
+            let mut context = ReadContext::new(endpoints(), ([127, 1, 1, 1], port).into(), vec![]);
+            context
+                .metadata
+                .insert(key, crate::metadata::Value::Bytes(token.to_vec().into()));
+            filter.read(&mut context).unwrap();
+            context.endpoints[0].address.clone()
+        };
+
+        // Packets with the same token are routed to the same endpoint,
+        // regardless of their source.
+        for token in [&b"abc"[..], b"def", b"ghi"] {
+            let expected = choose_with_token(token, 1);
+            for port in 2..100 {
+                assert_eq!(expected, choose_with_token(token, port));
+            }
+        }
+
+        // Different tokens are routed to different endpoints.
+        assert!(
+            (0..100u8)
+                .map(|token| choose_with_token(&[token], 1))
+                .collect::<HashSet<_>>()
+                .len()
+                > 1
+        );
+    }
 }
@@ -14,13 +14,22 @@
  * limitations under the License.
  */
 
+use std::sync::Arc;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use super::endpoint_chooser::{
-    EndpointChooser, HashEndpointChooser, RandomEndpointChooser, RoundRobinEndpointChooser,
+    EndpointChooser, HashEndpointChooser, KeyHasher, Localities, LocalityCache,
+    MaglevEndpointChooser, RandomEndpointChooser, RingHashEndpointChooser,
+    RoundRobinEndpointChooser, WeightedRandomEndpointChooser, WeightedRoundRobinEndpointChooser,
 };
 use super::proto;
+use crate::{
+    endpoint::Locality,
+    filters::{metadata::CAPTURED_BYTES, ReadContext},
+    metadata,
+};
 
 /// The configuration for [`load_balancer`][super].
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, JsonSchema)]
@@ -28,12 +37,44 @@ use super::proto;
 pub struct Config {
     #[serde(default)]
     pub policy: Policy,
+    /// Prefers endpoints in the proxy's own locality, only sending packets to
+    /// other localities when there are no endpoints available in it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locality: Option<LocalityPreference>,
+    /// The part of the packet that's hashed to choose an endpoint, with the
+    /// `HASH`, `RING_HASH` and `MAGLEV` policies.
+    #[serde(rename = "hashKey", default)]
+    pub hash_key: HashKey,
+    /// The key of the dynamic metadata value that's hashed, when `hashKey`
+    /// is `METADATA`.
+    #[serde(rename = "metadataKey", default = "default_metadata_key")]
+    pub metadata_key: metadata::Key,
+}
+
+impl Config {
+    pub(super) fn as_endpoint_chooser(
+        &self,
+        localities: Arc<LocalityCache>,
+    ) -> Box<dyn EndpointChooser> {
+        self.policy
+            .as_endpoint_chooser(KeyHasher::new(self.hash_key, self.metadata_key), localities)
+    }
+}
+
+/// Default value for [`Config::metadata_key`]
+fn default_metadata_key() -> metadata::Key {
+    metadata::Key::from_static(CAPTURED_BYTES)
 }
 
 impl From<Config> for super::proto::LoadBalancer {
     fn from(config: Config) -> Self {
         Self {
             policy: Some(config.policy.into()),
+            locality: config.locality.map(From::from),
+            hash_key: Some(proto::load_balancer::HashKeyValue {
+                value: proto::load_balancer::HashKey::from(config.hash_key) as i32,
+            }),
+            metadata_key: Some(config.metadata_key.to_string()),
         }
     }
 }
@@ -46,6 +87,16 @@ impl From<proto::LoadBalancer> for Config {
                 .map(|p| p.value())
                 .map(Policy::from)
                 .unwrap_or_default(),
+            locality: p.locality.map(From::from),
+            hash_key: p
+                .hash_key
+                .map(|key| key.value())
+                .map(HashKey::from)
+                .unwrap_or_default(),
+            metadata_key: p
+                .metadata_key
+                .map(metadata::Key::new)
+                .unwrap_or_else(default_metadata_key),
         }
     }
 }
@@ -63,14 +114,44 @@ pub enum Policy {
     /// Send packets to endpoints based on hash of source IP and port.
     #[serde(rename = "HASH")]
     Hash,
+    /// Send packets to endpoints in turns, proportionally to the weight of
+    /// their locality and their weight within the locality.
+    #[serde(rename = "WEIGHTED_ROUND_ROBIN")]
+    WeightedRoundRobin,
+    /// Send packets to endpoints chosen at random, proportionally to the
+    /// weight of their locality and their weight within the locality.
+    #[serde(rename = "WEIGHTED_RANDOM")]
+    WeightedRandom,
+    /// Send packets to endpoints chosen by consistent hashing with a hash
+    /// ring, so that only a small number of keys are moved to another
+    /// endpoint when endpoints are added or removed.
+    #[serde(rename = "RING_HASH")]
+    RingHash,
+    /// Send packets to endpoints chosen by consistent hashing with a Maglev
+    /// lookup table, which spreads keys more evenly than `RING_HASH`, and
+    /// only moves a small number of keys when endpoints are added or removed.
+    #[serde(rename = "MAGLEV")]
+    Maglev,
 }
 
 impl Policy {
-    pub fn as_endpoint_chooser(&self) -> Box<dyn EndpointChooser> {
+    /// Returns the endpoint chooser for the policy, the weighted policies
+    /// looking up the localities of endpoints in `localities`.
+    pub fn as_endpoint_chooser(
+        &self,
+        hasher: KeyHasher,
+        localities: Arc<LocalityCache>,
+    ) -> Box<dyn EndpointChooser> {
         match self {
             Policy::RoundRobin => Box::new(RoundRobinEndpointChooser::new()),
             Policy::Random => Box::new(RandomEndpointChooser),
-            Policy::Hash => Box::new(HashEndpointChooser),
+            Policy::Hash => Box::new(HashEndpointChooser::new(hasher)),
+            Policy::RingHash => Box::new(RingHashEndpointChooser::new(hasher)),
+            Policy::Maglev => Box::new(MaglevEndpointChooser::new(hasher)),
+            Policy::WeightedRoundRobin => {
+                Box::new(WeightedRoundRobinEndpointChooser::new(localities))
+            }
+            Policy::WeightedRandom => Box::new(WeightedRandomEndpointChooser::new(localities)),
         }
     }
 }
@@ -87,6 +168,10 @@ impl From<Policy> for proto::load_balancer::Policy {
             Policy::RoundRobin => Self::RoundRobin,
             Policy::Random => Self::Random,
             Policy::Hash => Self::Hash,
+            Policy::WeightedRoundRobin => Self::WeightedRoundRobin,
+            Policy::WeightedRandom => Self::WeightedRandom,
+            Policy::RingHash => Self::RingHash,
+            Policy::Maglev => Self::Maglev,
         }
     }
 }
@@ -97,6 +182,10 @@ impl From<proto::load_balancer::Policy> for Policy {
             proto::load_balancer::Policy::RoundRobin => Self::RoundRobin,
             proto::load_balancer::Policy::Random => Self::Random,
             proto::load_balancer::Policy::Hash => Self::Hash,
+            proto::load_balancer::Policy::WeightedRoundRobin => Self::WeightedRoundRobin,
+            proto::load_balancer::Policy::WeightedRandom => Self::WeightedRandom,
+            proto::load_balancer::Policy::RingHash => Self::RingHash,
+            proto::load_balancer::Policy::Maglev => Self::Maglev,
         }
     }
 }
@@ -108,3 +197,177 @@ impl From<Policy> for proto::load_balancer::PolicyValue {
         }
     }
 }
+
+/// The part of a packet that's hashed by the hash based load balancing
+/// policies, packets with the same key are sent to the same endpoint.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq, JsonSchema)]
+pub enum HashKey {
+    /// The packet's source IP address and port.
+    #[serde(rename = "SOURCE_IP_AND_PORT")]
+    SourceIpAndPort,
+    /// The packet's source IP address, so that every port of a client is
+    /// sent to the same endpoint.
+    #[serde(rename = "SOURCE_IP")]
+    SourceIp,
+    /// The dynamic metadata value at `metadataKey`, such as a token captured
+    /// by an earlier filter. Packets without the value are hashed by their
+    /// source IP address and port.
+    #[serde(rename = "METADATA")]
+    Metadata,
+}
+
+impl Default for HashKey {
+    fn default() -> Self {
+        HashKey::SourceIpAndPort
+    }
+}
+
+impl From<HashKey> for proto::load_balancer::HashKey {
+    fn from(key: HashKey) -> Self {
+        match key {
+            HashKey::SourceIpAndPort => Self::SourceIpAndPort,
+            HashKey::SourceIp => Self::SourceIp,
+            HashKey::Metadata => Self::Metadata,
+        }
+    }
+}
+
+impl From<proto::load_balancer::HashKey> for HashKey {
+    fn from(key: proto::load_balancer::HashKey) -> Self {
+        match key {
+            proto::load_balancer::HashKey::SourceIpAndPort => Self::SourceIpAndPort,
+            proto::load_balancer::HashKey::SourceIp => Self::SourceIp,
+            proto::load_balancer::HashKey::Metadata => Self::Metadata,
+        }
+    }
+}
+
+/// The locality of the proxy, and which other localities packets can be sent
+/// to when there are no endpoints available in it.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LocalityPreference {
+    /// The region of the proxy.
+    pub region: String,
+    /// The zone of the proxy within `region`, if empty all endpoints in the
+    /// region are preferred equally.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub zone: String,
+    /// Where packets are sent when there are no endpoints available in the
+    /// proxy's zone.
+    #[serde(default)]
+    pub failover: Failover,
+}
+
+impl LocalityPreference {
+    /// Removes all but the endpoints in the closest locality to the proxy
+    /// that's allowed by the [`Failover`], leaving the endpoints empty when
+    /// none are allowed.
+    pub fn retain_preferred(&self, ctx: &mut ReadContext, localities: &Localities) {
+        let closest = ctx
+            .endpoints
+            .iter()
+            .map(|endpoint| self.distance(localities.locality(endpoint)))
+            .min();
+
+        match closest {
+            Some(closest) if closest <= self.failover.max_distance() => ctx
+                .endpoints
+                .retain(|endpoint| self.distance(localities.locality(endpoint)) == closest),
+            _ => ctx.endpoints.clear(),
+        }
+    }
+
+    /// Returns how far `locality` is from the proxy, `0` being the proxy's
+    /// zone, `1` its region, and `2` any other region.
+    fn distance(&self, locality: Option<&Locality>) -> u8 {
+        match locality {
+            Some(locality) if locality.region == self.region => {
+                if self.zone.is_empty() || locality.zone == self.zone {
+                    0
+                } else {
+                    1
+                }
+            }
+            _ => 2,
+        }
+    }
+}
+
+impl From<LocalityPreference> for proto::load_balancer::Locality {
+    fn from(locality: LocalityPreference) -> Self {
+        Self {
+            region: locality.region,
+            zone: locality.zone,
+            failover: Some(proto::load_balancer::locality::FailoverValue {
+                value: proto::load_balancer::locality::Failover::from(locality.failover) as i32,
+            }),
+        }
+    }
+}
+
+impl From<proto::load_balancer::Locality> for LocalityPreference {
+    fn from(locality: proto::load_balancer::Locality) -> Self {
+        Self {
+            region: locality.region,
+            zone: locality.zone,
+            failover: locality
+                .failover
+                .map(|failover| failover.value())
+                .map(Failover::from)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Which localities packets are sent to when there are no endpoints available
+/// in the proxy's zone.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq, JsonSchema)]
+pub enum Failover {
+    /// Send packets to the closest locality with available endpoints.
+    #[serde(rename = "ANY")]
+    Any,
+    /// Only send packets to other zones in the proxy's region.
+    #[serde(rename = "REGION")]
+    Region,
+    /// Only send packets to the proxy's zone, dropping packets when there are
+    /// no endpoints available in it.
+    #[serde(rename = "DISABLED")]
+    Disabled,
+}
+
+impl Failover {
+    fn max_distance(&self) -> u8 {
+        match self {
+            Failover::Any => 2,
+            Failover::Region => 1,
+            Failover::Disabled => 0,
+        }
+    }
+}
+
+impl Default for Failover {
+    fn default() -> Self {
+        Failover::Any
+    }
+}
+
+impl From<Failover> for proto::load_balancer::locality::Failover {
+    fn from(failover: Failover) -> Self {
+        match failover {
+            Failover::Any => Self::Any,
+            Failover::Region => Self::Region,
+            Failover::Disabled => Self::Disabled,
+        }
+    }
+}
+
+impl From<proto::load_balancer::locality::Failover> for Failover {
+    fn from(failover: proto::load_balancer::locality::Failover) -> Self {
+        match failover {
+            proto::load_balancer::locality::Failover::Any => Self::Any,
+            proto::load_balancer::locality::Failover::Region => Self::Region,
+            proto::load_balancer::locality::Failover::Disabled => Self::Disabled,
+        }
+    }
+}
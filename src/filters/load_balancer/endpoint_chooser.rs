@@ -19,11 +19,20 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use rand::{thread_rng, Rng};
 
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
     hash::{Hash, Hasher},
+    sync::{Arc, Weak},
 };
 
-use crate::filters::ReadContext;
+use parking_lot::RwLock;
+
+use super::config::HashKey;
+use crate::{
+    cluster::ClusterMap,
+    endpoint::{Endpoint, EndpointAddress, Locality},
+    filters::ReadContext,
+    metadata::{self, Value},
+};
 
 /// EndpointChooser chooses from a set of endpoints that a proxy is connected to.
 pub trait EndpointChooser: Send + Sync {
@@ -63,13 +72,437 @@ impl EndpointChooser for RandomEndpointChooser {
     }
 }
 
-/// HashEndpointChooser chooses endpoints based on a hash of source IP and port.
-pub struct HashEndpointChooser;
+/// KeyHasher hashes the part of a packet used to choose an endpoint by the
+/// hash based endpoint choosers.
+pub struct KeyHasher {
+    key: HashKey,
+    metadata_key: metadata::Key,
+}
+
+impl KeyHasher {
+    pub fn new(key: HashKey, metadata_key: metadata::Key) -> Self {
+        Self { key, metadata_key }
+    }
+
+    fn hash(&self, ctx: &ReadContext) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self.key {
+            HashKey::SourceIpAndPort => hash_source(&ctx.source, true, &mut hasher),
+            HashKey::SourceIp => hash_source(&ctx.source, false, &mut hasher),
+            HashKey::Metadata => match ctx.metadata.get(&self.metadata_key) {
+                Some(value) => hash_value(value, &mut hasher),
+                None => hash_source(&ctx.source, true, &mut hasher),
+            },
+        }
+        hasher.finish()
+    }
+}
+
+fn hash_source(source: &EndpointAddress, with_port: bool, hasher: &mut DefaultHasher) {
+    // Hash the canonical form of the address, so that IPv4-mapped IPv6
+    // sources are routed the same way as their IPv4 equivalent.
+    match source.to_socket_addr() {
+        Ok(addr) if with_port => crate::utils::net::to_canonical(addr).hash(hasher),
+        Ok(addr) => crate::utils::net::to_canonical(addr).ip().hash(hasher),
+        Err(_) if with_port => source.hash(hasher),
+        Err(_) => source.host.hash(hasher),
+    }
+}
+
+/// Hashes `value` so that values which are equal have the same hash, strings
+/// being equal to their bytes.
+fn hash_value(value: &Value, hasher: &mut DefaultHasher) {
+    match value {
+        Value::Bool(value) => value.hash(hasher),
+        Value::Number(number) => number.hash(hasher),
+        Value::List(values) => values.iter().for_each(|value| hash_value(value, hasher)),
+        Value::String(string) => string.as_bytes().hash(hasher),
+        Value::Bytes(bytes) => bytes[..].hash(hasher),
+    }
+}
+
+/// HashEndpointChooser chooses endpoints based on a hash of the packet's key,
+/// the source IP and port by default.
+pub struct HashEndpointChooser {
+    hasher: KeyHasher,
+}
+
+impl HashEndpointChooser {
+    pub fn new(hasher: KeyHasher) -> Self {
+        Self { hasher }
+    }
+}
 
 impl EndpointChooser for HashEndpointChooser {
     fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let hash = self.hasher.hash(ctx);
+        ctx.endpoints = vec![ctx.endpoints[hash as usize % ctx.endpoints.len()].clone()];
+    }
+}
+
+/// A lookup table from a hash to one of a set of endpoints, which only maps a
+/// small number of hashes to a different endpoint when the set changes.
+pub trait ConsistentHashTable: Send + Sync + Default {
+    /// Builds the table for `endpoints`.
+    fn build(endpoints: &[Endpoint]) -> Self;
+    /// Returns the index of the endpoint that `hash` maps to, or `None` if
+    /// the table was built without any endpoints.
+    fn lookup(&self, hash: u64) -> Option<usize>;
+}
+
+/// The maximum number of tables a [`ConsistentHashEndpointChooser`] keeps,
+/// all of them are dropped when it's reached, as most are for sets of
+/// endpoints that no longer exist.
+const MAX_CONSISTENT_HASH_TABLES: usize = 16;
+
+/// ConsistentHashEndpointChooser chooses endpoints based on a hash of the
+/// packet's key, using a [`ConsistentHashTable`] for each set of endpoints
+/// it chooses from, as earlier filters may each leave a different subset of
+/// the upstream endpoints.
+pub struct ConsistentHashEndpointChooser<T> {
+    hasher: KeyHasher,
+    /// The tables keyed by the fingerprint of the addresses of the endpoints
+    /// they were built from, along with the addresses themselves.
+    tables: RwLock<HashMap<u64, (Vec<EndpointAddress>, T)>>,
+}
+
+/// Chooses endpoints with a [`Ring`].
+pub type RingHashEndpointChooser = ConsistentHashEndpointChooser<Ring>;
+/// Chooses endpoints with a [`Maglev`] table.
+pub type MaglevEndpointChooser = ConsistentHashEndpointChooser<Maglev>;
+
+impl<T: ConsistentHashTable> ConsistentHashEndpointChooser<T> {
+    pub fn new(hasher: KeyHasher) -> Self {
+        Self {
+            hasher,
+            tables: <_>::default(),
+        }
+    }
+
+    fn lookup(&self, endpoints: &[Endpoint], hash: u64) -> Option<usize> {
+        let addresses = endpoints.iter().map(|endpoint| &endpoint.address);
+        let is_current =
+            |built_from: &Vec<EndpointAddress>| built_from.iter().eq(addresses.clone());
+
         let mut hasher = DefaultHasher::new();
-        ctx.source.hash(&mut hasher);
-        ctx.endpoints = vec![ctx.endpoints[hasher.finish() as usize % ctx.endpoints.len()].clone()];
+        addresses
+            .clone()
+            .for_each(|address| address.hash(&mut hasher));
+        let fingerprint = hasher.finish();
+
+        {
+            let tables = self.tables.read();
+            if let Some((_, table)) = tables
+                .get(&fingerprint)
+                .filter(|(built_from, _)| is_current(built_from))
+            {
+                return table.lookup(hash);
+            }
+        }
+
+        let mut tables = self.tables.write();
+        if !matches!(tables.get(&fingerprint), Some((built_from, _)) if is_current(built_from)) {
+            if tables.len() >= MAX_CONSISTENT_HASH_TABLES {
+                tables.clear();
+            }
+
+            tables.insert(
+                fingerprint,
+                (addresses.cloned().collect(), T::build(endpoints)),
+            );
+        }
+
+        tables[&fingerprint].1.lookup(hash)
     }
 }
+
+impl<T: ConsistentHashTable> EndpointChooser for ConsistentHashEndpointChooser<T> {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let hash = self.hasher.hash(ctx);
+        if let Some(index) = self.lookup(&ctx.endpoints, hash) {
+            ctx.endpoints = vec![ctx.endpoints[index].clone()];
+        }
+    }
+}
+
+/// The number of points each endpoint has on a [`Ring`].
+const RING_POINTS_PER_ENDPOINT: u64 = 128;
+
+/// A hash ring, where each endpoint is placed at multiple points, and a hash
+/// maps to the endpoint at the next point on the ring.
+#[derive(Default)]
+pub struct Ring {
+    /// The hash and endpoint index of each point, sorted by hash.
+    points: Vec<(u64, usize)>,
+}
+
+impl ConsistentHashTable for Ring {
+    fn build(endpoints: &[Endpoint]) -> Self {
+        let mut points = endpoints
+            .iter()
+            .enumerate()
+            .flat_map(|(index, endpoint)| {
+                (0..RING_POINTS_PER_ENDPOINT)
+                    .map(move |point| (hash(&(&endpoint.address, point)), index))
+            })
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+
+        Self { points }
+    }
+
+    fn lookup(&self, hash: u64) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let point = self.points.partition_point(|(point, _)| *point < hash);
+        Some(self.points[point % self.points.len()].1)
+    }
+}
+
+/// The size of a [`Maglev`] table, which must be a prime number much larger
+/// than the number of endpoints.
+const MAGLEV_TABLE_SIZE: usize = 65_537;
+
+/// A Maglev lookup table, where each endpoint fills the table's entries in
+/// the order of its own permutation until the table is full, see
+/// ["Maglev: A Fast and Reliable Software Network Load Balancer"][paper].
+///
+/// [paper]: https://research.google/pubs/pub44824/
+#[derive(Default)]
+pub struct Maglev {
+    entries: Vec<usize>,
+}
+
+impl ConsistentHashTable for Maglev {
+    fn build(endpoints: &[Endpoint]) -> Self {
+        if endpoints.is_empty() {
+            return Self::default();
+        }
+
+        // Endpoints are placed in order of their address, so that the table
+        // doesn't depend on the order of the endpoints.
+        let mut order = (0..endpoints.len()).collect::<Vec<_>>();
+        order.sort_by_key(|index| &endpoints[*index].address);
+
+        let size = MAGLEV_TABLE_SIZE as u64;
+        let permutations = order
+            .iter()
+            .map(|index| {
+                let address = &endpoints[*index].address;
+                let offset = hash(&(address, 0u8)) % size;
+                let skip = hash(&(address, 1u8)) % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect::<Vec<_>>();
+
+        let mut entries = vec![usize::MAX; MAGLEV_TABLE_SIZE];
+        let mut next = vec![0; order.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (i, (offset, skip)) in permutations.iter().enumerate() {
+                let mut entry = ((offset + next[i] * skip) % size) as usize;
+                while entries[entry] != usize::MAX {
+                    next[i] += 1;
+                    entry = ((offset + next[i] * skip) % size) as usize;
+                }
+
+                entries[entry] = order[i];
+                next[i] += 1;
+                filled += 1;
+                if filled == MAGLEV_TABLE_SIZE {
+                    break 'fill;
+                }
+            }
+        }
+
+        Self { entries }
+    }
+
+    fn lookup(&self, hash: u64) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        Some(self.entries[(hash % self.entries.len() as u64) as usize])
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// WeightedRoundRobinEndpointChooser chooses endpoints in round-robin order,
+/// choosing each locality and each endpoint within a locality in proportion
+/// to their weight.
+pub struct WeightedRoundRobinEndpointChooser {
+    next_endpoint: AtomicUsize,
+    localities: Arc<LocalityCache>,
+}
+
+impl WeightedRoundRobinEndpointChooser {
+    pub fn new(localities: Arc<LocalityCache>) -> Self {
+        WeightedRoundRobinEndpointChooser {
+            next_endpoint: AtomicUsize::new(0),
+            localities,
+        }
+    }
+}
+
+impl EndpointChooser for WeightedRoundRobinEndpointChooser {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let count = self.next_endpoint.fetch_add(1, Ordering::Relaxed) as u64;
+        let located = self.localities.get(ctx.clusters.as_ref());
+        let localities = group_by_locality(&located, &ctx.endpoints);
+        let total_weight = localities.iter().map(|(weight, _)| weight).sum::<u64>();
+
+        // Every locality is visited in turn, and the endpoints within a
+        // locality advance each time all of the localities have been visited.
+        let (_, endpoints) = select_weighted(&localities, |(weight, _)| *weight, count);
+        let endpoint = select_weighted(
+            endpoints,
+            |endpoint| endpoint.weight().into(),
+            count / total_weight,
+        );
+        ctx.endpoints = vec![Endpoint::clone(endpoint)];
+    }
+}
+
+/// WeightedRandomEndpointChooser chooses endpoints at random, choosing each
+/// locality and each endpoint within a locality in proportion to their
+/// weight.
+pub struct WeightedRandomEndpointChooser {
+    localities: Arc<LocalityCache>,
+}
+
+impl WeightedRandomEndpointChooser {
+    pub fn new(localities: Arc<LocalityCache>) -> Self {
+        WeightedRandomEndpointChooser { localities }
+    }
+}
+
+impl EndpointChooser for WeightedRandomEndpointChooser {
+    fn choose_endpoints(&self, ctx: &mut ReadContext) {
+        let mut rng = thread_rng();
+        let located = self.localities.get(ctx.clusters.as_ref());
+        let localities = group_by_locality(&located, &ctx.endpoints);
+        let (_, endpoints) = select_weighted(&localities, |(weight, _)| *weight, rng.gen());
+        let endpoint = select_weighted(endpoints, |endpoint| endpoint.weight().into(), rng.gen());
+        ctx.endpoints = vec![Endpoint::clone(endpoint)];
+    }
+}
+
+/// Groups `endpoints` by their locality in `located`, returning each
+/// locality's weight and endpoints.
+fn group_by_locality<'a>(
+    located: &Localities,
+    endpoints: &'a [Endpoint],
+) -> Vec<(u64, Vec<&'a Endpoint>)> {
+    let mut localities = BTreeMap::<_, (u64, Vec<&Endpoint>)>::new();
+    for endpoint in endpoints {
+        localities
+            .entry(located.locality(endpoint))
+            .or_insert_with(|| (located.weight(endpoint), Vec::new()))
+            .1
+            .push(endpoint);
+    }
+
+    localities.into_values().collect()
+}
+
+/// The locality of each endpoint in a [`ClusterMap`], and its weight.
+/// Endpoints which aren't in any of the clusters have no locality, and a
+/// locality weight of `1`.
+#[derive(Default)]
+pub struct Localities {
+    /// The clusters the localities were taken from, which is weak so that
+    /// the cache doesn't keep clusters which have been replaced alive.
+    clusters: Option<Weak<ClusterMap>>,
+    endpoints: HashMap<EndpointAddress, (Option<Locality>, u64)>,
+}
+
+impl Localities {
+    fn new(clusters: Option<&Arc<ClusterMap>>) -> Self {
+        Self {
+            clusters: clusters.map(Arc::downgrade),
+            endpoints: clusters
+                .into_iter()
+                .flat_map(|clusters| clusters.localities())
+                .flat_map(|locality| {
+                    let weight = locality.weight.map_or(1, |weight| weight.get().into());
+                    locality.endpoints.iter().map(move |endpoint| {
+                        (
+                            endpoint.address.clone(),
+                            (locality.locality.clone(), weight),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns whether the localities were taken from `clusters`.
+    fn is_from(&self, clusters: Option<&Arc<ClusterMap>>) -> bool {
+        match (&self.clusters, clusters) {
+            // A weak reference keeps the allocation of the clusters, so it
+            // can't be reused by newer clusters while it's compared.
+            (Some(cached), Some(clusters)) => cached.as_ptr() == Arc::as_ptr(clusters),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the locality of `endpoint`.
+    pub fn locality(&self, endpoint: &Endpoint) -> Option<&Locality> {
+        self.endpoints
+            .get(&endpoint.address)
+            .and_then(|(locality, _)| locality.as_ref())
+    }
+
+    /// Returns the weight of the locality of `endpoint`.
+    pub fn weight(&self, endpoint: &Endpoint) -> u64 {
+        self.endpoints
+            .get(&endpoint.address)
+            .map_or(1, |(_, weight)| *weight)
+    }
+}
+
+/// The [`Localities`] of the clusters the last packet was sent with, which
+/// are only rebuilt when the clusters change, rather than for every packet.
+#[derive(Default)]
+pub struct LocalityCache(RwLock<Arc<Localities>>);
+
+impl LocalityCache {
+    /// Returns the localities of `clusters`, building them if the clusters
+    /// have changed since the last call.
+    pub fn get(&self, clusters: Option<&Arc<ClusterMap>>) -> Arc<Localities> {
+        let cached = self.0.read().clone();
+        if cached.is_from(clusters) {
+            return cached;
+        }
+
+        let localities = Arc::new(Localities::new(clusters));
+        *self.0.write() = localities.clone();
+        localities
+    }
+}
+
+/// Selects the item at `position` (modulo the total weight), where each item
+/// occupies as many positions as its weight.
+fn select_weighted<T>(items: &[T], weight: impl Fn(&T) -> u64, position: u64) -> &T {
+    let total_weight = items.iter().map(&weight).sum::<u64>();
+    // Note: Weights are never zero, so the total is only zero without items.
+    let mut position = position % total_weight;
+    for item in items {
+        let weight = weight(item);
+        if position < weight {
+            return item;
+        }
+        position -= weight;
+    }
+
+    unreachable!("position is always less than the total weight")
+}
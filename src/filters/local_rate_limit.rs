@@ -21,11 +21,14 @@ use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
 use crate::{
-    endpoint::EndpointAddress,
-    filters::prelude::*,
+    endpoint::{AddressKind, EndpointAddress},
+    filters::{metadata::CAPTURED_BYTES, prelude::*},
+    metadata::{self, DynamicMetadata},
     ttl_map::{Entry, TtlMap},
 };
 
@@ -34,20 +37,18 @@ use metrics::Metrics;
 crate::include_proto!("quilkin.filters.local_rate_limit.v1alpha1");
 use self::quilkin::filters::local_rate_limit::v1alpha1 as proto;
 
-// TODO: we should make these values configurable and transparent to the filter.
-/// SESSION_TIMEOUT_SECONDS is the default session timeout.
-pub const SESSION_TIMEOUT_SECONDS: Duration = Duration::from_secs(60);
-
 /// SESSION_EXPIRY_POLL_INTERVAL is the default interval to check for expired sessions.
-const SESSION_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const SESSION_EXPIRY_POLL_INTERVAL: Duration =
+    Duration::from_secs(crate::config::session::DEFAULT_EXPIRY_POLL_INTERVAL_SECONDS);
 
-/// Bucket stores two atomics.
+/// Bucket stores three atomics.
 /// - A counter that tracks how many packets we've processed within a time window.
-/// - A timestamp that stores the time we last reset the counter. It tracks
+/// - A counter that tracks how many bytes we've processed within a time window.
+/// - A timestamp that stores the time we last reset the counters. It tracks
 ///   the start of the time window.
 /// This allows us to have a simpler implementation for calculating token
 /// exhaustion without needing a write lock in the common case. The downside
-/// however is that since we're relying on two independent atomics, there is
+/// however is that since we're relying on independent atomics, there is
 /// in theory, a chance that we could allow a few packets through (i.e in-between
 /// checking the counter and the timestamp). However, in practice this would be
 /// quite rare and the number of such packets that do get through will likely be
@@ -56,17 +57,156 @@ const SESSION_EXPIRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
 #[derive(Debug)]
 struct Bucket {
     counter: Arc<AtomicUsize>,
+    bytes: Arc<AtomicU64>,
     window_start_time_secs: Arc<AtomicU64>,
 }
 
+impl Bucket {
+    fn new(now_secs: u64) -> Self {
+        Self {
+            counter: Arc::new(AtomicUsize::new(0)),
+            bytes: Arc::new(AtomicU64::new(0)),
+            window_start_time_secs: Arc::new(AtomicU64::new(now_secs)),
+        }
+    }
+
+    /// Returns whether a packet of `len` bytes is within the limits of the
+    /// current time window, starting a new time window if the current one
+    /// has ended.
+    fn acquire(&self, config: &Config, now_secs: u64, len: u64) -> bool {
+        let window_start_secs = self.window_start_time_secs.load(Ordering::Relaxed);
+        if now_secs.saturating_sub(window_start_secs) > config.period as u64 {
+            // Current time window has ended, so we can reset the counters and
+            // start a new time window instead.
+            self.counter.store(0, Ordering::Relaxed);
+            self.bytes.store(0, Ordering::Relaxed);
+            self.window_start_time_secs
+                .store(now_secs, Ordering::Relaxed);
+        }
+
+        if let Some(max_bytes) = config.max_bytes {
+            // Only count the bytes of packets that are allowed, so that a large
+            // packet doesn't use up the bytes available to smaller packets.
+            let allowed = self
+                .bytes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                    Some(bytes + len).filter(|bytes| *bytes <= max_bytes)
+                })
+                .is_ok();
+
+            if !allowed {
+                return false;
+            }
+        }
+
+        let allowed = config.max_packets.map_or(true, |max_packets| {
+            self.counter.fetch_add(1, Ordering::Relaxed) < max_packets
+        });
+
+        if !allowed && config.max_bytes.is_some() {
+            // The packet is dropped, so give back the bytes it took.
+            let _ = self
+                .bytes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                    Some(bytes.saturating_sub(len))
+                });
+        }
+
+        allowed
+    }
+}
+
+/// TokenBucket stores the packets and bytes that can be forwarded right now.
+/// Both are refilled continuously at the configured rate, up to the configured
+/// [`Burst`], so that a source that has been idle can briefly exceed the rate.
+#[derive(Debug)]
+struct TokenBucket {
+    packets: f64,
+    bytes: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Returns a full bucket.
+    fn new(config: &Config) -> Self {
+        Self {
+            packets: config.burst_packets(),
+            bytes: config.burst_bytes(),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket, and returns whether it holds enough tokens for a
+    /// packet of `len` bytes, taking them if so.
+    fn acquire(&mut self, config: &Config, len: u64) -> bool {
+        let now = Instant::now();
+        let periods = now.duration_since(self.refilled_at).as_secs_f64() / config.period as f64;
+        self.refilled_at = now;
+
+        let mut packets = 0.0;
+        if let Some(max_packets) = config.max_packets {
+            self.packets =
+                (self.packets + periods * max_packets as f64).min(config.burst_packets());
+            packets = 1.0;
+        }
+
+        let mut bytes = 0.0;
+        if let Some(max_bytes) = config.max_bytes {
+            self.bytes = (self.bytes + periods * max_bytes as f64).min(config.burst_bytes());
+            bytes = len as f64;
+        }
+
+        if self.packets < packets || self.bytes < bytes {
+            return false;
+        }
+
+        self.packets -= packets;
+        self.bytes -= bytes;
+        true
+    }
+}
+
+/// The rate limiting state of a single key.
+#[derive(Debug)]
+enum Limiter {
+    Window(Bucket),
+    TokenBucket(Mutex<TokenBucket>),
+}
+
+impl Limiter {
+    fn new(config: &Config, now_secs: u64) -> Self {
+        match config.burst {
+            Some(_) => Self::TokenBucket(Mutex::new(TokenBucket::new(config))),
+            None => Self::Window(Bucket::new(now_secs)),
+        }
+    }
+
+    fn acquire(&self, config: &Config, now_secs: u64, len: u64) -> bool {
+        match self {
+            Self::Window(bucket) => bucket.acquire(config, now_secs, len),
+            Self::TokenBucket(bucket) => bucket.lock().acquire(config, len),
+        }
+    }
+}
+
+/// The key that packets are rate limited by.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum BucketKey {
+    Address(EndpointAddress),
+    Metadata(bytes::Bytes),
+}
+
 /// A filter that implements rate limiting on packets based on the token-bucket
-/// algorithm.  Packets that violate the rate limit are dropped.  It only
-/// applies rate limiting on packets received from a downstream connection (processed
-/// through [`LocalRateLimit::read`]). Packets coming from upstream endpoints
-/// flow through the filter untouched.
+/// algorithm.  Packets that violate the rate limit are dropped.  By default it
+/// only applies rate limiting on packets received from a downstream connection
+/// (processed through [`LocalRateLimit::read`]), packets coming from upstream
+/// endpoints (processed through [`LocalRateLimit::write`]) are only limited
+/// when [`Config::direction`] includes them.
 pub struct LocalRateLimit {
-    /// Tracks rate limiting state per source address.
-    state: TtlMap<EndpointAddress, Bucket>,
+    /// Tracks rate limiting state of packets from downstream, per key.
+    read_state: TtlMap<BucketKey, Limiter>,
+    /// Tracks rate limiting state of packets from upstream, per key.
+    write_state: TtlMap<BucketKey, Limiter>,
     /// Filter configuration.
     config: Config,
     /// metrics reporter for this filter.
@@ -77,15 +217,11 @@ impl LocalRateLimit {
     /// new returns a new LocalRateLimit. It spawns a future in the background
     /// that periodically refills the rate limiter's tokens.
     fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
-        if config.period < 1 {
-            return Err(Error::FieldInvalid {
-                field: "period".into(),
-                reason: "value must be at least 1 second".into(),
-            });
-        }
+        config.validate()?;
 
         Ok(LocalRateLimit {
-            state: TtlMap::new(SESSION_TIMEOUT_SECONDS, SESSION_EXPIRY_POLL_INTERVAL),
+            read_state: TtlMap::new(config.session_timeout(), SESSION_EXPIRY_POLL_INTERVAL),
+            write_state: TtlMap::new(config.session_timeout(), SESSION_EXPIRY_POLL_INTERVAL),
             config,
             metrics,
         })
@@ -93,72 +229,108 @@ impl LocalRateLimit {
 
     /// acquire_token is called on behalf of every packet that is eligible
     /// for rate limiting. It returns whether there exists a token for the corresponding
-    /// address in the current period - determining whether or not the packet
+    /// key in the current period - determining whether or not the packet
     /// should be forwarded or dropped.
-    fn acquire_token(&self, address: &EndpointAddress) -> Option<()> {
-        if self.config.max_packets == 0 {
-            return None;
-        }
+    fn acquire_token(
+        &self,
+        state: &TtlMap<BucketKey, Limiter>,
+        key: BucketKey,
+        len: usize,
+    ) -> bool {
+        let now_secs = state.now_relative_secs();
+        let len = len as u64;
 
-        if let Some(bucket) = self.state.get(address) {
-            let prev_count = bucket.value.counter.fetch_add(1, Ordering::Relaxed);
-
-            let now_secs = self.state.now_relative_secs();
-            let window_start_secs = bucket.value.window_start_time_secs.load(Ordering::Relaxed);
+        if let Some(limiter) = state.get(&key) {
+            return limiter.acquire(&self.config, now_secs, len);
+        }
 
-            let elapsed_secs = now_secs - window_start_secs;
-            let start_new_window = elapsed_secs > self.config.period as u64;
+        match state.entry(key) {
+            // It is possible that some other task has added the item since we
+            // checked for it.
+            Entry::Occupied(entry) => entry.get().acquire(&self.config, now_secs, len),
+            Entry::Vacant(entry) => entry.insert(Limiter::new(&self.config, now_secs)).acquire(
+                &self.config,
+                now_secs,
+                len,
+            ),
+        }
+    }
 
-            // Check if allowing this packet will put us over the maximum.
-            if prev_count >= self.config.max_packets {
-                // If so, then we can only allow the packet if the current time
-                // window has ended.
-                if !start_new_window {
-                    return None;
-                }
-            }
+    /// Returns the key of a packet from `source`, for every [`LimitKey`] other
+    /// than [`LimitKey::Destination`].
+    fn source_key(&self, source: &EndpointAddress, metadata: &DynamicMetadata) -> BucketKey {
+        match self.config.key {
+            LimitKey::SourceIp => BucketKey::Address(EndpointAddress {
+                // IPv4 sources can be received as IPv4-mapped IPv6 addresses
+                // on dual-stack sockets, which must share the same limit.
+                host: match &source.host {
+                    AddressKind::Ip(ip) => AddressKind::Ip(crate::utils::net::to_canonical_ip(*ip)),
+                    host => host.clone(),
+                },
+                port: None,
+            }),
+            LimitKey::Metadata => match metadata.get(&self.config.metadata_key) {
+                Some(metadata::Value::Bytes(bytes)) => BucketKey::Metadata(bytes.clone()),
+                Some(metadata::Value::String(string)) => BucketKey::Metadata(string.clone().into()),
+                Some(value) => BucketKey::Metadata(value.to_string().into()),
+                None => BucketKey::Address(source.clone()),
+            },
+            _ => BucketKey::Address(source.clone()),
+        }
+    }
 
-            if start_new_window {
-                // Current time window has ended, so we can reset the counter and
-                // start a new time window instead.
-                bucket.value.counter.store(1, Ordering::Relaxed);
-                bucket
-                    .value
-                    .window_start_time_secs
-                    .store(now_secs, Ordering::Relaxed);
-            }
+    /// Returns `Some` if the packet is allowed, counting it as dropped otherwise.
+    fn allow(&self, allowed: bool) -> Option<()> {
+        if allowed {
+            Some(())
+        } else {
+            self.metrics.packets_dropped_total.inc();
+            None
+        }
+    }
+}
 
+impl Filter for LocalRateLimit {
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        if !self.config.direction.on_read() {
             return Some(());
         }
 
-        match self.state.entry(address.clone()) {
-            Entry::Occupied(entry) => {
-                // It is possible that some other task has added the item since we
-                // checked for it. If so, only increment the counter - no need to
-                // update the window start time since the window has just started.
-                let bucket = entry.get();
-                bucket.value.counter.fetch_add(1, Ordering::Relaxed);
-            }
-            Entry::Vacant(entry) => {
-                // New entry, set both the time stamp and
-                let now_secs = self.state.now_relative_secs();
-                entry.insert(Bucket {
-                    counter: Arc::new(AtomicUsize::new(1)),
-                    window_start_time_secs: Arc::new(AtomicU64::new(now_secs)),
+        let len = ctx.contents.len();
+        let allowed = match self.config.key {
+            LimitKey::Destination => {
+                // Only forward the packet to the endpoints that are within
+                // their limit, dropping it if none are.
+                ctx.endpoints.retain(|endpoint| {
+                    self.acquire_token(
+                        &self.read_state,
+                        BucketKey::Address(endpoint.address.clone()),
+                        len,
+                    )
                 });
+                !ctx.endpoints.is_empty()
             }
+            _ => self.acquire_token(
+                &self.read_state,
+                self.source_key(&ctx.source, &ctx.metadata),
+                len,
+            ),
         };
 
-        Some(())
+        self.allow(allowed)
     }
-}
 
-impl Filter for LocalRateLimit {
-    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
-        self.acquire_token(&ctx.source).or_else(|| {
-            self.metrics.packets_dropped_total.inc();
-            None
-        })
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        if !self.config.direction.on_write() {
+            return Some(());
+        }
+
+        let key = match self.config.key {
+            LimitKey::Destination => BucketKey::Address(ctx.dest.clone()),
+            _ => self.source_key(&ctx.source, &ctx.metadata),
+        };
+
+        self.allow(self.acquire_token(&self.write_state, key, ctx.contents.len()))
     }
 }
 
@@ -170,17 +342,131 @@ impl StaticFilter for LocalRateLimit {
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
         Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
     }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config
+            .ok_or(Error::MissingConfig(Self::NAME))
+            .and_then(Config::validate)
+    }
 }
 
 /// Config represents a [self]'s configuration.
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
 pub struct Config {
     /// The maximum number of packets allowed to be forwarded by the rate
-    /// limiter in a given duration.
-    pub max_packets: usize,
+    /// limiter in a given duration. If none is provided, the number of packets
+    /// isn't limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_packets: Option<usize>,
+    /// The maximum number of bytes allowed to be forwarded by the rate
+    /// limiter in a given duration. If none is provided, the number of bytes
+    /// isn't limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
     /// The duration in seconds during which max_packets applies. If none is provided, it
     /// defaults to one second.
+    #[serde(default = "default_period")]
     pub period: u32,
+    /// If provided, packets and bytes are limited with a token bucket that's
+    /// refilled continuously, instead of in fixed time windows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<Burst>,
+    /// What the limits are applied to, each key having its own limit.
+    #[serde(default)]
+    pub key: LimitKey,
+    /// The key of the dynamic metadata value that packets are limited by,
+    /// when `key` is `METADATA`.
+    #[serde(rename = "metadataKey", default = "default_metadata_key")]
+    pub metadata_key: metadata::Key,
+    /// Which packets are rate limited.
+    #[serde(default)]
+    pub direction: Direction,
+    /// The number of seconds the limits of a key are kept for after its last
+    /// packet, defaults to the proxy's default session timeout.
+    #[serde(default = "default_session_timeout")]
+    pub session_timeout: u64,
+}
+
+impl Config {
+    /// Checks that the limits are consistent.
+    fn validate(&self) -> Result<(), Error> {
+        if self.period < 1 {
+            return Err(Error::FieldInvalid {
+                field: "period".into(),
+                reason: "value must be at least 1 second".into(),
+            });
+        }
+
+        if self.session_timeout < 1 {
+            return Err(Error::FieldInvalid {
+                field: "session_timeout".into(),
+                reason: "value must be at least 1 second".into(),
+            });
+        }
+
+        if self.max_packets.is_none() && self.max_bytes.is_none() {
+            return Err(Error::FieldInvalid {
+                field: "max_packets".into(),
+                reason: "either max_packets or max_bytes must be set".into(),
+            });
+        }
+
+        if let Some(burst) = &self.burst {
+            if burst.packets.is_some() && self.max_packets.is_none() {
+                return Err(Error::FieldInvalid {
+                    field: "burst.packets".into(),
+                    reason: "max_packets must be set".into(),
+                });
+            }
+
+            if burst.bytes.is_some() && self.max_bytes.is_none() {
+                return Err(Error::FieldInvalid {
+                    field: "burst.bytes".into(),
+                    reason: "max_bytes must be set".into(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How long the limits of a key are kept for after its last packet.
+    fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout)
+    }
+
+    /// The number of packets a full token bucket holds.
+    fn burst_packets(&self) -> f64 {
+        self.burst
+            .as_ref()
+            .and_then(|burst| burst.packets)
+            .or(self.max_packets)
+            .unwrap_or_default() as f64
+    }
+
+    /// The number of bytes a full token bucket holds.
+    fn burst_bytes(&self) -> f64 {
+        self.burst
+            .as_ref()
+            .and_then(|burst| burst.bytes)
+            .or(self.max_bytes)
+            .unwrap_or_default() as f64
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_packets: None,
+            max_bytes: None,
+            period: default_period(),
+            burst: None,
+            key: LimitKey::default(),
+            metadata_key: default_metadata_key(),
+            direction: Direction::default(),
+            session_timeout: default_session_timeout(),
+        }
+    }
 }
 
 /// default value for [`Config::period`]
@@ -188,11 +474,147 @@ fn default_period() -> u32 {
     1
 }
 
+/// Default value for [`Config::session_timeout`]
+fn default_session_timeout() -> u64 {
+    crate::config::session::DEFAULT_TIMEOUT_SECONDS
+}
+
+/// Default value for [`Config::metadata_key`]
+fn default_metadata_key() -> metadata::Key {
+    metadata::Key::from_static(CAPTURED_BYTES)
+}
+
+/// The most packets and bytes that can be forwarded at once by a source that
+/// has been idle, while limiting with a token bucket.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Burst {
+    /// The number of packets, defaults to `max_packets`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packets: Option<usize>,
+    /// The number of bytes, defaults to `max_bytes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+/// What a [`LocalRateLimit`] applies its limits to.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq, schemars::JsonSchema)]
+pub enum LimitKey {
+    /// The packet's source IP address and port.
+    #[serde(rename = "SOURCE_IP_AND_PORT")]
+    SourceIpAndPort,
+    /// The packet's source IP address, so that every port of a source shares
+    /// the same limit.
+    #[serde(rename = "SOURCE_IP")]
+    SourceIp,
+    /// The dynamic metadata value at `metadataKey`, such as a token captured
+    /// by an earlier filter. Packets without the value are limited by their
+    /// source IP address and port.
+    #[serde(rename = "METADATA")]
+    Metadata,
+    /// The packet's destination, each upstream endpoint for packets from
+    /// downstream, and the downstream address for packets from upstream.
+    #[serde(rename = "DESTINATION")]
+    Destination,
+}
+
+impl Default for LimitKey {
+    fn default() -> Self {
+        LimitKey::SourceIpAndPort
+    }
+}
+
+impl From<LimitKey> for proto::local_rate_limit::LimitKey {
+    fn from(key: LimitKey) -> Self {
+        match key {
+            LimitKey::SourceIpAndPort => Self::SourceIpAndPort,
+            LimitKey::SourceIp => Self::SourceIp,
+            LimitKey::Metadata => Self::Metadata,
+            LimitKey::Destination => Self::Destination,
+        }
+    }
+}
+
+impl From<proto::local_rate_limit::LimitKey> for LimitKey {
+    fn from(key: proto::local_rate_limit::LimitKey) -> Self {
+        match key {
+            proto::local_rate_limit::LimitKey::SourceIpAndPort => Self::SourceIpAndPort,
+            proto::local_rate_limit::LimitKey::SourceIp => Self::SourceIp,
+            proto::local_rate_limit::LimitKey::Metadata => Self::Metadata,
+            proto::local_rate_limit::LimitKey::Destination => Self::Destination,
+        }
+    }
+}
+
+/// Which packets a [`LocalRateLimit`] rate limits.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq, schemars::JsonSchema)]
+pub enum Direction {
+    /// Packets received from downstream.
+    #[serde(rename = "READ")]
+    Read,
+    /// Packets received from upstream endpoints, such as game servers
+    /// sending to clients.
+    #[serde(rename = "WRITE")]
+    Write,
+    /// Packets in both directions, each direction having its own limits.
+    #[serde(rename = "BOTH")]
+    Both,
+}
+
+impl Direction {
+    fn on_read(&self) -> bool {
+        matches!(self, Direction::Read | Direction::Both)
+    }
+
+    fn on_write(&self) -> bool {
+        matches!(self, Direction::Write | Direction::Both)
+    }
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Direction::Read
+    }
+}
+
+impl From<Direction> for proto::local_rate_limit::Direction {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Read => Self::Read,
+            Direction::Write => Self::Write,
+            Direction::Both => Self::Both,
+        }
+    }
+}
+
+impl From<proto::local_rate_limit::Direction> for Direction {
+    fn from(direction: proto::local_rate_limit::Direction) -> Self {
+        match direction {
+            proto::local_rate_limit::Direction::Read => Self::Read,
+            proto::local_rate_limit::Direction::Write => Self::Write,
+            proto::local_rate_limit::Direction::Both => Self::Both,
+        }
+    }
+}
+
 impl From<Config> for proto::LocalRateLimit {
     fn from(config: Config) -> Self {
         Self {
-            max_packets: config.max_packets as u64,
+            max_packets: config.max_packets.map(|max_packets| max_packets as u64),
             period: Some(config.period),
+            max_bytes: config.max_bytes,
+            burst: config.burst.map(|burst| proto::local_rate_limit::Burst {
+                packets: burst.packets.map(|packets| packets as u64),
+                bytes: burst.bytes,
+            }),
+            key: Some(proto::local_rate_limit::LimitKeyValue {
+                value: proto::local_rate_limit::LimitKey::from(config.key) as i32,
+            }),
+            metadata_key: Some(config.metadata_key.to_string()),
+            direction: Some(proto::local_rate_limit::DirectionValue {
+                value: proto::local_rate_limit::Direction::from(config.direction) as i32,
+            }),
+            session_timeout: Some(config.session_timeout),
         }
     }
 }
@@ -202,8 +624,28 @@ impl TryFrom<proto::LocalRateLimit> for Config {
 
     fn try_from(p: proto::LocalRateLimit) -> Result<Self, Self::Error> {
         Ok(Self {
-            max_packets: p.max_packets as usize,
+            max_packets: p.max_packets.map(|max_packets| max_packets as usize),
+            max_bytes: p.max_bytes,
             period: p.period.unwrap_or_else(default_period),
+            burst: p.burst.map(|burst| Burst {
+                packets: burst.packets.map(|packets| packets as usize),
+                bytes: burst.bytes,
+            }),
+            key: p
+                .key
+                .map(|key| key.value())
+                .map(LimitKey::from)
+                .unwrap_or_default(),
+            metadata_key: p
+                .metadata_key
+                .map(metadata::Key::new)
+                .unwrap_or_else(default_metadata_key),
+            direction: p
+                .direction
+                .map(|direction| direction.value())
+                .map(Direction::from)
+                .unwrap_or_default(),
+            session_timeout: p.session_timeout.unwrap_or_else(default_session_timeout),
         })
     }
 }
@@ -230,21 +672,37 @@ mod tests {
 
     /// Send a packet to the filter and assert whether or not it was processed.
     fn read(r: &LocalRateLimit, address: &EndpointAddress, should_succeed: bool) {
+        read_bytes(r, address, 1, should_succeed)
+    }
+
+    /// Send a packet of `len` bytes to the filter and assert whether or not it
+    /// was processed.
+    fn read_bytes(r: &LocalRateLimit, address: &EndpointAddress, len: usize, should_succeed: bool) {
         let endpoints = vec![crate::endpoint::Endpoint::new(
             (Ipv4Addr::LOCALHOST, 8089).into(),
         )];
 
-        let mut context = ReadContext::new(endpoints, address.clone(), vec![9]);
+        let mut context = ReadContext::new(endpoints, address.clone(), vec![9; len]);
         let result = r.read(&mut context);
 
         if should_succeed {
             result.unwrap();
-            assert_eq!(context.contents, vec![9]);
+            assert_eq!(context.contents, vec![9; len]);
         } else {
             assert!(result.is_none());
         }
     }
 
+    /// Send a packet from upstream to `dest` through the filter and assert
+    /// whether or not it was processed.
+    fn write(r: &LocalRateLimit, dest: &EndpointAddress, should_succeed: bool) {
+        let endpoint = crate::endpoint::Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into());
+        let mut context =
+            WriteContext::new(endpoint.clone(), endpoint.address, dest.clone(), vec![9]);
+
+        assert_eq!(r.write(&mut context).is_some(), should_succeed);
+    }
+
     #[tokio::test]
     async fn config_minimum_period() {
         let factory = LocalRateLimit::factory();
@@ -261,29 +719,109 @@ period: 0
         assert!(format!("{err:?}").contains("value must be at least 1 second"));
     }
 
+    #[tokio::test]
+    async fn session_timeout() {
+        let r = rate_limiter(Config {
+            max_packets: Some(10),
+            session_timeout: 90,
+            ..Config::default()
+        });
+        assert_eq!(Duration::from_secs(90), r.read_state.ttl());
+        assert_eq!(Duration::from_secs(90), r.write_state.ttl());
+
+        let config = "
+max_packets: 10
+session_timeout: 0
+";
+        let err = LocalRateLimit::factory()
+            .create_filter(CreateFilterArgs {
+                config: Some(ConfigType::Static(serde_yaml::from_str(config).unwrap())),
+            })
+            .err()
+            .unwrap();
+        assert!(format!("{err:?}").contains("session_timeout"));
+    }
+
+    #[tokio::test]
+    async fn config_requires_limit() {
+        let factory = LocalRateLimit::factory();
+        let configs = [
+            ("period: 1", "either max_packets or max_bytes must be set"),
+            (
+                "
+max_bytes: 10
+burst:
+  packets: 10
+",
+                "max_packets must be set",
+            ),
+            (
+                "
+max_packets: 10
+burst:
+  bytes: 10
+",
+                "max_bytes must be set",
+            ),
+        ];
+
+        for (config, reason) in configs {
+            let err = factory
+                .create_filter(CreateFilterArgs {
+                    config: Some(ConfigType::Static(serde_yaml::from_str(config).unwrap())),
+                })
+                .err()
+                .unwrap();
+            assert!(format!("{err:?}").contains(reason), "{config}");
+        }
+    }
+
     #[test]
     fn convert_proto_config() {
         let test_cases = vec![
             (
                 "should succeed when all valid values are provided",
                 proto::LocalRateLimit {
-                    max_packets: 10,
+                    max_packets: Some(10),
                     period: Some(2),
+                    max_bytes: Some(100),
+                    burst: Some(proto::local_rate_limit::Burst {
+                        packets: Some(20),
+                        bytes: None,
+                    }),
+                    key: Some(proto::local_rate_limit::LimitKeyValue {
+                        value: proto::local_rate_limit::LimitKey::Metadata as i32,
+                    }),
+                    metadata_key: Some("foobar".into()),
+                    direction: Some(proto::local_rate_limit::DirectionValue {
+                        value: proto::local_rate_limit::Direction::Both as i32,
+                    }),
+                    session_timeout: Some(90),
                 },
                 Some(Config {
-                    max_packets: 10,
+                    max_packets: Some(10),
+                    max_bytes: Some(100),
                     period: 2,
+                    burst: Some(Burst {
+                        packets: Some(20),
+                        bytes: None,
+                    }),
+                    key: LimitKey::Metadata,
+                    metadata_key: "foobar".into(),
+                    direction: Direction::Both,
+                    session_timeout: 90,
                 }),
             ),
             (
                 "should use correct default values",
                 proto::LocalRateLimit {
-                    max_packets: 10,
-                    period: None,
+                    max_packets: Some(10),
+                    ..<_>::default()
                 },
                 Some(Config {
-                    max_packets: 10,
+                    max_packets: Some(10),
                     period: 1,
+                    ..<_>::default()
                 }),
             ),
         ];
@@ -305,8 +843,9 @@ period: 0
     async fn initially_available_tokens() {
         // Test that we always start with the max number of tokens available.
         let r = rate_limiter(Config {
-            max_packets: 3,
+            max_packets: Some(3),
             period: 1,
+            ..<_>::default()
         });
 
         let (address, _) = address_pair();
@@ -320,8 +859,9 @@ period: 0
     #[tokio::test]
     async fn filter_with_no_available_tokens() {
         let r = rate_limiter(Config {
-            max_packets: 0,
+            max_packets: Some(0),
             period: 1,
+            ..<_>::default()
         });
 
         let (address, _) = address_pair();
@@ -338,8 +878,9 @@ period: 0
         time::pause();
 
         let r = rate_limiter(Config {
-            max_packets: 2,
+            max_packets: Some(2),
             period: 1,
+            ..<_>::default()
         });
 
         let (address1, address2) = address_pair();
@@ -382,8 +923,9 @@ period: 0
         time::pause();
 
         let r = rate_limiter(Config {
-            max_packets: 2,
+            max_packets: Some(2),
             period: 1,
+            ..<_>::default()
         });
 
         let (address, _) = address_pair();
@@ -402,4 +944,203 @@ period: 0
         // Check that other routes are not affected.
         assert_write_no_change(&r);
     }
+
+    #[tokio::test]
+    async fn rate_limit_bytes() {
+        time::pause();
+
+        let r = rate_limiter(Config {
+            max_packets: Some(3),
+            max_bytes: Some(10),
+            period: 1,
+            ..<_>::default()
+        });
+
+        let (address, _) = address_pair();
+
+        read_bytes(&r, &address, 4, true);
+        read_bytes(&r, &address, 4, true);
+        // Dropped packets don't use up the remaining bytes.
+        read_bytes(&r, &address, 4, false);
+        read_bytes(&r, &address, 2, true);
+        // Check that the packet limit still applies.
+        read_bytes(&r, &address, 0, false);
+
+        // Advance time to start a new window.
+        time::advance(Duration::from_secs(2)).await;
+
+        read_bytes(&r, &address, 10, true);
+        read_bytes(&r, &address, 1, false);
+    }
+
+    #[test]
+    fn bucket_packet_limit_returns_bytes() {
+        let config = Config {
+            max_packets: Some(1),
+            max_bytes: Some(10),
+            period: 1,
+            ..<_>::default()
+        };
+
+        let bucket = Bucket::new(0);
+        assert!(bucket.acquire(&config, 0, 4));
+        // A packet over the packet limit doesn't keep the bytes it took.
+        assert!(!bucket.acquire(&config, 0, 4));
+        assert_eq!(4, bucket.bytes.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_by_source_ip() {
+        let r = rate_limiter(Config {
+            max_packets: Some(2),
+            key: LimitKey::SourceIp,
+            ..<_>::default()
+        });
+
+        let (address1, address2) = address_pair();
+
+        // Both addresses share the same IP address, and so the same limit.
+        read(&r, &address1, true);
+        read(&r, &address2, true);
+        read(&r, &address1, false);
+        read(&r, &address2, false);
+
+        read(&r, &(Ipv4Addr::new(127, 0, 0, 2), 8080).into(), true);
+
+        // IPv4-mapped IPv6 addresses share the limit of their IPv4 address.
+        let mapped: EndpointAddress = (Ipv4Addr::new(127, 0, 0, 2).to_ipv6_mapped(), 8081).into();
+        read(&r, &mapped, true);
+        read(&r, &mapped, false);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_by_metadata() {
+        let r = rate_limiter(Config {
+            max_packets: Some(1),
+            key: LimitKey::Metadata,
+            ..<_>::default()
+        });
+
+        let (address1, address2) = address_pair();
+        let read = |address: &EndpointAddress, token: Option<&'static [u8]>| {
+            let endpoints = vec![crate::endpoint::Endpoint::new(
+                (Ipv4Addr::LOCALHOST, 8089).into(),
+            )];
+            let mut context = ReadContext::new(endpoints, address.clone(), vec![9]);
+            if let Some(token) = token {
+                context
+                    .metadata
+                    .insert(CAPTURED_BYTES.into(), metadata::Value::Bytes(token.into()));
+            }
+            r.read(&mut context).is_some()
+        };
+
+        assert!(read(&address1, Some(b"abc")));
+        // The same token from another address shares the limit.
+        assert!(!read(&address2, Some(b"abc")));
+        assert!(read(&address2, Some(b"xyz")));
+        // Packets without a token are limited by their source.
+        assert!(read(&address1, None));
+        assert!(!read(&address1, None));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_by_destination() {
+        let r = rate_limiter(Config {
+            max_packets: Some(1),
+            key: LimitKey::Destination,
+            ..<_>::default()
+        });
+
+        let (address1, address2) = address_pair();
+        let endpoint1 = crate::endpoint::Endpoint::new((Ipv4Addr::LOCALHOST, 8089).into());
+        let endpoint2 = crate::endpoint::Endpoint::new((Ipv4Addr::LOCALHOST, 8090).into());
+
+        let mut context = ReadContext::new(vec![endpoint1.clone()], address1, vec![9]);
+        r.read(&mut context).unwrap();
+
+        // Only the endpoint within its limit is kept.
+        let mut context = ReadContext::new(
+            vec![endpoint1.clone(), endpoint2.clone()],
+            address2.clone(),
+            vec![9],
+        );
+        r.read(&mut context).unwrap();
+        assert_eq!(vec![endpoint2.clone()], context.endpoints);
+
+        let mut context = ReadContext::new(vec![endpoint1, endpoint2], address2, vec![9]);
+        assert!(r.read(&mut context).is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_writes() {
+        let r = rate_limiter(Config {
+            max_packets: Some(1),
+            key: LimitKey::Destination,
+            direction: Direction::Write,
+            ..<_>::default()
+        });
+
+        let (address1, address2) = address_pair();
+
+        write(&r, &address1, true);
+        write(&r, &address1, false);
+        write(&r, &address2, true);
+
+        // Check that reads are not affected.
+        read(&r, &address1, true);
+        read(&r, &address1, true);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_both_directions() {
+        let r = rate_limiter(Config {
+            max_packets: Some(1),
+            direction: Direction::Both,
+            ..<_>::default()
+        });
+
+        let (address, _) = address_pair();
+
+        // Each direction has its own limit.
+        read(&r, &address, true);
+        write(&r, &address, true);
+        read(&r, &address, false);
+        write(&r, &address, false);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_burst() {
+        time::pause();
+
+        let r = rate_limiter(Config {
+            max_packets: Some(2),
+            period: 1,
+            burst: Some(Burst {
+                packets: Some(4),
+                bytes: None,
+            }),
+            ..<_>::default()
+        });
+
+        let (address, _) = address_pair();
+
+        // The bucket starts full.
+        for _ in 0..4 {
+            read(&r, &address, true);
+        }
+        read(&r, &address, false);
+
+        // Tokens are refilled continuously at the configured rate.
+        time::advance(Duration::from_millis(500)).await;
+        read(&r, &address, true);
+        read(&r, &address, false);
+
+        // And never beyond the burst.
+        time::advance(Duration::from_secs(10)).await;
+        for _ in 0..4 {
+            read(&r, &address, true);
+        }
+        read(&r, &address, false);
+    }
 }
@@ -155,6 +155,31 @@ impl StaticFilter for Match {
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
         Self::new(Self::ensure_config_exists(config)?, Metrics::new()?)
     }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        let config = config.ok_or(Error::MissingConfig(Self::NAME))?;
+        if config.on_read.is_none() && config.on_write.is_none() {
+            return Err(Error::MissingConfig(Self::NAME));
+        }
+
+        config
+            .on_read
+            .iter()
+            .chain(&config.on_write)
+            .flat_map(|config| {
+                config
+                    .branches
+                    .iter()
+                    .map(|branch| &branch.filter)
+                    .chain([&config.fallthrough.0])
+            })
+            .try_for_each(|filter| {
+                crate::filters::FilterRegistry::validate(
+                    &filter.name,
+                    CreateFilterArgs::new(filter.config.clone().map(From::from)),
+                )
+            })
+    }
 }
 
 #[cfg(test)]
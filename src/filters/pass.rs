@@ -52,6 +52,10 @@ impl StaticFilter for Pass {
     fn try_from_config(_config: Option<Self::Configuration>) -> Result<Self, Error> {
         Ok(Pass::new())
     }
+
+    fn validate_config(_: Option<&Self::Configuration>) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// `pass` filter's configuration.
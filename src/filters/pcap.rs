@@ -0,0 +1,265 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+crate::include_proto!("quilkin.filters.pcap.v1alpha1");
+
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
+
+use ipnetwork::IpNetwork;
+
+use crate::{
+    endpoint::EndpointAddress,
+    filters::prelude::*,
+    pcap::{SessionSelector, Stage, Tap},
+};
+
+use self::quilkin::filters::pcap::v1alpha1 as proto;
+
+pub use crate::pcap::Config;
+
+/// Captures the packets going through the filter into pcapng files, so that
+/// the packets can be inspected at any point in the filter chain.
+pub struct Pcap {
+    /// The capture, or `None` when captures are disabled.
+    tap: Option<Tap>,
+}
+
+impl Pcap {
+    /// Creates the filter, writing its capture to the file named by the
+    /// config's `path` in `directory`. Nothing is captured without a
+    /// `directory`, but the `path` is still checked.
+    fn new(mut config: Config, directory: Option<&Path>) -> Result<Self, Error> {
+        let path = capture_path(&config, directory)?;
+        if directory.is_none() {
+            tracing::warn!(path = %config.path.display(), "Not capturing packets, as there's no `--capture-dir`");
+            return Ok(Self { tap: None });
+        }
+
+        config.path = path;
+        let tap = Tap::new(config).map_err(|error| Error::FieldInvalid {
+            field: "path".into(),
+            reason: format!("failed to start capture: {error}"),
+        })?;
+
+        Ok(Self { tap: Some(tap) })
+    }
+}
+
+/// Returns the path of the capture file within the capture `directory`,
+/// erroring if it's outside of it.
+fn capture_path(config: &Config, directory: Option<&Path>) -> Result<PathBuf, Error> {
+    crate::pcap::in_directory(&config.path, directory.unwrap_or(Path::new(""))).map_err(|error| {
+        Error::FieldInvalid {
+            field: "path".into(),
+            reason: error.to_string(),
+        }
+    })
+}
+
+impl Filter for Pcap {
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn read(&self, ctx: &mut ReadContext) -> Option<()> {
+        if let Some(tap) = &self.tap {
+            tap.record(Stage::FilterRead, &ctx.source, None, &ctx.contents);
+        }
+        Some(())
+    }
+
+    #[cfg_attr(feature = "instrument", tracing::instrument(skip(self, ctx)))]
+    fn write(&self, ctx: &mut WriteContext) -> Option<()> {
+        if let Some(tap) = &self.tap {
+            tap.record(
+                Stage::FilterWrite,
+                &ctx.dest,
+                Some(&ctx.source),
+                &ctx.contents,
+            );
+        }
+        Some(())
+    }
+}
+
+impl StaticFilter for Pcap {
+    const NAME: &'static str = "quilkin.filters.pcap.v1alpha1.Pcap";
+    type Configuration = Config;
+    type BinaryConfiguration = proto::Pcap;
+
+    fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
+        let directory = crate::pcap::capture_dir();
+        Self::new(
+            Self::ensure_config_exists(config)?,
+            directory.as_deref().map(PathBuf::as_path),
+        )
+    }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        capture_path(config.ok_or(Error::MissingConfig(Self::NAME))?, None).map(drop)
+    }
+}
+
+impl From<Config> for proto::Pcap {
+    fn from(config: Config) -> Self {
+        Self {
+            path: config.path.to_string_lossy().into_owned(),
+            sources: config.sources.iter().map(ToString::to_string).collect(),
+            endpoints: config.endpoints.iter().map(ToString::to_string).collect(),
+            sessions: config
+                .sessions
+                .iter()
+                .map(|session| proto::pcap::Session {
+                    source: session.source.to_string(),
+                    dest: session.dest.to_string(),
+                })
+                .collect(),
+            max_file_size: Some(config.max_file_size),
+            max_file_duration: config.max_file_duration,
+            max_files: Some(config.max_files),
+            max_duration: config.max_duration,
+        }
+    }
+}
+
+impl TryFrom<proto::Pcap> for Config {
+    type Error = ConvertProtoConfigError;
+
+    fn try_from(p: proto::Pcap) -> Result<Self, Self::Error> {
+        let parse = |field: &'static str, value: &str| {
+            value
+                .parse::<EndpointAddress>()
+                .map_err(|error| ConvertProtoConfigError::new(error, Some(field.into())))
+        };
+
+        let mut config: Config = serde_json::from_value(serde_json::json!({ "path": p.path }))
+            .map_err(|error| ConvertProtoConfigError::new(error, Some("path".into())))?;
+
+        config.sources = p
+            .sources
+            .iter()
+            .map(|source| {
+                source
+                    .parse::<IpNetwork>()
+                    .map_err(|error| ConvertProtoConfigError::new(error, Some("sources".into())))
+            })
+            .collect::<Result<_, _>>()?;
+        config.endpoints = p
+            .endpoints
+            .iter()
+            .map(|endpoint| parse("endpoints", endpoint))
+            .collect::<Result<_, _>>()?;
+        config.sessions = p
+            .sessions
+            .iter()
+            .map(|session| {
+                Ok(SessionSelector {
+                    source: parse("sessions.source", &session.source)?,
+                    dest: parse("sessions.dest", &session.dest)?,
+                })
+            })
+            .collect::<Result<_, ConvertProtoConfigError>>()?;
+
+        if let Some(max_file_size) = p.max_file_size {
+            config.max_file_size = max_file_size;
+        }
+        if let Some(max_files) = p.max_files {
+            config.max_files = max_files;
+        }
+        config.max_file_duration = p.max_file_duration;
+        config.max_duration = p.max_duration;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_filter_read_no_change, assert_write_no_change};
+
+    fn config(path: &str) -> Config {
+        serde_json::from_value(serde_json::json!({ "path": path })).unwrap()
+    }
+
+    #[test]
+    fn read_and_write() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        let filter = Pcap::new(config("capture.pcapng"), Some(dir.path())).unwrap();
+
+        assert_filter_read_no_change(&filter);
+        assert_write_no_change(&filter);
+
+        let status = filter.tap.as_ref().unwrap().stop();
+        assert_eq!(2, status.packets);
+        assert_eq!(vec![dir.path().join("capture-00000.pcapng")], status.files);
+    }
+
+    #[test]
+    fn capture_dir() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        for path in ["../capture.pcapng", "/tmp/capture.pcapng", "nested/capture"] {
+            assert!(Pcap::new(config(path), Some(dir.path())).is_err(), "{path}");
+            assert!(Pcap::new(config(path), None).is_err(), "{path}");
+        }
+
+        // Nothing is captured without a capture directory.
+        let filter = Pcap::new(config("capture.pcapng"), None).unwrap();
+        assert!(filter.tap.is_none());
+        assert_filter_read_no_change(&filter);
+    }
+
+    #[test]
+    fn validate_config() {
+        let factory = Pcap::factory();
+        let validate = |path: &str| {
+            factory.validate_config(CreateFilterArgs::fixed(Some(serde_json::json!({
+                "path": path
+            }))))
+        };
+
+        assert!(validate("capture.pcapng").is_ok());
+        assert!(validate("../capture.pcapng").is_err());
+        assert!(Pcap::validate_config(None).is_err());
+    }
+
+    #[test]
+    fn convert_proto_config() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "path": "/tmp/capture.pcapng",
+            "sources": ["10.0.0.0/8"],
+            "endpoints": ["127.0.0.1:7000"],
+            "sessions": [{ "source": "10.0.0.1:8000", "dest": "127.0.0.1:7000" }],
+            "max_file_size": 1024,
+            "max_file_duration": 60,
+            "max_files": 2,
+            "max_duration": 600,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config::try_from(proto::Pcap::from(config.clone())).unwrap()
+        );
+
+        let proto = proto::Pcap {
+            path: "/tmp/capture.pcapng".into(),
+            sources: vec!["not a network".into()],
+            ..<_>::default()
+        };
+        assert!(Config::try_from(proto).is_err());
+    }
+}
@@ -16,9 +16,13 @@
 
 #[cfg(doc)]
 use crate::filters::Filter;
+use std::sync::Arc;
+
 use crate::{
+    cluster::ClusterMap,
     endpoint::{Endpoint, EndpointAddress},
     metadata::DynamicMetadata,
+    pool::PacketBuffer,
 };
 
 /// The input arguments to [`Filter::read`].
@@ -29,19 +33,27 @@ pub struct ReadContext {
     /// The source of the received packet.
     pub source: EndpointAddress,
     /// Contents of the received packet.
-    pub contents: Vec<u8>,
+    pub contents: PacketBuffer,
     /// Arbitrary values that can be passed from one filter to another.
     pub metadata: DynamicMetadata,
+    /// The clusters that `endpoints` were taken from, which describe the
+    /// locality of each endpoint.
+    pub clusters: Option<Arc<ClusterMap>>,
 }
 
 impl ReadContext {
     /// Creates a new [`ReadContext`].
-    pub fn new(endpoints: Vec<Endpoint>, source: EndpointAddress, contents: Vec<u8>) -> Self {
+    pub fn new(
+        endpoints: Vec<Endpoint>,
+        source: EndpointAddress,
+        contents: impl Into<PacketBuffer>,
+    ) -> Self {
         Self {
             endpoints,
             source,
-            contents,
+            contents: contents.into(),
             metadata: DynamicMetadata::new(),
+            clusters: None,
         }
     }
 
@@ -49,4 +61,9 @@ impl ReadContext {
         self.metadata = metadata;
         self
     }
+
+    pub fn clusters(mut self, clusters: Arc<ClusterMap>) -> Self {
+        self.clusters = Some(clusters);
+        self
+    }
 }
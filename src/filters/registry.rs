@@ -51,6 +51,16 @@ impl FilterRegistry {
         }
     }
 
+    /// Checks the configuration of the [`Filter`][crate::filters::Filter] for
+    /// a given `key` without creating it. Errors if the filter cannot be
+    /// found, or if there is a configuration issue.
+    pub fn validate(key: &str, args: CreateFilterArgs) -> Result<(), Error> {
+        match REGISTRY.load().get(key).map(|p| p.validate_config(args)) {
+            None => Err(Error::NotFound(key.to_owned())),
+            Some(result) => result,
+        }
+    }
+
     /// Returns a [`DynFilterFactory`] for a given `key`. Returning `None` if the
     /// factory cannot be found.
     pub fn get_factory(key: &str) -> Option<std::sync::Arc<DynFilterFactory>> {
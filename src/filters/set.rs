@@ -35,11 +35,14 @@ impl FilterSet {
     /// Current default filters:
     /// - [`debug`][filters::debug]
     /// - [`local_rate_limit`][filters::local_rate_limit]
+    /// - [`global_rate_limit`][filters::global_rate_limit]
     /// - [`concatenate_bytes`][filters::concatenate_bytes]
     /// - [`load_balancer`][filters::load_balancer]
     /// - [`capture`][filters::capture]
     /// - [`token_router`][filters::token_router]
     /// - [`compress`][filters::compress]
+    /// - [`encrypt`][filters::encrypt]
+    /// - [`pcap`][filters::pcap]
     pub fn default() -> Self {
         Self::default_with(Option::into_iter(None))
     }
@@ -57,11 +60,14 @@ impl FilterSet {
                 filters::ConcatenateBytes::factory(),
                 filters::Debug::factory(),
                 filters::Drop::factory(),
+                filters::Encrypt::factory(),
                 filters::Firewall::factory(),
+                filters::GlobalRateLimit::factory(),
                 filters::LoadBalancer::factory(),
                 filters::LocalRateLimit::factory(),
                 filters::Match::factory(),
                 filters::Pass::factory(),
+                filters::Pcap::factory(),
                 filters::Timestamp::factory(),
                 filters::TokenRouter::factory(),
             ]
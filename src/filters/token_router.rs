@@ -15,19 +15,23 @@
  */
 
 mod metrics;
+mod signed;
 
 crate::include_proto!("quilkin.filters.token_router.v1alpha1");
 
 use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    config::Base64Standard,
     filters::{metadata::CAPTURED_BYTES, prelude::*},
     metadata,
 };
 
 use metrics::Metrics;
+use signed::{Rejection, Verifier};
 
 use self::quilkin::filters::token_router::v1alpha1 as proto;
 
@@ -35,12 +39,68 @@ use self::quilkin::filters::token_router::v1alpha1 as proto;
 /// connection_id to the token stored in the Filter's dynamic metadata.
 pub struct TokenRouter {
     config: Config,
+    verifier: Option<Verifier>,
     metrics: Metrics,
 }
 
 impl TokenRouter {
-    fn new(config: Config, metrics: Metrics) -> Self {
-        Self { config, metrics }
+    fn new(config: Config, metrics: Metrics) -> Result<Self, Error> {
+        let verifier = config
+            .verification
+            .as_ref()
+            .map(Verifier::new)
+            .transpose()?;
+
+        Ok(Self {
+            config,
+            verifier,
+            metrics,
+        })
+    }
+
+    /// Returns whether tokens that aren't validly signed are dropped, rather
+    /// than matched as static tokens.
+    fn require_signed(&self) -> bool {
+        self.config
+            .verification
+            .as_ref()
+            .map_or(false, |verification| verification.require_signed)
+    }
+
+    /// Returns the endpoint token that `token` routes to, which is the token
+    /// itself unless it's a signed token.
+    fn endpoint_token<'token>(&self, token: &'token [u8]) -> Option<&'token [u8]> {
+        let verifier = match &self.verifier {
+            Some(verifier) => verifier,
+            None => return Some(token),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        match verifier.verify(token, now) {
+            Ok(endpoint_token) => Some(endpoint_token),
+            Err(Rejection::Expired) => {
+                tracing::trace!(
+                    token = &*base64::encode(token),
+                    "Dropping packet, signed routing token has expired"
+                );
+                self.metrics.packets_dropped_total_expired_token.inc();
+                None
+            }
+            Err(Rejection::Invalid) if self.require_signed() => {
+                tracing::trace!(
+                    token = &*base64::encode(token),
+                    "Dropping packet, routing token isn't a valid signed token"
+                );
+                self.metrics.packets_dropped_total_invalid_token.inc();
+                None
+            }
+            // Not a signed token, so it's matched as a static token.
+            Err(Rejection::Invalid) => Some(token),
+        }
     }
 }
 
@@ -50,10 +110,13 @@ impl StaticFilter for TokenRouter {
     type BinaryConfiguration = proto::TokenRouter;
 
     fn try_from_config(config: Option<Self::Configuration>) -> Result<Self, Error> {
-        Ok(TokenRouter::new(
-            config.unwrap_or_default(),
-            Metrics::new()?,
-        ))
+        TokenRouter::new(config.unwrap_or_default(), Metrics::new()?)
+    }
+
+    fn validate_config(config: Option<&Self::Configuration>) -> Result<(), Error> {
+        config
+            .and_then(|config| config.verification.as_ref())
+            .map_or(Ok(()), |verification| Verifier::new(verification).map(drop))
     }
 }
 
@@ -70,8 +133,10 @@ impl Filter for TokenRouter {
             }
             Some(value) => match value {
                 metadata::Value::Bytes(token) => {
+                    let endpoint_token = self.endpoint_token(token)?;
+
                     ctx.endpoints.retain(|endpoint| {
-                        if endpoint.metadata.known.tokens.contains(&**token) {
+                        if endpoint.metadata.known.tokens.contains(endpoint_token) {
                             tracing::trace!(%endpoint.address, token = &*base64::encode(token), "Endpoint matched");
                             true
                         } else {
@@ -110,6 +175,11 @@ pub struct Config {
     /// the key to use when retrieving the token from the Filter's dynamic metadata
     #[serde(rename = "metadataKey", default = "default_metadata_key")]
     pub metadata_key: metadata::Key,
+    /// When set, tokens signed by one of the verification keys are routed to
+    /// the endpoint token they carry until they expire. Any other token is
+    /// dropped, unless [`Verification::require_signed`] is disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
 }
 
 /// Default value for [`Config::metadata_key`]
@@ -121,14 +191,67 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             metadata_key: default_metadata_key(),
+            verification: None,
         }
     }
 }
 
+/// The keys that signed tokens are verified with.
+///
+/// A signed token is made of the identifier of the key it was signed with,
+/// the big-endian 64-bit UNIX timestamp in seconds after which it expires,
+/// the token of the endpoint it routes to, and the Ed25519 signature of
+/// everything before it.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Verification {
+    pub keys: Vec<VerificationKey>,
+    /// Whether tokens which aren't signed by one of the keys are dropped.
+    /// When disabled, they're matched against the endpoint tokens as is,
+    /// which also allows a captured signed token to be replayed as a static
+    /// token. Defaults to `true`.
+    #[serde(rename = "requireSigned", default = "default_require_signed")]
+    pub require_signed: bool,
+}
+
+/// Default value for [`Verification::require_signed`]
+fn default_require_signed() -> bool {
+    true
+}
+
+/// A public key that signed tokens are verified with.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VerificationKey {
+    /// The identifier of the key, which is the first byte of every token
+    /// signed with it.
+    pub id: u8,
+    /// The base64 encoded 32 byte Ed25519 public key.
+    #[serde(
+        rename = "publicKey",
+        deserialize_with = "Base64Standard::deserialize",
+        serialize_with = "Base64Standard::serialize"
+    )]
+    pub public_key: Vec<u8>,
+}
+
 impl From<Config> for proto::TokenRouter {
     fn from(config: Config) -> Self {
         Self {
             metadata_key: Some(config.metadata_key.to_string()),
+            verification: config.verification.map(|verification| {
+                proto::token_router::Verification {
+                    keys: verification
+                        .keys
+                        .into_iter()
+                        .map(|key| proto::token_router::VerificationKey {
+                            id: key.id.into(),
+                            public_key: key.public_key,
+                        })
+                        .collect(),
+                    require_signed: Some(verification.require_signed),
+                }
+            }),
         }
     }
 }
@@ -137,11 +260,40 @@ impl TryFrom<proto::TokenRouter> for Config {
     type Error = ConvertProtoConfigError;
 
     fn try_from(p: proto::TokenRouter) -> Result<Self, Self::Error> {
+        let verification = p
+            .verification
+            .map(|verification| {
+                let keys = verification
+                    .keys
+                    .into_iter()
+                    .map(|key| {
+                        Ok(VerificationKey {
+                            id: u8::try_from(key.id).map_err(|error| {
+                                ConvertProtoConfigError::new(
+                                    error,
+                                    Some("verification.keys.id".into()),
+                                )
+                            })?,
+                            public_key: key.public_key,
+                        })
+                    })
+                    .collect::<Result<_, ConvertProtoConfigError>>()?;
+
+                Ok::<_, ConvertProtoConfigError>(Verification {
+                    keys,
+                    require_signed: verification
+                        .require_signed
+                        .unwrap_or_else(default_require_signed),
+                })
+            })
+            .transpose()?;
+
         Ok(Self {
             metadata_key: p
                 .metadata_key
                 .map(metadata::Key::new)
                 .unwrap_or_else(default_metadata_key),
+            verification,
         })
     }
 }
@@ -165,18 +317,67 @@ mod tests {
                 "should succeed when all valid values are provided",
                 proto::TokenRouter {
                     metadata_key: Some("foobar".into()),
+                    verification: Some(proto::token_router::Verification {
+                        keys: vec![proto::token_router::VerificationKey {
+                            id: 1,
+                            public_key: vec![1; 32],
+                        }],
+                        require_signed: Some(false),
+                    }),
                 },
                 Some(Config {
                     metadata_key: "foobar".into(),
+                    verification: Some(Verification {
+                        keys: vec![VerificationKey {
+                            id: 1,
+                            public_key: vec![1; 32],
+                        }],
+                        require_signed: false,
+                    }),
+                }),
+            ),
+            (
+                "should require signed tokens by default",
+                proto::TokenRouter {
+                    metadata_key: None,
+                    verification: Some(proto::token_router::Verification {
+                        keys: vec![],
+                        require_signed: None,
+                    }),
+                },
+                Some(Config {
+                    metadata_key: default_metadata_key(),
+                    verification: Some(Verification {
+                        keys: vec![],
+                        require_signed: true,
+                    }),
                 }),
             ),
             (
                 "should use correct default values",
-                proto::TokenRouter { metadata_key: None },
+                proto::TokenRouter {
+                    metadata_key: None,
+                    verification: None,
+                },
                 Some(Config {
                     metadata_key: default_metadata_key(),
+                    verification: None,
                 }),
             ),
+            (
+                "should fail when a key id is out of range",
+                proto::TokenRouter {
+                    metadata_key: None,
+                    verification: Some(proto::token_router::Verification {
+                        keys: vec![proto::token_router::VerificationKey {
+                            id: 256,
+                            public_key: vec![1; 32],
+                        }],
+                        require_signed: None,
+                    }),
+                },
+                None,
+            ),
         ];
         for (name, proto_config, expected) in test_cases {
             let result = Config::try_from(proto_config);
@@ -197,6 +398,7 @@ mod tests {
         let filter = TokenRouter::from_config(
             Config {
                 metadata_key: TOKEN_KEY.into(),
+                verification: None,
             }
             .into(),
         );
@@ -220,6 +422,7 @@ mod tests {
         // valid key
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            verification: None,
         };
         let filter = TokenRouter::from_config(config.into());
 
@@ -252,10 +455,99 @@ mod tests {
         assert_eq!(1, filter.metrics.packets_dropped_total_invalid_token.get());
     }
 
+    #[test]
+    fn signed_tokens() {
+        let config = Config {
+            metadata_key: CAPTURED_BYTES.into(),
+            verification: Some(Verification {
+                keys: vec![signed::tests::verification_key(1)],
+                require_signed: true,
+            }),
+        };
+        let filter = TokenRouter::from_config(config.into());
+
+        // valid signed token
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            CAPTURED_BYTES.into(),
+            Value::Bytes(signed::tests::sign(1, u64::MAX, b"123").into()),
+        );
+        filter.read(&mut ctx).unwrap();
+        assert_eq!(1, ctx.endpoints.len());
+        assert_eq!("127.0.0.1:80", ctx.endpoints[0].address.to_string());
+
+        // static tokens are dropped, so that the endpoint token carried by a
+        // signed token can't be replayed on its own
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(CAPTURED_BYTES.into(), Value::Bytes(b"123".to_vec().into()));
+        assert!(filter.read(&mut ctx).is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_total_invalid_token.get());
+
+        // expired signed token
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            CAPTURED_BYTES.into(),
+            Value::Bytes(signed::tests::sign(1, 0, b"123").into()),
+        );
+        assert!(filter.read(&mut ctx).is_none());
+        assert_eq!(1, filter.metrics.packets_dropped_total_expired_token.get());
+
+        // token signed by an unknown key
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            CAPTURED_BYTES.into(),
+            Value::Bytes(signed::tests::sign(2, u64::MAX, b"123").into()),
+        );
+        assert!(filter.read(&mut ctx).is_none());
+        assert_eq!(2, filter.metrics.packets_dropped_total_invalid_token.get());
+    }
+
+    #[test]
+    fn signed_tokens_not_required() {
+        let config = Config {
+            metadata_key: CAPTURED_BYTES.into(),
+            verification: Some(Verification {
+                keys: vec![signed::tests::verification_key(1)],
+                require_signed: false,
+            }),
+        };
+        let filter = TokenRouter::from_config(config.into());
+
+        // valid signed token
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            CAPTURED_BYTES.into(),
+            Value::Bytes(signed::tests::sign(1, u64::MAX, b"123").into()),
+        );
+        filter.read(&mut ctx).unwrap();
+        assert_eq!("127.0.0.1:80", ctx.endpoints[0].address.to_string());
+
+        // static tokens still match
+        let mut ctx = new_ctx();
+        ctx.metadata
+            .insert(CAPTURED_BYTES.into(), Value::Bytes(b"456".to_vec().into()));
+        filter.read(&mut ctx).unwrap();
+        assert_eq!(1, ctx.endpoints.len());
+
+        // token signed by an unknown key
+        let mut ctx = new_ctx();
+        ctx.metadata.insert(
+            CAPTURED_BYTES.into(),
+            Value::Bytes(signed::tests::sign(2, u64::MAX, b"123").into()),
+        );
+        assert!(filter.read(&mut ctx).is_none());
+        assert_eq!(
+            1,
+            filter.metrics.packets_dropped_total_no_endpoint_match.get()
+        );
+    }
+
     #[test]
     fn write() {
         let config = Config {
             metadata_key: CAPTURED_BYTES.into(),
+            verification: None,
         };
         let filter = TokenRouter::from_config(config.into());
         assert_write_no_change(&filter);
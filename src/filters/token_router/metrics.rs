@@ -25,6 +25,7 @@ pub(super) struct Metrics {
     pub(super) packets_dropped_total_no_token_found: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_total_invalid_token: GenericCounter<AtomicU64>,
     pub(super) packets_dropped_total_no_endpoint_match: GenericCounter<AtomicU64>,
+    pub(super) packets_dropped_total_expired_token: GenericCounter<AtomicU64>,
 }
 
 impl Metrics {
@@ -47,6 +48,8 @@ impl Metrics {
                 .get_metric_with_label_values(vec!["InvalidToken"].as_slice())?,
             packets_dropped_total_no_endpoint_match: metric
                 .get_metric_with_label_values(vec!["NoEndpointMatch"].as_slice())?,
+            packets_dropped_total_expired_token: metric
+                .get_metric_with_label_values(vec!["ExpiredToken"].as_slice())?,
         })
     }
 }
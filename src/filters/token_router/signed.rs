@@ -0,0 +1,258 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use dashmap::DashMap;
+use ring::signature::{UnparsedPublicKey, ED25519, ED25519_PUBLIC_KEY_LEN};
+
+use super::Verification;
+use crate::filters::Error;
+
+/// The length of the key identifier and expiry time at the start of a token.
+const HEADER_LEN: usize = 1 + 8;
+
+/// The length of the Ed25519 signature at the end of a token.
+const SIGNATURE_LEN: usize = 64;
+
+/// The maximum number of verified tokens that are remembered, so that their
+/// signatures aren't verified again on every packet.
+const MAX_VERIFIED_TOKENS: usize = 10_000;
+
+/// Why a signed token was rejected.
+#[derive(Debug, Eq, PartialEq)]
+pub(super) enum Rejection {
+    /// The token is malformed, or wasn't signed by any of the keys.
+    Invalid,
+    /// The token was signed by one of the keys, but has expired.
+    Expired,
+}
+
+/// Verifies signed tokens, which are made of the identifier of the key they
+/// were signed with, the big-endian UNIX timestamp in seconds at which they
+/// expire, the token of the endpoint they route to, and the Ed25519 signature
+/// of everything before it.
+pub(super) struct Verifier {
+    keys: Vec<(u8, UnparsedPublicKey<Vec<u8>>)>,
+    /// The tokens whose signatures have been verified, and when they expire.
+    verified: DashMap<Vec<u8>, u64>,
+}
+
+impl Verifier {
+    pub(super) fn new(verification: &Verification) -> Result<Self, Error> {
+        let mut ids = HashSet::new();
+        let keys = verification
+            .keys
+            .iter()
+            .map(|key| {
+                if !ids.insert(key.id) {
+                    return Err(Error::FieldInvalid {
+                        field: "verification.keys.id".into(),
+                        reason: format!("key id {} is used by more than one key", key.id),
+                    });
+                }
+
+                if key.public_key.len() != ED25519_PUBLIC_KEY_LEN {
+                    return Err(Error::FieldInvalid {
+                        field: "verification.keys.public_key".into(),
+                        reason: format!("public key must be {ED25519_PUBLIC_KEY_LEN} bytes"),
+                    });
+                }
+
+                Ok((
+                    key.id,
+                    UnparsedPublicKey::new(&ED25519, key.public_key.clone()),
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            keys,
+            verified: <_>::default(),
+        })
+    }
+
+    /// Returns the endpoint token of `token`, if it was signed by one of the
+    /// keys and hasn't expired at `now`.
+    pub(super) fn verify<'token>(
+        &self,
+        token: &'token [u8],
+        now: u64,
+    ) -> Result<&'token [u8], Rejection> {
+        let signed_len = token
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .filter(|len| *len >= HEADER_LEN)
+            .ok_or(Rejection::Invalid)?;
+        let (signed, signature) = token.split_at(signed_len);
+        // The length is checked above, so this can't fail.
+        let expires_at = u64::from_be_bytes(signed[1..HEADER_LEN].try_into().unwrap());
+
+        if !self.verified.contains_key(token) {
+            let (_, key) = self
+                .keys
+                .iter()
+                .find(|(id, _)| *id == signed[0])
+                .ok_or(Rejection::Invalid)?;
+            key.verify(signed, signature)
+                .map_err(|_| Rejection::Invalid)?;
+
+            if now <= expires_at {
+                self.remember(token, expires_at, now);
+            }
+        }
+
+        if now > expires_at {
+            self.verified.remove(token);
+            return Err(Rejection::Expired);
+        }
+
+        Ok(&signed[HEADER_LEN..])
+    }
+
+    /// Remembers that `token` has been verified until it expires, making
+    /// room by forgetting expired tokens when too many are remembered.
+    fn remember(&self, token: &[u8], expires_at: u64, now: u64) {
+        if self.verified.len() >= MAX_VERIFIED_TOKENS {
+            self.verified.retain(|_, expires_at| now <= *expires_at);
+        }
+
+        // Tokens which don't fit are verified again on every packet.
+        if self.verified.len() < MAX_VERIFIED_TOKENS {
+            self.verified.insert(token.to_vec(), expires_at);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+    use crate::filters::token_router::VerificationKey;
+
+    /// Returns the key pair with identifier `id`.
+    pub(in crate::filters::token_router) fn key_pair(id: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[id; 32]).unwrap()
+    }
+
+    pub(in crate::filters::token_router) fn verification_key(id: u8) -> VerificationKey {
+        VerificationKey {
+            id,
+            public_key: key_pair(id).public_key().as_ref().to_vec(),
+        }
+    }
+
+    /// Signs a token for `endpoint_token`, with the key pair with identifier `id`.
+    pub(in crate::filters::token_router) fn sign(
+        id: u8,
+        expires_at: u64,
+        endpoint_token: &[u8],
+    ) -> Vec<u8> {
+        let mut token = vec![id];
+        token.extend_from_slice(&expires_at.to_be_bytes());
+        token.extend_from_slice(endpoint_token);
+        let signature = key_pair(id).sign(&token);
+        token.extend_from_slice(signature.as_ref());
+        token
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::new(&Verification {
+            keys: vec![verification_key(1), verification_key(2)],
+            require_signed: true,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn verify() {
+        let verifier = verifier();
+
+        assert_eq!(Ok(&b"abc"[..]), verifier.verify(&sign(1, 100, b"abc"), 100));
+        assert_eq!(Ok(&b"abc"[..]), verifier.verify(&sign(2, 100, b"abc"), 50));
+        assert_eq!(Ok(&b""[..]), verifier.verify(&sign(1, 100, b""), 50));
+        assert_eq!(
+            Err(Rejection::Expired),
+            verifier.verify(&sign(1, 100, b"abc"), 101)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let verifier = verifier();
+        let token = sign(1, 100, b"abc");
+
+        for index in 0..token.len() {
+            let mut tampered = token.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                Err(Rejection::Invalid),
+                verifier.verify(&tampered, 50),
+                "{index}"
+            );
+        }
+
+        assert_eq!(
+            Err(Rejection::Invalid),
+            verifier.verify(&sign(3, 100, b"abc"), 50)
+        );
+        assert_eq!(Err(Rejection::Invalid), verifier.verify(&token[1..], 50));
+        assert_eq!(Err(Rejection::Invalid), verifier.verify(b"abc", 50));
+    }
+
+    #[test]
+    fn remembers_verified_tokens() {
+        let verifier = verifier();
+        let token = sign(1, 100, b"abc");
+
+        assert_eq!(Ok(&b"abc"[..]), verifier.verify(&token, 50));
+        assert!(verifier.verified.contains_key(&token));
+        assert_eq!(Ok(&b"abc"[..]), verifier.verify(&token, 100));
+
+        // Remembered tokens still expire, and are then forgotten.
+        assert_eq!(Err(Rejection::Expired), verifier.verify(&token, 101));
+        assert!(verifier.verified.is_empty());
+
+        // Expired tokens make room for new ones once the limit is reached.
+        for expires_at in 0..MAX_VERIFIED_TOKENS as u64 {
+            verifier
+                .verified
+                .insert(expires_at.to_be_bytes().to_vec(), expires_at);
+        }
+        assert_eq!(Ok(&b"abc"[..]), verifier.verify(&token, 50));
+        assert_eq!(MAX_VERIFIED_TOKENS - 49, verifier.verified.len());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(Verifier::new(&Verification {
+            keys: vec![verification_key(1), verification_key(1)],
+            require_signed: true,
+        })
+        .is_err());
+
+        assert!(Verifier::new(&Verification {
+            keys: vec![VerificationKey {
+                id: 1,
+                public_key: vec![1; 16],
+            }],
+            require_signed: true,
+        })
+        .is_err());
+    }
+}
@@ -19,6 +19,7 @@ use std::collections::HashMap;
 use crate::{
     endpoint::{Endpoint, EndpointAddress},
     metadata::DynamicMetadata,
+    pool::PacketBuffer,
 };
 
 #[cfg(doc)]
@@ -34,7 +35,7 @@ pub struct WriteContext {
     /// The destination of the received packet.
     pub dest: EndpointAddress,
     /// Contents of the received packet.
-    pub contents: Vec<u8>,
+    pub contents: PacketBuffer,
     /// Arbitrary values that can be passed from one filter to another
     pub metadata: DynamicMetadata,
 }
@@ -45,13 +46,13 @@ impl WriteContext {
         endpoint: Endpoint,
         source: EndpointAddress,
         dest: EndpointAddress,
-        contents: Vec<u8>,
+        contents: impl Into<PacketBuffer>,
     ) -> Self {
         Self {
             endpoint,
             source,
             dest,
-            contents,
+            contents: contents.into(),
             metadata: HashMap::new(),
         }
     }
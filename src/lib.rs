@@ -29,7 +29,11 @@ pub mod cli;
 pub mod config;
 pub mod endpoint;
 pub mod filters;
+pub mod logging;
 pub mod metadata;
+pub mod pcap;
+pub mod pool;
+pub mod telemetry;
 pub mod xds;
 
 #[doc(hidden)]
@@ -54,10 +58,12 @@ mod external_doc_tests {
     #![doc = include_str!("../docs/src/services/proxy/filters/compress.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/concatenate_bytes.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/debug.md")]
+    #![doc = include_str!("../docs/src/services/proxy/filters/encrypt.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/firewall.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/load_balancer.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/local_rate_limit.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/match.md")]
+    #![doc = include_str!("../docs/src/services/proxy/filters/pcap.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/timestamp.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/token_router.md")]
     #![doc = include_str!("../docs/src/services/proxy/filters/writing_custom_filters.md")]
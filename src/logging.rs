@@ -0,0 +1,338 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Installs the global `tracing` subscriber, and allows its filter to be
+//! changed at runtime through the admin server.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::writer::BoxMakeWriter,
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+/// The environment variable the initial filter is read from.
+const ENV_VAR: &str = "RUST_LOG";
+
+type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+type FilteredRegistry = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type ExporterHandle = reload::Handle<Option<BoxedLayer<FilteredRegistry>>, FilteredRegistry>;
+type ExportingRegistry = Layered<
+    reload::Layer<Option<BoxedLayer<FilteredRegistry>>, FilteredRegistry>,
+    FilteredRegistry,
+>;
+
+/// The filter of the global subscriber, if one has been installed.
+static FILTER: OnceCell<Arc<LogFilter>> = OnceCell::new();
+/// The span exporter of the global subscriber, which is empty until a tracer
+/// is set, as the tracer's resource depends on the configuration.
+static EXPORTER: OnceCell<ExporterHandle> = OnceCell::new();
+
+/// The format that log events are written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One JSON object per event.
+    Json,
+    /// Multi-line, human readable events.
+    Pretty,
+    /// Single line, human readable events.
+    Compact,
+}
+
+/// Where log events are written to, either `stdout`, `stderr`, or the path of
+/// a file that events are appended to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+impl std::str::FromStr for LogOutput {
+    type Err = std::convert::Infallible;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(match input {
+            "stdout" => Self::Stdout,
+            "stderr" => Self::Stderr,
+            path => Self::File(path.into()),
+        })
+    }
+}
+
+impl LogOutput {
+    fn make_writer(&self) -> std::io::Result<BoxMakeWriter> {
+        Ok(match self {
+            Self::Stdout => BoxMakeWriter::new(std::io::stdout),
+            Self::Stderr => BoxMakeWriter::new(std::io::stderr),
+            Self::File(path) => BoxMakeWriter::new(std::sync::Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )),
+        })
+    }
+}
+
+/// Installs the global subscriber, with its filter read from `RUST_LOG`,
+/// logging `INFO` events by default. Events are written to `output`, or not
+/// at all if it's `None`, in which case spans are only exported once a tracer
+/// is set with [`set_tracer`].
+pub(crate) fn init(format: LogFormat, output: Option<&LogOutput>) -> crate::Result<()> {
+    let directives = std::env::var(ENV_VAR).unwrap_or_default();
+    let (filter, handle) = reload::Layer::new(parse_lossy(&directives));
+    let (exporter, exporter_handle) = reload::Layer::new(None);
+
+    let layer: Option<BoxedLayer<ExportingRegistry>> = match output {
+        Some(output) => {
+            let writer = output.make_writer()?;
+            Some(match format {
+                LogFormat::Json => tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_file(true)
+                    .with_writer(writer)
+                    .boxed(),
+                LogFormat::Pretty => tracing_subscriber::fmt::layer()
+                    .pretty()
+                    .with_writer(writer)
+                    .boxed(),
+                LogFormat::Compact => tracing_subscriber::fmt::layer()
+                    .compact()
+                    .with_writer(writer)
+                    .boxed(),
+            })
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(exporter)
+        .with(layer)
+        .try_init()?;
+
+    FILTER
+        .set(Arc::new(LogFilter::new(handle, directives)))
+        .map_err(|_| eyre::eyre!("the log filter has already been set"))?;
+    EXPORTER
+        .set(exporter_handle)
+        .map_err(|_| eyre::eyre!("the span exporter has already been set"))?;
+
+    Ok(())
+}
+
+/// Exports the spans of the global subscriber with `tracer`, which are
+/// filtered by the same filter as log events.
+pub(crate) fn set_tracer(tracer: opentelemetry_sdk::trace::Tracer) -> crate::Result<()> {
+    let handle = EXPORTER
+        .get()
+        .ok_or_else(|| eyre::eyre!("the global subscriber hasn't been installed"))?;
+    handle.reload(Some(
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+    ))?;
+    Ok(())
+}
+
+/// Returns the filter of the global subscriber, or `None` if logging is
+/// disabled.
+pub(crate) fn filter() -> Option<&'static Arc<LogFilter>> {
+    FILTER.get()
+}
+
+fn parse_lossy(directives: &str) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(directives)
+}
+
+/// A point in time snapshot of the log filter, as exposed by the admin server.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct FilterStatus {
+    /// The directives of the current filter.
+    pub filter: String,
+    /// The directives of the filter the proxy was started with.
+    pub default: String,
+    /// When the current filter will be reverted to the default, in seconds
+    /// since the UNIX epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revert_at: Option<u64>,
+}
+
+/// The reloadable filter of a subscriber.
+pub(crate) struct LogFilter {
+    handle: FilterHandle,
+    default: String,
+    /// When the current filter will be reverted, if it will be.
+    revert_at: Mutex<Option<u64>>,
+    /// Incremented every time the filter changes, so that a pending revert
+    /// doesn't undo a newer change.
+    generation: AtomicU64,
+}
+
+impl LogFilter {
+    fn new(handle: FilterHandle, default: String) -> Self {
+        Self {
+            handle,
+            default,
+            revert_at: Mutex::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn status(&self) -> FilterStatus {
+        FilterStatus {
+            filter: self
+                .handle
+                .with_current(ToString::to_string)
+                .unwrap_or_default(),
+            default: self.default.clone(),
+            revert_at: *self.revert_at.lock(),
+        }
+    }
+
+    /// Replaces the filter with `directives`, reverting back to the default
+    /// filter after `revert_after` if it's set. Unlike the filter read from
+    /// the environment, invalid directives are rejected.
+    pub(crate) fn set(
+        self: &Arc<Self>,
+        directives: &str,
+        revert_after: Option<Duration>,
+    ) -> crate::Result<()> {
+        let filter = EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .parse(directives)?;
+
+        let generation = self.replace(filter)?;
+        tracing::info!(filter = directives, ?revert_after, "Log filter changed");
+
+        if let Some(revert_after) = revert_after {
+            *self.revert_at.lock() = Some(
+                (SystemTime::now() + revert_after)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            );
+
+            let this = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+                if this.generation.load(Ordering::SeqCst) == generation {
+                    if let Err(error) = this.reset() {
+                        tracing::warn!(%error, "Failed to revert log filter");
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reverts the filter back to the one the proxy was started with.
+    pub(crate) fn reset(&self) -> crate::Result<()> {
+        self.replace(parse_lossy(&self.default))?;
+        tracing::info!(filter = %self.default, "Log filter reverted");
+        Ok(())
+    }
+
+    fn replace(&self, filter: EnvFilter) -> crate::Result<u64> {
+        self.handle.reload(filter)?;
+        *self.revert_at.lock() = None;
+        Ok(self.generation.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    /// Creates a filter that isn't installed globally, along with the
+    /// subscriber it belongs to, which has to be kept alive while the filter
+    /// is used.
+    #[cfg(test)]
+    pub(crate) fn test(default: &str) -> (Arc<Self>, impl tracing::Subscriber) {
+        let (filter, handle) = reload::Layer::new(parse_lossy(default));
+        let subscriber = tracing_subscriber::registry().with(filter);
+        (Arc::new(Self::new(handle, default.into())), subscriber)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output() {
+        assert_eq!(LogOutput::Stdout, "stdout".parse().unwrap());
+        assert_eq!(LogOutput::Stderr, "stderr".parse().unwrap());
+        assert_eq!(
+            LogOutput::File("/var/log/quilkin.log".into()),
+            "/var/log/quilkin.log".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn set_and_reset() {
+        let (filter, _subscriber) = LogFilter::test("quilkin=debug");
+        let default = filter.status();
+        assert_eq!("quilkin=debug", default.default);
+        assert_eq!(None, default.revert_at);
+
+        filter.set("quilkin::proxy=trace", None).unwrap();
+        let status = filter.status();
+        assert_ne!(default.filter, status.filter);
+        assert!(status.filter.contains("quilkin::proxy=trace"));
+        assert_eq!(None, status.revert_at);
+
+        assert!(filter.set("quilkin=not_a_level", None).is_err());
+        assert_eq!(status, filter.status());
+
+        filter.reset().unwrap();
+        assert_eq!(default, filter.status());
+    }
+
+    #[tokio::test]
+    async fn revert() {
+        tokio::time::pause();
+        let (filter, _subscriber) = LogFilter::test("quilkin=debug");
+        let default = filter.status();
+
+        filter
+            .set("quilkin=trace", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(filter.status().revert_at.is_some());
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(default, filter.status());
+
+        // A newer change isn't reverted by an older timeout.
+        filter
+            .set("quilkin=trace", Some(Duration::from_secs(60)))
+            .unwrap();
+        filter.set("quilkin=warn", None).unwrap();
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert!(filter.status().filter.contains("quilkin=warn"));
+    }
+}
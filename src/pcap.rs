@@ -0,0 +1,682 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Packet captures of the traffic going through the proxy, written as
+//! pcapng files, and read back from pcap or pcapng files.
+
+mod pcapng;
+pub(crate) mod read;
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwapOption;
+use ipnetwork::IpNetwork;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoint::{AddressKind, EndpointAddress};
+
+/// The number of packets waiting to be written to a capture, after which
+/// packets are dropped from the capture rather than slowing down the proxy.
+const QUEUE_CAPACITY: usize = 8192;
+
+/// The capture started through the admin server, if any.
+static TAP: ArcSwapOption<Tap> = ArcSwapOption::const_empty();
+
+/// The directory that the `Pcap` filter writes its captures to.
+static CAPTURE_DIR: ArcSwapOption<PathBuf> = ArcSwapOption::const_empty();
+
+/// Sets the directory that the `Pcap` filter writes its captures to. Filters
+/// created while it isn't set don't capture anything.
+pub fn set_capture_dir(directory: Option<PathBuf>) {
+    CAPTURE_DIR.store(directory.map(Arc::new));
+}
+
+/// Returns the directory that the `Pcap` filter writes its captures to.
+pub(crate) fn capture_dir() -> Option<Arc<PathBuf>> {
+    CAPTURE_DIR.load_full()
+}
+
+/// Returns `path` in `directory`, `path` must be a file name so that captures
+/// can't be written anywhere else.
+pub(crate) fn in_directory(path: &Path, directory: &Path) -> io::Result<PathBuf> {
+    let mut components = path.components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "`path` must be a file name, without any directories",
+        ));
+    }
+
+    Ok(directory.join(path))
+}
+
+/// Starts capturing the proxy's traffic with `config`, replacing the current
+/// capture if there is one. The config's `path` must be a file name, which is
+/// written to in `directory`.
+pub(crate) fn start(mut config: Config, directory: &Path) -> io::Result<()> {
+    config.path = in_directory(&config.path, directory)?;
+    TAP.store(Some(Arc::new(Tap::new(config)?)));
+    Ok(())
+}
+
+/// Stops the current capture, returning its final status.
+pub(crate) fn stop() -> Option<Status> {
+    TAP.swap(None).map(|tap| tap.stop())
+}
+
+/// Returns the status of the current capture.
+pub(crate) fn status() -> Option<Status> {
+    TAP.load().as_ref().map(|tap| tap.status())
+}
+
+/// Records a packet with the current capture, if there is one.
+pub(crate) fn record(
+    stage: Stage,
+    client: &EndpointAddress,
+    endpoint: Option<&EndpointAddress>,
+    contents: &[u8],
+) {
+    if let Some(tap) = &*TAP.load() {
+        tap.record(stage, client, endpoint, contents);
+    }
+}
+
+/// Where a packet was seen in the proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Received from a client, before the filter chain.
+    DownstreamReceive,
+    /// Sent to an endpoint, after the filter chain.
+    UpstreamSend,
+    /// Received from an endpoint, before the filter chain.
+    UpstreamReceive,
+    /// Sent to a client, after the filter chain.
+    DownstreamSend,
+    /// Read by a filter, at its position in the filter chain.
+    FilterRead,
+    /// Written by a filter, at its position in the filter chain.
+    FilterWrite,
+}
+
+impl Stage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::DownstreamReceive => "downstream receive",
+            Self::UpstreamSend => "upstream send",
+            Self::UpstreamReceive => "upstream receive",
+            Self::DownstreamSend => "downstream send",
+            Self::FilterRead => "filter read",
+            Self::FilterWrite => "filter write",
+        }
+    }
+
+    /// Whether the packet is travelling from the client to the endpoint.
+    fn is_from_client(&self) -> bool {
+        matches!(
+            self,
+            Self::DownstreamReceive | Self::UpstreamSend | Self::FilterRead
+        )
+    }
+}
+
+/// default value for [`Config::max_file_size`]
+fn default_max_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// default value for [`Config::max_files`]
+fn default_max_files() -> u32 {
+    4
+}
+
+/// A capture's configuration.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The file name of the capture files, which are written to in the proxy's
+    /// `--capture-dir`. Files are numbered, so `capture.pcapng` is written to
+    /// `capture-00000.pcapng`, `capture-00001.pcapng`, etc, skipping files
+    /// that already exist.
+    pub path: PathBuf,
+    /// Only capture packets to or from clients in these networks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<String>")]
+    pub sources: Vec<IpNetwork>,
+    /// Only capture packets to or from these endpoints. Packets received from
+    /// clients aren't routed to an endpoint until they've gone through the
+    /// filter chain, so they're only captured once they're sent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(with = "Vec<String>")]
+    pub endpoints: Vec<EndpointAddress>,
+    /// Only capture packets in these sessions, with the same caveat as
+    /// `endpoints`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<SessionSelector>,
+    /// The size in bytes after which a new file is started.
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// The number of seconds after which a new file is started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_duration: Option<u64>,
+    /// The number of files that are kept, the oldest file being removed once
+    /// a new file is started.
+    #[serde(default = "default_max_files")]
+    pub max_files: u32,
+    /// The number of seconds after which the capture stops.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration: Option<u64>,
+}
+
+impl Config {
+    fn matches(&self, client: &EndpointAddress, endpoint: Option<&EndpointAddress>) -> bool {
+        let source_matches = self.sources.is_empty()
+            || matches!(client.host, AddressKind::Ip(ip) if self.sources.iter().any(|network| network.contains(ip)));
+
+        let endpoint_matches = self.endpoints.is_empty()
+            || endpoint.map_or(false, |endpoint| self.endpoints.contains(endpoint));
+
+        let session_matches = self.sessions.is_empty()
+            || endpoint.map_or(false, |endpoint| {
+                self.sessions
+                    .iter()
+                    .any(|session| session.source == *client && session.dest == *endpoint)
+            });
+
+        source_matches && endpoint_matches && session_matches
+    }
+
+    /// Returns the path of the file numbered `index`.
+    fn file_path(&self, index: u64) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let extension = self
+            .path
+            .extension()
+            .map_or("pcapng".into(), |extension| extension.to_string_lossy());
+
+        self.path
+            .with_file_name(format!("{stem}-{index:05}.{extension}"))
+    }
+}
+
+/// Selects the session between a client and an endpoint.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SessionSelector {
+    /// The address of the client.
+    #[schemars(with = "String")]
+    pub source: EndpointAddress,
+    /// The address of the endpoint.
+    #[schemars(with = "String")]
+    pub dest: EndpointAddress,
+}
+
+/// A point in time snapshot of a capture, as exposed by the admin server.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub config: Config,
+    /// Whether packets are still being captured.
+    pub active: bool,
+    /// The files that have been written and haven't been removed yet.
+    pub files: Vec<PathBuf>,
+    pub packets: u64,
+    pub bytes: u64,
+    /// The packets that weren't captured, because they were received faster
+    /// than they could be written.
+    pub dropped: u64,
+}
+
+/// Writes the packets that match its [`Config`] to a ring of pcapng files.
+/// Packets are written by a dedicated thread, so that recording a packet
+/// never waits on the file system.
+pub struct Tap {
+    config: Config,
+    /// The queue of packets to write, or `None` once the capture has stopped.
+    packets: ArcSwapOption<SyncSender<Packet>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    state: Arc<Mutex<State>>,
+    dropped: AtomicU64,
+}
+
+/// A packet waiting to be written to the capture.
+struct Packet {
+    stage: Stage,
+    source: SocketAddr,
+    dest: SocketAddr,
+    contents: Vec<u8>,
+}
+
+struct State {
+    /// The current file, or `None` once the capture has stopped.
+    writer: Option<Writer>,
+    files: VecDeque<PathBuf>,
+    next_index: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl Tap {
+    /// Creates a capture, opening its first file, and starts the thread
+    /// writing its packets.
+    pub fn new(config: Config) -> io::Result<Self> {
+        if config.path.file_name().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`path` must be the path of a file",
+            ));
+        }
+
+        if config.max_files == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`max_files` must be at least 1",
+            ));
+        }
+
+        let mut state = State {
+            writer: None,
+            files: VecDeque::new(),
+            next_index: 0,
+            packets: 0,
+            bytes: 0,
+        };
+        state.rotate(&config)?;
+        let state = Arc::new(Mutex::new(state));
+
+        let (packets, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = std::thread::Builder::new().name("pcap".into()).spawn({
+            let config = config.clone();
+            let state = state.clone();
+            move || write_packets(&config, &state, &receiver)
+        })?;
+
+        Ok(Self {
+            config,
+            packets: ArcSwapOption::new(Some(Arc::new(packets))),
+            writer: Mutex::new(Some(writer)),
+            state,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Records the packet sent between `client` and `endpoint` at `stage`,
+    /// if it matches the capture's filters. `endpoint` is `None` when the
+    /// packet hasn't been routed yet.
+    pub fn record(
+        &self,
+        stage: Stage,
+        client: &EndpointAddress,
+        endpoint: Option<&EndpointAddress>,
+        contents: &[u8],
+    ) {
+        if !self.config.matches(client, endpoint) {
+            return;
+        }
+
+        let packets = self.packets.load();
+        let packets = match &*packets {
+            Some(packets) => packets,
+            None => return,
+        };
+
+        let client = to_socket_addr(Some(client));
+        let endpoint = to_socket_addr(endpoint);
+        let (source, dest) = if stage.is_from_client() {
+            (client, endpoint)
+        } else {
+            (endpoint, client)
+        };
+
+        match packets.try_send(Packet {
+            stage,
+            source,
+            dest,
+            contents: contents.to_vec(),
+        }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The writer has stopped by itself.
+            Err(TrySendError::Disconnected(_)) => self.packets.store(None),
+        }
+    }
+
+    /// Waits for the packets that have been recorded to be written, then
+    /// flushes and closes the current file, returning the final status.
+    pub fn stop(&self) -> Status {
+        self.packets.store(None);
+        if let Some(writer) = self.writer.lock().take() {
+            if writer.join().is_err() {
+                tracing::warn!(path = %self.config.path.display(), "Packet capture writer panicked");
+            }
+        }
+
+        self.status()
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock();
+        Status {
+            config: self.config.clone(),
+            active: state.writer.is_some(),
+            files: state.files.iter().cloned().collect(),
+            packets: state.packets,
+            bytes: state.bytes,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Writes the packets received from `packets` until the capture is stopped,
+/// fails to write, or reaches its maximum duration.
+fn write_packets(config: &Config, state: &Mutex<State>, packets: &mpsc::Receiver<Packet>) {
+    let deadline = config
+        .max_duration
+        .map(|max| Instant::now() + Duration::from_secs(max));
+
+    loop {
+        let packet = match deadline {
+            Some(deadline) => {
+                match packets.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(packet) => packet,
+                    Err(RecvTimeoutError::Timeout) => {
+                        tracing::info!(path = %config.path.display(), "Packet capture reached its maximum duration");
+                        break;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match packets.recv() {
+                Ok(packet) => packet,
+                Err(_) => break,
+            },
+        };
+
+        if let Err(error) = state.lock().write(config, &packet) {
+            tracing::warn!(%error, path = %config.path.display(), "Stopping packet capture, failed to write packet");
+            break;
+        }
+    }
+
+    state.lock().close();
+}
+
+impl State {
+    fn write(&mut self, config: &Config, packet: &Packet) -> io::Result<()> {
+        let needs_rotation = self.writer.as_ref().map_or(true, |writer| {
+            writer.size >= config.max_file_size
+                || config.max_file_duration.map_or(false, |max| {
+                    writer.opened_at.elapsed() >= Duration::from_secs(max)
+                })
+        });
+
+        if needs_rotation {
+            self.rotate(config)?;
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        let written = pcapng::write_packet(
+            &mut writer.file,
+            packet.source,
+            packet.dest,
+            &packet.contents,
+            packet.stage.as_str(),
+        )?;
+        writer.size += written as u64;
+        self.packets += 1;
+        self.bytes += packet.contents.len() as u64;
+        Ok(())
+    }
+
+    /// Starts a new file, removing the oldest files beyond the limit. Existing
+    /// files are skipped rather than overwritten, as they can be from an
+    /// earlier capture with the same path that's still being written to,
+    /// such as when a filter chain with a `Pcap` filter is updated.
+    fn rotate(&mut self, config: &Config) -> io::Result<()> {
+        self.close();
+
+        let (path, file) = loop {
+            let path = config.file_path(self.next_index);
+            self.next_index += 1;
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        };
+
+        let mut file = BufWriter::new(file);
+        let size = pcapng::write_header(&mut file)? as u64;
+        self.writer = Some(Writer {
+            file,
+            size,
+            opened_at: Instant::now(),
+        });
+        self.files.push_back(path);
+
+        while self.files.len() > config.max_files as usize {
+            if let Some(path) = self.files.pop_front() {
+                if let Err(error) = std::fs::remove_file(&path) {
+                    tracing::warn!(%error, path = %path.display(), "Failed to remove old packet capture");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(error) = writer.file.flush() {
+                tracing::warn!(%error, "Failed to flush packet capture");
+            }
+        }
+    }
+}
+
+/// Returns the socket address written to the capture for `address`. Names
+/// and unknown addresses are written as the unspecified address.
+fn to_socket_addr(address: Option<&EndpointAddress>) -> SocketAddr {
+    match address {
+        Some(EndpointAddress {
+            host: AddressKind::Ip(ip),
+            port,
+        }) => (*ip, port.unwrap_or_default()).into(),
+        Some(address) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), address.port()).into(),
+        None => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path: PathBuf) -> Config {
+        serde_json::from_value(serde_json::json!({ "path": path })).unwrap()
+    }
+
+    #[test]
+    fn matches() {
+        let client: EndpointAddress = "10.0.0.1:7000".parse().unwrap();
+        let endpoint: EndpointAddress = "127.0.0.1:8000".parse().unwrap();
+        let other: EndpointAddress = "127.0.0.1:9000".parse().unwrap();
+
+        let mut config = config("capture.pcapng".into());
+        assert!(config.matches(&client, None));
+
+        config.sources = vec!["10.0.0.0/8".parse().unwrap()];
+        assert!(config.matches(&client, None));
+        assert!(!config.matches(&"192.168.0.1:7000".parse().unwrap(), None));
+
+        config.endpoints = vec![endpoint.clone()];
+        assert!(!config.matches(&client, None));
+        assert!(config.matches(&client, Some(&endpoint)));
+        assert!(!config.matches(&client, Some(&other)));
+
+        config.endpoints.clear();
+        config.sessions = vec![SessionSelector {
+            source: client.clone(),
+            dest: other.clone(),
+        }];
+        assert!(!config.matches(&client, Some(&endpoint)));
+        assert!(config.matches(&client, Some(&other)));
+    }
+
+    #[test]
+    fn file_path() {
+        let config = config("/tmp/capture.pcapng".into());
+        assert_eq!(
+            PathBuf::from("/tmp/capture-00001.pcapng"),
+            config.file_path(1)
+        );
+
+        let config = self::config("/tmp/capture".into());
+        assert_eq!(
+            PathBuf::from("/tmp/capture-00010.pcapng"),
+            config.file_path(10)
+        );
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        let mut config = config(dir.path().join("capture.pcapng"));
+        config.max_file_size = 100;
+        config.max_files = 2;
+
+        let tap = Tap::new(config).unwrap();
+        let client: EndpointAddress = "127.0.0.1:7000".parse().unwrap();
+        let endpoint: EndpointAddress = "127.0.0.1:8000".parse().unwrap();
+        for _ in 0..4 {
+            tap.record(Stage::UpstreamSend, &client, Some(&endpoint), &[0; 64]);
+        }
+
+        let status = tap.stop();
+        assert!(!status.active);
+        assert_eq!(4, status.packets);
+        assert_eq!(256, status.bytes);
+        assert_eq!(
+            vec![
+                dir.path().join("capture-00002.pcapng"),
+                dir.path().join("capture-00003.pcapng"),
+            ],
+            status.files
+        );
+        assert!(!dir.path().join("capture-00000.pcapng").exists());
+        assert!(status.files.iter().all(|path| path.exists()));
+
+        // No more packets are recorded once the capture has stopped.
+        tap.record(Stage::UpstreamSend, &client, Some(&endpoint), &[0; 64]);
+        assert_eq!(4, tap.status().packets);
+    }
+
+    #[test]
+    fn keeps_existing_files() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        let client: EndpointAddress = "127.0.0.1:7000".parse().unwrap();
+
+        let first = Tap::new(config(dir.path().join("capture.pcapng"))).unwrap();
+        first.record(Stage::DownstreamReceive, &client, None, &[0; 64]);
+
+        // A second capture with the same path, while the first one is still
+        // writing, starts from the next file.
+        let second = Tap::new(config(dir.path().join("capture.pcapng"))).unwrap();
+        second.record(Stage::DownstreamReceive, &client, None, &[0; 64]);
+
+        let first = first.stop();
+        let second = second.stop();
+        assert_eq!(vec![dir.path().join("capture-00000.pcapng")], first.files);
+        assert_eq!(vec![dir.path().join("capture-00001.pcapng")], second.files);
+
+        let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+        assert_eq!(size(&first.files[0]), size(&second.files[0]));
+    }
+
+    #[test]
+    fn max_duration() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        let mut config = config(dir.path().join("capture.pcapng"));
+        config.max_duration = Some(0);
+
+        // The capture stops by itself, without receiving any packets.
+        let tap = Tap::new(config).unwrap();
+        let started_at = Instant::now();
+        while tap.status().active {
+            assert!(started_at.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        tap.record(
+            Stage::DownstreamReceive,
+            &"127.0.0.1:7000".parse().unwrap(),
+            None,
+            b"hello",
+        );
+
+        let status = tap.stop();
+        assert!(!status.active);
+        assert_eq!(0, status.packets);
+    }
+
+    #[test]
+    fn start_outside_directory() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        for path in [
+            "../capture.pcapng",
+            "/tmp/capture.pcapng",
+            "nested/capture.pcapng",
+            "",
+        ] {
+            assert!(start(config(path.into()), dir.path()).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = tempdir::TempDir::new("pcap").unwrap();
+        let mut config = config(dir.path().join("capture.pcapng"));
+        config.max_files = 0;
+        assert!(Tap::new(config).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A minimal writer for the [pcapng] format, which records every packet as a
+//! UDP datagram in a raw IP packet, so that captures can be opened with tools
+//! such as Wireshark.
+//!
+//! [pcapng]: https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-05.html
+
+use std::{
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Packets start with an IPv4 or IPv6 header, with no link layer header.
+const LINKTYPE_RAW: u16 = 101;
+const OPTION_COMMENT: u16 = 1;
+const OPTION_END: u16 = 0;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writes the section header and interface description blocks that start
+/// every capture file, returning the number of bytes written.
+pub(super) fn write_header(writer: &mut impl Write) -> io::Result<usize> {
+    let mut block = Vec::with_capacity(48);
+
+    block.extend_from_slice(&SECTION_HEADER_BLOCK.to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+    block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0
+    block.extend_from_slice(&1u16.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    // The length of the section isn't known up front.
+    block.extend_from_slice(&(-1i64).to_le_bytes());
+    block.extend_from_slice(&28u32.to_le_bytes());
+
+    block.extend_from_slice(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+    block.extend_from_slice(&20u32.to_le_bytes());
+    block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    // No limit on the length of captured packets.
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&20u32.to_le_bytes());
+
+    writer.write_all(&block)?;
+    Ok(block.len())
+}
+
+/// Writes `contents` as a UDP datagram sent from `source` to `dest`, with
+/// `comment` attached to the packet, returning the number of bytes written.
+pub(super) fn write_packet(
+    writer: &mut impl Write,
+    source: SocketAddr,
+    dest: SocketAddr,
+    contents: &[u8],
+    comment: &str,
+) -> io::Result<usize> {
+    let packet = udp_packet(source, dest, contents);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let mut options = Vec::new();
+    options.extend_from_slice(&OPTION_COMMENT.to_le_bytes());
+    options.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+    options.extend_from_slice(comment.as_bytes());
+    pad(&mut options);
+    options.extend_from_slice(&OPTION_END.to_le_bytes());
+    options.extend_from_slice(&0u16.to_le_bytes());
+
+    let len = 28 + padded_len(packet.len()) + options.len() + 4;
+    let mut block = Vec::with_capacity(len);
+    block.extend_from_slice(&ENHANCED_PACKET_BLOCK.to_le_bytes());
+    block.extend_from_slice(&(len as u32).to_le_bytes());
+    // Interface identifier
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    block.extend_from_slice(&packet);
+    pad(&mut block);
+    block.extend_from_slice(&options);
+    block.extend_from_slice(&(len as u32).to_le_bytes());
+
+    writer.write_all(&block)?;
+    Ok(block.len())
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Pads `block` with zeroes to a multiple of 32 bits.
+fn pad(block: &mut Vec<u8>) {
+    block.resize(padded_len(block.len()), 0);
+}
+
+/// Returns an IP packet containing a UDP datagram. The UDP checksum is left
+/// empty, as the packet was never actually sent this way.
+fn udp_packet(source: SocketAddr, dest: SocketAddr, contents: &[u8]) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_LEN + contents.len()).min(u16::MAX as usize) as u16;
+
+    let mut packet = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(dest)) => ipv4_header(source, dest, udp_len),
+        (source, dest) => ipv6_header(source, dest, udp_len),
+    };
+
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&dest.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(contents);
+    packet
+}
+
+fn ipv4_header(source: Ipv4Addr, dest: Ipv4Addr, udp_len: u16) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN as u16).saturating_add(udp_len);
+    let mut header = Vec::with_capacity(IPV4_HEADER_LEN + UDP_HEADER_LEN);
+    // Version 4, with a 5 word header.
+    header.push(0x45);
+    header.push(0);
+    header.extend_from_slice(&total_len.to_be_bytes());
+    // Identification, and flags with fragment offset.
+    header.extend_from_slice(&[0, 0, 0, 0]);
+    header.push(TTL);
+    header.push(UDP_PROTOCOL);
+    header.extend_from_slice(&[0, 0]);
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&dest.octets());
+
+    let checksum = !header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .fold(0u32, |sum, word| {
+            let sum = sum + word;
+            (sum & 0xFFFF) + (sum >> 16)
+        }) as u16;
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn ipv6_header(source: IpAddr, dest: IpAddr, udp_len: u16) -> Vec<u8> {
+    let to_ipv6 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+
+    let mut header = Vec::with_capacity(IPV6_HEADER_LEN + UDP_HEADER_LEN);
+    // Version 6, with no traffic class or flow label.
+    header.extend_from_slice(&[0x60, 0, 0, 0]);
+    header.extend_from_slice(&udp_len.to_be_bytes());
+    header.push(UDP_PROTOCOL);
+    header.push(TTL);
+    header.extend_from_slice(&to_ipv6(source).octets());
+    header.extend_from_slice(&to_ipv6(dest).octets());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let mut file = Vec::new();
+        assert_eq!(48, write_header(&mut file).unwrap());
+        assert_eq!(48, file.len());
+        assert_eq!(&SECTION_HEADER_BLOCK.to_le_bytes(), &file[..4]);
+        assert_eq!(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes(), &file[28..32]);
+    }
+
+    #[test]
+    fn packet() {
+        let mut file = Vec::new();
+        let written = write_packet(
+            &mut file,
+            "127.0.0.1:7000".parse().unwrap(),
+            "127.0.0.2:8000".parse().unwrap(),
+            b"hello",
+            "read",
+        )
+        .unwrap();
+
+        assert_eq!(written, file.len());
+        assert_eq!(0, written % 4);
+        let len = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+        assert_eq!(written, len);
+        assert_eq!(&file[len - 4..], &file[4..8]);
+
+        let captured_len = u32::from_le_bytes(file[20..24].try_into().unwrap()) as usize;
+        assert_eq!(IPV4_HEADER_LEN + UDP_HEADER_LEN + 5, captured_len);
+        let packet = &file[28..28 + captured_len];
+        assert_eq!(0x45, packet[0]);
+        // A valid header sums to zero, including its checksum.
+        assert_eq!(
+            0xFFFF,
+            packet[..IPV4_HEADER_LEN]
+                .chunks(2)
+                .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+                .fold(0u32, |sum, word| {
+                    let sum = sum + word;
+                    (sum & 0xFFFF) + (sum >> 16)
+                })
+        );
+        assert_eq!(&7000u16.to_be_bytes(), &packet[20..22]);
+        assert_eq!(&8000u16.to_be_bytes(), &packet[22..24]);
+        assert_eq!(b"hello", &packet[28..]);
+    }
+
+    #[test]
+    fn ipv6_packet() {
+        let packet = udp_packet(
+            "127.0.0.1:7000".parse().unwrap(),
+            "[::1]:8000".parse().unwrap(),
+            b"hello",
+        );
+
+        assert_eq!(IPV6_HEADER_LEN + UDP_HEADER_LEN + 5, packet.len());
+        assert_eq!(0x60, packet[0]);
+        assert_eq!(&13u16.to_be_bytes(), &packet[4..6]);
+        assert_eq!(b"hello", &packet[48..]);
+    }
+}
@@ -0,0 +1,375 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A minimal reader for the classic [pcap] and [pcapng] formats, which
+//! extracts the UDP datagrams from IPv4 and IPv6 packets, skipping any other
+//! packets.
+//!
+//! [pcap]: https://www.ietf.org/archive/id/draft-gharris-opsawg-pcap-01.html
+//! [pcapng]: https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-05.html
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_NANOSECOND_MAGIC: u32 = 0xA1B2_3C4D;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const SIMPLE_PACKET_BLOCK: u32 = 3;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// BSD loopback, with a four byte address family in the host's byte order.
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const UDP_PROTOCOL: u8 = 17;
+
+/// A UDP datagram read from a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Datagram {
+    pub source: SocketAddr,
+    pub dest: SocketAddr,
+    pub contents: Vec<u8>,
+}
+
+/// Returns whether `file` starts like a pcap or pcapng file.
+pub(crate) fn is_capture(file: &[u8]) -> bool {
+    let Some(magic) = file.get(..4) else {
+        return false;
+    };
+    let magic: [u8; 4] = magic.try_into().unwrap();
+
+    [PCAP_MAGIC, PCAP_NANOSECOND_MAGIC, SECTION_HEADER_BLOCK]
+        .into_iter()
+        .any(|expected| {
+            u32::from_le_bytes(magic) == expected || u32::from_be_bytes(magic) == expected
+        })
+}
+
+/// Returns every UDP datagram in the pcap or pcapng `file`, in the order they
+/// were captured.
+pub(crate) fn read(file: &[u8]) -> io::Result<Vec<Datagram>> {
+    let magic = Reader::new(file, Endian::Little).u32(0)?;
+    if magic == SECTION_HEADER_BLOCK {
+        read_pcapng(file)
+    } else {
+        read_pcap(file)
+    }
+}
+
+fn read_pcap(file: &[u8]) -> io::Result<Vec<Datagram>> {
+    let endian = match Reader::new(file, Endian::Little).u32(0)? {
+        PCAP_MAGIC | PCAP_NANOSECOND_MAGIC => Endian::Little,
+        _ => match Reader::new(file, Endian::Big).u32(0)? {
+            PCAP_MAGIC | PCAP_NANOSECOND_MAGIC => Endian::Big,
+            _ => return Err(invalid("not a pcap or pcapng file")),
+        },
+    };
+
+    let reader = Reader::new(file, endian);
+    let link_type = reader.u32(20)? & 0xFFFF;
+    let mut datagrams = Vec::new();
+    let mut offset = PCAP_HEADER_LEN;
+
+    while offset < file.len() {
+        let captured_len = reader.u32(offset + 8)? as usize;
+        let start = offset + PCAP_RECORD_HEADER_LEN;
+        let packet = reader.bytes(start, captured_len)?;
+        datagrams.extend(datagram(link_type, endian, packet));
+        offset = start + captured_len;
+    }
+
+    Ok(datagrams)
+}
+
+fn read_pcapng(file: &[u8]) -> io::Result<Vec<Datagram>> {
+    let mut datagrams = Vec::new();
+    // The link type of each interface in the current section.
+    let mut interfaces = Vec::new();
+    let mut endian = Endian::Little;
+    let mut offset = 0;
+
+    while offset < file.len() {
+        // The byte order of every block is set by the section header, whose
+        // block type reads the same either way.
+        if Reader::new(file, endian).u32(offset)? == SECTION_HEADER_BLOCK {
+            endian = match Reader::new(file, Endian::Little).u32(offset + 8)? {
+                BYTE_ORDER_MAGIC => Endian::Little,
+                _ => Endian::Big,
+            };
+            interfaces.clear();
+        }
+
+        let reader = Reader::new(file, endian);
+        let block_type = reader.u32(offset)?;
+        let len = reader.u32(offset + 4)? as usize;
+        if len < 12 || len % 4 != 0 {
+            return Err(invalid("invalid pcapng block length"));
+        }
+        let block = reader.bytes(offset, len)?;
+        let reader = Reader::new(block, endian);
+
+        match block_type {
+            INTERFACE_DESCRIPTION_BLOCK => interfaces.push(u32::from(reader.u16(8)?)),
+            ENHANCED_PACKET_BLOCK => {
+                let interface = reader.u32(8)? as usize;
+                let link_type = *interfaces
+                    .get(interface)
+                    .ok_or_else(|| invalid("packet from an undescribed interface"))?;
+                let captured_len = reader.u32(20)? as usize;
+                datagrams.extend(datagram(link_type, endian, reader.bytes(28, captured_len)?));
+            }
+            SIMPLE_PACKET_BLOCK => {
+                let link_type = *interfaces
+                    .first()
+                    .ok_or_else(|| invalid("packet from an undescribed interface"))?;
+                // Simple packets are only truncated by the block's length.
+                let packet_len = (reader.u32(8)? as usize).min(len - 16);
+                datagrams.extend(datagram(link_type, endian, reader.bytes(12, packet_len)?));
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Ok(datagrams)
+}
+
+/// Returns the UDP datagram in `packet`, if it contains one.
+fn datagram(link_type: u32, endian: Endian, packet: &[u8]) -> Option<Datagram> {
+    let ip = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => packet,
+        LINKTYPE_NULL => {
+            let family = Reader::new(packet, endian).u32(0).ok()?;
+            // AF_INET, and the values of AF_INET6 on various platforms.
+            if ![2, 10, 24, 28, 30].contains(&family) {
+                return None;
+            }
+            packet.get(4..)?
+        }
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = Reader::new(packet, Endian::Big).u16(offset).ok()?;
+            while ethertype == ETHERTYPE_VLAN {
+                offset += 4;
+                ethertype = Reader::new(packet, Endian::Big).u16(offset).ok()?;
+            }
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return None;
+            }
+            packet.get(offset + 2..)?
+        }
+        LINKTYPE_LINUX_SLL => {
+            let protocol = Reader::new(packet, Endian::Big).u16(14).ok()?;
+            if protocol != ETHERTYPE_IPV4 && protocol != ETHERTYPE_IPV6 {
+                return None;
+            }
+            packet.get(16..)?
+        }
+        _ => return None,
+    };
+
+    udp_datagram(ip)
+}
+
+/// Returns the UDP datagram in the IP packet `packet`, if it contains one.
+/// Fragmented IPv4 packets, and IPv6 packets with extension headers, are
+/// skipped.
+fn udp_datagram(packet: &[u8]) -> Option<Datagram> {
+    let reader = Reader::new(packet, Endian::Big);
+    let (source, dest, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0F) * 4;
+            let fragment = reader.u16(6).ok()? & 0x3FFF;
+            if packet.get(9)? != &UDP_PROTOCOL || fragment != 0 {
+                return None;
+            }
+            let address = |offset| {
+                let octets: [u8; 4] = reader.bytes(offset, 4).ok()?.try_into().ok()?;
+                Some(IpAddr::from(Ipv4Addr::from(octets)))
+            };
+            (address(12)?, address(16)?, packet.get(header_len..)?)
+        }
+        6 => {
+            if packet.get(6)? != &UDP_PROTOCOL {
+                return None;
+            }
+            let address = |offset| {
+                let octets: [u8; 16] = reader.bytes(offset, 16).ok()?.try_into().ok()?;
+                Some(IpAddr::from(Ipv6Addr::from(octets)))
+            };
+            (address(8)?, address(24)?, packet.get(40..)?)
+        }
+        _ => return None,
+    };
+
+    let reader = Reader::new(udp, Endian::Big);
+    let source_port = reader.u16(0).ok()?;
+    let dest_port = reader.u16(2).ok()?;
+    // The length can be shorter than what was captured because of padding,
+    // or longer if the packet was truncated.
+    let len = usize::from(reader.u16(4).ok()?).clamp(8, udp.len().max(8));
+    let contents = udp.get(8..len)?.to_vec();
+
+    Some(Datagram {
+        source: SocketAddr::new(source, source_port),
+        dest: SocketAddr::new(dest, dest_port),
+        contents,
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+/// Reads integers from a buffer, with any out of bounds read being an error.
+struct Reader<'a> {
+    buffer: &'a [u8],
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8], endian: Endian) -> Self {
+        Self { buffer, endian }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.buffer.get(offset..end))
+            .ok_or_else(|| invalid("the capture is truncated"))
+    }
+
+    fn u16(&self, offset: usize) -> io::Result<u16> {
+        let bytes = self.bytes(offset, 2)?.try_into().unwrap();
+        Ok(match self.endian {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        let bytes = self.bytes(offset, 4)?.try_into().unwrap();
+        Ok(match self.endian {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcapng() {
+        let mut file = Vec::new();
+        crate::pcap::pcapng::write_header(&mut file).unwrap();
+        for (source, dest, contents) in [
+            ("127.0.0.1:7000", "127.0.0.2:8000", &b"hello"[..]),
+            ("[::1]:8000", "[::1]:7000", &b"world"[..]),
+        ] {
+            crate::pcap::pcapng::write_packet(
+                &mut file,
+                source.parse().unwrap(),
+                dest.parse().unwrap(),
+                contents,
+                "read",
+            )
+            .unwrap();
+        }
+
+        assert!(is_capture(&file));
+        assert_eq!(
+            vec![
+                Datagram {
+                    source: "127.0.0.1:7000".parse().unwrap(),
+                    dest: "127.0.0.2:8000".parse().unwrap(),
+                    contents: b"hello".to_vec(),
+                },
+                Datagram {
+                    source: "[::1]:8000".parse().unwrap(),
+                    dest: "[::1]:7000".parse().unwrap(),
+                    contents: b"world".to_vec(),
+                },
+            ],
+            read(&file).unwrap()
+        );
+    }
+
+    #[test]
+    fn pcap_ethernet() {
+        let mut file = Vec::new();
+        file.extend_from_slice(&PCAP_MAGIC.to_be_bytes());
+        file.extend_from_slice(&2u16.to_be_bytes());
+        file.extend_from_slice(&4u16.to_be_bytes());
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&65535u32.to_be_bytes());
+        file.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, UDP_PROTOCOL, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend_from_slice(&7000u16.to_be_bytes());
+        frame.extend_from_slice(&8000u16.to_be_bytes());
+        frame.extend_from_slice(&13u16.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(b"hello");
+        // Ethernet frames are padded to a minimum length.
+        frame.resize(60, 0);
+
+        for frame in [&frame[..], &frame[..20]] {
+            file.extend_from_slice(&[0; 8]);
+            file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            file.extend_from_slice(frame);
+        }
+
+        assert!(is_capture(&file));
+        assert_eq!(
+            vec![Datagram {
+                source: "10.0.0.1:7000".parse().unwrap(),
+                dest: "10.0.0.2:8000".parse().unwrap(),
+                contents: b"hello".to_vec(),
+            }],
+            read(&file).unwrap()
+        );
+
+        assert!(read(&file[..file.len() - 1]).is_err());
+        assert!(!is_capture(b"68656c6c6f"));
+    }
+}
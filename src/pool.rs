@@ -0,0 +1,429 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pooled, reference counted buffers for packet contents, allowing packets to
+//! be passed through the proxy and the filter chain without allocating.
+
+use std::{
+    fmt, io,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use bytes::{buf::UninitSlice, BufMut, Bytes, BytesMut};
+use parking_lot::Mutex;
+
+/// The number of bytes reserved in front of the contents of a pooled buffer,
+/// so that filters can prepend to a packet without moving its contents.
+pub const HEADROOM: usize = 64;
+
+/// The default maximum number of idle buffers kept by a [`BufferPool`].
+pub const DEFAULT_MAX_BUFFERS: usize = 1024;
+
+/// A pool of reusable packet buffers. Buffers are returned to the pool when
+/// dropped, and their allocation is reused once nothing else references it.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool which keeps up to `max_buffers` idle buffers.
+    pub fn new(max_buffers: usize) -> Arc<Self> {
+        Arc::new(Self {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+        })
+    }
+
+    /// Returns a buffer containing a copy of `contents`, reusing an idle
+    /// buffer from the pool if one is available.
+    pub fn alloc(self: &Arc<Self>, contents: &[u8]) -> PacketBuffer {
+        let mut buffer = self.buffer(contents.len());
+        buffer.contents.extend_from_slice(contents);
+        PacketBuffer(Arc::new(buffer))
+    }
+
+    /// Returns an empty buffer that can hold at least `capacity` bytes
+    /// without allocating, for receiving packets into with
+    /// [`PacketBuffer::spare_capacity_mut`].
+    pub fn alloc_empty(self: &Arc<Self>, capacity: usize) -> PacketBuffer {
+        PacketBuffer(Arc::new(self.buffer(capacity)))
+    }
+
+    fn buffer(self: &Arc<Self>, capacity: usize) -> Buffer {
+        let mut buffer = self.buffers.lock().pop().unwrap_or_default();
+        // Reserving reclaims the buffer's original allocation when nothing
+        // else references it, and only allocates when it's shared or too small.
+        buffer.clear();
+        buffer.reserve(HEADROOM + capacity);
+        buffer.resize(HEADROOM, 0);
+
+        Buffer {
+            headroom: buffer.split_to(HEADROOM),
+            contents: buffer,
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// Returns the number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().len()
+    }
+
+    fn release(&self, buffer: BytesMut) {
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers: DEFAULT_MAX_BUFFERS,
+        }
+    }
+}
+
+/// The contents of a packet, which dereferences to `[u8]`. Bytes can be
+/// removed from either end without copying the remaining contents, and
+/// buffers allocated from a [`BufferPool`] can be prepended to without moving
+/// their contents. Clones share the same contents, which are only copied
+/// when a buffer that's shared is modified.
+#[derive(Clone, Default)]
+pub struct PacketBuffer(Arc<Buffer>);
+
+#[derive(Default)]
+struct Buffer {
+    /// Unused space in front of `contents`, which always starts at the
+    /// beginning of the buffer's allocation.
+    headroom: BytesMut,
+    contents: BytesMut,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl PacketBuffer {
+    /// Creates an empty buffer that doesn't belong to a pool.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the buffer for modification, copying its contents first if
+    /// they're shared with a clone.
+    fn get_mut(&mut self) -> &mut Buffer {
+        Arc::make_mut(&mut self.0)
+    }
+
+    /// Returns the number of bytes that can be appended without allocating.
+    pub(crate) fn spare_capacity(&self) -> usize {
+        self.0.contents.capacity() - self.0.contents.len()
+    }
+
+    /// Returns the unused capacity after the end of the contents, which is
+    /// at least the capacity requested from [`BufferPool::alloc_empty`].
+    pub(crate) fn spare_capacity_mut(&mut self) -> &mut UninitSlice {
+        self.get_mut().contents.chunk_mut()
+    }
+
+    /// Appends the first `len` bytes of the spare capacity to the contents.
+    ///
+    /// # Safety
+    /// The bytes must have been initialized, e.g. by receiving a packet into
+    /// [`PacketBuffer::spare_capacity_mut`].
+    pub(crate) unsafe fn advance(&mut self, len: usize) {
+        self.get_mut().contents.advance_mut(len);
+    }
+
+    /// Resizes the buffer to `len` bytes, filling any new bytes with `value`.
+    pub fn resize(&mut self, len: usize, value: u8) {
+        self.get_mut().contents.resize(len, value);
+    }
+
+    /// Removes and returns the first `len` bytes of the buffer. The returned
+    /// bytes share the buffer's allocation, so no copy is made.
+    ///
+    /// # Panics
+    /// If `len` is greater than the length of the buffer.
+    pub fn split_prefix(&mut self, len: usize) -> Bytes {
+        self.get_mut().contents.split_to(len).freeze()
+    }
+
+    /// Removes and returns the last `len` bytes of the buffer. The returned
+    /// bytes share the buffer's allocation, so no copy is made.
+    ///
+    /// # Panics
+    /// If `len` is greater than the length of the buffer.
+    pub fn split_suffix(&mut self, len: usize) -> Bytes {
+        let contents = &mut self.get_mut().contents;
+        contents.split_off(contents.len() - len).freeze()
+    }
+
+    /// Shortens the buffer to `len` bytes, has no effect if the buffer is
+    /// already shorter than `len`.
+    pub fn truncate(&mut self, len: usize) {
+        self.get_mut().contents.truncate(len);
+    }
+
+    /// Removes all of the buffer's contents.
+    pub fn clear(&mut self) {
+        self.get_mut().contents.clear();
+    }
+
+    /// Appends `bytes` to the end of the buffer.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.get_mut().contents.extend_from_slice(bytes);
+    }
+
+    /// Inserts `bytes` at the start of the buffer. This doesn't move the
+    /// existing contents if `bytes` fits in the buffer's headroom.
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        let buffer = self.get_mut();
+        if let Some(at) = buffer.headroom.len().checked_sub(bytes.len()) {
+            let mut prefix = buffer.headroom.split_off(at);
+            prefix.copy_from_slice(bytes);
+            // This is `O(1)` as the prefix directly precedes the contents,
+            // unless the start of the contents has been split off.
+            prefix.unsplit(std::mem::take(&mut buffer.contents));
+            buffer.contents = prefix;
+        } else {
+            let mut contents = BytesMut::with_capacity(bytes.len() + buffer.contents.len());
+            contents.extend_from_slice(bytes);
+            contents.extend_from_slice(&buffer.contents);
+            buffer.contents = contents;
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            // The contents are dropped first, so that the allocation can be
+            // reclaimed when the buffer is reused.
+            self.contents = BytesMut::new();
+            pool.release(std::mem::take(&mut self.headroom));
+        }
+    }
+}
+
+/// Copies the contents when a shared [`PacketBuffer`] is modified.
+impl Clone for Buffer {
+    fn clone(&self) -> Self {
+        match &self.pool {
+            Some(pool) => {
+                let mut buffer = pool.buffer(self.contents.len());
+                buffer.contents.extend_from_slice(&self.contents);
+                buffer
+            }
+            None => Self {
+                contents: BytesMut::from(&self.contents[..]),
+                ..Self::default()
+            },
+        }
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.contents
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.get_mut().contents
+    }
+}
+
+impl AsRef<[u8]> for PacketBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PacketBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PacketBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl From<&'_ [u8]> for PacketBuffer {
+    fn from(contents: &[u8]) -> Self {
+        Self(Arc::new(Buffer {
+            contents: BytesMut::from(contents),
+            ..Buffer::default()
+        }))
+    }
+}
+
+impl From<Vec<u8>> for PacketBuffer {
+    fn from(contents: Vec<u8>) -> Self {
+        Self::from(&*contents)
+    }
+}
+
+impl io::Write for PacketBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PartialEq for PacketBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for PacketBuffer {}
+
+impl PartialEq<[u8]> for PacketBuffer {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for PacketBuffer {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<PacketBuffer> for Vec<u8> {
+    fn eq(&self, other: &PacketBuffer) -> bool {
+        **self == **other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(1);
+        let buffer = pool.alloc(b"hello");
+        assert_eq!(b"hello", &*buffer);
+        assert_eq!(0, pool.idle());
+
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(1, pool.idle());
+
+        let buffer = pool.alloc(b"world");
+        assert_eq!(b"world", &*buffer);
+        assert_eq!(ptr, buffer.as_ptr());
+        assert_eq!(0, pool.idle());
+
+        // Buffers past the pool's maximum are freed.
+        let mut other = buffer.clone();
+        other.extend_from_slice(b"!");
+        drop((buffer, other));
+        assert_eq!(1, pool.idle());
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let pool = BufferPool::new(2);
+        let buffer = pool.alloc(b"hello");
+
+        // Clones share the contents until one of them is modified.
+        let mut other = buffer.clone();
+        assert_eq!(buffer.as_ptr(), other.as_ptr());
+
+        other.extend_from_slice(b" world");
+        assert_ne!(buffer.as_ptr(), other.as_ptr());
+        assert_eq!(b"hello", &*buffer);
+        assert_eq!(b"hello world", &*other);
+
+        // Modifying a buffer that isn't shared doesn't copy it.
+        let ptr = other.as_ptr();
+        other.truncate(5);
+        assert_eq!(ptr, other.as_ptr());
+
+        // Both are returned to the pool once dropped.
+        drop((buffer, other));
+        assert_eq!(2, pool.idle());
+    }
+
+    #[test]
+    fn receive_into_spare_capacity() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.alloc_empty(1500);
+        assert!(buffer.is_empty());
+        assert!(buffer.spare_capacity() >= 1500);
+
+        buffer.spare_capacity_mut()[..5].copy_from_slice(b"hello");
+        // SAFETY: The first five bytes were just written.
+        unsafe { buffer.advance(5) };
+        assert_eq!(b"hello", &*buffer);
+    }
+
+    #[test]
+    fn prepend() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.alloc(b"world");
+        let ptr = buffer.as_ptr();
+
+        buffer.prepend(b"hello ");
+        assert_eq!(b"hello world", &*buffer);
+        assert_eq!(ptr, buffer.as_ptr().wrapping_add(6));
+
+        // Prepending more than the remaining headroom moves the contents.
+        buffer.prepend(&[b'!'; HEADROOM]);
+        assert_eq!(HEADROOM + 11, buffer.len());
+        assert!(buffer.ends_with(b"hello world"));
+
+        let mut buffer = PacketBuffer::from(b"world".to_vec());
+        buffer.prepend(b"hello ");
+        assert_eq!(b"hello world", &*buffer);
+    }
+
+    #[test]
+    fn split() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.alloc(b"abchelloxyz");
+        let ptr = buffer.as_ptr();
+
+        let prefix = buffer.split_prefix(3);
+        assert_eq!(b"abc", &*prefix);
+        assert_eq!(ptr, prefix.as_ptr());
+
+        let suffix = buffer.split_suffix(3);
+        assert_eq!(b"xyz", &*suffix);
+        assert_eq!(b"hello", &*buffer);
+
+        buffer.extend_from_slice(b" world");
+        assert_eq!(b"hello world", &*buffer);
+        buffer.truncate(5);
+        assert_eq!(b"hello".to_vec(), buffer);
+    }
+}
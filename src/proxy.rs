@@ -14,31 +14,52 @@
  * limitations under the License.
  */
 
+mod health;
 mod sessions;
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use prometheus::HistogramTimer;
-use tokio::{net::UdpSocket, sync::watch};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    config::LOG_SAMPLING_RATE,
     endpoint::{Endpoint, EndpointAddress},
     filters::{Filter, ReadContext},
+    pool::{BufferPool, PacketBuffer},
     ttl_map::TryResult,
-    utils::debug,
+    utils::{debug, net},
     Config,
 };
 
-pub use sessions::{Session, SessionArgs, SessionKey, SessionMap};
+pub(crate) use health::{spawn_active_checks, spawn_ejection_expiry};
+pub use sessions::{
+    Session, SessionArgs, SessionInfo, SessionKey, SessionMap, SessionRegistry, TrafficInfo,
+};
 
 /// Packet received from local port
 #[derive(Debug)]
 struct DownstreamPacket {
     source: EndpointAddress,
-    contents: Vec<u8>,
+    contents: PacketBuffer,
     timer: HistogramTimer,
 }
 
+/// Packets from the same source to the same endpoint, which are sent
+/// through the same session.
+struct SessionPackets {
+    source: EndpointAddress,
+    endpoint: Endpoint,
+    packets: Vec<PacketBuffer>,
+}
+
 /// Represents the required arguments to run a worker task that
 /// processes packets received downstream.
 pub(crate) struct DownstreamReceiveWorkerConfig {
@@ -47,7 +68,16 @@ pub(crate) struct DownstreamReceiveWorkerConfig {
     /// Socket with reused port from which the worker receives packets.
     pub socket: Arc<UdpSocket>,
     pub config: Arc<Config>,
+    /// The name of the listener the socket belongs to, or `None` for the
+    /// top level listener.
+    pub listener: Option<Arc<str>>,
     pub sessions: SessionMap,
+    /// The maximum number of packets received and processed at once.
+    pub batch_size: usize,
+    /// The size of the largest packet received, larger packets are dropped.
+    pub max_packet_size: usize,
+    /// Whether the proxy is draining, no new sessions are created while set.
+    pub draining: Arc<AtomicBool>,
     /// The worker task exits when a value is received from this shutdown channel.
     pub shutdown_rx: watch::Receiver<()>,
 }
@@ -58,24 +88,29 @@ impl DownstreamReceiveWorkerConfig {
             worker_id,
             socket,
             config,
+            listener,
             sessions,
+            batch_size,
+            max_packet_size,
+            draining,
             mut shutdown_rx,
         } = self;
 
         tokio::spawn(async move {
-            // Initialize a buffer for the UDP packet. We use the maximum size of a UDP
-            // packet, which is the maximum value of 16 a bit integer.
-            let mut buf = vec![0; 1 << 16];
+            // Packets are received in batches, which are processed in the
+            // worker's task rather than in a task per packet.
+            let pool = BufferPool::new(batch_size.max(crate::pool::DEFAULT_MAX_BUFFERS));
+            let mut batch = net::RecvBatch::new(batch_size, max_packet_size, pool);
             loop {
                 tracing::debug!(
                     id = worker_id,
                     addr = ?socket.local_addr(),
-                    "Awaiting packet"
+                    "Awaiting packets"
                 );
                 tokio::select! {
-                    result = socket.recv_from(&mut buf) => {
+                    result = batch.recv(&socket) => {
                         match result {
-                            Ok((size, source)) => Self::spawn_process_task(&buf, size, source, worker_id, &socket, &config, &sessions),
+                            Ok(_) => Self::process_batch(&mut batch, worker_id, &socket, &config, &listener, &sessions, draining.load(Ordering::Relaxed)).await,
                             Err(error) => {
                                 tracing::error!(%error, "error receiving packet");
                                 return;
@@ -91,64 +126,174 @@ impl DownstreamReceiveWorkerConfig {
         });
     }
 
-    #[inline]
-    fn spawn_process_task(
-        buf: &[u8],
-        size: usize,
-        source: std::net::SocketAddr,
+    /// Processes a batch of packets through the filter chain, then sends the
+    /// packets for each session together, with as few system calls as possible.
+    #[allow(clippy::too_many_arguments)]
+    async fn process_batch(
+        batch: &mut net::RecvBatch,
         worker_id: usize,
         socket: &Arc<UdpSocket>,
         config: &Arc<Config>,
+        listener: &Option<Arc<str>>,
         sessions: &SessionMap,
+        draining: bool,
     ) {
-        let timer = crate::metrics::processing_time(crate::metrics::READ).start_timer();
-        let contents = buf[..size].to_vec();
-
-        tracing::trace!(
-            id = worker_id,
-            size = size,
-            source = %source,
-            contents=&*debug::bytes_to_string(&contents),
-            "received packet from downstream"
-        );
+        let mut pending: Vec<SessionPackets> = Vec::new();
+        let mut timers = Vec::new();
 
-        let packet = DownstreamPacket {
-            source: source.into(),
-            contents,
-            timer,
-        };
-        let config = config.clone();
-        let sessions = sessions.clone();
-        let socket = socket.clone();
+        if batch.truncated() > 0 {
+            let dropped = crate::metrics::packets_dropped_total(
+                crate::metrics::READ,
+                "proxy::RecvBatch::truncated",
+            );
+            if dropped.get() % LOG_SAMPLING_RATE == 0 {
+                tracing::warn!(
+                    count = dropped.get(),
+                    "Packets larger than the maximum packet size are being dropped"
+                );
+            }
+            dropped.inc_by(batch.truncated() as u64);
+        }
 
-        tokio::spawn(async move {
-            match Self::process_downstream_received_packet(packet, config, socket, sessions).await {
-                Ok(size) => {
-                    crate::metrics::packets_total(crate::metrics::READ).inc();
-                    crate::metrics::bytes_total(crate::metrics::READ).inc_by(size as u64);
+        for (contents, source) in batch.drain() {
+            tracing::trace!(
+                id = worker_id,
+                size = contents.len(),
+                source = %source,
+                contents=&*debug::bytes_to_string(&contents),
+                "received packet from downstream"
+            );
+
+            // Sources received on dual-stack sockets are IPv4-mapped IPv6
+            // addresses, so we normalise them back to IPv4 for filters and sessions.
+            let packet = DownstreamPacket {
+                source: net::to_canonical(source).into(),
+                contents,
+                timer: crate::metrics::processing_time(crate::metrics::READ).start_timer(),
+            };
+            crate::pcap::record(
+                crate::pcap::Stage::DownstreamReceive,
+                &packet.source,
+                None,
+                &packet.contents,
+            );
+
+            match Self::process_downstream_received_packet(packet, config, listener) {
+                Ok((Some(mut context), timer)) => {
+                    timers.push(timer);
+                    let mut endpoints = std::mem::take(&mut context.endpoints)
+                        .into_iter()
+                        .peekable();
+                    while let Some(endpoint) = endpoints.next() {
+                        // Clones share the same buffer, the last endpoint
+                        // takes it so it isn't kept alive by the context.
+                        let contents = if endpoints.peek().is_some() {
+                            context.contents.clone()
+                        } else {
+                            std::mem::take(&mut context.contents)
+                        };
+
+                        let session = pending.iter_mut().find(|session| {
+                            session.source == context.source
+                                && session.endpoint.address == endpoint.address
+                        });
+
+                        match session {
+                            Some(session) => session.packets.push(contents),
+                            None => pending.push(SessionPackets {
+                                source: context.source.clone(),
+                                endpoint,
+                                packets: vec![contents],
+                            }),
+                        }
+                    }
                 }
-                Err(error) => {
-                    crate::metrics::packets_dropped_total(
-                        crate::metrics::READ,
-                        "proxy::Session::send",
-                    )
-                    .inc();
-                    crate::metrics::errors_total(crate::metrics::READ).inc();
-                    tracing::error!(kind=%error.kind(), "{}", error);
+                // The packet was dropped by the filter chain.
+                Ok((None, timer)) => {
+                    timer.stop_and_record();
+                    crate::metrics::packets_total(crate::metrics::READ).inc();
                 }
+                Err(error) => Self::record_read_error(&error, 1),
             }
-        });
+        }
+
+        // Each session's packets are sent concurrently, so that a session
+        // that's waiting to send doesn't hold up the others.
+        let sends = pending.iter().map(
+            |SessionPackets {
+                 source,
+                 endpoint,
+                 packets,
+             }| async move {
+                let contents = packets.iter().map(|packet| &**packet).collect::<Vec<_>>();
+                for packet in &contents {
+                    crate::pcap::record(
+                        crate::pcap::Stage::UpstreamSend,
+                        source,
+                        Some(&endpoint.address),
+                        packet,
+                    );
+                }
+                let result = Self::session_send_packets(
+                    &contents, source, endpoint, socket, config, listener, sessions, draining,
+                )
+                .await;
+
+                match result {
+                    Ok(size) => {
+                        crate::metrics::packets_total(crate::metrics::READ)
+                            .inc_by(contents.len() as u64);
+                        crate::metrics::bytes_total(crate::metrics::READ).inc_by(size as u64);
+                    }
+                    Err(error) => Self::record_read_error(&error, contents.len()),
+                }
+            },
+        );
+        futures::future::join_all(sends).await;
+
+        for timer in timers {
+            timer.stop_and_record();
+        }
+    }
+
+    fn record_read_error(error: &std::io::Error, packets: usize) {
+        crate::metrics::packets_dropped_total(crate::metrics::READ, "proxy::Session::send")
+            .inc_by(packets as u64);
+        crate::metrics::errors_total(crate::metrics::READ).inc();
+        tracing::error!(kind=%error.kind(), "{}", error);
     }
 
-    /// Processes a packet by running it through the filter chain.
-    async fn process_downstream_received_packet(
+    /// Processes a packet by running it through the filter chain, returning
+    /// the resulting context, or `None` if the packet was dropped.
+    fn process_downstream_received_packet(
         packet: DownstreamPacket,
-        config: Arc<Config>,
-        downstream_socket: Arc<UdpSocket>,
-        sessions: SessionMap,
-    ) -> std::io::Result<usize> {
+        config: &Config,
+        listener: &Option<Arc<str>>,
+    ) -> std::io::Result<(Option<ReadContext>, HistogramTimer)> {
         let clusters = config.clusters.load();
-        let endpoints: Vec<_> = clusters.endpoints().collect();
+        let (filters, endpoints) = match listener.as_deref() {
+            Some(name) => {
+                let listeners = config.listeners.load();
+                let listener = listeners.get(name).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("dropping packet, listener `{name}` no longer exists"),
+                    )
+                })?;
+                (
+                    listener.filters.clone(),
+                    listener.endpoints(&clusters, &config.endpoint_health),
+                )
+            }
+            None => (
+                config.filters.load(),
+                clusters
+                    .available_endpoints()
+                    .filter(|endpoint| config.endpoint_health.is_available(endpoint))
+                    .collect(),
+            ),
+        };
+
         if endpoints.is_empty() {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -156,38 +301,25 @@ impl DownstreamReceiveWorkerConfig {
             ));
         }
 
-        let filters = config.filters.load();
-        let mut context = ReadContext::new(endpoints, packet.source, packet.contents);
+        let mut context =
+            ReadContext::new(endpoints, packet.source, packet.contents).clusters(clusters);
         let result = filters.read(&mut context);
 
-        let mut bytes_written = 0;
-        if let Some(()) = result {
-            for endpoint in context.endpoints.iter() {
-                bytes_written += Self::session_send_packet(
-                    &context.contents,
-                    &context.source,
-                    endpoint,
-                    &downstream_socket,
-                    &config,
-                    &sessions,
-                )
-                .await?;
-            }
-        }
-
-        packet.timer.stop_and_record();
-        Ok(bytes_written)
+        Ok((result.map(|_| context), packet.timer))
     }
 
-    /// Send a packet received from `recv_addr` to an endpoint.
+    /// Send packets received from `recv_addr` to an endpoint.
     #[tracing::instrument(level="trace", skip_all, fields(source = %recv_addr, dest = %endpoint.address))]
-    async fn session_send_packet(
-        packet: &[u8],
+    #[allow(clippy::too_many_arguments)]
+    async fn session_send_packets(
+        packets: &[&[u8]],
         recv_addr: &EndpointAddress,
         endpoint: &Endpoint,
         downstream_socket: &Arc<UdpSocket>,
         config: &Arc<Config>,
+        listener: &Option<Arc<str>>,
         sessions: &SessionMap,
+        draining: bool,
     ) -> std::io::Result<usize> {
         let session_key = SessionKey {
             source: recv_addr.clone(),
@@ -195,18 +327,29 @@ impl DownstreamReceiveWorkerConfig {
         };
 
         let send_future = match sessions.try_get(&session_key) {
-            TryResult::Present(entry) => entry.send(packet),
+            TryResult::Present(entry) => entry.send_batch(packets),
+            TryResult::Absent if draining => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "dropping packet, the proxy is draining and not accepting new sessions",
+                ));
+            }
             TryResult::Absent => {
                 let session_args = SessionArgs {
                     config: config.clone(),
+                    listener: listener.clone(),
                     source: session_key.source.clone(),
                     downstream_socket: downstream_socket.clone(),
                     dest: endpoint.clone(),
                 };
 
                 let session = session_args.into_session().await?;
-                let future = session.send(packet);
-                sessions.insert(session_key, session);
+                let future = session.send_batch(packets);
+                let ttl = config
+                    .session
+                    .load()
+                    .timeout_for(&config.clusters.load(), &endpoint.address);
+                sessions.insert_with_ttl(session_key, session, ttl);
                 future
             }
             TryResult::Locked => {
@@ -220,3 +363,46 @@ impl DownstreamReceiveWorkerConfig {
         send_future.await
     }
 }
+
+/// Stops the creation of new sessions, and waits for all of the existing
+/// `sessions` to go idle, or for `timeout` to elapse, whichever happens first.
+pub(crate) async fn drain(sessions: &[SessionMap], draining: &AtomicBool, timeout: Duration) {
+    const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    let remaining = || {
+        sessions
+            .iter()
+            .map(|sessions| {
+                sessions.remove_expired();
+                sessions.len()
+            })
+            .sum::<usize>()
+    };
+
+    draining.store(true, Ordering::Relaxed);
+    sessions::metrics::draining().set(1);
+    tracing::info!(sessions = remaining(), ?timeout, "Draining sessions");
+
+    let deadline = Instant::now() + timeout;
+    let mut interval = tokio::time::interval(DRAIN_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let remaining = remaining();
+        sessions::metrics::drain_remaining().set(remaining as i64);
+
+        if remaining == 0 {
+            tracing::info!("All sessions drained");
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            tracing::warn!(
+                sessions = remaining,
+                "Drain timeout reached, closing sessions"
+            );
+            break;
+        }
+    }
+
+    sessions::metrics::draining().set(0);
+}
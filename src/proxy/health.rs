@@ -0,0 +1,452 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Active and passive health checking of upstream endpoints.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntCounterVec, Opts};
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, watch},
+    time::Instant,
+};
+
+use crate::{
+    config::health_check::{ActiveHealthCheck, DEFAULT_INTERVAL_SECONDS},
+    endpoint::{EndpointAddress, HealthStatus},
+    utils::net,
+    Config,
+};
+
+const SUBSYSTEM: &str = "health_check";
+const RESULT_LABEL: &str = "result";
+const STATUS_LABEL: &str = "status";
+
+/// The maximum number of endpoints that are waiting for a response to an
+/// active health check at once.
+const MAX_CONCURRENT_PROBES: usize = 64;
+
+pub(crate) fn checks_total(success: bool) -> IntCounter {
+    static CHECKS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("checks_total", "total number of active health checks sent to endpoints")
+                .subsystem(SUBSYSTEM),
+            &[RESULT_LABEL],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    CHECKS_TOTAL.with_label_values(&[if success { "success" } else { "failure" }])
+}
+
+pub(crate) fn transitions_total(health: HealthStatus) -> IntCounter {
+    static TRANSITIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+        prometheus::register_int_counter_vec_with_registry! {
+            Opts::new("transitions_total", "total number of changes to the health of endpoints")
+                .subsystem(SUBSYSTEM),
+            &[STATUS_LABEL],
+            crate::metrics::registry(),
+        }
+        .unwrap()
+    });
+
+    TRANSITIONS_TOTAL.with_label_values(&[&health.to_string()])
+}
+
+/// Sets the health of the endpoints with `address`, returning whether it
+/// changed.
+pub(crate) fn set_health(
+    config: &Config,
+    address: &EndpointAddress,
+    health: HealthStatus,
+    reason: &str,
+) -> bool {
+    if !config.endpoint_health.set(address, health) {
+        return false;
+    }
+
+    tracing::info!(%address, %health, reason, "endpoint health changed");
+    transitions_total(health).inc();
+    config.apply_metrics();
+    true
+}
+
+/// The number of consecutive successful and failed checks of an endpoint.
+#[derive(Debug, Default)]
+struct CheckCounts {
+    successes: u32,
+    failures: u32,
+}
+
+/// Spawns a task which periodically probes every endpoint while active health
+/// checking is enabled. The task exits when a value is received from
+/// `shutdown_rx`.
+pub(crate) fn spawn_active_checks(config: Arc<Config>, mut shutdown_rx: watch::Receiver<()>) {
+    tokio::spawn(async move {
+        let mut counts = HashMap::new();
+        loop {
+            let interval = config
+                .health_check
+                .load()
+                .active
+                .as_ref()
+                .map_or(Duration::from_secs(DEFAULT_INTERVAL_SECONDS), |check| {
+                    check.interval()
+                });
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown_rx.changed() => return,
+            }
+
+            // The config is loaded after sleeping, so that disabling active
+            // health checks takes effect immediately.
+            match config.health_check.load().active.clone() {
+                Some(check) => check_endpoints(&config, &check, &mut counts).await,
+                None => counts.clear(),
+            }
+        }
+    });
+}
+
+/// Probes every endpoint from a single socket, updating the health of the
+/// endpoints which have passed or failed enough consecutive checks.
+async fn check_endpoints(
+    config: &Config,
+    check: &ActiveHealthCheck,
+    counts: &mut HashMap<EndpointAddress, CheckCounts>,
+) {
+    let addresses = config
+        .clusters
+        .load()
+        .endpoints()
+        .map(|endpoint| endpoint.address)
+        .collect::<BTreeSet<_>>();
+    counts.retain(|address, _| addresses.contains(address));
+    config.endpoint_health.retain(&addresses);
+
+    let prober = match Prober::bind().await {
+        Ok(prober) => prober,
+        Err(error) => {
+            tracing::warn!(%error, "failed to bind the active health check socket");
+            return;
+        }
+    };
+
+    let probes = futures::stream::iter(addresses)
+        .map(|address| {
+            let prober = &prober;
+            async move {
+                let result = prober.probe(&address, check).await;
+                (address, result)
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_PROBES)
+        .collect::<Vec<_>>();
+
+    let results = tokio::select! {
+        results = probes => results,
+        _ = prober.receive(check) => unreachable!(),
+    };
+
+    for (address, result) in results {
+        checks_total(result.is_ok()).inc();
+        let count = counts.entry(address.clone()).or_default();
+        match result {
+            Ok(()) => {
+                count.failures = 0;
+                count.successes = count.successes.saturating_add(1);
+                if count.successes >= check.healthy_threshold.max(1) {
+                    set_health(
+                        config,
+                        &address,
+                        HealthStatus::Healthy,
+                        "active health check succeeded",
+                    );
+                }
+            }
+            Err(error) => {
+                tracing::debug!(%address, %error, "active health check failed");
+                count.successes = 0;
+                count.failures = count.failures.saturating_add(1);
+                if count.failures >= check.unhealthy_threshold.max(1) {
+                    set_health(
+                        config,
+                        &address,
+                        HealthStatus::Unhealthy,
+                        "active health check failed",
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// A socket shared by all of the endpoints probed by an active health check,
+/// which routes each response to the probe waiting on its source.
+struct Prober {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    pending: Mutex<HashMap<SocketAddr, oneshot::Sender<bool>>>,
+}
+
+impl Prober {
+    /// Binds a dual-stack socket, which can probe both IPv4 and IPv6
+    /// endpoints, falling back to IPv4 only where IPv6 isn't available.
+    async fn bind() -> std::io::Result<Self> {
+        let socket = match net::socket_with_reuse((Ipv6Addr::UNSPECIFIED, 0).into()) {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+        };
+
+        Ok(Self {
+            local_addr: socket.local_addr()?,
+            socket,
+            pending: <_>::default(),
+        })
+    }
+
+    /// Sends the health check's payload to `address`, and waits for the
+    /// expected response.
+    async fn probe(
+        &self,
+        address: &EndpointAddress,
+        check: &ActiveHealthCheck,
+    ) -> std::io::Result<()> {
+        let addr = net::to_canonical(address.to_socket_addr()?);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(addr, tx);
+
+        let result = async {
+            self.socket
+                .send_to(&check.payload, net::to_socket_family(addr, self.local_addr))
+                .await?;
+
+            tokio::time::timeout(check.timeout(), rx)
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out waiting for a health check response",
+                    )
+                })
+        }
+        .await;
+        self.pending.lock().remove(&addr);
+
+        if result? {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "unexpected health check response",
+            ))
+        }
+    }
+
+    /// Receives responses until the probes are finished, passing whether
+    /// each was the expected response to the probe of the endpoint it came
+    /// from. Responses from endpoints which aren't being probed are ignored.
+    async fn receive(&self, check: &ActiveHealthCheck) {
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let (size, source) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    tracing::debug!(%error, "error receiving a health check response");
+                    continue;
+                }
+            };
+
+            if let Some(tx) = self.pending.lock().remove(&net::to_canonical(source)) {
+                let _ = tx.send(check.is_expected_response(&buf[..size]));
+            }
+        }
+    }
+}
+
+/// Marks the endpoint at `address` as unhealthy when a session has gone
+/// `unanswered_for` longer than the passive health check's timeout, ejecting
+/// it until the ejection time has elapsed. Returns whether the timeout was
+/// exceeded.
+pub(crate) fn check_passive(
+    config: &Arc<Config>,
+    address: &EndpointAddress,
+    unanswered_for: Duration,
+) -> bool {
+    let passive = match config.health_check.load().passive.clone() {
+        Some(passive) if unanswered_for >= passive.timeout() => passive,
+        _ => return false,
+    };
+
+    if set_health(
+        config,
+        address,
+        HealthStatus::Unhealthy,
+        "no response received from endpoint",
+    ) {
+        config
+            .endpoint_health
+            .eject(address, Instant::now() + passive.ejection_time());
+    }
+
+    true
+}
+
+/// Spawns a task which makes the endpoints ejected by passive health checking
+/// available again once their ejection time has elapsed. The task exits when
+/// a value is received from `shutdown_rx`.
+pub(crate) fn spawn_ejection_expiry(config: Arc<Config>, mut shutdown_rx: watch::Receiver<()>) {
+    tokio::spawn(async move {
+        loop {
+            let next_end = config.endpoint_health.next_ejection_end();
+            let ejection_ended = async {
+                match next_end {
+                    Some(until) => tokio::time::sleep_until(until).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // An ejection may end sooner than the next one that's scheduled,
+            // so the task is woken up to reschedule when one is added.
+            tokio::select! {
+                _ = ejection_ended => {}
+                _ = config.endpoint_health.ejected() => {}
+                _ = shutdown_rx.changed() => return,
+            }
+
+            for address in config.endpoint_health.end_ejections(Instant::now()) {
+                set_health(
+                    &config,
+                    &address,
+                    HealthStatus::Unknown,
+                    "passive health check ejection time elapsed",
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        config::health_check::{HealthCheckConfig, PassiveHealthCheck},
+        endpoint::Endpoint,
+        test_utils::TestHelper,
+    };
+
+    fn active_check(response: &[u8]) -> ActiveHealthCheck {
+        ActiveHealthCheck {
+            interval: 1,
+            timeout: 1,
+            payload: b"ping".to_vec(),
+            response: response.to_vec(),
+            unhealthy_threshold: 1,
+            healthy_threshold: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn active() {
+        let mut t = TestHelper::default();
+        let echo = t.run_echo_server().await;
+        let missing: EndpointAddress = crate::test_utils::available_addr().await.into();
+
+        let config = Config::default();
+        config.clusters.modify(|clusters| {
+            clusters.insert_default(vec![
+                Endpoint::new(echo.clone()),
+                Endpoint::new(missing.clone()),
+            ])
+        });
+
+        let mut counts = HashMap::new();
+        check_endpoints(&config, &active_check(b""), &mut counts).await;
+
+        let health = |address: &EndpointAddress| config.endpoint_health.get(address);
+        let available = || {
+            config
+                .clusters
+                .load()
+                .endpoints()
+                .filter(|endpoint| config.endpoint_health.is_available(endpoint))
+                .map(|endpoint| endpoint.address)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(HealthStatus::Healthy, health(&echo));
+        assert_eq!(HealthStatus::Unhealthy, health(&missing));
+        assert_eq!(vec![echo.clone()], available());
+
+        // Updating the clusters doesn't reset the health of their endpoints.
+        config.clusters.modify(|clusters| {
+            clusters.insert_default(vec![
+                Endpoint::new(echo.clone()),
+                Endpoint::new(missing.clone()),
+            ])
+        });
+        assert_eq!(vec![echo.clone()], available());
+
+        // The echo server doesn't send the expected response.
+        check_endpoints(&config, &active_check(b"pong"), &mut counts).await;
+        assert_eq!(HealthStatus::Unhealthy, health(&echo));
+    }
+
+    #[tokio::test]
+    async fn passive() {
+        let address: EndpointAddress = "127.0.0.1:8000".parse().unwrap();
+        let config = Arc::new(Config::default());
+        config
+            .clusters
+            .modify(|clusters| clusters.insert_default(vec![Endpoint::new(address.clone())]));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        spawn_ejection_expiry(config.clone(), shutdown_rx);
+
+        // Passive health checking is disabled by default.
+        assert!(!check_passive(&config, &address, Duration::from_secs(60)));
+
+        config.health_check.store(Arc::new(HealthCheckConfig {
+            passive: Some(PassiveHealthCheck {
+                timeout: 5,
+                ejection_time: 0,
+            }),
+            ..<_>::default()
+        }));
+        assert!(!check_passive(&config, &address, Duration::from_secs(1)));
+        assert!(check_passive(&config, &address, Duration::from_secs(5)));
+        assert_eq!(
+            HealthStatus::Unhealthy,
+            config.endpoint_health.get(&address)
+        );
+
+        // The endpoint is available again after the ejection time.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(HealthStatus::Unknown, config.endpoint_health.get(&address));
+    }
+}
@@ -16,7 +16,10 @@
 
 pub(crate) mod metrics;
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
 use prometheus::HistogramTimer;
 use tokio::{net::UdpSocket, select, sync::watch, time::Instant};
@@ -24,18 +27,27 @@ use tokio::{net::UdpSocket, select, sync::watch, time::Instant};
 use crate::{
     endpoint::{Endpoint, EndpointAddress},
     filters::{Filter, WriteContext},
-    utils::{debug, Loggable},
+    pool::{BufferPool, PacketBuffer},
+    utils::{debug, net, Loggable},
 };
 
 pub type SessionMap = crate::ttl_map::TtlMap<SessionKey, Session>;
 
+/// How often sessions check whether their endpoint has stopped responding,
+/// when passive health checking is enabled.
+const PASSIVE_HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 /// Session encapsulates a UDP stream session
 pub struct Session {
     config: Arc<crate::Config>,
+    /// the listener the session was created by
+    listener: Option<Arc<str>>,
     /// created_at is time at which the session was created
     created_at: Instant,
     /// socket that sends and receives from and to the endpoint address
     upstream_socket: Arc<UdpSocket>,
+    /// Whether batches are sent through `upstream_socket` with UDP GSO.
+    gso: Arc<net::Gso>,
     /// dest is where to send data to
     dest: Endpoint,
     /// address of original sender
@@ -44,6 +56,159 @@ pub struct Session {
     shutdown_tx: watch::Sender<()>,
     /// The ASN information.
     asn_info: Option<crate::maxmind_db::IpNetEntry>,
+    /// The packets and bytes sent through the session.
+    counters: Arc<Counters>,
+}
+
+/// Packet and byte counters for a single session, `read` being traffic from
+/// the client to the endpoint, and `write` from the endpoint to the client.
+#[derive(Debug, Default)]
+struct Counters {
+    read_packets: AtomicU64,
+    read_bytes: AtomicU64,
+    write_packets: AtomicU64,
+    write_bytes: AtomicU64,
+    /// When the oldest packet sent to the endpoint without a response since
+    /// was sent, in milliseconds since the UNIX epoch, or `0` if there's none.
+    unanswered_since: AtomicU64,
+}
+
+impl Counters {
+    fn record_read(&self, packets: usize, size: usize) {
+        self.read_packets
+            .fetch_add(packets as u64, Ordering::Relaxed);
+        self.read_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        let _ = self.unanswered_since.compare_exchange(
+            0,
+            unix_millis(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn record_write(&self, size: usize) {
+        self.write_packets.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        self.unanswered_since.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns how long the endpoint has gone without responding to packets
+    /// sent to it, if there are any unanswered packets.
+    fn unanswered_for(&self) -> Option<std::time::Duration> {
+        match self.unanswered_since.load(Ordering::Relaxed) {
+            0 => None,
+            since => Some(std::time::Duration::from_millis(
+                unix_millis().saturating_sub(since),
+            )),
+        }
+    }
+
+    fn reset_unanswered(&self) {
+        self.unanswered_since.store(0, Ordering::Relaxed);
+    }
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// A point in time snapshot of a session, as exposed by the admin server.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SessionInfo {
+    /// The listener the session was created by, `None` for the top level
+    /// listener.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    pub source: EndpointAddress,
+    pub dest: EndpointAddress,
+    /// When the session was created, in seconds since the UNIX epoch.
+    pub created_at: u64,
+    /// When the session will expire if it stays idle, in seconds since the
+    /// UNIX epoch.
+    pub expires_at: u64,
+    pub read: TrafficInfo,
+    pub write: TrafficInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<crate::maxmind_db::IpNetEntry>,
+}
+
+/// The amount of traffic that has gone through a session in one direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct TrafficInfo {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// The session maps of a running proxy, one for each of its listeners.
+/// Shared with the admin server so that sessions can be inspected and closed.
+#[derive(Clone, Default)]
+pub struct SessionRegistry(Arc<parking_lot::RwLock<Vec<SessionMap>>>);
+
+impl SessionRegistry {
+    /// Adds `sessions` to the registry.
+    pub fn register(&self, sessions: SessionMap) {
+        self.0.write().push(sessions);
+    }
+
+    /// Removes `sessions` from the registry.
+    pub fn unregister(&self, sessions: &SessionMap) {
+        self.0.write().retain(|map| !map.ptr_eq(sessions));
+    }
+
+    /// Removes all of the session maps from the registry.
+    pub fn clear(&self) {
+        self.0.write().clear();
+    }
+
+    /// Returns all of the registered session maps.
+    pub fn maps(&self) -> Vec<SessionMap> {
+        self.0.read().clone()
+    }
+
+    /// Returns a snapshot of every session that matches `predicate`.
+    pub fn list(&self, predicate: impl Fn(&SessionKey) -> bool) -> Vec<SessionInfo> {
+        self.0
+            .read()
+            .iter()
+            .flat_map(|sessions| {
+                sessions
+                    .iter()
+                    .filter(|entry| predicate(entry.key()))
+                    .map(|entry| entry.value.info(entry.expiration_secs()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Closes every session that matches `predicate`, returning the number
+    /// of sessions that were closed.
+    pub fn remove(&self, predicate: impl Fn(&SessionKey) -> bool) -> usize {
+        self.0
+            .read()
+            .iter()
+            .map(|sessions| {
+                // Collect the keys first, as removing an entry while iterating
+                // over the map would deadlock.
+                let keys = sessions
+                    .iter()
+                    .filter(|entry| predicate(entry.key()))
+                    .map(|entry| entry.key().clone())
+                    .collect::<Vec<_>>();
+
+                keys.iter().filter_map(|key| sessions.remove(key)).count()
+            })
+            .sum()
+    }
+}
+
+impl std::fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("maps", &self.0.read().len())
+            .finish()
+    }
 }
 
 // A (source, destination) address pair that uniquely identifies a session.
@@ -61,8 +226,9 @@ impl From<(EndpointAddress, EndpointAddress)> for SessionKey {
 
 /// ReceivedPacketContext contains state needed to process a received packet.
 struct ReceivedPacketContext<'a> {
-    packet: &'a [u8],
+    packet: PacketBuffer,
     config: Arc<crate::Config>,
+    listener: Option<&'a str>,
     endpoint: &'a Endpoint,
     source: EndpointAddress,
     dest: EndpointAddress,
@@ -71,6 +237,7 @@ struct ReceivedPacketContext<'a> {
 
 pub struct SessionArgs {
     pub config: Arc<crate::Config>,
+    pub listener: Option<Arc<str>>,
     pub source: EndpointAddress,
     pub downstream_socket: Arc<UdpSocket>,
     pub dest: Endpoint,
@@ -88,23 +255,29 @@ impl Session {
     /// internal constructor for a Session from SessionArgs
     #[tracing::instrument(skip_all)]
     async fn new(args: SessionArgs) -> std::io::Result<Self> {
-        let addr = (std::net::Ipv4Addr::UNSPECIFIED, 0);
+        let dest = args.dest.address.to_socket_addr()?;
+        // The upstream socket's address family has to match the endpoint's.
+        let addr: std::net::SocketAddr = match dest {
+            std::net::SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+            std::net::SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
         let upstream_socket = Arc::new(UdpSocket::bind(addr).await?);
-        upstream_socket
-            .connect(args.dest.address.to_socket_addr()?)
-            .await?;
+        upstream_socket.connect(dest).await?;
         let (shutdown_tx, shutdown_rx) = watch::channel::<()>(());
 
         let ip = args.source.to_socket_addr().unwrap().ip();
         let asn_info = crate::MaxmindDb::lookup(ip);
         let s = Session {
             config: args.config.clone(),
+            listener: args.listener,
             upstream_socket,
+            gso: <_>::default(),
             source: args.source.clone(),
             dest: args.dest,
             created_at: Instant::now(),
             shutdown_tx,
             asn_info,
+            counters: <_>::default(),
         };
 
         tracing::debug!(source = %s.source, dest = ?s.dest, "Session created");
@@ -120,11 +293,17 @@ impl Session {
     fn run(&self, downstream_socket: Arc<UdpSocket>, mut shutdown_rx: watch::Receiver<()>) {
         let source = self.source.clone();
         let config = self.config.clone();
+        let listener = self.listener.clone();
         let endpoint = self.dest.clone();
         let upstream_socket = self.upstream_socket.clone();
+        let counters = self.counters.clone();
 
         tokio::spawn(async move {
             let mut buf: Vec<u8> = vec![0; 65535];
+            // Packets are processed one at a time, so the pool only needs
+            // to hold the buffer of the previous packet.
+            let pool = BufferPool::new(1);
+            let mut passive_health_check = tokio::time::interval(PASSIVE_HEALTH_CHECK_INTERVAL);
             loop {
                 tracing::debug!(source = %source, dest = ?endpoint, "Awaiting incoming packet");
 
@@ -138,19 +317,30 @@ impl Session {
                             Ok((size, recv_addr)) => {
                                 crate::metrics::bytes_total(crate::metrics::WRITE).inc_by(size as u64);
                                 crate::metrics::packets_total(crate::metrics::WRITE).inc();
+                                counters.record_write(size);
+                                let recv_addr = EndpointAddress::from(recv_addr);
+                                crate::pcap::record(crate::pcap::Stage::UpstreamReceive, &source, Some(&recv_addr), &buf[..size]);
                                 Session::process_recv_packet(
                                     &downstream_socket,
                                     ReceivedPacketContext {
                                         config: config.clone(),
-                                        packet: &buf[..size],
+                                        listener: listener.as_deref(),
+                                        packet: pool.alloc(&buf[..size]),
                                         endpoint: &endpoint,
-                                        source: recv_addr.into(),
+                                        source: recv_addr,
                                         dest: source.clone(),
                                         timer: crate::metrics::processing_time(crate::metrics::WRITE).start_timer(),
                                     }).await
                             }
                         };
                     }
+                    _ = passive_health_check.tick() => {
+                        if let Some(unanswered_for) = counters.unanswered_for() {
+                            if super::health::check_passive(&config, &endpoint.address, unanswered_for) {
+                                counters.reset_unanswered();
+                            }
+                        }
+                    }
                     _ = shutdown_rx.changed() => {
                         tracing::debug!(%source, dest = ?endpoint, "Closing Session");
                         return;
@@ -178,31 +368,33 @@ impl Session {
         let ReceivedPacketContext {
             packet,
             config,
+            listener,
             endpoint,
             source: from,
             dest,
             timer,
         } = packet_ctx;
 
-        tracing::trace!(%from, dest = %endpoint.address, contents = %debug::bytes_to_string(packet), "received packet from upstream");
+        tracing::trace!(%from, dest = %endpoint.address, contents = %debug::bytes_to_string(&packet), "received packet from upstream");
 
-        let mut context = WriteContext::new(
-            endpoint.clone(),
-            from.clone(),
-            dest.clone(),
-            packet.to_vec(),
-        );
+        let mut context = WriteContext::new(endpoint.clone(), from.clone(), dest.clone(), packet);
 
         let result = config
-            .filters
-            .load()
-            .write(&mut context)
-            .ok_or(Error::FilterDroppedPacket)
+            .filter_chain(listener)
+            .ok_or_else(|| Error::ListenerNotFound(listener.unwrap_or_default().into()))
+            .and_then(|filters| {
+                filters
+                    .write(&mut context)
+                    .ok_or(Error::FilterDroppedPacket)
+            })
             .map(|_| context)
             .and_then(|context| {
-                dest.to_socket_addr()
-                    .map(|addr| (addr, context))
-                    .map_err(Error::ToSocketAddr)
+                let addr = dest.to_socket_addr().map_err(Error::ToSocketAddr)?;
+                let addr = downstream_socket
+                    .local_addr()
+                    .map(|local_addr| net::to_socket_family(addr, local_addr))
+                    .unwrap_or(addr);
+                Ok((addr, context))
             });
 
         let handle_error = |error: Error| {
@@ -217,8 +409,14 @@ impl Session {
 
         match result {
             Ok((addr, context)) => {
-                let packet = context.contents.as_ref();
+                let packet = &*context.contents;
                 tracing::trace!(%from, dest = %addr, contents = %debug::bytes_to_string(packet), "sending packet downstream");
+                crate::pcap::record(
+                    crate::pcap::Stage::DownstreamSend,
+                    &dest,
+                    Some(&from),
+                    packet,
+                );
                 let _ = downstream_socket
                     .send_to(packet, addr)
                     .await
@@ -242,7 +440,65 @@ impl Session {
         "sending packet upstream");
 
         let socket = self.upstream_socket.clone();
-        async move { socket.send(buf).await }
+        let counters = self.counters.clone();
+        async move {
+            let size = socket.send(buf).await?;
+            counters.record_read(1, size);
+            Ok(size)
+        }
+    }
+
+    /// Sends a batch of packets to the Session's dest, with as few system
+    /// calls as possible.
+    pub fn send_batch<'buf>(
+        &self,
+        packets: &'buf [&'buf [u8]],
+    ) -> impl std::future::Future<Output = std::io::Result<usize>> + 'buf {
+        tracing::trace!(
+        dest_address = %self.dest.address,
+        packets = packets.len(),
+        "sending packets upstream");
+
+        let socket = self.upstream_socket.clone();
+        let gso = self.gso.clone();
+        let counters = self.counters.clone();
+        async move {
+            let size = net::send_batch(&socket, &gso, None, packets).await?;
+            counters.record_read(packets.len(), size);
+            Ok(size)
+        }
+    }
+
+    /// Returns the address of the endpoint the session sends packets to.
+    pub fn dest(&self) -> &EndpointAddress {
+        &self.dest.address
+    }
+
+    /// Returns a snapshot of the session, `expires_at` being when the session
+    /// will expire in seconds since the UNIX epoch.
+    pub fn info(&self, expires_at: u64) -> SessionInfo {
+        let created_at = std::time::SystemTime::now()
+            .checked_sub(self.created_at.elapsed())
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+
+        SessionInfo {
+            listener: self.listener.as_deref().map(String::from),
+            source: self.source.clone(),
+            dest: self.dest.address.clone(),
+            created_at,
+            expires_at,
+            read: TrafficInfo {
+                packets: self.counters.read_packets.load(Ordering::Relaxed),
+                bytes: self.counters.read_bytes.load(Ordering::Relaxed),
+            },
+            write: TrafficInfo {
+                packets: self.counters.write_packets.load(Ordering::Relaxed),
+                bytes: self.counters.write_bytes.load(Ordering::Relaxed),
+            },
+            asn: self.asn_info.clone(),
+        }
     }
 }
 
@@ -267,6 +523,8 @@ pub enum Error {
     SendTo(std::io::Error),
     #[error("filter dropped packet from upstream")]
     FilterDroppedPacket,
+    #[error("listener `{0}` no longer exists")]
+    ListenerNotFound(String),
 }
 
 impl Loggable for Error {
@@ -278,6 +536,9 @@ impl Loggable for Error {
             Self::FilterDroppedPacket => {
                 tracing::trace!("{}", self)
             }
+            Self::ListenerNotFound(_) => {
+                tracing::warn!("{}", self)
+            }
         }
     }
 }
@@ -307,6 +568,7 @@ mod tests {
 
         let sess = Session::new(SessionArgs {
             config: <_>::default(),
+            listener: None,
             source: addr.clone(),
             downstream_socket: socket.clone(),
             dest: endpoint,
@@ -326,6 +588,83 @@ mod tests {
         assert_eq!(addr.port(), recv_addr.port());
     }
 
+    #[tokio::test]
+    async fn session_send_and_receive_ipv6() {
+        let echo = UdpSocket::bind((std::net::Ipv6Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr: EndpointAddress = echo.local_addr().unwrap().into();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (size, source) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], source).await.unwrap();
+        });
+
+        let socket = Arc::new(create_socket().await);
+        let source: EndpointAddress = socket.local_addr().unwrap().into();
+        let msg = "hello";
+
+        let sess = Session::new(SessionArgs {
+            config: <_>::default(),
+            listener: None,
+            source,
+            downstream_socket: socket.clone(),
+            dest: Endpoint::new(addr),
+        })
+        .await
+        .unwrap();
+
+        assert!(sess.upstream_socket.local_addr().unwrap().is_ipv6());
+        sess.send(msg.as_bytes()).await.unwrap();
+
+        let mut buf = vec![0; 1024];
+        let (size, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg, from_utf8(&buf[..size]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn session_info() {
+        let mut t = TestHelper::default();
+        let addr = t.run_echo_server().await;
+        let socket = Arc::new(create_socket().await);
+        let source: EndpointAddress = socket.local_addr().unwrap().into();
+        let msg = "hello";
+
+        let sess = Session::new(SessionArgs {
+            config: <_>::default(),
+            listener: None,
+            source: source.clone(),
+            downstream_socket: socket.clone(),
+            dest: Endpoint::new(addr.clone()),
+        })
+        .await
+        .unwrap();
+
+        sess.send(msg.as_bytes()).await.unwrap();
+
+        let mut buf = vec![0; 1024];
+        timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let info = sess.info(0);
+        assert_eq!(None, info.listener);
+        assert_eq!(source, info.source);
+        assert_eq!(addr, info.dest);
+        assert!(info.created_at > 0);
+
+        let expected = TrafficInfo {
+            packets: 1,
+            bytes: msg.len() as u64,
+        };
+        assert_eq!(expected, info.read);
+        assert_eq!(expected, info.write);
+    }
+
     #[tokio::test]
     async fn process_recv_packet() {
         crate::test_utils::load_test_filters();
@@ -341,6 +680,7 @@ mod tests {
             &socket,
             ReceivedPacketContext {
                 config: <_>::default(),
+                listener: None,
                 packet: msg.as_bytes(),
                 endpoint: &endpoint,
                 source: endpoint.address.clone(),
@@ -364,6 +704,7 @@ mod tests {
             &socket,
             ReceivedPacketContext {
                 config,
+                listener: None,
                 packet: msg.as_bytes(),
                 endpoint: &endpoint,
                 source: endpoint.address.clone(),
@@ -49,6 +49,40 @@ pub(crate) fn total_sessions() -> &'static IntCounter {
     &TOTAL_SESSIONS
 }
 
+pub(crate) fn draining() -> &'static IntGauge {
+    static DRAINING: Lazy<IntGauge> = Lazy::new(|| {
+        register(
+            IntGauge::with_opts(
+                Opts::new(
+                    "draining",
+                    "whether the proxy is draining sessions before shutting down",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &DRAINING
+}
+
+pub(crate) fn drain_remaining() -> &'static IntGauge {
+    static DRAIN_REMAINING: Lazy<IntGauge> = Lazy::new(|| {
+        register(
+            IntGauge::with_opts(
+                Opts::new(
+                    "drain_remaining",
+                    "number of sessions remaining to be drained before shutting down",
+                )
+                .subsystem(SUBSYSTEM),
+            )
+            .unwrap(),
+        )
+    });
+
+    &DRAIN_REMAINING
+}
+
 pub(crate) fn duration_secs() -> &'static Histogram {
     static DURATION_SECS: Lazy<Histogram> = Lazy::new(|| {
         register(
@@ -0,0 +1,481 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Exports `tracing` spans and Prometheus metrics to an [OpenTelemetry]
+//! collector with OTLP.
+//!
+//! [OpenTelemetry]: https://opentelemetry.io/
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::{
+    propagation::{Extractor, Injector},
+    trace::TraceContextExt,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_proto::tonic::{
+    collector::metrics::v1::{
+        metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest,
+    },
+    common::v1 as common,
+    metrics::v1 as otlp,
+    resource::v1 as resource,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::Sampler, Resource};
+use prometheus::proto::{MetricFamily, MetricType};
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The configuration of the OTLP exporter.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Otlp {
+    /// The OTLP gRPC endpoint of an OpenTelemetry collector, which spans and
+    /// metrics are exported to. Nothing is exported if not set.
+    #[clap(long = "otlp-endpoint", env = "QUILKIN_OTLP_ENDPOINT")]
+    pub endpoint: Option<String>,
+    /// The ratio of traces that are sampled, from `0.0` to `1.0`. The xDS
+    /// streams between proxies and management servers carry the W3C trace
+    /// context, and traces continued from the other side of a stream follow
+    /// the sampling decision of their parent.
+    #[clap(
+        long = "otlp-sampling-ratio",
+        env = "QUILKIN_OTLP_SAMPLING_RATIO",
+        default_value_t = 1.0
+    )]
+    pub sampling_ratio: f64,
+    /// How often metrics are exported, in seconds.
+    #[clap(
+        long = "otlp-metrics-interval",
+        env = "QUILKIN_OTLP_METRICS_INTERVAL",
+        default_value_t = 60
+    )]
+    pub metrics_interval: u64,
+    /// Additional resource attributes describing this instance, as `key=value`
+    /// pairs, e.g. `cloud.region=europe-west1`.
+    #[clap(
+        long = "otlp-resource-attribute",
+        env = "QUILKIN_OTLP_RESOURCE_ATTRIBUTES",
+        value_delimiter = ','
+    )]
+    pub resource_attributes: Vec<ResourceAttribute>,
+}
+
+/// A `key=value` attribute of the resource that telemetry is exported for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceAttribute {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for ResourceAttribute {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                key: key.trim().into(),
+                value: value.trim().into(),
+            }),
+            _ => Err(format!("`{input}` is not a `key=value` pair")),
+        }
+    }
+}
+
+impl Otlp {
+    /// Starts exporting spans and metrics, if an endpoint has been set.
+    /// `id` is the ID of this instance, which is added to the resource
+    /// attributes along with the service's name and version.
+    pub(crate) fn start(&self, id: &str) -> crate::Result<()> {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let Some(endpoint) = &self.endpoint else {
+            return Ok(());
+        };
+
+        if !(0.0..=1.0).contains(&self.sampling_ratio) {
+            return Err(eyre::eyre!(
+                "the OTLP sampling ratio must be between 0.0 and 1.0, got {}",
+                self.sampling_ratio
+            ));
+        }
+
+        let attributes = self.resource_attributes(id);
+
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                opentelemetry_sdk::trace::config()
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        self.sampling_ratio,
+                    ))))
+                    .with_resource(Resource::new(attributes.clone())),
+            )
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+        crate::logging::set_tracer(tracer)?;
+
+        // Metrics are exported with the OTLP client directly, as they're
+        // gathered from the Prometheus registry rather than recorded with
+        // OpenTelemetry instruments.
+        let mut channel = tonic::transport::Endpoint::from_shared(endpoint.clone())?;
+        if channel.uri().scheme_str() == Some("https") {
+            channel = channel.tls_config(tonic::transport::ClientTlsConfig::new())?;
+        }
+        let resource = resource::Resource {
+            attributes: attributes
+                .into_iter()
+                .map(|attribute| key_value(attribute.key.as_str(), attribute.value.to_string()))
+                .collect(),
+            dropped_attributes_count: 0,
+        };
+
+        let period = Duration::from_secs(self.metrics_interval.max(1));
+        let start_time = SystemTime::now();
+        tokio::spawn(async move {
+            let mut client = MetricsServiceClient::new(channel.connect_lazy());
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let request = ExportMetricsServiceRequest {
+                    resource_metrics: vec![resource_metrics(
+                        resource.clone(),
+                        start_time,
+                        &crate::metrics::registry().gather(),
+                    )],
+                };
+                if let Err(error) = client.export(request).await {
+                    tracing::warn!(%error, "Failed to export metrics");
+                }
+            }
+        });
+
+        tracing::info!(%endpoint, "Exporting telemetry with OTLP");
+        Ok(())
+    }
+
+    fn resource_attributes(&self, id: &str) -> Vec<KeyValue> {
+        [
+            KeyValue::new("service.name", "quilkin"),
+            KeyValue::new("service.version", clap::crate_version!()),
+            KeyValue::new("service.instance.id", id.to_owned()),
+        ]
+        .into_iter()
+        .chain(
+            self.resource_attributes
+                .iter()
+                .map(|attribute| KeyValue::new(attribute.key.clone(), attribute.value.clone())),
+        )
+        .collect()
+    }
+}
+
+/// Flushes any spans which haven't been exported yet.
+pub(crate) async fn shutdown() {
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+        .await
+        .ok();
+}
+
+/// Adds the trace context of the current span to the metadata of a gRPC
+/// request or response, so that the other side can continue the trace.
+pub(crate) fn inject(metadata: &mut MetadataMap) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(metadata))
+    });
+}
+
+/// Continues the trace of the other side of a gRPC request or response in
+/// `span`, if its metadata carries a trace context.
+pub(crate) fn set_parent(span: &tracing::Span, metadata: &MetadataMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(metadata))
+    });
+
+    if context.span().span_context().is_valid() {
+        span.set_parent(context);
+    }
+}
+
+struct MetadataInjector<'metadata>(&'metadata mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'metadata>(&'metadata MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Converts metrics gathered from a Prometheus registry into OpenTelemetry
+/// metrics. Counters become monotonic sums, which like histograms are
+/// cumulative since `start_time`, and summaries, which Quilkin doesn't use,
+/// are skipped.
+fn resource_metrics(
+    resource: resource::Resource,
+    start_time: SystemTime,
+    families: &[MetricFamily],
+) -> otlp::ResourceMetrics {
+    let start_time_unix_nano = unix_nanos(start_time);
+    let time_unix_nano = unix_nanos(SystemTime::now());
+    let cumulative = otlp::AggregationTemporality::Cumulative as i32;
+
+    let metrics = families
+        .iter()
+        .filter_map(|family| {
+            let attributes = |metric: &prometheus::proto::Metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .map(|label| key_value(label.get_name(), label.get_value().to_owned()))
+                    .collect::<Vec<_>>()
+            };
+            let data_points = |value: fn(&prometheus::proto::Metric) -> f64| {
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| otlp::NumberDataPoint {
+                        attributes: attributes(metric),
+                        start_time_unix_nano,
+                        time_unix_nano,
+                        value: Some(otlp::number_data_point::Value::AsDouble(value(metric))),
+                        ..<_>::default()
+                    })
+                    .collect()
+            };
+
+            let data = match family.get_field_type() {
+                MetricType::COUNTER => otlp::metric::Data::Sum(otlp::Sum {
+                    data_points: data_points(|metric| metric.get_counter().get_value()),
+                    aggregation_temporality: cumulative,
+                    is_monotonic: true,
+                }),
+                MetricType::GAUGE => otlp::metric::Data::Gauge(otlp::Gauge {
+                    data_points: data_points(|metric| metric.get_gauge().get_value()),
+                }),
+                MetricType::UNTYPED => otlp::metric::Data::Gauge(otlp::Gauge {
+                    data_points: data_points(|metric| metric.get_untyped().get_value()),
+                }),
+                MetricType::HISTOGRAM => otlp::metric::Data::Histogram(otlp::Histogram {
+                    data_points: family
+                        .get_metric()
+                        .iter()
+                        .map(|metric| {
+                            let histogram = metric.get_histogram();
+                            otlp::HistogramDataPoint {
+                                attributes: attributes(metric),
+                                start_time_unix_nano,
+                                time_unix_nano,
+                                count: histogram.get_sample_count(),
+                                sum: Some(histogram.get_sample_sum()),
+                                bucket_counts: bucket_counts(histogram),
+                                explicit_bounds: histogram
+                                    .get_bucket()
+                                    .iter()
+                                    .map(|bucket| bucket.get_upper_bound())
+                                    .collect(),
+                                ..<_>::default()
+                            }
+                        })
+                        .collect(),
+                    aggregation_temporality: cumulative,
+                }),
+                MetricType::SUMMARY => return None,
+            };
+
+            Some(otlp::Metric {
+                name: family.get_name().to_owned(),
+                description: family.get_help().to_owned(),
+                data: Some(data),
+                ..<_>::default()
+            })
+        })
+        .collect();
+
+    otlp::ResourceMetrics {
+        resource: Some(resource),
+        scope_metrics: vec![otlp::ScopeMetrics {
+            scope: Some(common::InstrumentationScope {
+                name: "quilkin".into(),
+                version: clap::crate_version!().into(),
+                ..<_>::default()
+            }),
+            metrics,
+            ..<_>::default()
+        }],
+        ..<_>::default()
+    }
+}
+
+fn key_value(key: &str, value: String) -> common::KeyValue {
+    common::KeyValue {
+        key: key.to_owned(),
+        value: Some(common::AnyValue {
+            value: Some(common::any_value::Value::StringValue(value)),
+        }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Prometheus buckets count every observation up to their bound, whereas
+/// OpenTelemetry buckets only count the observations since the previous
+/// bound, and have an extra bucket for observations above the last bound.
+fn bucket_counts(histogram: &prometheus::proto::Histogram) -> Vec<u64> {
+    let mut previous = 0;
+    let mut counts = histogram
+        .get_bucket()
+        .iter()
+        .map(|bucket| {
+            let count = bucket.get_cumulative_count() - previous;
+            previous = bucket.get_cumulative_count();
+            count
+        })
+        .collect::<Vec<_>>();
+    counts.push(histogram.get_sample_count() - previous);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{HistogramOpts, IntCounterVec, Opts, Registry};
+
+    use super::*;
+
+    #[test]
+    fn resource_attribute() {
+        assert_eq!(
+            ResourceAttribute {
+                key: "cloud.region".into(),
+                value: "europe-west1".into()
+            },
+            "cloud.region=europe-west1".parse().unwrap()
+        );
+        assert!("europe-west1".parse::<ResourceAttribute>().is_err());
+        assert!("=europe-west1".parse::<ResourceAttribute>().is_err());
+    }
+
+    #[test]
+    fn propagate_context() {
+        use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let span_context = SpanContext::new(
+            TraceId::from_bytes(1u128.to_be_bytes()),
+            SpanId::from_bytes(2u64.to_be_bytes()),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let context = opentelemetry::Context::new().with_remote_span_context(span_context);
+
+        let mut metadata = MetadataMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut MetadataInjector(&mut metadata))
+        });
+        assert_eq!(
+            "00-00000000000000000000000000000001-0000000000000002-01",
+            metadata.get("traceparent").unwrap().to_str().unwrap()
+        );
+
+        let extracted = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&MetadataExtractor(&metadata))
+        });
+        assert_eq!(
+            TraceId::from_bytes(1u128.to_be_bytes()),
+            extracted.span().span_context().trace_id()
+        );
+        assert!(extracted.span().span_context().is_sampled());
+    }
+
+    #[test]
+    fn convert_metrics() {
+        let registry = Registry::new_custom(Some("quilkin".into()), None).unwrap();
+        let counter =
+            IntCounterVec::new(Opts::new("packets_total", "packets"), &["event"]).unwrap();
+        let histogram = prometheus::Histogram::with_opts(
+            HistogramOpts::new("duration_seconds", "duration").buckets(vec![1.0, 2.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.with_label_values(&["read"]).inc_by(3);
+        for value in [0.5, 0.5, 1.5, 5.0] {
+            histogram.observe(value);
+        }
+
+        let metrics = resource_metrics(<_>::default(), SystemTime::now(), &registry.gather());
+        let metrics = &metrics.scope_metrics[0].metrics;
+        assert_eq!(2, metrics.len());
+
+        let duration = metrics
+            .iter()
+            .find(|metric| metric.name == "quilkin_duration_seconds")
+            .unwrap();
+        let Some(otlp::metric::Data::Histogram(duration)) = &duration.data else {
+            panic!("expected a histogram, got {:?}", duration.data);
+        };
+        assert_eq!(4, duration.data_points[0].count);
+        assert_eq!(vec![1.0, 2.0], duration.data_points[0].explicit_bounds);
+        assert_eq!(vec![2, 1, 1], duration.data_points[0].bucket_counts);
+        assert_eq!(Some(7.5), duration.data_points[0].sum);
+
+        let packets = metrics
+            .iter()
+            .find(|metric| metric.name == "quilkin_packets_total")
+            .unwrap();
+        let Some(otlp::metric::Data::Sum(packets)) = &packets.data else {
+            panic!("expected a sum, got {:?}", packets.data);
+        };
+        assert!(packets.is_monotonic);
+        assert_eq!(
+            Some(otlp::number_data_point::Value::AsDouble(3.0)),
+            packets.data_points[0].value
+        );
+        assert_eq!(1, packets.data_points[0].attributes.len());
+    }
+}
@@ -68,7 +68,7 @@ impl Filter for TestFilter {
             .or_insert_with(|| Value::String("receive".into()));
 
         ctx.contents
-            .append(&mut format!(":odr:{}", ctx.source).into_bytes());
+            .extend_from_slice(format!(":odr:{}", ctx.source).as_bytes());
         Some(())
     }
 
@@ -80,7 +80,7 @@ impl Filter for TestFilter {
             .or_insert_with(|| Value::String("receive".to_string()));
 
         ctx.contents
-            .append(&mut format!(":our:{}:{}", ctx.source, ctx.dest).into_bytes());
+            .extend_from_slice(format!(":our:{}:{}", ctx.source, ctx.dest).as_bytes());
         Some(())
     }
 }
@@ -93,6 +93,10 @@ impl StaticFilter for TestFilter {
     fn try_from_config(_: Option<Self::Configuration>) -> Result<Self, Error> {
         Ok(Self)
     }
+
+    fn validate_config(_: Option<&Self::Configuration>) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 #[derive(Default)]
@@ -231,8 +235,9 @@ impl TestHelper {
 
         if let Some(address) = with_admin {
             tokio::spawn(crate::admin::server(
-                crate::admin::Mode::Proxy,
+                crate::admin::Mode::Proxy(server.sessions.clone(), server.capture_dir.clone()),
                 config.clone(),
+                shutdown_rx.clone(),
                 address,
             ));
         }
@@ -310,6 +315,7 @@ pub fn config_with_dummy_endpoint() -> Config {
                     ..<_>::default()
                 }]
                 .into(),
+                weight: None,
             }]
             .into_iter()
             .collect(),
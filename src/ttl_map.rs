@@ -15,6 +15,7 @@
  */
 
 use dashmap::mapref::entry::Entry as DashMapEntry;
+use dashmap::mapref::multiple::RefMulti;
 use dashmap::mapref::one::{Ref, RefMut};
 use dashmap::DashMap;
 use tracing::warn;
@@ -23,7 +24,10 @@ use std::hash::Hash;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::oneshot::{channel, Receiver, Sender};
+use tokio::sync::{
+    oneshot::{channel, Receiver, Sender},
+    watch,
+};
 
 pub use dashmap::try_result::TryResult;
 
@@ -38,6 +42,7 @@ use tokio::time::Instant;
 pub struct Value<V> {
     pub value: V,
     expires_at: Arc<AtomicU64>,
+    ttl_secs: AtomicU64,
     clock: Clock,
 }
 
@@ -46,16 +51,40 @@ impl<V> Value<V> {
         let value = Value {
             value,
             expires_at: Arc::new(AtomicU64::new(0)),
+            ttl_secs: AtomicU64::new(ttl.as_secs()),
             clock,
         };
         value.update_expiration(ttl);
         value
     }
 
+    /// Returns the value's TTL.
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs.load(Ordering::Relaxed))
+    }
+
+    /// Updates the value's TTL, moving its expiration time by the difference
+    /// between the previous and the new TTL.
+    fn set_ttl(&self, ttl: Duration) {
+        let ttl = ttl.as_secs();
+        let previous = self.ttl_secs.swap(ttl, Ordering::Relaxed);
+        // The closure always returns `Some`, so this can't fail.
+        let _ = self
+            .expires_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |expires_at| {
+                Some(expires_at.saturating_sub(previous).saturating_add(ttl))
+            });
+    }
+
+    /// Resets the value's expiration time to (now + its TTL).
+    fn reset_expiration(&self) {
+        self.update_expiration(self.ttl());
+    }
+
     /// Get the expiration time for this value. The returned value is the
     /// number of seconds relative to some reference point (e.g UNIX_EPOCH), based
     /// on the clock being used.
-    fn expiration_secs(&self) -> u64 {
+    pub fn expiration_secs(&self) -> u64 {
         self.expires_at.load(Ordering::Relaxed)
     }
 
@@ -84,11 +113,18 @@ impl<V> std::ops::Deref for Value<V> {
 /// Map contains the hash map implementation.
 struct Map<K, V> {
     inner: DashMap<K, Value<V>>,
-    ttl: Duration,
+    ttl_secs: AtomicU64,
+    poll_interval_tx: watch::Sender<Duration>,
     clock: Clock,
     shutdown_tx: Option<Sender<()>>,
 }
 
+impl<K, V> Map<K, V> {
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs.load(Ordering::Relaxed))
+    }
+}
+
 impl<K, V> Drop for Map<K, V> {
     fn drop(&mut self) {
         if let Some(shutdown_tx) = self.shutdown_tx.take() {
@@ -101,6 +137,8 @@ impl<K, V> Drop for Map<K, V> {
 /// When the TTL for an entry elapses, the entry is removed from the map.
 /// The TTL is reset each time the entry is (re)inserted or read via [`TtlMap::get`],
 /// [`TtlMap::get_mut`] functions, or via the [`TtlMap::entry`] interface.
+/// Entries can have their own TTL, and the TTL of existing entries can be
+/// changed with [`TtlMap::set_ttl`] and [`TtlMap::update_ttl`].
 /// During tests, the internal clock implementation is driven by [`tokio::time`] so
 /// functions like [`tokio::time::pause`] and [`tokio::time::advance`] can be used.
 pub struct TtlMap<K, V>(Arc<Map<K, V>>);
@@ -121,21 +159,38 @@ where
 
     fn initialize(inner: DashMap<K, Value<V>>, ttl: Duration, poll_interval: Duration) -> Self {
         let (shutdown_tx, shutdown_rx) = channel();
+        let (poll_interval_tx, poll_interval_rx) = watch::channel(poll_interval);
         let map = TtlMap(Arc::new(Map {
             inner,
             shutdown_tx: Some(shutdown_tx),
-            ttl,
+            ttl_secs: AtomicU64::new(ttl.as_secs()),
+            poll_interval_tx,
             clock: Clock::new(),
         }));
         spawn_cleanup_task(
             map.0.clone(),
-            poll_interval,
+            poll_interval_rx,
             map.0.clock.clone(),
             shutdown_rx,
         );
         map
     }
 
+    /// Changes how often the map is checked for expired entries,
+    /// `poll_interval` must be non-zero.
+    pub fn set_poll_interval(&self, poll_interval: Duration) {
+        self.0.poll_interval_tx.send_if_modified(|current| {
+            let modified = *current != poll_interval;
+            *current = poll_interval;
+            modified
+        });
+    }
+
+    /// Returns whether `self` and `other` are the same map.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
     /// Returns the current time as the number of seconds relative to some initial
     /// reference point (e.g UNIX_EPOCH), based on the clock implementation being used.
     /// In tests, this will be driven by [`tokio::time`]
@@ -154,7 +209,7 @@ where
     pub fn get(&self, key: &K) -> Option<Ref<K, Value<V>>> {
         let value = self.0.inner.get(key);
         if let Some(ref value) = value {
-            value.update_expiration(self.0.ttl)
+            value.reset_expiration()
         }
 
         value
@@ -164,7 +219,7 @@ where
     pub fn try_get(&self, key: &K) -> TryResult<Ref<K, Value<V>>> {
         let value = self.0.inner.try_get(key);
         if let TryResult::Present(ref value) = value {
-            value.update_expiration(self.0.ttl)
+            value.reset_expiration()
         }
 
         value
@@ -175,7 +230,7 @@ where
     pub fn get_mut(&self, key: &K) -> Option<RefMut<K, Value<V>>> {
         let value = self.0.inner.get_mut(key);
         if let Some(ref value) = value {
-            value.update_expiration(self.0.ttl);
+            value.reset_expiration();
         }
 
         value
@@ -187,6 +242,28 @@ where
         self.0.inner.len()
     }
 
+    /// Removes all of the entries that have expired, without waiting for the
+    /// next cleanup interval.
+    pub fn remove_expired(&self) {
+        prune_entries(&self.0, &self.0.clock);
+    }
+
+    /// Returns an iterator over the entries in the map.
+    /// Unlike [`TtlMap::get`], this does not reset the expiration of the entries.
+    pub fn iter(&self) -> impl Iterator<Item = RefMulti<'_, K, Value<V>>> {
+        self.0.inner.iter()
+    }
+
+    /// Removes an entry from the map, returning its value if it was present.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.0.inner.remove(key).map(|(_, value)| value.value)
+    }
+
+    /// Removes all of the entries from the map.
+    pub fn clear(&self) {
+        self.0.inner.clear();
+    }
+
     /// Returns true if the map contains a value for the specified key.
     pub fn contains_key(&self, key: &K) -> bool {
         self.0.inner.contains_key(key)
@@ -196,17 +273,47 @@ where
     /// The value will be set to expire at the configured TTL after the time of insertion.
     /// If a previous value existed for this key, that value is returned.
     pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.insert_with_ttl(key, value, self.0.ttl())
+    }
+
+    /// Inserts a key-value pair into the map, with its own `ttl` instead of
+    /// the configured TTL.
+    /// If a previous value existed for this key, that value is returned.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
         self.0
             .inner
-            .insert(key, Value::new(value, self.0.ttl, self.0.clock.clone()))
+            .insert(key, Value::new(value, ttl, self.0.clock.clone()))
             .map(|value| value.value)
     }
 
+    /// Returns the TTL of entries inserted with [`TtlMap::insert`].
+    pub fn ttl(&self) -> Duration {
+        self.0.ttl()
+    }
+
+    /// Sets the TTL of entries inserted with [`TtlMap::insert`], and updates
+    /// the TTL of all existing entries to `ttl`.
+    pub fn set_ttl(&self, ttl: Duration) {
+        self.update_ttl(ttl, |_, _| ttl);
+    }
+
+    /// Sets the TTL of entries inserted with [`TtlMap::insert`] to `ttl`, and
+    /// updates the TTL of each existing entry to the value returned by
+    /// `entry_ttl`. An existing entry's expiration time is moved by the
+    /// difference between its previous and its new TTL, so an entry that has
+    /// been idle for longer than its new TTL is removed on the next cleanup.
+    pub fn update_ttl(&self, ttl: Duration, entry_ttl: impl Fn(&K, &V) -> Duration) {
+        self.0.ttl_secs.store(ttl.as_secs(), Ordering::Relaxed);
+        for entry in self.0.inner.iter() {
+            entry.set_ttl(entry_ttl(entry.key(), &entry.value));
+        }
+    }
+
     /// Returns an entry for in-place updates of the specified key-value pair.
     /// Note: This acquires a write lock on the map's shard that corresponds
     /// to the entry.
     pub fn entry(&self, key: K) -> Entry<K, Value<V>> {
-        let ttl = self.0.ttl;
+        let ttl = self.0.ttl();
         match self.0.inner.entry(key) {
             inner @ DashMapEntry::Occupied(_) => Entry::Occupied(OccupiedEntry {
                 inner,
@@ -271,7 +378,7 @@ where
         match &self.inner {
             DashMapEntry::Occupied(entry) => {
                 let value = entry.get();
-                value.update_expiration(self.ttl);
+                value.reset_expiration();
                 value
             }
             _ => unreachable!("BUG: entry type should be occupied"),
@@ -285,7 +392,7 @@ where
         match &mut self.inner {
             DashMapEntry::Occupied(entry) => {
                 let value = entry.get_mut();
-                value.update_expiration(self.ttl);
+                value.reset_expiration();
                 value
             }
             _ => unreachable!("BUG: entry type should be occupied"),
@@ -323,20 +430,23 @@ where
 
 fn spawn_cleanup_task<K, V>(
     map: Arc<Map<K, V>>,
-    poll_interval: Duration,
+    mut poll_interval_rx: watch::Receiver<Duration>,
     clock: Clock,
     mut shutdown_rx: Receiver<()>,
 ) where
     K: Send + Sync + Hash + Eq + 'static,
     V: Send + Sync + 'static,
 {
-    let mut interval = tokio::time::interval(poll_interval);
+    let mut interval = tokio::time::interval(*poll_interval_rx.borrow());
 
     tokio::spawn(async move {
         loop {
             tokio::select! {
                 _ = interval.tick() => {
-                    prune_entries( &map, &clock).await;
+                    prune_entries(&map, &clock);
+                }
+                Ok(()) = poll_interval_rx.changed() => {
+                    interval = tokio::time::interval(*poll_interval_rx.borrow());
                 }
                 _ = &mut shutdown_rx => {
                     return;
@@ -346,10 +456,10 @@ fn spawn_cleanup_task<K, V>(
     });
 }
 
-async fn prune_entries<K, V>(map: &Arc<Map<K, V>>, clock: &Clock)
+fn prune_entries<K, V>(map: &Arc<Map<K, V>>, clock: &Clock)
 where
     K: Hash + Eq + Send + Sync + 'static,
-    V: Send + Sync + 'static,
+    V: Send + Sync,
 {
     let now_secs = if let Ok(now_secs) = clock.now_relative_secs() {
         now_secs
@@ -736,4 +846,108 @@ mod tests {
         assert!(!map.contains_key(&two));
         assert_eq!(map.len(), 0);
     }
+
+    #[tokio::test]
+    async fn iter_and_remove() {
+        time::pause();
+
+        let (one, two) = address_pair();
+
+        let map =
+            TtlMap::<EndpointAddress, usize>::new(Duration::from_secs(5), Duration::from_secs(1));
+        map.insert(one.clone(), 1);
+        map.insert(two.clone(), 2);
+
+        let mut values = map.iter().map(|entry| entry.value).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(vec![1, 2], values);
+
+        // Iterating shouldn't reset the expiration of entries.
+        let expires_at = map.iter().next().unwrap().expiration_secs();
+        time::advance(Duration::from_secs(3)).await;
+        assert!(map
+            .iter()
+            .all(|entry| entry.expiration_secs() == expires_at));
+
+        assert_eq!(Some(1), map.remove(&one));
+        assert_eq!(None, map.remove(&one));
+        assert!(!map.contains_key(&one));
+        assert_eq!(map.len(), 1);
+
+        map.clear();
+        assert!(!map.contains_key(&two));
+        assert_eq!(map.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn insert_with_ttl() {
+        time::pause();
+
+        let (one, two) = address_pair();
+
+        let map =
+            TtlMap::<EndpointAddress, usize>::new(Duration::from_secs(5), Duration::from_secs(1));
+        map.insert(one.clone(), 1);
+        map.insert_with_ttl(two.clone(), 2, Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(10), map.get(&two).unwrap().ttl());
+
+        time::advance(Duration::from_secs(6)).await;
+        assert!(!map.contains_key(&one));
+
+        // Reading the entry resets its expiration with its own TTL.
+        assert!(map.get(&two).is_some());
+        time::advance(Duration::from_secs(8)).await;
+        assert!(map.contains_key(&two));
+    }
+
+    #[tokio::test]
+    async fn set_ttl_updates_existing_entries() {
+        time::pause();
+
+        let (one, two) = address_pair();
+
+        let map =
+            TtlMap::<EndpointAddress, usize>::new(Duration::from_secs(5), Duration::from_secs(1));
+        map.insert(one.clone(), 1);
+        map.insert(two.clone(), 2);
+
+        time::advance(Duration::from_secs(3)).await;
+        map.set_ttl(Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(10), map.ttl());
+
+        // Both entries have been idle for 3 of their new 10 seconds.
+        time::advance(Duration::from_secs(4)).await;
+        assert!(map.contains_key(&one));
+        assert!(map.contains_key(&two));
+
+        // Shortening the TTL removes entries that have been idle for longer.
+        map.update_ttl(Duration::from_secs(10), |key, _| {
+            if *key == one {
+                Duration::from_secs(2)
+            } else {
+                Duration::from_secs(10)
+            }
+        });
+        time::advance(Duration::from_secs(2)).await;
+        assert!(!map.contains_key(&one));
+        assert!(map.contains_key(&two));
+
+        time::advance(Duration::from_secs(2)).await;
+        assert!(!map.contains_key(&two));
+    }
+
+    #[tokio::test]
+    async fn set_poll_interval() {
+        time::pause();
+
+        let (one, _) = address_pair();
+
+        let map =
+            TtlMap::<EndpointAddress, usize>::new(Duration::from_secs(1), Duration::from_secs(60));
+        map.insert(one.clone(), 1);
+
+        map.set_poll_interval(Duration::from_secs(1));
+        time::advance(Duration::from_secs(3)).await;
+        assert!(!map.contains_key(&one));
+    }
 }
@@ -14,13 +14,27 @@
  * limitations under the License.
  */
 
+mod batch;
+
 use crate::Result;
 use socket2::{Protocol, Socket, Type};
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+};
 use tokio::net::UdpSocket;
 
+pub use self::batch::{send_batch, Gso, RecvBatch, DEFAULT_BATCH_SIZE, DEFAULT_MAX_DATAGRAM_SIZE};
+
 /// returns a UdpSocket with address and port reuse.
 pub fn socket_with_reuse(addr: SocketAddr) -> Result<UdpSocket> {
+    socket_with_reuse_and_ipv6_only(addr, false)
+}
+
+/// returns a UdpSocket with address and port reuse. If `addr` is an IPv6
+/// address, `ipv6_only` controls whether the socket will also accept IPv4
+/// traffic (dual-stack), which is received as IPv4-mapped IPv6 addresses.
+pub fn socket_with_reuse_and_ipv6_only(addr: SocketAddr, ipv6_only: bool) -> Result<UdpSocket> {
     let sock = Socket::new(
         match addr {
             SocketAddr::V4(_) => socket2::Domain::IPV4,
@@ -30,12 +44,46 @@ pub fn socket_with_reuse(addr: SocketAddr) -> Result<UdpSocket> {
         Some(Protocol::UDP),
     )?;
     enable_reuse(&sock)?;
+    if addr.is_ipv6() {
+        sock.set_only_v6(ipv6_only)?;
+    }
     sock.set_nonblocking(true)?;
     sock.bind(&addr.into())?;
 
     UdpSocket::from_std(sock.into()).map_err(|error| eyre::eyre!(error))
 }
 
+/// Converts an IPv4-mapped IPv6 address (e.g. `::ffff:127.0.0.1`), such as the
+/// ones received on a dual-stack socket, back into its IPv4 form. All other
+/// addresses are returned as-is.
+pub fn to_canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => v6
+            .ip()
+            .to_ipv4_mapped()
+            .map(|ip| SocketAddr::from((ip, v6.port())))
+            .unwrap_or(addr),
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Same as [`to_canonical`] but for IP addresses.
+pub fn to_canonical_ip(ip: IpAddr) -> IpAddr {
+    to_canonical((ip, 0).into()).ip()
+}
+
+/// Converts `addr` into a form that can be used as the destination of a
+/// socket bound to `local`, mapping IPv4 addresses to IPv4-mapped IPv6
+/// addresses when sending from an IPv6 socket.
+pub fn to_socket_family(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr, local) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => {
+            SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0).into()
+        }
+        _ => addr,
+    }
+}
+
 #[cfg(not(target_family = "windows"))]
 fn enable_reuse(sock: &Socket) -> io::Result<()> {
     sock.set_reuse_port(true)?;
@@ -50,6 +98,8 @@ fn enable_reuse(sock: &Socket) -> io::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
     use crate::test_utils::available_addr;
 
     #[tokio::test]
@@ -65,4 +115,47 @@ mod tests {
         let addr2 = socket.local_addr().unwrap();
         assert_eq!(addr, addr2);
     }
+
+    #[tokio::test]
+    async fn dual_stack_socket() {
+        let socket =
+            super::socket_with_reuse_and_ipv6_only((Ipv6Addr::UNSPECIFIED, 0).into(), false)
+                .unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let client = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        client
+            .send_to(b"hello", (Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 5];
+        let (_, source) = socket.recv_from(&mut buf).await.unwrap();
+        assert!(source.is_ipv6());
+        assert_eq!(client.local_addr().unwrap(), super::to_canonical(source));
+    }
+
+    #[test]
+    fn to_canonical() {
+        let v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:8080".parse().unwrap();
+        let v6: SocketAddr = "[::1]:8080".parse().unwrap();
+
+        assert_eq!(v4, super::to_canonical(mapped));
+        assert_eq!(v4, super::to_canonical(v4));
+        assert_eq!(v6, super::to_canonical(v6));
+    }
+
+    #[test]
+    fn to_socket_family() {
+        let v4: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:8080".parse().unwrap();
+        let v4_local: SocketAddr = "0.0.0.0:7777".parse().unwrap();
+        let v6_local: SocketAddr = "[::]:7777".parse().unwrap();
+
+        assert_eq!(mapped, super::to_socket_family(v4, v6_local));
+        assert_eq!(v4, super::to_socket_family(v4, v4_local));
+    }
 }
@@ -0,0 +1,657 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Batched sending and receiving of UDP datagrams. On Linux, datagrams are
+//! received with `recvmmsg(2)`, and sent with UDP GSO or `sendmmsg(2)`. On
+//! other platforms the socket is drained with one system call per datagram.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::net::UdpSocket;
+
+use crate::pool::{BufferPool, PacketBuffer};
+
+/// The default maximum number of datagrams handled in a single batch.
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// The default maximum size of a received datagram, which is the most common
+/// MTU, so any datagram that wasn't fragmented fits.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// A reusable set of buffers for receiving a batch of datagrams. Datagrams
+/// are received directly into buffers from a [`BufferPool`], which are taken
+/// out of the batch, and replaced by the next call to [`RecvBatch::recv`].
+pub struct RecvBatch {
+    pool: Arc<BufferPool>,
+    max_datagram_size: usize,
+    buffers: Vec<PacketBuffer>,
+    /// The index of the buffer and the source of each datagram received.
+    received: Vec<(usize, SocketAddr)>,
+    /// The number of datagrams dropped for being larger than the buffers.
+    truncated: usize,
+    #[cfg(target_os = "linux")]
+    headers: linux::RecvHeaders,
+}
+
+impl RecvBatch {
+    /// Creates a batch that can receive up to `size` datagrams of up to
+    /// `max_datagram_size` bytes at once, larger datagrams are dropped.
+    pub fn new(size: usize, max_datagram_size: usize, pool: Arc<BufferPool>) -> Self {
+        let size = size.max(1);
+        // Outside of Linux, a datagram is only known to be too large when it
+        // fills a buffer with room for one more byte.
+        let max_datagram_size = if cfg!(target_os = "linux") {
+            max_datagram_size
+        } else {
+            max_datagram_size + 1
+        };
+
+        Self {
+            pool,
+            max_datagram_size,
+            buffers: vec![PacketBuffer::default(); size],
+            received: Vec::with_capacity(size),
+            truncated: 0,
+            #[cfg(target_os = "linux")]
+            headers: linux::RecvHeaders::new(size),
+        }
+    }
+
+    /// Waits until at least one datagram is available on `socket`, then
+    /// receives as many datagrams as are available, up to the batch's size.
+    /// Returns the number of datagrams received, which doesn't include the
+    /// ones dropped for being too large.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        self.truncated = 0;
+        for buffer in &mut self.buffers {
+            buffer.clear();
+            if buffer.spare_capacity() < self.max_datagram_size {
+                *buffer = self.pool.alloc_empty(self.max_datagram_size);
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            linux::io(socket, tokio::io::Interest::READABLE, |fd| {
+                linux::recv_mmsg(fd, self)
+            })
+            .await?;
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            for buffer in &mut self.buffers {
+                buffer.resize(self.max_datagram_size, 0);
+            }
+
+            let (size, source) = socket.recv_from(&mut self.buffers[0]).await?;
+            self.push(0, size, source);
+
+            // Any errors will be returned by the next call to `recv`.
+            for index in 1..self.buffers.len() {
+                match socket.try_recv_from(&mut self.buffers[index]) {
+                    Ok((size, source)) => self.push(index, size, source),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        Ok(self.received.len())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn push(&mut self, index: usize, size: usize, source: SocketAddr) {
+        if size == self.max_datagram_size {
+            self.truncated += 1;
+        } else {
+            self.buffers[index].truncate(size);
+            self.received.push((index, source));
+        }
+    }
+
+    /// Returns the contents and source address of each datagram received by
+    /// the last call to [`RecvBatch::recv`].
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+        self.received
+            .iter()
+            .map(|(index, source)| (&*self.buffers[*index], *source))
+    }
+
+    /// Takes the buffer and source address of each datagram received by the
+    /// last call to [`RecvBatch::recv`] out of the batch.
+    pub fn drain(&mut self) -> impl Iterator<Item = (PacketBuffer, SocketAddr)> + '_ {
+        let buffers = &mut self.buffers;
+        self.received
+            .drain(..)
+            .map(|(index, source)| (std::mem::take(&mut buffers[index]), source))
+    }
+
+    /// Returns the number of datagrams dropped by the last call to
+    /// [`RecvBatch::recv`] for being larger than the maximum datagram size.
+    pub fn truncated(&self) -> usize {
+        self.truncated
+    }
+}
+
+/// Whether batches sent through a socket use UDP GSO, which is turned off for
+/// the socket once the kernel or network device rejects a GSO send.
+#[derive(Debug)]
+pub struct Gso(AtomicBool);
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+impl Gso {
+    fn enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn disable(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Default for Gso {
+    fn default() -> Self {
+        Self(AtomicBool::new(true))
+    }
+}
+
+/// Sends all of `packets` to `dest`, or to the socket's connected address if
+/// `dest` is `None`, using as few system calls as possible. `gso` is the
+/// socket's GSO state. Returns the number of bytes sent.
+pub async fn send_batch(
+    socket: &UdpSocket,
+    gso: &Gso,
+    dest: Option<SocketAddr>,
+    packets: &[&[u8]],
+) -> io::Result<usize> {
+    match (packets, dest) {
+        ([], _) => return Ok(0),
+        ([packet], Some(dest)) => return socket.send_to(packet, dest).await,
+        ([packet], None) => return socket.send(packet).await,
+        _ => {}
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        linux::send_batch(socket, gso, dest, packets).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = gso;
+        let mut bytes = 0;
+        for packet in packets {
+            bytes += match dest {
+                Some(dest) => socket.send_to(packet, dest).await?,
+                None => socket.send(packet).await?,
+            };
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io, mem,
+        net::SocketAddr,
+        os::unix::io::{AsRawFd, RawFd},
+        ptr,
+    };
+
+    use socket2::SockAddr;
+    use tokio::{io::Interest, net::UdpSocket};
+
+    use super::{Gso, RecvBatch};
+
+    /// `UDP_SEGMENT` from `linux/udp.h`, the control message for UDP GSO.
+    const UDP_SEGMENT: libc::c_int = 103;
+    /// The maximum number of segments the kernel accepts in a GSO send.
+    const MAX_GSO_SEGMENTS: usize = 64;
+    /// The maximum payload of a GSO send, which has to fit in a single
+    /// (pre-segmentation) IP packet.
+    const MAX_GSO_SIZE: usize = 65_000;
+
+    /// Waits for `socket` to be ready for `interest`, and calls `f` with the
+    /// socket's file descriptor, until `f` doesn't return `WouldBlock`.
+    pub(super) async fn io<R>(
+        socket: &UdpSocket,
+        interest: Interest,
+        mut f: impl FnMut(RawFd) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let fd = socket.as_raw_fd();
+        loop {
+            socket.ready(interest).await?;
+            match socket.try_io(interest, || f(fd)) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub(super) async fn send_batch(
+        socket: &UdpSocket,
+        gso: &Gso,
+        dest: Option<SocketAddr>,
+        packets: &[&[u8]],
+    ) -> io::Result<usize> {
+        let dest = dest.map(SockAddr::from);
+
+        if let Some(segment_size) = gso_segment_size(packets).filter(|_| gso.enabled()) {
+            let contents = packets.concat();
+            let result = io(socket, Interest::WRITABLE, |fd| {
+                send_gso(fd, dest.as_ref(), &contents, segment_size)
+            })
+            .await;
+
+            match result {
+                Err(error) if is_gso_unsupported(&error) => {
+                    tracing::debug!(%error, "UDP GSO is unsupported, falling back to sendmmsg");
+                    gso.disable();
+                }
+                result => return result,
+            }
+        }
+
+        let mut sent = 0;
+        let mut bytes = 0;
+        while sent < packets.len() {
+            let count = io(socket, Interest::WRITABLE, |fd| {
+                send_mmsg(fd, dest.as_ref(), &packets[sent..])
+            })
+            .await?;
+
+            if count == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+
+            bytes += packets[sent..sent + count]
+                .iter()
+                .map(|packet| packet.len())
+                .sum::<usize>();
+            sent += count;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Returns the segment size to send `packets` with, if they can be sent
+    /// with a single GSO send. This requires every packet except the last to
+    /// be the same size, with the last packet being no larger than the others.
+    pub(super) fn gso_segment_size(packets: &[&[u8]]) -> Option<u16> {
+        if packets.len() < 2 || packets.len() > MAX_GSO_SEGMENTS {
+            return None;
+        }
+
+        let (last, rest) = packets.split_last()?;
+        let size = rest[0].len();
+        let total = packets.iter().map(|packet| packet.len()).sum::<usize>();
+
+        (size > 0
+            && !last.is_empty()
+            && last.len() <= size
+            && total <= MAX_GSO_SIZE
+            && rest.iter().all(|packet| packet.len() == size))
+        .then_some(size as u16)
+    }
+
+    /// Returns whether `error` is how the kernel rejects GSO sends, either
+    /// because it doesn't support `UDP_SEGMENT`, or because the socket's
+    /// network device can't segment packets.
+    fn is_gso_unsupported(error: &io::Error) -> bool {
+        matches!(error.raw_os_error(), Some(libc::EINVAL | libc::EOPNOTSUPP))
+    }
+
+    /// The headers that `recvmmsg` is called with, which are kept by a
+    /// [`RecvBatch`] so that they aren't allocated for every batch.
+    pub(super) struct RecvHeaders {
+        addresses: Vec<libc::sockaddr_storage>,
+        iovecs: Vec<libc::iovec>,
+        headers: Vec<libc::mmsghdr>,
+    }
+
+    // SAFETY: The pointers in the headers are only used by `recv_mmsg`, which
+    // points them at the batch's buffers and addresses before every call.
+    unsafe impl Send for RecvHeaders {}
+    // SAFETY: See above, the headers are never read through a shared reference.
+    unsafe impl Sync for RecvHeaders {}
+
+    impl RecvHeaders {
+        pub(super) fn new(size: usize) -> Self {
+            // SAFETY: These are plain data, for which zero is valid.
+            unsafe {
+                Self {
+                    addresses: vec![mem::zeroed(); size],
+                    iovecs: vec![mem::zeroed(); size],
+                    headers: vec![mem::zeroed(); size],
+                }
+            }
+        }
+    }
+
+    /// Receives up to a batch's size of datagrams with a single `recvmmsg`
+    /// call, adding the size and source of each datagram to the batch.
+    pub(super) fn recv_mmsg(fd: RawFd, batch: &mut RecvBatch) -> io::Result<()> {
+        let RecvHeaders {
+            addresses,
+            iovecs,
+            headers,
+        } = &mut batch.headers;
+
+        for (((buffer, iovec), address), header) in batch
+            .buffers
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(addresses.iter_mut())
+            .zip(headers.iter_mut())
+        {
+            let spare = buffer.spare_capacity_mut();
+            iovec.iov_base = spare.as_mut_ptr().cast();
+            iovec.iov_len = spare.len().min(batch.max_datagram_size);
+            // SAFETY: `mmsghdr` is plain data, for which zero is valid.
+            *header = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+            header.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: Each header points to an address and a buffer that are
+        // valid for the duration of the call.
+        let count = unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (index, (header, address)) in headers
+            .iter()
+            .zip(addresses.iter())
+            .take(count as usize)
+            .enumerate()
+        {
+            // SAFETY: The kernel has written an address of `msg_namelen` bytes.
+            let address = unsafe { SockAddr::new(*address, header.msg_hdr.msg_namelen) };
+            let address = address.as_socket().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "received a datagram from a non-IP address",
+                )
+            })?;
+            if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                batch.truncated += 1;
+            } else {
+                // SAFETY: The kernel has written `msg_len` bytes to the
+                // buffer's spare capacity.
+                unsafe { batch.buffers[index].advance(header.msg_len as usize) };
+                batch.received.push((index, address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends as many of `packets` as possible with a single `sendmmsg` call,
+    /// returning the number of packets sent.
+    fn send_mmsg(fd: RawFd, dest: Option<&SockAddr>, packets: &[&[u8]]) -> io::Result<usize> {
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                // SAFETY: `mmsghdr` is plain data, for which zero is valid.
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                if let Some(dest) = dest {
+                    header.msg_hdr.msg_name = dest.as_ptr() as *mut libc::c_void;
+                    header.msg_hdr.msg_namelen = dest.len();
+                }
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect();
+
+        // SAFETY: Each header points to a destination and a packet that are
+        // valid for the duration of the call, which are only read from.
+        let count = unsafe {
+            libc::sendmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT as _,
+            )
+        };
+
+        if count < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(count as usize)
+        }
+    }
+
+    /// Sends `contents` with a single `sendmsg` call, which the kernel (or
+    /// network device) splits into datagrams of `segment_size` bytes.
+    fn send_gso(
+        fd: RawFd,
+        dest: Option<&SockAddr>,
+        contents: &[u8],
+        segment_size: u16,
+    ) -> io::Result<usize> {
+        let mut iovec = libc::iovec {
+            iov_base: contents.as_ptr() as *mut libc::c_void,
+            iov_len: contents.len(),
+        };
+        // Large enough for a single control message containing a `u16`, with
+        // the alignment required for `cmsghdr`.
+        let mut control = [0u64; 4];
+
+        // SAFETY: `msghdr` is plain data, for which zero is valid.
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        if let Some(dest) = dest {
+            header.msg_name = dest.as_ptr() as *mut libc::c_void;
+            header.msg_namelen = dest.len();
+        }
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr().cast();
+
+        // SAFETY: `control` is large enough and suitably aligned for a
+        // `cmsghdr` followed by a `u16`, and every pointer in `header` is
+        // valid for the duration of the call.
+        let size = unsafe {
+            header.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as _) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_size);
+
+            libc::sendmsg(fd, &header, libc::MSG_DONTWAIT)
+        };
+
+        if size < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(size as usize)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::test_utils::create_socket;
+
+    async fn recv_all(socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut batch = RecvBatch::new(
+            DEFAULT_BATCH_SIZE,
+            DEFAULT_MAX_DATAGRAM_SIZE,
+            <_>::default(),
+        );
+        let mut packets = Vec::new();
+        while packets.len() < count {
+            timeout(Duration::from_secs(5), batch.recv(socket))
+                .await
+                .expect("should receive packets")
+                .unwrap();
+            packets.extend(batch.iter().map(|(contents, _)| contents.to_vec()));
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn recv_batch() {
+        let socket = create_socket().await;
+        let addr = socket.local_addr().unwrap();
+        let sender = create_socket().await;
+
+        for packet in ["one", "two", "three"] {
+            sender.send_to(packet.as_bytes(), addr).await.unwrap();
+        }
+
+        let mut batch = RecvBatch::new(2, DEFAULT_MAX_DATAGRAM_SIZE, <_>::default());
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let count = timeout(Duration::from_secs(5), batch.recv(&socket))
+                .await
+                .expect("should receive packets")
+                .unwrap();
+            assert!(count <= 2);
+            assert_eq!(count, batch.iter().count());
+            for (contents, source) in batch.iter() {
+                assert_eq!(sender.local_addr().unwrap().port(), source.port());
+                received.push(String::from_utf8(contents.to_vec()).unwrap());
+            }
+        }
+
+        assert_eq!(vec!["one", "two", "three"], received);
+    }
+
+    #[tokio::test]
+    async fn recv_batch_drops_large_datagrams() {
+        let socket = create_socket().await;
+        let addr = socket.local_addr().unwrap();
+        let sender = create_socket().await;
+
+        sender.send_to(&[1; 101], addr).await.unwrap();
+        sender.send_to(&[2; 100], addr).await.unwrap();
+
+        let mut batch = RecvBatch::new(2, 100, <_>::default());
+        let mut received = Vec::new();
+        let mut truncated = 0;
+        while received.len() + truncated < 2 {
+            timeout(Duration::from_secs(5), batch.recv(&socket))
+                .await
+                .expect("should receive packets")
+                .unwrap();
+            received.extend(batch.iter().map(|(contents, _)| contents.to_vec()));
+            truncated += batch.truncated();
+        }
+
+        assert_eq!(vec![vec![2; 100]], received);
+        assert_eq!(1, truncated);
+    }
+
+    #[tokio::test]
+    async fn send_batch_to() {
+        let socket = create_socket().await;
+        let addr = socket.local_addr().unwrap();
+        let sender = create_socket().await;
+
+        let packets: [&[u8]; 3] = [b"one", b"two", b"three"];
+        let bytes = send_batch(&sender, &Gso::default(), Some(addr), &packets)
+            .await
+            .unwrap();
+        assert_eq!(11, bytes);
+
+        let received = recv_all(&socket, 3).await;
+        assert_eq!(
+            packets
+                .iter()
+                .map(|packet| packet.to_vec())
+                .collect::<Vec<_>>(),
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn send_batch_segments() {
+        let socket = create_socket().await;
+        let addr = socket.local_addr().unwrap();
+        let sender = create_socket().await;
+        sender.connect(addr).await.unwrap();
+
+        // Equally sized packets, which can be sent with a single GSO send.
+        let packets = [[1u8; 100], [2; 100], [3; 100], [4; 100]];
+        let packets = packets.iter().map(|packet| &packet[..]).collect::<Vec<_>>();
+        let bytes = send_batch(&sender, &Gso::default(), None, &packets)
+            .await
+            .unwrap();
+        assert_eq!(400, bytes);
+
+        let received = recv_all(&socket, 4).await;
+        assert_eq!(4, received.len());
+        assert!(received.iter().all(|packet| packet.len() == 100));
+        assert_eq!(
+            BTreeSet::from([1, 2, 3, 4]),
+            received.iter().map(|packet| packet[0]).collect()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn gso_segment_size() {
+        let gso_segment_size = |packets: &[&str]| {
+            super::linux::gso_segment_size(
+                &packets
+                    .iter()
+                    .map(|packet| packet.as_bytes())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(None, gso_segment_size(&["one"]));
+        assert_eq!(Some(3), gso_segment_size(&["one", "two", "si"]));
+        assert_eq!(None, gso_segment_size(&["one", "three"]));
+        assert_eq!(None, gso_segment_size(&["one", "two", "four"]));
+    }
+}
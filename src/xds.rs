@@ -127,13 +127,17 @@ mod google {
 
 pub(crate) mod client;
 mod metrics;
+mod nodes;
 mod resource;
 pub(crate) mod server;
+pub(crate) mod tls;
 
 pub use client::Client;
+pub use nodes::{Acked, Nacked, NodeRegistry, NodeStatus, ResourceStatus};
 pub use resource::{Resource, ResourceType};
 pub use server::ControlPlane;
 pub use service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
+pub use tls::{ClientTls, ServerTls};
 pub use xds::*;
 
 #[cfg(test)]
@@ -177,11 +181,21 @@ mod tests {
         .unwrap();
 
         // Test that the client can handle the manager dropping out.
-        let handle = tokio::spawn(server::spawn(xds_port, xds_config.clone()));
+        let handle = tokio::spawn(server::spawn(
+            xds_port,
+            xds_config.clone(),
+            <_>::default(),
+            <_>::default(),
+        ));
 
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
         let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
-        tokio::spawn(server::spawn(xds_port, xds_config.clone()));
+        tokio::spawn(server::spawn(
+            xds_port,
+            xds_config.clone(),
+            <_>::default(),
+            <_>::default(),
+        ));
         let client_proxy = crate::cli::Proxy {
             port: client_addr.port(),
             management_server: vec![format!("http://0.0.0.0:{}", xds_port).parse().unwrap()],
@@ -194,7 +208,12 @@ mod tests {
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
         handle.abort();
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-        tokio::spawn(server::spawn(xds_port, xds_config.clone()));
+        tokio::spawn(server::spawn(
+            xds_port,
+            xds_config.clone(),
+            <_>::default(),
+            <_>::default(),
+        ));
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
 
         const VERSION_KEY: &str = "quilkin.dev/load_balancer/version";
@@ -239,6 +258,7 @@ mod tests {
                             value: 1.into(),
                             filter: TokenRouter::as_filter_config(token_router::Config {
                                 metadata_key: TOKEN_KEY.into(),
+                                ..<_>::default()
                             })
                             .unwrap(),
                         }],
@@ -288,18 +308,30 @@ mod tests {
         .map(Arc::new)
         .unwrap();
 
-        tokio::spawn(server::spawn(23456, config.clone()));
+        tokio::spawn(server::spawn(
+            23456,
+            config.clone(),
+            <_>::default(),
+            <_>::default(),
+        ));
         let client = Client::connect(
             "test-client".into(),
             vec!["http://127.0.0.1:23456".try_into().unwrap()],
+            <_>::default(),
         )
         .await
         .unwrap();
         let mut stream = client
-            .stream({
-                let config = config.clone();
-                move |resource| config.apply(resource)
-            })
+            .stream(
+                {
+                    let config = config.clone();
+                    move |resources| config.apply_all(resources)
+                },
+                {
+                    let config = config.clone();
+                    move |resource_type, name| config.remove(resource_type, name)
+                },
+            )
             .await
             .unwrap();
 
@@ -359,4 +391,68 @@ mod tests {
             assert_eq!(iter.next().unwrap(), filters[1].clone().into());
         }
     }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let directory = tempdir::TempDir::new("xds-tls").unwrap();
+        let ca = tls::tests::generate(directory.path(), &["localhost", "proxy-a"]);
+        let path = |name: &str| Some(directory.path().join(name));
+
+        let server_config = Arc::new(Config::default());
+        server_config.clusters.modify(|clusters| {
+            clusters.insert_default(vec![Endpoint::new("127.0.0.1:25999".parse().unwrap())])
+        });
+        let xds_port = crate::test_utils::available_addr().await.port();
+        tokio::spawn(server::spawn(
+            xds_port,
+            server_config,
+            ServerTls {
+                cert: path("localhost.pem"),
+                key: path("localhost.key"),
+                ca: Some(ca.clone()),
+            },
+            <_>::default(),
+        ));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        // Proxies can only use the node IDs that their certificate allows.
+        for (id, allowed) in [("proxy-a", true), ("proxy-b", false)] {
+            let config = Arc::new(Config::default());
+            let client = Client::connect(
+                id.into(),
+                vec![tonic::transport::Endpoint::from_shared(format!(
+                    "https://localhost:{xds_port}"
+                ))
+                .unwrap()],
+                ClientTls {
+                    ca: Some(ca.clone()),
+                    cert: path("proxy-a.pem"),
+                    key: path("proxy-a.key"),
+                },
+            )
+            .await
+            .unwrap();
+            let mut stream = client
+                .stream(
+                    {
+                        let config = config.clone();
+                        move |resources| config.apply_all(resources)
+                    },
+                    {
+                        let config = config.clone();
+                        move |resource_type, name| config.remove(resource_type, name)
+                    },
+                )
+                .await
+                .unwrap();
+
+            stream.send(ResourceType::Endpoint, &[]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            assert_eq!(
+                allowed,
+                config.clusters.load().endpoints().count() == 1,
+                "{id}"
+            );
+        }
+    }
 }
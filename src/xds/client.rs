@@ -14,7 +14,11 @@
  * limitations under the License.
  */
 
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use futures::StreamExt;
 use rand::Rng;
@@ -27,11 +31,13 @@ use tryhard::{
 };
 
 use crate::{
+    config::NodeInfo,
     xds::{
         config::core::v3::Node,
         metrics,
         service::discovery::v3::{
-            aggregated_discovery_service_client::AggregatedDiscoveryServiceClient, DiscoveryRequest,
+            aggregated_discovery_service_client::AggregatedDiscoveryServiceClient,
+            DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest,
         },
         Resource, ResourceType,
     },
@@ -43,23 +49,42 @@ type AdsClient = AggregatedDiscoveryServiceClient<TonicChannel>;
 /// Client that can talk to an XDS server using the aDS protocol.
 #[derive(Clone)]
 pub struct Client {
-    identifier: String,
+    node: NodeInfo,
     management_servers: Vec<Endpoint>,
+    tls: crate::xds::ClientTls,
     client: AdsClient,
 }
 
 impl Client {
     #[tracing::instrument(skip_all, level = "trace", fields(servers = ?management_servers))]
-    pub async fn connect(identifier: String, management_servers: Vec<Endpoint>) -> Result<Self> {
-        let client = Self::new_ads_client(&management_servers).await?;
+    pub async fn connect(
+        node: NodeInfo,
+        management_servers: Vec<Endpoint>,
+        tls: crate::xds::ClientTls,
+    ) -> Result<Self> {
+        let client = Self::new_ads_client(&management_servers, &tls).await?;
         Ok(Self {
             client,
-            identifier,
+            node,
             management_servers,
+            tls,
         })
     }
 
-    async fn new_ads_client(management_servers: &[Endpoint]) -> Result<AdsClient> {
+    /// Checks that every management server has everything needed to connect
+    /// to it, so that invalid endpoints can be reported before connecting.
+    pub fn check_endpoints(management_servers: &[Endpoint]) -> Result<()> {
+        for endpoint in management_servers {
+            check_endpoint(endpoint)?;
+        }
+
+        Ok(())
+    }
+
+    async fn new_ads_client(
+        management_servers: &[Endpoint],
+        tls: &crate::xds::ClientTls,
+    ) -> Result<AdsClient> {
         use crate::config::{
             BACKOFF_INITIAL_DELAY_MILLISECONDS, BACKOFF_MAX_DELAY_SECONDS,
             BACKOFF_MAX_JITTER_MILLISECONDS, CONNECTION_TIMEOUT,
@@ -93,6 +118,11 @@ impl Client {
                     // Do not retry if this is an invalid URL error that we cannot recover from.
                     RetryPolicy::Break
                 }
+                RpcSessionError::Tls(ref error) => {
+                    // The certificates may be in the middle of being renewed.
+                    tracing::warn!(%error, "Unable to configure TLS for the XDS server");
+                    RetryPolicy::Delay(delay)
+                }
                 RpcSessionError::InitialConnect(ref error) => {
                     tracing::warn!(?error, "Unable to connect to the XDS server");
                     RetryPolicy::Delay(delay)
@@ -113,19 +143,19 @@ impl Client {
                         "Failed initial connection",
                     ))),
                     Some(endpoint) => {
-                        let endpoint = endpoint
+                        let mut endpoint = endpoint
                             .clone()
                             .connect_timeout(Duration::from_secs(CONNECTION_TIMEOUT));
 
-                        // make sure that we have everything we will need in our URI
-                        if endpoint.uri().scheme().is_none() {
-                            return Err(RpcSessionError::InvalidEndpoint(
-                                "No scheme provided".into(),
-                            ));
-                        } else if endpoint.uri().host().is_none() {
-                            return Err(RpcSessionError::InvalidEndpoint(
-                                "No host provided".into(),
-                            ));
+                        check_endpoint(&endpoint)?;
+
+                        if endpoint.uri().scheme_str() == Some("https") {
+                            // The certificates are read on every connection, so
+                            // that renewed certificates are used when reconnecting.
+                            endpoint = tls
+                                .config()
+                                .and_then(|config| Ok(endpoint.tls_config(config)?))
+                                .map_err(|error| RpcSessionError::Tls(error.to_string()))?;
                         }
 
                         AggregatedDiscoveryServiceClient::connect(endpoint)
@@ -147,20 +177,35 @@ impl Client {
         Ok(client)
     }
 
-    /// Starts a new stream to the xDS management server.
+    /// Starts a new stream to the xDS management server, using Delta xDS if
+    /// the server supports it, and State of the World xDS otherwise.
+    /// With State of the World xDS, `on_removed_resource` is only called for
+    /// the listeners missing from a response, as every response contains all
+    /// of the listeners, but not necessarily all of the other resources.
     pub async fn stream(
         &self,
-        on_new_resource: impl Fn(&Resource) -> crate::Result<()> + Send + Sync + 'static,
+        on_new_resources: impl Fn(&[Resource]) -> crate::Result<()> + Send + Sync + 'static,
+        on_removed_resource: impl Fn(ResourceType, &str) -> crate::Result<()> + Send + Sync + 'static,
     ) -> Result<Stream> {
-        Stream::connect(self, on_new_resource).await
+        Stream::connect(self, on_new_resources, on_removed_resource).await
     }
 }
 
 type SubscribedResources = Arc<Mutex<HashSet<(ResourceType, Vec<String>)>>>;
+/// The versions of the resources received with Delta xDS, which are sent
+/// back to the server when reconnecting so that it only sends what changed.
+type ResourceVersions = Arc<parking_lot::Mutex<HashMap<ResourceType, HashMap<String, String>>>>;
+
+/// Why a Delta xDS stream ended.
+enum StreamEnd {
+    Disconnected,
+    /// The server only supports State of the World xDS.
+    DeltaUnsupported,
+}
 
 /// An active xDS gRPC management stream.
 pub struct Stream {
-    identifier: Arc<str>,
+    node: Arc<Node>,
     requests: broadcast::Sender<DiscoveryRequest>,
     handle_discovery_response: tokio::task::JoinHandle<Result<()>>,
     subscribed_resources: SubscribedResources,
@@ -171,152 +216,422 @@ impl Stream {
     async fn connect(
         Client {
             client,
-            identifier,
+            node,
             management_servers,
+            tls,
         }: &Client,
-        on_new_resource: impl Fn(&Resource) -> crate::Result<()> + Send + Sync + 'static,
+        on_new_resources: impl Fn(&[Resource]) -> crate::Result<()> + Send + Sync + 'static,
+        on_removed_resource: impl Fn(ResourceType, &str) -> crate::Result<()> + Send + Sync + 'static,
     ) -> Result<Self> {
         let (requests, mut rx) = broadcast::channel(12);
         let subscribed_resources: SubscribedResources = <_>::default();
-        let identifier: Arc<str> = Arc::from(&**identifier);
+        let node = Arc::new(Node::from(node));
 
         let handle_discovery_response = tokio::spawn({
             let mut client = client.clone();
-            let identifier = identifier.clone();
+            let node = node.clone();
             let mut requests = requests.clone();
             let management_servers = management_servers.clone();
+            let tls = tls.clone();
             let subscribed_resources = subscribed_resources.clone();
+            let versions = ResourceVersions::default();
+            let mut listeners = HashSet::new();
             async move {
                 loop {
-                    let mut responses = client
-                        .stream_aggregated_resources(
-                            tokio_stream::wrappers::BroadcastStream::from(rx)
-                                // Errors only happen if the stream is behind, which
-                                // we don't care about, we only want the latest
-                                // state of the world.
-                                .filter_map(|result| futures::future::ready(result.ok())),
+                    let end = Self::delta_stream(
+                        &mut client,
+                        rx,
+                        &versions,
+                        &on_new_resources,
+                        &on_removed_resource,
+                    )
+                    .await?;
+
+                    if let StreamEnd::DeltaUnsupported = end {
+                        tracing::info!(
+                            "xDS server doesn't support Delta xDS, using State of the World xDS"
+                        );
+                        rx = requests.subscribe();
+                        Self::refresh_resources(&node, &subscribed_resources, &mut requests)
+                            .await?;
+                        Self::state_of_the_world_stream(
+                            &mut client,
+                            &node,
+                            rx,
+                            &mut requests,
+                            &subscribed_resources,
+                            &mut listeners,
+                            &on_new_resources,
+                            &on_removed_resource,
                         )
-                        .in_current_span()
-                        .await?
-                        .into_inner();
-
-                    loop {
-                        let timeout = tokio::time::sleep(std::time::Duration::from_millis(500));
-                        let new_message = responses.message();
-
-                        tokio::select! {
-                            _ = timeout => {
-                                Self::refresh_resources(&identifier, &subscribed_resources, &mut requests).await?;
-                            }
-                            response = new_message => {
-                                let Some(response) = response.map_err(|error| tracing::warn!(%error, "Error from xDS server")).ok().flatten() else {
-                                    break;
-                                };
-
-                                let identifier = response
-                                    .control_plane
-                                    .as_ref()
-                                    .map(|cp| cp.identifier.clone())
-                                    .unwrap_or_default();
-                                let _stream_metrics =
-                                    super::metrics::StreamConnectionMetrics::new(&identifier);
-                                tracing::info!(
-                                    id = &*response.version_info,
-                                    r#type = &*response.type_url,
-                                    nonce = &*response.nonce,
-                                    control_plane = &*identifier,
-                                    "Received response"
-                                );
-
-                                let result = response
-                                    .resources
-                                    .iter()
-                                    .cloned()
-                                    .map(Resource::try_from)
-                                    .try_for_each(|resource| {
-                                        let resource = resource?;
-                                        metrics::DISCOVERY_RESPONSES
-                                            .with_label_values(&[&*identifier, resource.type_url()])
-                                            .inc();
-                                        (on_new_resource)(&resource)
-                                    });
-
-                                let mut request = DiscoveryRequest::try_from(response)?;
-                                if let Err(error) = result {
-                                    metrics::NACKS
-                                        .with_label_values(&[&*identifier, &*request.type_url])
-                                        .inc();
-                                    request.error_detail = Some(crate::xds::google::rpc::Status {
-                                        code: 3,
-                                        message: error.to_string(),
-                                        ..<_>::default()
-                                    });
-                                } else {
-                                    metrics::ACKS
-                                        .with_label_values(&[&*identifier, &*request.type_url])
-                                        .inc();
-                                }
-
-                                requests.send(request)?;
-                            }
-                            else => {
-                                break;
-                            }
-                        }
+                        .await?;
                     }
 
                     tracing::info!("Lost connection to xDS, retrying");
                     // If we've reached here, something has gone wrong with the
                     // connection, so we just create a new client and restart.
-                    client = Client::new_ads_client(&management_servers).await?;
+                    client = Client::new_ads_client(&management_servers, &tls).await?;
                     rx = requests.subscribe();
-                    Self::refresh_resources(&identifier, &subscribed_resources, &mut requests).await?;
+                    Self::refresh_resources(&node, &subscribed_resources, &mut requests).await?;
                 }
             }
             .instrument(tracing::trace_span!("handle_discovery_response"))
         });
 
         Ok(Self {
-            identifier,
+            node,
             requests,
             handle_discovery_response,
             subscribed_resources,
         })
     }
 
+    /// Streams resources with Delta xDS until the connection is lost,
+    /// subscribing to the resources requested through `rx`.
+    #[tracing::instrument(skip_all)]
+    async fn delta_stream(
+        client: &mut AdsClient,
+        rx: broadcast::Receiver<DiscoveryRequest>,
+        versions: &ResourceVersions,
+        on_new_resources: &(impl Fn(&[Resource]) -> crate::Result<()> + Send + Sync),
+        on_removed_resource: &(impl Fn(ResourceType, &str) -> crate::Result<()> + Send + Sync),
+    ) -> Result<StreamEnd> {
+        let (acks, ack_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut subscribed = HashSet::new();
+        let subscriptions = tokio_stream::wrappers::BroadcastStream::from(rx).filter_map({
+            let versions = versions.clone();
+            move |result| {
+                // Errors only happen if the stream is behind, any
+                // subscriptions that were missed are sent again when
+                // reconnecting.
+                let request = result.ok().and_then(|request: DiscoveryRequest| {
+                    let resource_type = request.type_url.parse::<ResourceType>().ok()?;
+                    if subscribed.insert(resource_type) {
+                        // The first request for a type lets the server know
+                        // which resources we already have.
+                        Some(DeltaDiscoveryRequest {
+                            node: request.node,
+                            type_url: request.type_url,
+                            resource_names_subscribe: request.resource_names,
+                            initial_resource_versions: versions
+                                .lock()
+                                .get(&resource_type)
+                                .cloned()
+                                .unwrap_or_default(),
+                            ..<_>::default()
+                        })
+                    } else if !request.resource_names.is_empty() {
+                        Some(DeltaDiscoveryRequest {
+                            type_url: request.type_url,
+                            resource_names_subscribe: request.resource_names,
+                            ..<_>::default()
+                        })
+                    } else {
+                        // Changes are sent without asking for them.
+                        None
+                    }
+                });
+
+                futures::future::ready(request)
+            }
+        });
+
+        let mut request = tonic::Request::new(futures::stream::select(
+            subscriptions,
+            tokio_stream::wrappers::UnboundedReceiverStream::new(ack_rx),
+        ));
+        crate::telemetry::inject(request.metadata_mut());
+        let (metadata, mut responses) = match client
+            .delta_aggregated_resources(request)
+            .in_current_span()
+            .await
+        {
+            Ok(responses) => {
+                let metadata = responses.metadata().clone();
+                (metadata, responses.into_inner())
+            }
+            Err(status) if status.code() == tonic::Code::Unimplemented => {
+                return Ok(StreamEnd::DeltaUnsupported)
+            }
+            Err(status) => {
+                // The server may be unavailable or not yet accept the proxy,
+                // so the stream is retried rather than given up on, after a
+                // delay as reconnecting to the server may succeed straight away.
+                tracing::warn!(%status, "Failed to start Delta xDS stream");
+                tokio::time::sleep(Duration::from_millis(
+                    crate::config::BACKOFF_INITIAL_DELAY_MILLISECONDS,
+                ))
+                .await;
+                return Ok(StreamEnd::Disconnected);
+            }
+        };
+
+        loop {
+            let response = match responses.message().await {
+                Ok(Some(response)) => response,
+                Ok(None) => break,
+                Err(error) => {
+                    tracing::warn!(%error, "Error from xDS server");
+                    break;
+                }
+            };
+
+            let identifier = response
+                .control_plane
+                .as_ref()
+                .map(|cp| cp.identifier.clone())
+                .unwrap_or_default();
+            let _stream_metrics = super::metrics::StreamConnectionMetrics::new(&identifier);
+            tracing::info!(
+                id = &*response.system_version_info,
+                r#type = &*response.type_url,
+                nonce = &*response.nonce,
+                control_plane = &*identifier,
+                added = response.resources.len(),
+                removed = response.removed_resources.len(),
+                "Received delta response"
+            );
+
+            let span = tracing::info_span!(
+                "delta_discovery_response",
+                r#type = &*response.type_url,
+                nonce = &*response.nonce,
+            );
+            crate::telemetry::set_parent(&span, &metadata);
+            let result = span.in_scope(|| {
+                Self::apply_delta_response(
+                    &identifier,
+                    &response,
+                    versions,
+                    on_new_resources,
+                    on_removed_resource,
+                )
+            });
+
+            let mut request = DeltaDiscoveryRequest {
+                type_url: response.type_url,
+                response_nonce: response.nonce,
+                ..<_>::default()
+            };
+            if let Err(error) = result {
+                metrics::NACKS
+                    .with_label_values(&[&*identifier, &*request.type_url])
+                    .inc();
+                request.error_detail = Some(crate::xds::google::rpc::Status {
+                    code: 3,
+                    message: error.to_string(),
+                    ..<_>::default()
+                });
+            } else {
+                metrics::ACKS
+                    .with_label_values(&[&*identifier, &*request.type_url])
+                    .inc();
+            }
+
+            acks.send(request)?;
+        }
+
+        Ok(StreamEnd::Disconnected)
+    }
+
+    fn apply_delta_response(
+        identifier: &str,
+        response: &DeltaDiscoveryResponse,
+        versions: &ResourceVersions,
+        on_new_resources: &impl Fn(&[Resource]) -> crate::Result<()>,
+        on_removed_resource: &impl Fn(ResourceType, &str) -> crate::Result<()>,
+    ) -> Result<()> {
+        let resource_type = response.type_url.parse::<ResourceType>()?;
+
+        // Every resource is decoded before any of them are applied, so that
+        // an invalid response leaves the configuration as it was.
+        let resources = response
+            .resources
+            .iter()
+            .map(|resource| {
+                let Some(any) = resource.resource.clone() else {
+                    return Err(eyre::eyre!("resource `{}` has no value", resource.name));
+                };
+                Resource::try_from(any)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        (on_new_resources)(&resources)?;
+        for resource in &resources {
+            metrics::DISCOVERY_RESPONSES
+                .with_label_values(&[identifier, resource.type_url()])
+                .inc();
+        }
+
+        for name in &response.removed_resources {
+            (on_removed_resource)(resource_type, name)?;
+        }
+
+        let mut versions = versions.lock();
+        let versions = versions.entry(resource_type).or_default();
+        for resource in &response.resources {
+            versions.insert(resource.name.clone(), resource.version.clone());
+        }
+        for name in &response.removed_resources {
+            versions.remove(name);
+        }
+
+        Ok(())
+    }
+
+    /// Streams resources with State of the World xDS until the connection is
+    /// lost, requesting them again every so often. `listeners` contains the
+    /// names of the listeners in the last response.
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip_all)]
+    async fn state_of_the_world_stream(
+        client: &mut AdsClient,
+        node: &Node,
+        rx: broadcast::Receiver<DiscoveryRequest>,
+        requests: &mut broadcast::Sender<DiscoveryRequest>,
+        subscribed_resources: &SubscribedResources,
+        listeners: &mut HashSet<String>,
+        on_new_resources: &(impl Fn(&[Resource]) -> crate::Result<()> + Send + Sync),
+        on_removed_resource: &(impl Fn(ResourceType, &str) -> crate::Result<()> + Send + Sync),
+    ) -> Result<()> {
+        let mut request = tonic::Request::new(
+            tokio_stream::wrappers::BroadcastStream::from(rx)
+                // Errors only happen if the stream is behind, which
+                // we don't care about, we only want the latest
+                // state of the world.
+                .filter_map(|result| futures::future::ready(result.ok())),
+        );
+        crate::telemetry::inject(request.metadata_mut());
+        let responses = client
+            .stream_aggregated_resources(request)
+            .in_current_span()
+            .await?;
+        let metadata = responses.metadata().clone();
+        let mut responses = responses.into_inner();
+
+        loop {
+            let timeout = tokio::time::sleep(std::time::Duration::from_millis(500));
+            let new_message = responses.message();
+
+            tokio::select! {
+                _ = timeout => {
+                    Self::refresh_resources(node, subscribed_resources, requests).await?;
+                }
+                response = new_message => {
+                    let Some(response) = response.map_err(|error| tracing::warn!(%error, "Error from xDS server")).ok().flatten() else {
+                        break;
+                    };
+
+                    let identifier = response
+                        .control_plane
+                        .as_ref()
+                        .map(|cp| cp.identifier.clone())
+                        .unwrap_or_default();
+                    let _stream_metrics =
+                        super::metrics::StreamConnectionMetrics::new(&identifier);
+                    tracing::info!(
+                        id = &*response.version_info,
+                        r#type = &*response.type_url,
+                        nonce = &*response.nonce,
+                        control_plane = &*identifier,
+                        "Received response"
+                    );
+
+                    let span = tracing::info_span!(
+                        "discovery_response",
+                        r#type = &*response.type_url,
+                        nonce = &*response.nonce,
+                    );
+                    crate::telemetry::set_parent(&span, &metadata);
+                    let _entered = span.enter();
+
+                    let mut names = HashSet::new();
+                    let mut result = response
+                        .resources
+                        .iter()
+                        .cloned()
+                        .map(Resource::try_from)
+                        .collect::<Result<Vec<_>>>()
+                        .and_then(|resources| {
+                            (on_new_resources)(&resources)?;
+                            for resource in &resources {
+                                metrics::DISCOVERY_RESPONSES
+                                    .with_label_values(&[&*identifier, resource.type_url()])
+                                    .inc();
+                                if let Resource::Listener(listener) = resource {
+                                    names.insert(listener.name.clone());
+                                }
+                            }
+                            Ok(())
+                        });
+
+                    // Every response contains all of the listeners, so the
+                    // ones missing from it have been removed.
+                    if result.is_ok() && response.type_url == ResourceType::Listener.type_url() {
+                        result = listeners.difference(&names).try_for_each(|name| {
+                            (on_removed_resource)(ResourceType::Listener, name)
+                        });
+                        if result.is_ok() {
+                            *listeners = names;
+                        }
+                    }
+
+                    let mut request = DiscoveryRequest::try_from(response)?;
+                    if let Err(error) = result {
+                        metrics::NACKS
+                            .with_label_values(&[&*identifier, &*request.type_url])
+                            .inc();
+                        request.error_detail = Some(crate::xds::google::rpc::Status {
+                            code: 3,
+                            message: error.to_string(),
+                            ..<_>::default()
+                        });
+                    } else {
+                        metrics::ACKS
+                            .with_label_values(&[&*identifier, &*request.type_url])
+                            .inc();
+                    }
+
+                    requests.send(request)?;
+                }
+                else => {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn send(&mut self, resource_type: ResourceType, names: &[String]) -> Result<()> {
         self.subscribed_resources
             .lock()
             .await
             .insert((resource_type, names.to_vec()));
-        Self::send_without_cache(&self.identifier, &mut self.requests, resource_type, names)
+        Self::send_without_cache(&self.node, &mut self.requests, resource_type, names)
     }
 
     async fn refresh_resources(
-        identifier: &str,
+        node: &Node,
         subscribed_resources: &SubscribedResources,
         requests: &mut broadcast::Sender<DiscoveryRequest>,
     ) -> Result<()> {
         for (resource, names) in subscribed_resources.lock().await.iter() {
-            Self::send_without_cache(identifier, requests, *resource, names)?;
+            Self::send_without_cache(node, requests, *resource, names)?;
         }
 
         Ok(())
     }
 
     fn send_without_cache(
-        identifier: &str,
+        node: &Node,
         requests: &mut broadcast::Sender<DiscoveryRequest>,
         resource_type: ResourceType,
         names: &[String],
     ) -> Result<()> {
         let request = DiscoveryRequest {
-            node: Some(Node {
-                id: identifier.into(),
-                user_agent_name: "quilkin".into(),
-                ..Node::default()
-            }),
+            node: Some(node.clone()),
             resource_names: names.to_vec(),
             type_url: resource_type.type_url().into(),
             ..DiscoveryRequest::default()
@@ -333,11 +648,27 @@ impl Drop for Stream {
     }
 }
 
+/// Makes sure that we have everything we will need in the endpoint's URI.
+fn check_endpoint(endpoint: &Endpoint) -> Result<(), RpcSessionError> {
+    if endpoint.uri().scheme().is_none() {
+        Err(RpcSessionError::InvalidEndpoint(
+            "No scheme provided".into(),
+        ))
+    } else if endpoint.uri().host().is_none() {
+        Err(RpcSessionError::InvalidEndpoint("No host provided".into()))
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 enum RpcSessionError {
     #[error("Invalid endpoint. \n {0}")]
     InvalidEndpoint(String),
 
+    #[error("Failed to configure TLS.\n {0}")]
+    Tls(String),
+
     #[error("Failed to establish initial connection.\n {0:?}")]
     InitialConnect(TonicError),
 
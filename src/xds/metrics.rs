@@ -15,7 +15,7 @@
  */
 
 use once_cell::sync::Lazy;
-use prometheus::{IntCounterVec, IntGaugeVec};
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec};
 
 pub(crate) const CONTROL_PLANE_LABEL: &str = "control_plane";
 pub(crate) const NODE_LABEL: &str = "node";
@@ -81,6 +81,52 @@ pub(crate) static NACKS: Lazy<IntCounterVec> = Lazy::new(|| {
     .unwrap()
 });
 
+pub(crate) static NODE_ACKED_VERSION: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "xds_node_acked_version",
+            "The version of each resource type that a connected node most recently accepted",
+        },
+        &[NODE_LABEL, TYPE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static NODE_NACKED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    prometheus::register_int_gauge_vec_with_registry! {
+        prometheus::opts! {
+            "xds_node_nacked",
+            "Whether a connected node rejected the most recent version of each resource type it was sent",
+        },
+        &[NODE_LABEL, TYPE_LABEL],
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static SNAPSHOT_STALE: Lazy<IntGauge> = Lazy::new(|| {
+    prometheus::register_int_gauge_with_registry! {
+        prometheus::opts! {
+            "xds_snapshot_stale",
+            "Whether the proxy is using configuration loaded from its xDS snapshot, rather than from a management server",
+        },
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
+pub(crate) static SNAPSHOT_AGE: Lazy<IntGauge> = Lazy::new(|| {
+    prometheus::register_int_gauge_with_registry! {
+        prometheus::opts! {
+            "xds_snapshot_age_seconds",
+            "The number of seconds since the stale configuration loaded from the xDS snapshot was saved",
+        },
+        crate::metrics::registry(),
+    }
+    .unwrap()
+});
+
 pub struct StreamConnectionMetrics {
     node: String,
 }
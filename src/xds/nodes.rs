@@ -0,0 +1,277 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::xds::{metrics, ResourceType};
+
+/// The number of unacknowledged responses remembered for each node and
+/// resource type, older ones are assumed to have been lost.
+const MAX_PENDING_RESPONSES: usize = 16;
+
+/// The status of the nodes connected to a management server, such as which
+/// versions of each resource type they've accepted or rejected. Shared with
+/// the admin server so that rollouts can be verified.
+#[derive(Clone, Default)]
+pub struct NodeRegistry(Arc<parking_lot::RwLock<BTreeMap<String, NodeStatus>>>);
+
+/// The status of a connected node.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NodeStatus {
+    /// The number of xDS streams that the node has open.
+    pub streams: usize,
+    /// When the node first connected, in seconds since the UNIX epoch.
+    pub connected_at: u64,
+    /// The status of each resource type, by type URL.
+    pub resources: BTreeMap<String, ResourceStatus>,
+}
+
+/// The versions of a resource type that have been sent to a node, and
+/// whether it accepted them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ResourceStatus {
+    /// The version most recently sent to the node.
+    pub sent: Option<String>,
+    /// The version the node most recently accepted.
+    pub acked: Option<Acked>,
+    /// The version the node most recently rejected, unless it has accepted a
+    /// version since.
+    pub nacked: Option<Nacked>,
+    /// The nonces and versions of the responses which haven't been
+    /// acknowledged yet.
+    #[serde(skip)]
+    pending: VecDeque<(String, String)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Acked {
+    pub version: String,
+    /// When the version was accepted, in seconds since the UNIX epoch.
+    pub at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Nacked {
+    pub version: String,
+    /// Why the node rejected the version.
+    pub error: String,
+    /// When the version was rejected, in seconds since the UNIX epoch.
+    pub at: u64,
+}
+
+impl NodeRegistry {
+    /// Registers a new stream from `node`, which is removed from the
+    /// registry once all of its streams have been dropped.
+    pub(crate) fn connect(&self, node: &str) -> NodeConnection {
+        self.0
+            .write()
+            .entry(node.to_owned())
+            .or_insert_with(|| NodeStatus {
+                connected_at: now(),
+                ..<_>::default()
+            })
+            .streams += 1;
+
+        NodeConnection {
+            registry: self.clone(),
+            node: node.to_owned(),
+        }
+    }
+
+    /// Records that the response with `nonce` sent `version` of
+    /// `resource_type` to `node`.
+    pub(crate) fn sent(&self, node: &str, resource_type: ResourceType, nonce: &str, version: &str) {
+        let mut nodes = self.0.write();
+        let Some(status) = nodes.get_mut(node) else {
+            return;
+        };
+
+        let resource = status
+            .resources
+            .entry(resource_type.type_url().into())
+            .or_default();
+        resource.sent = Some(version.to_owned());
+        if resource.pending.len() == MAX_PENDING_RESPONSES {
+            resource.pending.pop_front();
+        }
+        resource
+            .pending
+            .push_back((nonce.to_owned(), version.to_owned()));
+    }
+
+    /// Records that `node` accepted, or rejected with `error`, the response
+    /// with `nonce`. `version` is the version from the request, which is used
+    /// if the response is no longer pending.
+    pub(crate) fn acknowledge(
+        &self,
+        node: &str,
+        resource_type: ResourceType,
+        nonce: &str,
+        version: &str,
+        error: Option<&str>,
+    ) {
+        let mut nodes = self.0.write();
+        let Some(status) = nodes.get_mut(node) else {
+            return;
+        };
+
+        let resource = status
+            .resources
+            .entry(resource_type.type_url().into())
+            .or_default();
+        let version = match resource
+            .pending
+            .iter()
+            .position(|(pending, _)| pending == nonce)
+        {
+            // Responses are acknowledged in order, so any earlier ones
+            // won't be.
+            Some(index) => resource.pending.drain(..=index).last().unwrap().1,
+            None => version.to_owned(),
+        };
+
+        let labels = [node, resource_type.type_url()];
+        match error {
+            Some(error) => {
+                metrics::NODE_NACKED.with_label_values(&labels).set(1);
+                resource.nacked = Some(Nacked {
+                    version,
+                    error: error.to_owned(),
+                    at: now(),
+                });
+            }
+            None => {
+                metrics::NODE_NACKED.with_label_values(&labels).set(0);
+                if let Ok(version) = version.parse() {
+                    metrics::NODE_ACKED_VERSION
+                        .with_label_values(&labels)
+                        .set(version);
+                }
+                resource.nacked = None;
+                resource.acked = Some(Acked { version, at: now() });
+            }
+        }
+    }
+
+    /// Returns the status of every connected node, by node ID.
+    pub fn list(&self) -> BTreeMap<String, NodeStatus> {
+        self.0.read().clone()
+    }
+
+    fn disconnect(&self, node: &str) {
+        let mut nodes = self.0.write();
+        let Some(status) = nodes.get_mut(node) else {
+            return;
+        };
+
+        status.streams -= 1;
+        if status.streams == 0 {
+            for type_url in status.resources.keys() {
+                metrics::NODE_NACKED
+                    .remove_label_values(&[node, type_url.as_str()])
+                    .ok();
+                metrics::NODE_ACKED_VERSION
+                    .remove_label_values(&[node, type_url.as_str()])
+                    .ok();
+            }
+            nodes.remove(node);
+        }
+    }
+}
+
+impl std::fmt::Debug for NodeRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("NodeRegistry")
+            .field("nodes", &self.0.read().len())
+            .finish()
+    }
+}
+
+/// An open xDS stream from a node, which is unregistered when dropped.
+pub(crate) struct NodeConnection {
+    registry: NodeRegistry,
+    node: String,
+}
+
+impl Drop for NodeConnection {
+    fn drop(&mut self) {
+        self.registry.disconnect(&self.node);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE: ResourceType = ResourceType::Endpoint;
+
+    #[test]
+    fn acknowledgements() {
+        let registry = NodeRegistry::default();
+        let connection = registry.connect("proxy");
+        let status =
+            |registry: &NodeRegistry| registry.list()["proxy"].resources[TYPE.type_url()].clone();
+
+        registry.sent("proxy", TYPE, "a", "1");
+        registry.sent("proxy", TYPE, "b", "2");
+        registry.acknowledge("proxy", TYPE, "a", "", None);
+        let resource = status(&registry);
+        assert_eq!(Some("2".into()), resource.sent);
+        assert_eq!("1", resource.acked.unwrap().version);
+        assert!(resource.nacked.is_none());
+
+        registry.acknowledge("proxy", TYPE, "b", "1", Some("invalid filter"));
+        let resource = status(&registry);
+        assert_eq!("1", resource.acked.as_ref().unwrap().version);
+        let nacked = resource.nacked.unwrap();
+        assert_eq!("2", nacked.version);
+        assert_eq!("invalid filter", nacked.error);
+        assert_eq!(
+            1,
+            metrics::NODE_NACKED
+                .with_label_values(&["proxy", TYPE.type_url()])
+                .get()
+        );
+
+        // Responses that are no longer pending use the request's version.
+        registry.sent("proxy", TYPE, "c", "3");
+        registry.acknowledge("proxy", TYPE, "unknown", "3", None);
+        let resource = status(&registry);
+        assert_eq!("3", resource.acked.unwrap().version);
+        assert!(resource.nacked.is_none());
+
+        // Nodes are only listed while they're connected.
+        let second = registry.connect("proxy");
+        assert_eq!(2, registry.list()["proxy"].streams);
+        drop(connection);
+        assert_eq!(1, registry.list()["proxy"].streams);
+        drop(second);
+        assert!(registry.list().is_empty());
+    }
+}
@@ -14,7 +14,10 @@
  * limitations under the License.
  */
 
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
 
 use cached::Cached;
 use futures::Stream;
@@ -22,39 +25,97 @@ use tokio_stream::StreamExt;
 use tracing_futures::Instrument;
 
 use crate::{
-    config::Config,
+    config::{Config, NodeInfo},
     xds::{
+        config::core::v3::Node,
         metrics,
         service::discovery::v3::{
             aggregated_discovery_service_server::{
                 AggregatedDiscoveryService, AggregatedDiscoveryServiceServer,
             },
             DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
+            Resource as DeltaResource,
         },
-        ResourceType,
+        NodeRegistry, ResourceType,
     },
 };
 
 #[tracing::instrument(skip_all)]
-pub async fn spawn(port: u16, config: std::sync::Arc<crate::Config>) -> crate::Result<()> {
-    let server = AggregatedDiscoveryServiceServer::new(ControlPlane::from_arc(config));
-    let server = tonic::transport::Server::builder().add_service(server);
-    tracing::info!("Serving management server at {}", port);
-    Ok(server
-        .serve((std::net::Ipv4Addr::UNSPECIFIED, port).into())
-        .await?)
+pub async fn spawn(
+    port: u16,
+    config: std::sync::Arc<crate::Config>,
+    tls: crate::xds::ServerTls,
+    nodes: NodeRegistry,
+) -> crate::Result<()> {
+    let acceptor = crate::xds::tls::Acceptor::new(tls)?;
+    let server = AggregatedDiscoveryServiceServer::new(ControlPlane::with_nodes(config, nodes));
+    let server = tonic::transport::Server::builder()
+        .add_service(server)
+        .add_service(crate::filters::global_rate_limit::service::GlobalRateLimitService::server());
+    let address = (std::net::Ipv4Addr::UNSPECIFIED, port).into();
+
+    match acceptor {
+        Some(acceptor) => {
+            tracing::info!("Serving management server with TLS at {}", port);
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            Ok(server
+                .serve_with_incoming(acceptor.incoming(listener))
+                .await?)
+        }
+        None => {
+            tracing::info!("Serving management server at {}", port);
+            Ok(server.serve(address).await?)
+        }
+    }
+}
+
+/// Returns the node IDs that the client of `request` may use, which are the
+/// identities of its certificate when it's connected with mutual TLS, and any
+/// node ID otherwise.
+fn authorized_nodes<T>(request: &tonic::Request<T>) -> Result<Option<Vec<String>>, tonic::Status> {
+    let Some(certificates) = request.peer_certs() else {
+        return Ok(None);
+    };
+
+    let certificate = certificates
+        .first()
+        .ok_or_else(|| tonic::Status::unauthenticated("no client certificate"))?;
+    crate::xds::tls::certificate_identities(certificate.as_ref())
+        .map(Some)
+        .map_err(|error| tonic::Status::unauthenticated(error.to_string()))
+}
+
+fn authorize(node: Option<&Node>, identities: Option<&[String]>) -> Result<(), tonic::Status> {
+    match (node, identities) {
+        (Some(node), Some(identities)) if !identities.contains(&node.id) => {
+            tracing::warn!(id = %node.id, ?identities, "node ID not allowed by client certificate");
+            Err(tonic::Status::permission_denied(format!(
+                "node ID `{}` is not allowed by the client certificate",
+                node.id
+            )))
+        }
+        _ => Ok(()),
+    }
 }
 
+/// The resource name which subscribes to every resource of a type.
+const WILDCARD: &str = "*";
+
 #[derive(Clone)]
 pub struct ControlPlane {
     config: Arc<Config>,
     watchers: Arc<crate::xds::resource::ResourceMap<Watchers>>,
+    nodes: NodeRegistry,
 }
 
 struct Watchers {
     sender: tokio::sync::watch::Sender<()>,
     receiver: tokio::sync::watch::Receiver<()>,
     version: std::sync::atomic::AtomicU64,
+    /// The resources sent to Delta xDS clients, by the node they're sent to
+    /// without its ID, as nodes with the same locality and metadata receive
+    /// the same resources.
+    resources: parking_lot::Mutex<HashMap<NodeInfo, Arc<VersionedResources>>>,
 }
 
 impl Default for Watchers {
@@ -64,10 +125,129 @@ impl Default for Watchers {
             sender,
             receiver,
             version: <_>::default(),
+            resources: <_>::default(),
         }
     }
 }
 
+/// The resources of one type along with their versions, which are computed
+/// once per change and shared by every Delta xDS stream they're sent on.
+struct VersionedResources {
+    /// The version of the resource type when they were computed.
+    version: u64,
+    resources: Vec<DeltaResource>,
+}
+
+/// The maximum number of responses that a Delta xDS client can leave
+/// unacknowledged, after which the oldest are treated as rejected.
+const MAX_PENDING_RESPONSES: usize = 50;
+
+/// The resources of one type that a Delta xDS client is subscribed to, and the
+/// versions of them that it has.
+#[derive(Debug, Default)]
+struct DeltaSubscription {
+    /// Whether the client is subscribed to every resource of the type.
+    wildcard: bool,
+    names: HashSet<String>,
+    /// The versions of the resources that the client has acknowledged.
+    versions: HashMap<String, String>,
+    /// The changes sent in the responses that the client hasn't acknowledged
+    /// yet, by nonce, where removed resources have no version.
+    pending: VecDeque<(String, HashMap<String, Option<String>>)>,
+}
+
+impl DeltaSubscription {
+    /// Creates a subscription from the first request for a resource type,
+    /// where not subscribing to any names subscribes to every resource.
+    fn new(request: &DeltaDiscoveryRequest) -> Self {
+        let mut this = Self {
+            wildcard: request.resource_names_subscribe.is_empty(),
+            versions: request
+                .initial_resource_versions
+                .clone()
+                .into_iter()
+                .collect(),
+            ..<_>::default()
+        };
+        this.update(request);
+        this
+    }
+
+    fn update(&mut self, request: &DeltaDiscoveryRequest) {
+        for name in &request.resource_names_subscribe {
+            if name == WILDCARD {
+                self.wildcard = true;
+            } else {
+                self.names.insert(name.clone());
+            }
+        }
+
+        for name in &request.resource_names_unsubscribe {
+            if name == WILDCARD {
+                self.wildcard = false;
+            } else {
+                self.names.remove(name);
+                // Clients forget unsubscribed resources themselves.
+                self.versions.remove(name);
+                for (_, changes) in &mut self.pending {
+                    changes.remove(name);
+                }
+            }
+        }
+    }
+
+    fn is_subscribed(&self, name: &str) -> bool {
+        self.wildcard || self.names.contains(name)
+    }
+
+    /// Returns the version of `name` that the client has once it has
+    /// acknowledged every response sent to it.
+    fn sent_version(&self, name: &str) -> Option<&str> {
+        self.pending
+            .iter()
+            .rev()
+            .find_map(|(_, changes)| changes.get(name))
+            .map(Option::as_deref)
+            .unwrap_or_else(|| self.versions.get(name).map(String::as_str))
+    }
+
+    /// Records the changes sent in the response with `nonce`.
+    fn sent(&mut self, nonce: String, changes: HashMap<String, Option<String>>) {
+        if self.pending.len() == MAX_PENDING_RESPONSES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((nonce, changes));
+    }
+
+    /// Commits the changes of the response with `nonce` if the client
+    /// `accepted` it, otherwise they're forgotten, so that the resources are
+    /// sent again with the next response.
+    fn acknowledge(&mut self, nonce: &str, accepted: bool) {
+        let Some(index) = self.pending.iter().position(|(pending, _)| pending == nonce) else {
+            return;
+        };
+
+        let (_, changes) = self.pending.remove(index).unwrap();
+        if accepted {
+            for (name, version) in changes {
+                match version {
+                    Some(version) => self.versions.insert(name, version),
+                    None => self.versions.remove(&name),
+                };
+            }
+        }
+    }
+}
+
+/// Returns the version of an encoded resource, which only changes when the
+/// resource does, and is the same across management servers and restarts.
+fn resource_version(resource: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, resource).as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
 impl ControlPlane {
     /// Creates a new server for managing [`Config`].
     pub fn new(config: Config) -> Self {
@@ -75,11 +255,27 @@ impl ControlPlane {
     }
 
     pub fn from_arc(config: Arc<Config>) -> Self {
+        Self::with_nodes(config, <_>::default())
+    }
+
+    /// Creates a new server for managing [`Config`], which records the status
+    /// of the nodes connected to it in `nodes`.
+    pub fn with_nodes(config: Arc<Config>, nodes: NodeRegistry) -> Self {
         let this = Self {
             config,
             watchers: <_>::default(),
+            nodes,
         };
 
+        this.config.nodes.watch({
+            let this = this.clone();
+            move |_| {
+                this.push_update(ResourceType::Endpoint);
+                this.push_update(ResourceType::Cluster);
+                this.push_update(ResourceType::Listener);
+            }
+        });
+
         this.config.clusters.watch({
             let this = this.clone();
             move |_| {
@@ -95,6 +291,27 @@ impl ControlPlane {
             }
         });
 
+        this.config.listeners.watch({
+            let this = this.clone();
+            move |_| {
+                this.push_update(ResourceType::Listener);
+            }
+        });
+
+        this.config.session.watch({
+            let this = this.clone();
+            move |_| {
+                this.push_update(ResourceType::Listener);
+            }
+        });
+
+        this.config.health_check.watch({
+            let this = this.clone();
+            move |_| {
+                this.push_update(ResourceType::Listener);
+            }
+        });
+
         this
     }
 
@@ -103,21 +320,23 @@ impl ControlPlane {
         watchers
             .version
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        watchers.resources.lock().clear();
         tracing::trace!(%resource_type, watchers=watchers.sender.receiver_count(), "pushing update");
         if let Err(error) = watchers.sender.send(()) {
             tracing::warn!(%error, "pushing update failed");
         }
     }
 
+    #[tracing::instrument(skip_all, fields(node = &*node.id, r#type = %resource_type))]
     fn discovery_response(
         &self,
-        id: &str,
+        node: &NodeInfo,
         resource_type: ResourceType,
         names: &[String],
     ) -> Result<DiscoveryResponse, tonic::Status> {
         let mut response = self
             .config
-            .discovery_request(id, resource_type, names)
+            .discovery_request(node, resource_type, names)
             .map_err(|error| tonic::Status::internal(error.to_string()))?;
         let watchers = &self.watchers[resource_type];
 
@@ -130,6 +349,12 @@ impl ControlPlane {
             identifier: (*self.config.id.load()).clone(),
         });
         response.nonce = nonce.to_string();
+        self.nodes.sent(
+            &node.id,
+            resource_type,
+            &response.nonce,
+            &response.version_info,
+        );
 
         tracing::trace!(
             id = &*response.version_info,
@@ -141,6 +366,283 @@ impl ControlPlane {
         Ok(response)
     }
 
+    /// Returns every resource of `resource_type` that `node` receives, along
+    /// with their versions, which are shared with the other nodes with the
+    /// same locality and metadata until the resources change.
+    fn versioned_resources(
+        &self,
+        node: &NodeInfo,
+        resource_type: ResourceType,
+    ) -> crate::Result<Arc<VersionedResources>> {
+        let watchers = &self.watchers[resource_type];
+        let version = watchers.version.load(std::sync::atomic::Ordering::Relaxed);
+        let key = NodeInfo {
+            id: String::new(),
+            ..node.clone()
+        };
+        if let Some(resources) = watchers
+            .resources
+            .lock()
+            .get(&key)
+            .filter(|resources| resources.version == version)
+        {
+            return Ok(resources.clone());
+        }
+
+        // Clusters are only returned by name, and Delta xDS clients may be
+        // subscribed to all of them.
+        let names = match resource_type {
+            ResourceType::Cluster => self.config.clusters.load().keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+        let resources = self
+            .config
+            .discovery_request(node, resource_type, &names)?
+            .resources
+            .into_iter()
+            .map(|resource| {
+                Ok(DeltaResource {
+                    name: crate::xds::Resource::try_from(resource.clone())?
+                        .name()
+                        .to_owned(),
+                    version: resource_version(&resource.value),
+                    resource: Some(resource),
+                    ..<_>::default()
+                })
+            })
+            .collect::<crate::Result<_>>()?;
+
+        let resources = Arc::new(VersionedResources { version, resources });
+        watchers.resources.lock().insert(key, resources.clone());
+        Ok(resources)
+    }
+
+    /// Returns the resources of `resource_type` that have been added or
+    /// changed since they were last sent to a Delta xDS client with
+    /// `subscription`, along with the ones that have been removed, recording
+    /// them in `subscription` until the client acknowledges them. Returns
+    /// `None` when nothing has changed, unless the response is `required`.
+    #[tracing::instrument(skip_all, fields(node = &*node.id, r#type = %resource_type))]
+    fn delta_discovery_response(
+        &self,
+        node: &NodeInfo,
+        resource_type: ResourceType,
+        subscription: &mut DeltaSubscription,
+        required: bool,
+    ) -> Result<Option<DeltaDiscoveryResponse>, tonic::Status> {
+        let snapshot = self
+            .versioned_resources(node, resource_type)
+            .map_err(|error| tonic::Status::internal(error.to_string()))?;
+
+        let mut changes = HashMap::new();
+        let mut resources = Vec::new();
+        let mut current = HashSet::new();
+        for resource in &snapshot.resources {
+            if !subscription.is_subscribed(&resource.name) {
+                continue;
+            }
+
+            current.insert(&*resource.name);
+            if subscription.sent_version(&resource.name) != Some(&*resource.version) {
+                changes.insert(resource.name.clone(), Some(resource.version.clone()));
+                resources.push(resource.clone());
+            }
+        }
+
+        let removed_resources = subscription
+            .versions
+            .keys()
+            .chain(
+                subscription
+                    .pending
+                    .iter()
+                    .flat_map(|(_, changes)| changes.keys()),
+            )
+            .filter(|name| !current.contains(name.as_str()))
+            .filter(|name| subscription.sent_version(name).is_some())
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        for name in &removed_resources {
+            changes.insert(name.clone(), None);
+        }
+
+        if resources.is_empty() && removed_resources.is_empty() && !required {
+            return Ok(None);
+        }
+
+        let response = DeltaDiscoveryResponse {
+            system_version_info: snapshot.version.to_string(),
+            resources,
+            type_url: resource_type.type_url().into(),
+            removed_resources,
+            nonce: uuid::Uuid::new_v4().to_string(),
+            control_plane: Some(crate::xds::config::core::v3::ControlPlane {
+                identifier: (*self.config.id.load()).clone(),
+            }),
+            ..<_>::default()
+        };
+        subscription.sent(response.nonce.clone(), changes);
+
+        tracing::trace!(
+            id = &*response.system_version_info,
+            r#type = &*response.type_url,
+            nonce = &*response.nonce,
+            added = response.resources.len(),
+            removed = response.removed_resources.len(),
+            "delta discovery response"
+        );
+        self.nodes.sent(
+            &node.id,
+            resource_type,
+            &response.nonce,
+            &response.system_version_info,
+        );
+
+        Ok(Some(response))
+    }
+
+    /// Waits until any of the resource types in `receivers` has changed.
+    async fn next_change(
+        receivers: &mut HashMap<ResourceType, tokio::sync::watch::Receiver<()>>,
+    ) -> ResourceType {
+        if receivers.is_empty() {
+            return std::future::pending().await;
+        }
+
+        let changes = receivers.iter_mut().map(|(resource_type, receiver)| {
+            Box::pin(async move {
+                // The senders live as long as the control plane, so this
+                // can't fail.
+                receiver.changed().await.ok();
+                *resource_type
+            })
+        });
+
+        futures::future::select_all(changes).await.0
+    }
+
+    pub async fn delta_aggregated_resources<S>(
+        &self,
+        mut streaming: S,
+    ) -> Result<
+        impl Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>> + Send,
+        tonic::Status,
+    >
+    where
+        S: Stream<Item = Result<DeltaDiscoveryRequest, tonic::Status>>
+            + Send
+            + std::marker::Unpin
+            + 'static,
+    {
+        tracing::trace!("starting delta stream");
+        let message = streaming.next().await.ok_or_else(|| {
+            tracing::error!("No message found");
+            tonic::Status::invalid_argument("No message found")
+        })??;
+
+        let Some(node) = message.node.clone() else {
+            tracing::error!("Node identifier was not found");
+            return Err(tonic::Status::invalid_argument("Node identifier required"));
+        };
+
+        // The first request is handled along with the rest of the stream, as
+        // any request can subscribe to a new resource type.
+        let mut streaming = futures::stream::iter([Ok(message)]).chain(streaming);
+        let mut subscriptions = HashMap::<ResourceType, DeltaSubscription>::new();
+        let mut receivers = HashMap::new();
+        let mut pending_acks = cached::TimedSizedCache::with_size_and_lifespan(50, 1);
+        let this = Self::clone(self);
+        let info = NodeInfo::from(&node);
+        let id = node.id.clone();
+        let connection = this.nodes.connect(&id);
+
+        Ok(Box::pin(async_stream::try_stream! {
+            let _connection = connection;
+            let _span = tracing::trace_span!("delta stream loop");
+            loop {
+                tokio::select! {
+                    resource_type = Self::next_change(&mut receivers) => {
+                        let Some(subscription) = subscriptions.get_mut(&resource_type) else {
+                            continue;
+                        };
+
+                        if let Some(response) = this.delta_discovery_response(&info, resource_type, subscription, false)? {
+                            tracing::trace!("sending new delta discovery response");
+                            pending_acks.cache_set(response.nonce.clone(), ());
+                            yield response;
+                        }
+                    }
+                    new_message = streaming.next() => {
+                        let new_message = match new_message.transpose() {
+                            Ok(Some(value)) => value,
+                            Ok(None) => break,
+                            Err(error) => {
+                                tracing::error!(%error, "error receiving delta request");
+                                continue;
+                            }
+                        };
+
+                        let resource_type = match new_message.type_url.parse::<ResourceType>() {
+                            Ok(value @ (ResourceType::Cluster | ResourceType::Endpoint | ResourceType::Listener)) => value,
+                            Ok(value) => {
+                                tracing::error!(resource_type = %value, "unsupported resource type");
+                                continue;
+                            }
+                            Err(error) => {
+                                tracing::error!(%error, "unknown resource type");
+                                continue;
+                            }
+                        };
+
+                        tracing::trace!(%resource_type, "new delta request");
+                        metrics::DISCOVERY_REQUESTS.with_label_values(&[&*id, resource_type.type_url()]).inc();
+
+                        if let Some(error) = &new_message.error_detail {
+                            metrics::NACKS.with_label_values(&[&*id, resource_type.type_url()]).inc();
+                            tracing::error!(nonce = %new_message.response_nonce, ?error, "NACK");
+                            this.nodes.acknowledge(&id, resource_type, &new_message.response_nonce, "", Some(&error.message));
+                            if let Some(subscription) = subscriptions.get_mut(&resource_type) {
+                                subscription.acknowledge(&new_message.response_nonce, false);
+                            }
+                        } else if !new_message.response_nonce.is_empty() {
+                            this.nodes.acknowledge(&id, resource_type, &new_message.response_nonce, "", None);
+                            if let Some(subscription) = subscriptions.get_mut(&resource_type) {
+                                subscription.acknowledge(&new_message.response_nonce, true);
+                            }
+                            if pending_acks.cache_get(&new_message.response_nonce).is_some() {
+                                tracing::info!(nonce = %new_message.response_nonce, "ACK");
+                            } else {
+                                tracing::trace!(nonce = %new_message.response_nonce, "Unknown nonce: could not be found in cache");
+                            }
+                        }
+
+                        // Only new subscriptions need a response, any changes
+                        // are sent as they happen.
+                        let subscribed = !subscriptions.contains_key(&resource_type);
+                        let subscription = subscriptions
+                            .entry(resource_type)
+                            .and_modify(|subscription| subscription.update(&new_message))
+                            .or_insert_with(|| DeltaSubscription::new(&new_message));
+                        receivers
+                            .entry(resource_type)
+                            .or_insert_with(|| this.watchers[resource_type].receiver.clone());
+
+                        if subscribed || !new_message.resource_names_subscribe.is_empty() {
+                            if let Some(response) = this.delta_discovery_response(&info, resource_type, subscription, subscribed)? {
+                                pending_acks.cache_set(response.nonce.clone(), ());
+                                yield response;
+                            }
+                        }
+                    }
+                }
+            }
+
+            tracing::info!("terminating delta stream");
+        }.instrument(tracing::info_span!("delta_xds_stream", %node.id))))
+    }
+
     pub async fn stream_aggregated_resources<S>(
         &self,
         mut streaming: S,
@@ -171,11 +673,13 @@ impl ControlPlane {
         let mut rx = self.watchers[resource_type].receiver.clone();
         let mut pending_acks = cached::TimedSizedCache::with_size_and_lifespan(50, 1);
         let this = Self::clone(self);
-        let response = this.discovery_response(&node.id, resource_type, &message.resource_names)?;
+        let connection = this.nodes.connect(&node.id);
+        let info = NodeInfo::from(&node);
+        let response = this.discovery_response(&info, resource_type, &message.resource_names)?;
         pending_acks.cache_set(response.nonce.clone(), ());
 
-        let id = node.id.clone();
         Ok(Box::pin(async_stream::try_stream! {
+            let _connection = connection;
             yield response;
 
             let _span = tracing::trace_span!("stream loop");
@@ -183,7 +687,7 @@ impl ControlPlane {
                 tokio::select! {
                     _ = rx.changed() => {
                         tracing::trace!("sending new discovery response");
-                        yield this.discovery_response(&id, resource_type, &message.resource_names).map(|response| {
+                        yield this.discovery_response(&info, resource_type, &message.resource_names).map(|response| {
                             pending_acks.cache_set(response.nonce.clone(), ());
                             response
                         })?;
@@ -198,7 +702,9 @@ impl ControlPlane {
                             }
                         };
 
-                        let id = new_message.node.as_ref().map(|node| &*node.id).unwrap_or(&*id);
+                        let new_info = new_message.node.as_ref().map(NodeInfo::from);
+                        let info = new_info.as_ref().unwrap_or(&info);
+                        let id = &*info.id;
                         let resource_type = match new_message.type_url.parse::<ResourceType>() {
                             Ok(value) => value,
                             Err(error) => {
@@ -213,8 +719,10 @@ impl ControlPlane {
                         if let Some(error) = &new_message.error_detail {
                             metrics::NACKS.with_label_values(&[id, resource_type.type_url()]).inc();
                             tracing::error!(nonce = %new_message.response_nonce, ?error, "NACK");
+                            this.nodes.acknowledge(id, resource_type, &new_message.response_nonce, &new_message.version_info, Some(&error.message));
                             // Currently just resend previous discovery response.
                         } else if uuid::Uuid::parse_str(&new_message.response_nonce).is_ok() {
+                            this.nodes.acknowledge(id, resource_type, &new_message.response_nonce, &new_message.version_info, None);
                             if pending_acks.cache_get(&new_message.response_nonce).is_some() {
                                 tracing::info!(nonce = %new_message.response_nonce, "ACK");
                                 continue
@@ -224,7 +732,7 @@ impl ControlPlane {
                             }
                         }
 
-                        yield this.discovery_response(id, resource_type, &message.resource_names).map(|response| {
+                        yield this.discovery_response(info, resource_type, &message.resource_names).map(|response| {
                             pending_acks.cache_set(response.nonce.clone(), ());
                             response
                         }).unwrap();
@@ -242,27 +750,54 @@ impl AggregatedDiscoveryService for ControlPlane {
     type StreamAggregatedResourcesStream =
         std::pin::Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, tonic::Status>> + Send>>;
     type DeltaAggregatedResourcesStream =
-        tokio_stream::wrappers::ReceiverStream<Result<DeltaDiscoveryResponse, tonic::Status>>;
+        std::pin::Pin<Box<dyn Stream<Item = Result<DeltaDiscoveryResponse, tonic::Status>> + Send>>;
 
     #[tracing::instrument(skip_all)]
     async fn stream_aggregated_resources(
         &self,
         request: tonic::Request<tonic::Streaming<DiscoveryRequest>>,
     ) -> Result<tonic::Response<Self::StreamAggregatedResourcesStream>, tonic::Status> {
-        Ok(tonic::Response::new(Box::pin(
-            self.stream_aggregated_resources(request.into_inner())
+        crate::telemetry::set_parent(&tracing::Span::current(), request.metadata());
+        let identities = authorized_nodes(&request)?;
+        let streaming = request
+            .into_inner()
+            .map(move |request| -> Result<_, tonic::Status> {
+                let request = request?;
+                authorize(request.node.as_ref(), identities.as_deref())?;
+                Ok(request)
+            });
+
+        let mut response = tonic::Response::new(Box::pin(
+            self.stream_aggregated_resources(streaming)
                 .in_current_span()
                 .await?,
-        )))
+        ) as Self::StreamAggregatedResourcesStream);
+        crate::telemetry::inject(response.metadata_mut());
+        Ok(response)
     }
 
+    #[tracing::instrument(skip_all)]
     async fn delta_aggregated_resources(
         &self,
-        _request: tonic::Request<tonic::Streaming<DeltaDiscoveryRequest>>,
+        request: tonic::Request<tonic::Streaming<DeltaDiscoveryRequest>>,
     ) -> Result<tonic::Response<Self::DeltaAggregatedResourcesStream>, tonic::Status> {
-        Err(tonic::Status::unimplemented(
-            "Quilkin doesn't currently support Delta xDS",
-        ))
+        crate::telemetry::set_parent(&tracing::Span::current(), request.metadata());
+        let identities = authorized_nodes(&request)?;
+        let streaming = request
+            .into_inner()
+            .map(move |request| -> Result<_, tonic::Status> {
+                let request = request?;
+                authorize(request.node.as_ref(), identities.as_deref())?;
+                Ok(request)
+            });
+
+        let mut response = tonic::Response::new(Box::pin(
+            self.delta_aggregated_resources(streaming)
+                .in_current_span()
+                .await?,
+        ) as Self::DeltaAggregatedResourcesStream);
+        crate::telemetry::inject(response.metadata_mut());
+        Ok(response)
     }
 }
 
@@ -283,6 +818,14 @@ mod tests {
 
     const TIMEOUT_DURATION: std::time::Duration = std::time::Duration::from_secs(10);
 
+    async fn next<T>(stream: &mut (impl Stream<Item = Result<T, tonic::Status>> + Unpin)) -> T {
+        timeout(TIMEOUT_DURATION, stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn valid_response() {
         const RESOURCE: ResourceType = ResourceType::Endpoint;
@@ -392,4 +935,193 @@ mod tests {
             .unwrap()
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn delta_response() {
+        const RESOURCE: ResourceType = ResourceType::Endpoint;
+
+        let endpoint = |port| {
+            crate::endpoint::LocalityEndpoints::from(crate::endpoint::Endpoint::new(
+                (std::net::Ipv4Addr::LOCALHOST, port).into(),
+            ))
+        };
+        let config = Arc::new(Config::default());
+        config.clusters.modify(|clusters| {
+            clusters.insert(crate::cluster::Cluster::new(
+                "a".into(),
+                vec![endpoint(4321)],
+            ));
+            clusters.insert(crate::cluster::Cluster::new(
+                "b".into(),
+                vec![endpoint(4322)],
+            ));
+        });
+        let control_plane = ControlPlane::from_arc(config.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+
+        let request = DeltaDiscoveryRequest {
+            node: Some(Node {
+                id: "quilkin".into(),
+                user_agent_name: "quilkin".into(),
+                ..Node::default()
+            }),
+            type_url: RESOURCE.type_url().into(),
+            ..<_>::default()
+        };
+        tx.send(Ok(request.clone())).await.unwrap();
+
+        let mut stream = timeout(
+            TIMEOUT_DURATION,
+            control_plane
+                .delta_aggregated_resources(tokio_stream::wrappers::ReceiverStream::new(rx)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        // Every resource is sent when subscribing to them.
+        let message = next(&mut stream).await;
+        let mut names = message
+            .resources
+            .iter()
+            .map(|resource| &*resource.name)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(vec!["a", "b"], names);
+        assert!(message.removed_resources.is_empty());
+        let versions = message
+            .resources
+            .iter()
+            .map(|resource| (resource.name.clone(), resource.version.clone()))
+            .collect::<std::collections::HashMap<_, _>>();
+
+        // Only the resources that changed are sent after that.
+        config.clusters.modify(|clusters| {
+            clusters.get_mut("b").unwrap().insert(endpoint(4323));
+        });
+        let message = next(&mut stream).await;
+        assert_eq!(1, message.resources.len());
+        assert_eq!("b", message.resources[0].name);
+        assert_ne!(versions["b"], message.resources[0].version);
+        assert!(message.removed_resources.is_empty());
+        let version = message.resources[0].version.clone();
+
+        config.clusters.modify(|clusters| {
+            clusters.remove("a");
+        });
+        let message = next(&mut stream).await;
+        assert!(message.resources.is_empty());
+        assert_eq!(vec!["a".to_owned()], message.removed_resources);
+
+        // Clients which reconnect are only sent the differences from the
+        // resources they already have.
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+        tx.send(Ok(DeltaDiscoveryRequest {
+            initial_resource_versions: [
+                ("a".to_owned(), versions["a"].clone()),
+                ("b".to_owned(), version),
+            ]
+            .into(),
+            ..request
+        }))
+        .await
+        .unwrap();
+        let mut stream = control_plane
+            .delta_aggregated_resources(tokio_stream::wrappers::ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        let message = next(&mut stream).await;
+        assert!(message.resources.is_empty());
+        assert_eq!(vec!["a".to_owned()], message.removed_resources);
+    }
+
+    #[tokio::test]
+    async fn delta_nack() {
+        const RESOURCE: ResourceType = ResourceType::Endpoint;
+        const ACK_DURATION: std::time::Duration = std::time::Duration::from_millis(50);
+
+        let endpoint = |port| {
+            crate::endpoint::LocalityEndpoints::from(crate::endpoint::Endpoint::new(
+                (std::net::Ipv4Addr::LOCALHOST, port).into(),
+            ))
+        };
+        let config = Arc::new(Config::default());
+        config.clusters.modify(|clusters| {
+            clusters.insert(crate::cluster::Cluster::new(
+                "a".into(),
+                vec![endpoint(4321)],
+            ));
+        });
+        let control_plane = ControlPlane::from_arc(config.clone());
+        let (tx, rx) = tokio::sync::mpsc::channel(256);
+
+        tx.send(Ok(DeltaDiscoveryRequest {
+            node: Some(Node {
+                id: "quilkin".into(),
+                ..Node::default()
+            }),
+            type_url: RESOURCE.type_url().into(),
+            ..<_>::default()
+        }))
+        .await
+        .unwrap();
+        let mut stream = control_plane
+            .delta_aggregated_resources(tokio_stream::wrappers::ReceiverStream::new(rx))
+            .await
+            .unwrap();
+
+        let message = next(&mut stream).await;
+        assert_eq!("a", message.resources[0].name);
+        tx.send(Ok(DeltaDiscoveryRequest {
+            type_url: RESOURCE.type_url().into(),
+            response_nonce: message.nonce,
+            error_detail: Some(crate::xds::google::rpc::Status {
+                code: 3,
+                message: "rejected".into(),
+                ..<_>::default()
+            }),
+            ..<_>::default()
+        }))
+        .await
+        .unwrap();
+        // Polls the stream until the NACK has been handled.
+        assert!(timeout(ACK_DURATION, stream.next()).await.is_err());
+
+        // The rejected resource is sent again along with the new one.
+        config.clusters.modify(|clusters| {
+            clusters.insert(crate::cluster::Cluster::new(
+                "b".into(),
+                vec![endpoint(4322)],
+            ));
+        });
+        let message = next(&mut stream).await;
+        let mut names = message
+            .resources
+            .iter()
+            .map(|resource| &*resource.name)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(vec!["a", "b"], names);
+        tx.send(Ok(DeltaDiscoveryRequest {
+            type_url: RESOURCE.type_url().into(),
+            response_nonce: message.nonce,
+            ..<_>::default()
+        }))
+        .await
+        .unwrap();
+        assert!(timeout(ACK_DURATION, stream.next()).await.is_err());
+
+        // Accepted resources aren't.
+        config.clusters.modify(|clusters| {
+            clusters.get_mut("b").unwrap().insert(endpoint(4323));
+        });
+        let message = next(&mut stream).await;
+        assert_eq!(1, message.resources.len());
+        assert_eq!("b", message.resources[0].name);
+    }
+
+    #[test]
+    fn stable_resource_version() {
+        assert_eq!("ba7816bf8f01cfea", resource_version(b"abc"));
+    }
 }
@@ -0,0 +1,378 @@
+/*
+ * Copyright 2023 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! TLS for the xDS connections between proxies and management servers.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures::Stream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{self, server::AllowAnyAuthenticatedClient, RootCertStore},
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+/// How long a client has to complete its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of TLS handshakes in progress at once, after which new
+/// connections wait to be accepted.
+const MAX_CONCURRENT_HANDSHAKES: usize = 128;
+
+/// The TLS configuration of a management server.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ServerTls {
+    /// The PEM encoded certificate chain that the management server presents
+    /// to proxies. xDS is served over TLS when this is set.
+    #[clap(long = "xds-tls-cert", env = "QUILKIN_XDS_TLS_CERT", requires = "key")]
+    pub cert: Option<PathBuf>,
+    /// The PEM encoded private key of `--xds-tls-cert`.
+    #[clap(long = "xds-tls-key", env = "QUILKIN_XDS_TLS_KEY", requires = "cert")]
+    pub key: Option<PathBuf>,
+    /// The PEM encoded certificates of the CAs that sign the certificates of
+    /// proxies. When set, proxies must present a certificate (mutual TLS), and
+    /// can only use the node IDs that its identity allows.
+    #[clap(long = "xds-tls-ca", env = "QUILKIN_XDS_TLS_CA", requires = "cert")]
+    pub ca: Option<PathBuf>,
+}
+
+/// The TLS configuration proxies use to connect to `https` management
+/// servers, and to `https` rate limit services.
+#[derive(
+    clap::Args,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct ClientTls {
+    /// The PEM encoded certificates of the CAs that sign the certificates of
+    /// management servers. The web PKI roots are used if not set.
+    #[clap(long = "xds-tls-ca", env = "QUILKIN_XDS_TLS_CA")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,
+    /// The PEM encoded certificate chain that the proxy presents to
+    /// management servers which require mutual TLS.
+    #[clap(long = "xds-tls-cert", env = "QUILKIN_XDS_TLS_CERT", requires = "key")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    /// The PEM encoded private key of `--xds-tls-cert`.
+    #[clap(long = "xds-tls-key", env = "QUILKIN_XDS_TLS_KEY", requires = "cert")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+}
+
+impl ClientTls {
+    /// Whether none of the files are set.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ca.is_none() && self.cert.is_none() && self.key.is_none()
+    }
+
+    /// Returns the TLS configuration for connecting to a management server.
+    /// The files are read on every call, so that certificates which have been
+    /// renewed are used when reconnecting.
+    pub(crate) fn config(&self) -> crate::Result<ClientTlsConfig> {
+        let mut config = ClientTlsConfig::new();
+
+        if let Some(ca) = &self.ca {
+            config = config.ca_certificate(Certificate::from_pem(read(ca)?));
+        }
+
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            config = config.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+
+        Ok(config)
+    }
+}
+
+impl ServerTls {
+    fn server_config(&self) -> crate::Result<rustls::ServerConfig> {
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            return Err(eyre::eyre!("TLS requires both a certificate and a key"));
+        };
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in certificates(ca)? {
+                    roots.add(&certificate).map_err(|error| {
+                        eyre::eyre!("invalid CA certificate in {}: {error:?}", ca.display())
+                    })?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certificates(cert)?, private_key(key)?)?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(config)
+    }
+
+    /// Returns when each of the files was last modified, which is `None` for
+    /// the ones that can't currently be read.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.cert, &self.key, &self.ca]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Accepts TLS connections with the certificates of a [`ServerTls`], which
+/// are reloaded when their files change.
+pub(crate) struct Acceptor {
+    tls: ServerTls,
+    current: parking_lot::Mutex<(Vec<Option<SystemTime>>, TlsAcceptor)>,
+}
+
+impl Acceptor {
+    /// Returns `None` if `tls` doesn't have a certificate, in which case xDS
+    /// is served without TLS.
+    pub(crate) fn new(tls: ServerTls) -> crate::Result<Option<Self>> {
+        if tls.cert.is_none() {
+            return Ok(None);
+        }
+
+        let modified = tls.modified();
+        let acceptor = TlsAcceptor::from(Arc::new(tls.server_config()?));
+        Ok(Some(Self {
+            tls,
+            current: parking_lot::Mutex::new((modified, acceptor)),
+        }))
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        let modified = self.tls.modified();
+        let mut current = self.current.lock();
+        if current.0 != modified {
+            // The previous certificates are kept when the new ones are
+            // invalid, such as while they're only partially written.
+            match self.tls.server_config() {
+                Ok(config) => {
+                    tracing::info!("reloaded xDS TLS certificates");
+                    *current = (modified, TlsAcceptor::from(Arc::new(config)));
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "failed to reload xDS TLS certificates");
+                }
+            }
+        }
+
+        current.1.clone()
+    }
+
+    /// Returns the TLS connections accepted by `listener`, performing each
+    /// handshake separately so that one slow client can't block the others.
+    /// Handshakes that take longer than [`HANDSHAKE_TIMEOUT`] are abandoned.
+    pub(crate) fn incoming(
+        self,
+        listener: TcpListener,
+    ) -> impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let handshakes = Arc::new(tokio::sync::Semaphore::new(MAX_CONCURRENT_HANDSHAKES));
+        tokio::spawn(async move {
+            loop {
+                let permit = match handshakes.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+
+                let (stream, address) = tokio::select! {
+                    result = listener.accept() => match result {
+                        Ok(value) => value,
+                        Err(error) => {
+                            tracing::warn!(%error, "failed to accept xDS connection");
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => break,
+                };
+
+                let acceptor = self.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            tx.send(Ok(stream)).ok();
+                        }
+                        Ok(Err(error)) => {
+                            tracing::warn!(%error, %address, "xDS TLS handshake failed");
+                        }
+                        Err(_) => {
+                            tracing::warn!(%address, "xDS TLS handshake timed out");
+                        }
+                    }
+                });
+            }
+        });
+
+        tokio_stream::wrappers::UnboundedReceiverStream::new(rx)
+    }
+}
+
+/// Returns the identities of a DER encoded client certificate, which are the
+/// DNS names and URIs in its subject alternative names, and its common name.
+/// A proxy can only use the node IDs that match one of these.
+pub(crate) fn certificate_identities(certificate: &[u8]) -> crate::Result<Vec<String>> {
+    use x509_parser::extensions::GeneralName;
+
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate)
+        .map_err(|error| eyre::eyre!("invalid certificate: {error}"))?;
+
+    let mut identities = Vec::new();
+    if let Ok(Some(names)) = certificate.subject_alternative_name() {
+        for name in &names.value.general_names {
+            if let GeneralName::DNSName(name) | GeneralName::URI(name) = name {
+                identities.push((*name).to_owned());
+            }
+        }
+    }
+
+    identities.extend(
+        certificate
+            .subject()
+            .iter_common_name()
+            .filter_map(|name| name.as_str().ok())
+            .map(String::from),
+    );
+
+    Ok(identities)
+}
+
+fn read(path: &Path) -> crate::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|error| eyre::eyre!("failed to read {}: {error}", path.display()))
+}
+
+fn certificates(path: &Path) -> crate::Result<Vec<rustls::Certificate>> {
+    let certificates = rustls_pemfile::certs(&mut &*read(path)?)?;
+    if certificates.is_empty() {
+        return Err(eyre::eyre!("no certificates found in {}", path.display()));
+    }
+
+    Ok(certificates.into_iter().map(rustls::Certificate).collect())
+}
+
+fn private_key(path: &Path) -> crate::Result<rustls::PrivateKey> {
+    rustls_pemfile::read_all(&mut &*read(path)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| eyre::eyre!("no private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+
+    use super::*;
+
+    /// Generates a CA in `directory`, and certificates signed by it for each
+    /// of `names`, returning the path of the CA's certificate.
+    pub(crate) fn generate(directory: &Path, names: &[&str]) -> PathBuf {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let ca_path = directory.join("ca.pem");
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+        for name in names {
+            let mut params = CertificateParams::new(vec![(*name).to_owned()]);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, format!("{name}-cn"));
+            let certificate = rcgen::Certificate::from_params(params).unwrap();
+            std::fs::write(
+                directory.join(format!("{name}.pem")),
+                certificate.serialize_pem_with_signer(&ca).unwrap(),
+            )
+            .unwrap();
+            std::fs::write(
+                directory.join(format!("{name}.key")),
+                certificate.serialize_private_key_pem(),
+            )
+            .unwrap();
+        }
+
+        ca_path
+    }
+
+    #[test]
+    fn identities() {
+        let certificate = rcgen::generate_simple_self_signed(vec![
+            "proxy-a".into(),
+            "spiffe://quilkin/proxy-b".into(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                "proxy-a",
+                "spiffe://quilkin/proxy-b",
+                "rcgen self signed cert"
+            ],
+            certificate_identities(&certificate.serialize_der().unwrap()).unwrap()
+        );
+        assert!(certificate_identities(b"not a certificate").is_err());
+    }
+
+    #[test]
+    fn reload() {
+        let directory = tempdir::TempDir::new("tls").unwrap();
+        let ca = generate(directory.path(), &["localhost"]);
+        let tls = ServerTls {
+            cert: Some(directory.path().join("localhost.pem")),
+            key: Some(directory.path().join("localhost.key")),
+            ca: Some(ca),
+        };
+        let acceptor = Acceptor::new(tls.clone()).unwrap().unwrap();
+
+        // Invalid certificates are ignored until they're valid again.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        std::fs::write(tls.cert.as_ref().unwrap(), "").unwrap();
+        acceptor.acceptor();
+        assert_ne!(tls.modified(), acceptor.current.lock().0);
+
+        generate(directory.path(), &["localhost"]);
+        acceptor.acceptor();
+        assert_eq!(tls.modified(), acceptor.current.lock().0);
+
+        assert!(Acceptor::new(ServerTls::default()).unwrap().is_none());
+    }
+}